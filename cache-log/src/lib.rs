@@ -1,7 +1,11 @@
 use std::{
-    fs::File,
-    io::{BufRead, BufReader, BufWriter, Write as _},
+    fmt,
+    fs::{File, Permissions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    os::unix::fs::PermissionsExt as _,
     path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
 };
 
 use anyhow::Context;
@@ -10,6 +14,64 @@ use fd_lock::RwLock;
 use serde::{Deserialize, Serialize};
 
 const LOG_FILE_NAME: &str = "hope-log.jsonl";
+const BINARY_LOG_FILE_NAME: &str = "hope-log.cbor";
+
+/// On-disk encoding for the cache log.
+///
+/// JSON lines are the default because they're easy to poke at with
+/// standard tools. The binary format is a plain CBOR sequence (RFC 8742),
+/// which is considerably smaller and faster to parse once a shared
+/// cache has accumulated a lot of history.
+///
+/// Each format lives in its own file, so switching formats never
+/// corrupts an existing log.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Jsonl,
+    Cbor,
+}
+
+impl LogFormat {
+    /// Read the log format from the `HOPE_LOG_FORMAT` environment variable,
+    /// defaulting to JSON lines if it isn't set.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("HOPE_LOG_FORMAT") {
+            Ok(format) => format
+                .parse()
+                .context("Invalid 'HOPE_LOG_FORMAT' environment variable"),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    fn file_name(self) -> &'static str {
+        match self {
+            Self::Jsonl => LOG_FILE_NAME,
+            Self::Cbor => BINARY_LOG_FILE_NAME,
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Self::Jsonl),
+            "cbor" => Ok(Self::Cbor),
+            _ => anyhow::bail!("Unrecognised log format \"{s}\""),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Jsonl => f.write_str("jsonl"),
+            Self::Cbor => f.write_str("cbor"),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum CacheLogLine {
@@ -17,6 +79,36 @@ pub enum CacheLogLine {
     PushedCrateOutputs(PushCrateOutputsEvent),
     RanBuildScript(BuildScriptRunEvent),
     RanBuildScriptWrapper(BuildScriptWrapperRunEvent),
+    SkippedPush(SkipPushEvent),
+    CheckedPortability(PortabilityCheckEvent),
+    CompiledCrate(CompileCrateEvent),
+    AbandonedPull(AbandonPullEvent),
+    DetectedClockSkew(ClockSkewEvent),
+    UnrecognisedLayout(UnrecognisedLayoutEvent),
+    SessionStarted(SessionStartedEvent),
+    ExhaustedSessionBudget(SessionBudgetEvent),
+    FellBackToLocal(RemoteFallbackEvent),
+}
+
+impl CacheLogLine {
+    /// When the event happened.
+    pub fn timestamp(&self) -> chrono::DateTime<Utc> {
+        match self {
+            Self::PulledCrateOutputs(event) => event.copied_at,
+            Self::PushedCrateOutputs(event) => event.copied_at,
+            Self::RanBuildScript(event) => event.ran_at,
+            Self::RanBuildScriptWrapper(event) => event.ran_at,
+            Self::SkippedPush(event) => event.skipped_at,
+            Self::CheckedPortability(event) => event.checked_at,
+            Self::CompiledCrate(event) => event.compiled_at,
+            Self::AbandonedPull(event) => event.abandoned_at,
+            Self::DetectedClockSkew(event) => event.detected_at,
+            Self::UnrecognisedLayout(event) => event.detected_at,
+            Self::SessionStarted(event) => event.started_at,
+            Self::ExhaustedSessionBudget(event) => event.exhausted_at,
+            Self::FellBackToLocal(event) => event.fell_back_at,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -41,6 +133,128 @@ pub struct PushCrateOutputsEvent {
     pub duration_secs: f64,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SkipPushEvent {
+    pub crate_unit_name: String,
+    pub skipped_at: chrono::DateTime<Utc>,
+    // Human-readable explanation of why we didn't push.
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortabilityCheckEvent {
+    pub crate_unit_name: String,
+    pub checked_at: chrono::DateTime<Utc>,
+    // Percentage of artifacts that don't mention any machine-specific paths.
+    pub score: u8,
+    // The machine-specific path prefixes that we found.
+    pub leaked_prefixes: Vec<String>,
+}
+
+/// We had to ask the real `rustc` to build a unit that we could have cached.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CompileCrateEvent {
+    pub crate_unit_name: String,
+    pub compiled_at: chrono::DateTime<Utc>,
+    // How long did `rustc` take?
+    pub duration_secs: f64,
+    // Was it built by a remote builder rather than locally?
+    #[serde(default)]
+    pub remote: bool,
+    // Could it have been pulled instead, if we weren't only observing?
+    #[serde(default)]
+    pub observed_hit: bool,
+    // What did `rustc` use? (Not known for remote builds.)
+    #[serde(default)]
+    pub usage: Option<ResourceUsage>,
+}
+
+/// Resources used by a child process, including any children of its own
+/// that it waited for.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ResourceUsage {
+    /// Peak resident set size of the biggest process in the tree.
+    pub max_rss_bytes: u64,
+    /// User plus system CPU time, summed over the tree.
+    pub cpu_secs: f64,
+}
+
+/// A pull took longer than we were willing to wait, so we built the unit instead.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AbandonPullEvent {
+    pub crate_unit_name: String,
+    pub abandoned_at: chrono::DateTime<Utc>,
+    pub budget_secs: f64,
+}
+
+/// Some timestamp was a long way ahead of our own clock.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClockSkewEvent {
+    pub crate_unit_name: String,
+    pub detected_at: chrono::DateTime<Utc>,
+    // How far ahead of our clock it was.
+    pub skew_secs: f64,
+    // Human-readable description of where the timestamp came from.
+    pub source: String,
+}
+
+/// Cargo's target dir wasn't laid out the way we expected,
+/// so we left something alone rather than risk caching it wrongly.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UnrecognisedLayoutEvent {
+    pub path: String,
+    pub detected_at: chrono::DateTime<Utc>,
+    // Human-readable description of what we didn't recognise.
+    pub problem: String,
+}
+
+/// The first unit of a Cargo build that Hope saw, with enough about the
+/// build to make sense of the lines that follow without knowing where
+/// (or when) they came from.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionStartedEvent {
+    pub started_at: chrono::DateTime<Utc>,
+    // e.g. "cargo 1.80.0 (376290515 2024-07-16)"; missing if we couldn't ask Cargo.
+    pub cargo_version: Option<String>,
+    // e.g. "1.80.0"
+    pub rustc_version: String,
+    // As named in the target dir, so "debug" for the `dev` profile.
+    pub profile: String,
+    pub target: String,
+    // Only known if given explicitly, with `-j` or `CARGO_BUILD_JOBS`.
+    pub jobs: Option<i32>,
+    // Feature resolver version, e.g. "2"; missing if we couldn't find the workspace.
+    pub resolver: Option<String>,
+}
+
+/// Cache operations in one Cargo build cost more than it was allowed
+/// (see `HOPE_SESSION_BUDGET_SECS`), so the rest of it compiled instead.
+/// Logged once per build, by the first unit to notice.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionBudgetEvent {
+    pub crate_unit_name: String,
+    pub exhausted_at: chrono::DateTime<Utc>,
+    // Time spent pulling and pushing so far, across all units of the build.
+    pub spent_secs: f64,
+    pub pulled_bytes: u64,
+    // Human-readable description of which budget ran out.
+    pub reason: String,
+}
+
+/// The remote cache kept failing during one Cargo build (see
+/// `HOPE_REMOTE_ERROR_LIMIT`), so the rest of it used just the local cache.
+/// Logged once per build, by the unit that gave up on it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RemoteFallbackEvent {
+    pub crate_unit_name: String,
+    pub fell_back_at: chrono::DateTime<Utc>,
+    // Where the remote cache is, as in pull and push events.
+    pub location: String,
+    // How many times in a row it failed, across units of the build.
+    pub errors: u32,
+    pub last_error: String,
+}
+
 // TODO: The existence of this kinda suggests that this log
 // should probably not be associated with a specific cache,
 // but be global by default (with ability to override).
@@ -49,6 +263,8 @@ pub struct BuildScriptRunEvent {
     // TODO: Lots of other details
     pub ran_at: chrono::DateTime<Utc>,
     pub crate_name: String,
+    #[serde(default)]
+    pub usage: Option<ResourceUsage>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -58,34 +274,149 @@ pub struct BuildScriptWrapperRunEvent {
     pub crate_name: String,
 }
 
+/// Log size (in bytes) beyond which `write_log_line` silently drops lines.
+static SIZE_CAP: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Stop the log growing beyond `cap` bytes, for the rest of this process.
+///
+/// This is for when the disk is nearly full; losing some history
+/// is better than failing builds.
+pub fn set_size_cap(cap: u64) {
+    SIZE_CAP.store(cap, Ordering::Relaxed);
+}
+
+/// Mode that `write_log_line` gives the log, or `u32::MAX` to leave it be.
+static FILE_MODE: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// Give the log this mode (e.g. 0o664), for the rest of this process,
+/// so that everyone sharing a cache can append to it.
+pub fn set_file_mode(mode: Option<u32>) {
+    FILE_MODE.store(mode.unwrap_or(u32::MAX), Ordering::Relaxed);
+}
+
+/// Append a line to the log, in the format selected by `HOPE_LOG_FORMAT`.
 pub fn write_log_line(cache_dir: &Path, log_line: CacheLogLine) -> anyhow::Result<()> {
+    let format = LogFormat::from_env()?;
     let file = File::options()
         .create(true)
         .append(true)
-        .open(cache_dir.join(LOG_FILE_NAME))?;
+        .open(cache_dir.join(format.file_name()))?;
+    let mode = FILE_MODE.load(Ordering::Relaxed);
+    if mode != u32::MAX && file.metadata()?.permissions().mode() & 0o7777 != mode {
+        // Only the owner can change it, and it's their problem if they don't.
+        let _ = file.set_permissions(Permissions::from_mode(mode));
+    }
     let mut file = RwLock::new(file);
     let mut write_guard = file.write()?;
+    if write_guard.metadata()?.len() >= SIZE_CAP.load(Ordering::Relaxed) {
+        return Ok(());
+    }
     let mut writer = BufWriter::new(&mut *write_guard);
-    serde_json::to_writer(&mut writer, &log_line)?;
-    writeln!(&mut writer)?;
+    write_log_lines(&mut writer, format, std::slice::from_ref(&log_line))?;
     writer.flush()?;
 
     Ok(())
 }
 
+/// Encode log lines to an arbitrary writer, e.g., for exporting
+/// the log in a different format to the one it's stored in.
+pub fn write_log_lines(
+    writer: &mut impl Write,
+    format: LogFormat,
+    log_lines: &[CacheLogLine],
+) -> anyhow::Result<()> {
+    for log_line in log_lines {
+        match format {
+            LogFormat::Jsonl => {
+                serde_json::to_writer(&mut *writer, log_line)?;
+                writeln!(writer)?;
+            }
+            LogFormat::Cbor => ciborium::into_writer(log_line, &mut *writer)
+                .context("Failed to encode CBOR log line")?,
+        }
+    }
+    Ok(())
+}
+
+/// Read the whole log.
+///
+/// If logs exist in both formats (e.g. because `HOPE_LOG_FORMAT` was changed
+/// at some point) then they're merged by when their events happened,
+/// so that the result is in order either way.
+///
+/// This only takes a shared lock, which is enough to keep out half-written
+/// lines, so any number of readers can go at once.
 pub fn read_log(cache_dir: &Path) -> anyhow::Result<Vec<CacheLogLine>> {
+    read_log_if_any(cache_dir)?
+        .with_context(|| format!("No log file found in cache dir {cache_dir:?}"))
+}
+
+/// Read the whole log, like `read_log`, or `None` if nothing's been logged yet.
+pub fn read_log_if_any(cache_dir: &Path) -> anyhow::Result<Option<Vec<CacheLogLine>>> {
+    let mut log: Option<Vec<CacheLogLine>> = None;
+    for format in [LogFormat::Jsonl, LogFormat::Cbor] {
+        let path = cache_dir.join(format.file_name());
+        if !path.exists() {
+            continue;
+        }
+        let file = File::open(path)?;
+        let file = RwLock::new(file);
+        let read_guard = file.read()?;
+        let reader = BufReader::new(&*read_guard);
+        let lines = read_log_lines(reader, format)?;
+        log = Some(match log {
+            Some(log) => merge_by_time(log, lines),
+            None => lines,
+        });
+    }
+    Ok(log)
+}
+
+/// Merge two logs, each in the order it was written, by when their events
+/// happened. Events from the same log keep their order, even if concurrent
+/// writers left their timestamps slightly out of order.
+fn merge_by_time(a: Vec<CacheLogLine>, b: Vec<CacheLogLine>) -> Vec<CacheLogLine> {
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let mut a = a.into_iter().peekable();
+    let mut b = b.into_iter().peekable();
+    loop {
+        let next = match (a.peek(), b.peek()) {
+            (Some(line_a), Some(line_b)) if line_b.timestamp() < line_a.timestamp() => b.next(),
+            (Some(_), _) => a.next(),
+            (None, _) => b.next(),
+        };
+        match next {
+            Some(line) => merged.push(line),
+            None => return merged,
+        }
+    }
+}
+
+fn read_log_lines(
+    mut reader: impl BufRead,
+    format: LogFormat,
+) -> anyhow::Result<Vec<CacheLogLine>> {
     let mut log = Vec::new();
-    let file = File::open(cache_dir.join(LOG_FILE_NAME))?;
-    let mut file = RwLock::new(file);
-    let mut read_guard = file.write()?;
-    let reader = BufReader::new(&mut *read_guard);
-
-    for line in reader.lines() {
-        let line = line?;
-        log.push(
-            serde_json::from_str(&line)
-                .with_context(|| format!("Failed to deserialize log line:\n{line}"))?,
-        );
+    match format {
+        LogFormat::Jsonl => {
+            for line in reader.lines() {
+                let line = line?;
+                log.push(
+                    serde_json::from_str(&line)
+                        .with_context(|| format!("Failed to deserialize log line:\n{line}"))?,
+                );
+            }
+        }
+        LogFormat::Cbor => {
+            // CBOR items are self-delimiting, so we just keep decoding
+            // until we run out of input.
+            while !reader.fill_buf()?.is_empty() {
+                log.push(
+                    ciborium::from_reader(reader.by_ref())
+                        .context("Failed to deserialize CBOR log line")?,
+                );
+            }
+        }
     }
     Ok(log)
 }
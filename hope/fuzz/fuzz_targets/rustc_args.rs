@@ -0,0 +1,63 @@
+//! Feeding arbitrary command lines through the `rustc` argument parser.
+//!
+//! Inputs are one argument per line, like the command lines in
+//! "tests/rustc-args", which make a good seed corpus. From "hope":
+//!
+//! ```text
+//! cargo +nightly fuzz run rustc_args fuzz/corpus/rustc_args tests/rustc-args
+//! ```
+//!
+//! Most inputs won't be anything `rustc` would accept, and that's fine as
+//! long as parsing doesn't panic. But for those that parse, anything Hope
+//! passes on or hashes has to have come through whole, exactly as given.
+
+#![no_main]
+
+use clap::Parser as _;
+use libfuzzer_sys::fuzz_target;
+
+// `hope` is just a binary, so borrow the module itself.
+#[allow(dead_code)]
+#[path = "../../src/rustc_args.rs"]
+mod rustc_args;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let args: Vec<&str> = text.lines().collect();
+    let Ok(parsed) =
+        rustc_args::Args::try_parse_from(std::iter::once("rustc").chain(args.iter().copied()))
+    else {
+        return;
+    };
+
+    let codegen_options: Vec<String> = parsed
+        .codegen_options
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let whole_values = parsed
+        .lib_search_paths
+        .iter()
+        .chain(&parsed.link_to_native_libs)
+        .chain(&parsed.extern_)
+        .chain(&parsed.remap_path_prefixes)
+        .chain(&codegen_options);
+    for value in whole_values {
+        assert!(
+            args.iter().any(|arg| is_given_as(arg, value)),
+            "{value:?} isn't any of the arguments {args:?}"
+        );
+    }
+});
+
+/// Is `arg` either `value` itself, or an option with `value` attached,
+/// as in "-Lvalue" or "--extern=value"?
+fn is_given_as(arg: &str, value: &str) -> bool {
+    arg.strip_suffix(value).is_some_and(|option| {
+        option.is_empty()
+            || option.ends_with('=')
+            || (option.len() == 2 && option.starts_with('-'))
+    })
+}
@@ -0,0 +1,323 @@
+//! Provenance attestations for cache entries.
+//!
+//! Whenever we push an entry, we also store an [in-toto Statement] next to
+//! it, with a [SLSA provenance] predicate recording who built the entry's
+//! files, from what, and how. `hope attest verify` then checks that every
+//! entry's files are still exactly what its attestation says was built.
+//!
+//! That's an integrity check only. Hope doesn't sign statements, so the
+//! builder recorded in one is just what whoever pushed the entry said
+//! about themselves, and anyone who can write to the cache can write an
+//! attestation to match whatever they put there. If you need provenance
+//! you can trust, get statements out with `hope attest export` and sign
+//! them with your usual tooling (e.g. `cosign attest-blob`).
+//!
+//! [in-toto Statement]: https://github.com/in-toto/attestation/blob/main/spec/v1/statement.md
+//! [SLSA provenance]: https://slsa.dev/spec/v1.0/provenance
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{Cache, LocalCache},
+    chunks::{self, BlobStore},
+    config,
+    entry_manifest::EntryManifest,
+    key::CacheKey,
+    verify::read_artifact,
+};
+
+const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v1";
+const PREDICATE_TYPE: &str = "https://slsa.dev/provenance/v1";
+const BUILD_TYPE: &str = "https://github.com/jeffparsons/hope/rustc@v1";
+const BLOB_KEY_SUFFIX: &str = ".intoto.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Statement {
+    #[serde(rename = "_type")]
+    type_: String,
+    subject: Vec<ResourceDescriptor>,
+    #[serde(rename = "predicateType")]
+    predicate_type: String,
+    predicate: Provenance,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ResourceDescriptor {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    digest: BTreeMap<String, String>,
+}
+
+impl ResourceDescriptor {
+    fn file(name: &str, sha256: String) -> Self {
+        Self {
+            name: Some(name.to_owned()),
+            uri: None,
+            digest: BTreeMap::from([("sha256".to_owned(), sha256)]),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Provenance {
+    build_definition: BuildDefinition,
+    run_details: RunDetails,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BuildDefinition {
+    build_type: String,
+    external_parameters: ExternalParameters,
+    internal_parameters: InternalParameters,
+    /// The package's source, and the dependencies it was linked against.
+    resolved_dependencies: Vec<ResourceDescriptor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExternalParameters {
+    /// "{crate name}{extra filename}"
+    unit: String,
+    target: String,
+    /// The full `rustc` command line.
+    command: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InternalParameters {
+    storage_name: String,
+    key_policy: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RunDetails {
+    builder: Builder,
+    metadata: BuildMetadata,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Builder {
+    id: String,
+    #[serde(default)]
+    version: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BuildMetadata {
+    started_on: DateTime<Utc>,
+    finished_on: DateTime<Utc>,
+}
+
+/// What we know about a build that we've just pushed.
+pub struct BuildRecord<'a> {
+    pub package_name: &'a str,
+    pub package_version: &'a str,
+    pub rustc_path: &'a Path,
+    pub rustc_release: &'a str,
+    pub args: &'a [String],
+    /// `--extern` arguments, as "{name}={path}".
+    pub externs: &'a [String],
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+impl Statement {
+    pub fn blob_key(storage_name: &str) -> String {
+        format!("{storage_name}{BLOB_KEY_SUFFIX}")
+    }
+
+    pub fn load(
+        store: &(impl BlobStore + ?Sized),
+        storage_name: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let blob_key = Self::blob_key(storage_name);
+        if !store.has_blob(&blob_key)? {
+            return Ok(None);
+        }
+        let bytes = store.get_blob(&blob_key)?;
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .with_context(|| format!("Invalid attestation {blob_key:?}"))
+    }
+
+    fn store(&self, store: &(impl BlobStore + ?Sized), storage_name: &str) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(self).context("Failed to serialize attestation")?;
+        store.put_blob(&Self::blob_key(storage_name), &bytes)
+    }
+
+    fn subject_digests(&self) -> BTreeMap<&str, Option<&str>> {
+        self.subject
+            .iter()
+            .filter_map(|subject| {
+                Some((
+                    subject.name.as_deref()?,
+                    subject.digest.get("sha256").map(String::as_str),
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Store an attestation for an entry we've just pushed.
+///
+/// If the entry was already there (pushed by an equivalent build), then
+/// its files are the ones from that build, so we leave its attestation be.
+pub fn attest(cache: &dyn Cache, key: &CacheKey, build: &BuildRecord) -> anyhow::Result<()> {
+    let storage_name = key.storage_name();
+    if Statement::load(cache, &storage_name)?.is_some() {
+        return Ok(());
+    }
+    let manifest = EntryManifest::load(cache, &storage_name)?
+        .context("Entry has no manifest; was it pushed?")?;
+
+    let mut resolved_dependencies = vec![ResourceDescriptor {
+        name: None,
+        uri: Some(format!(
+            "pkg:cargo/{}@{}",
+            build.package_name, build.package_version
+        )),
+        digest: BTreeMap::new(),
+    }];
+    for extern_ in build.externs {
+        // `--extern name` on its own is for sysroot crates.
+        let Some((_name, path)) = extern_.split_once('=') else {
+            continue;
+        };
+        let path = Path::new(path);
+        let content =
+            std::fs::read(path).with_context(|| format!("Failed to read {path:?} to hash it"))?;
+        let file_name = path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .unwrap_or_default();
+        resolved_dependencies.push(ResourceDescriptor::file(
+            file_name,
+            chunks::hash_bytes(&content),
+        ));
+    }
+
+    let statement = Statement {
+        type_: STATEMENT_TYPE.to_owned(),
+        subject: manifest
+            .files
+            .iter()
+            .map(|(file_name, sha256)| ResourceDescriptor::file(file_name, sha256.clone()))
+            .collect(),
+        predicate_type: PREDICATE_TYPE.to_owned(),
+        predicate: Provenance {
+            build_definition: BuildDefinition {
+                build_type: BUILD_TYPE.to_owned(),
+                external_parameters: ExternalParameters {
+                    unit: key.unit_name.clone(),
+                    target: key.target.triple().to_owned(),
+                    command: std::iter::once(build.rustc_path.display().to_string())
+                        .chain(build.args.iter().cloned())
+                        .collect(),
+                },
+                internal_parameters: InternalParameters {
+                    storage_name: storage_name.clone(),
+                    key_policy: key.policy.clone(),
+                },
+                resolved_dependencies,
+            },
+            run_details: RunDetails {
+                builder: Builder {
+                    id: builder_id(),
+                    version: BTreeMap::from([
+                        ("hope".to_owned(), env!("CARGO_PKG_VERSION").to_owned()),
+                        ("rustc".to_owned(), build.rustc_release.to_owned()),
+                    ]),
+                },
+                metadata: BuildMetadata {
+                    started_on: build.started_at,
+                    finished_on: build.finished_at,
+                },
+            },
+        },
+    };
+    statement.store(cache, &storage_name)
+}
+
+/// Who we say built things, unless `HOPE_BUILDER_ID` says otherwise.
+fn builder_id() -> String {
+    config::builder_id().unwrap_or_else(|| {
+        let uname = rustix::system::uname();
+        format!("hope@{}", uname.nodename().to_string_lossy())
+    })
+}
+
+/// Check that entries' files match their attestations.
+pub fn verify(crate_name: Option<&str>) -> anyhow::Result<()> {
+    let cache = LocalCache::from_env()?;
+    let mut checked = 0;
+    let mut problems = 0;
+    for (storage_name, manifest) in entries_for(&cache, crate_name)? {
+        checked += 1;
+        let mut problem = |description: String| {
+            problems += 1;
+            println!("{storage_name}: {description}");
+        };
+        let Some(statement) = Statement::load(&cache, &storage_name)? else {
+            problem("no attestation".to_owned());
+            continue;
+        };
+        let subjects = statement.subject_digests();
+        let files: BTreeMap<&str, Option<&str>> = manifest
+            .files
+            .iter()
+            .map(|(file_name, sha256)| (file_name.as_str(), Some(sha256.as_str())))
+            .collect();
+        if subjects != files {
+            problem("attestation doesn't match the entry's manifest".to_owned());
+            continue;
+        }
+        for (file_name, sha256) in subjects {
+            let matches = read_artifact(&cache, file_name)
+                .is_ok_and(|content| Some(chunks::hash_bytes(&content).as_str()) == sha256);
+            if !matches {
+                problem(format!("{file_name} isn't what was attested"));
+            }
+        }
+    }
+    println!("Checked {checked} entries; {problems} problems found.");
+    anyhow::ensure!(problems == 0, "Some entries failed attestation checks");
+    Ok(())
+}
+
+/// Print attestations as JSON Lines, the usual format for in-toto bundles.
+pub fn export(crate_name: Option<&str>) -> anyhow::Result<()> {
+    let cache = LocalCache::from_env()?;
+    for (storage_name, _) in entries_for(&cache, crate_name)? {
+        if let Some(statement) = Statement::load(&cache, &storage_name)? {
+            println!("{}", serde_json::to_string(&statement)?);
+        }
+    }
+    Ok(())
+}
+
+fn entries_for(
+    cache: &LocalCache,
+    crate_name: Option<&str>,
+) -> anyhow::Result<Vec<(String, EntryManifest)>> {
+    let crate_name = crate_name.map(|crate_name| crate_name.replace('-', "_"));
+    let mut entries = cache.entries()?;
+    if let Some(crate_name) = crate_name {
+        // "{crate name}-{extra filename hash}-{key digest}"
+        entries.retain(|(storage_name, _)| {
+            storage_name.rsplitn(3, '-').nth(2) == Some(crate_name.as_str())
+        });
+    }
+    Ok(entries)
+}
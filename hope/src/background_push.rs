@@ -0,0 +1,149 @@
+//! Pushing a freshly compiled unit without holding up the build.
+//!
+//! Cargo doesn't consider a unit done (or start anything that depends on it)
+//! until the wrapper exits, and pushing into even the local cache takes a
+//! while for big units. So with `HOPE_BACKGROUND_PUSH=1`, once `rustc` has
+//! finished and its outputs have been copied to a departure dir, the wrapper
+//! forks, and the child does the pushing while the parent exits straight away.
+//!
+//! A thread wouldn't do: the process has to exit for Cargo to carry on,
+//! and that would take any threads with it. Nor would spawning a fresh
+//! `hope` to do the push, as everything it needs (the key, the manifest,
+//! and the rest of what the wrapper worked out along the way) would have
+//! to be written down for it first. A forked child just carries on with
+//! all of that already in memory.
+//!
+//! The outputs are copied before forking, so that what's pushed is what
+//! `rustc` built, even if the target dir changes straight after the wrapper
+//! exits (e.g. the next build, or a `cargo clean`). The child removes the
+//! departure dir when it's done.
+//!
+//! The child starts its own session, so that Ctrl-C in the terminal that's
+//! running Cargo doesn't cut it off half way through a push, and it writes
+//! anything it has to say to "background-push.log" in the local cache dir,
+//! because by then Cargo has stopped listening.
+//!
+//! Entries pushed in the background turn up in the cache a little after the
+//! build that built them finishes, so a build started straight after might
+//! miss them. Build scripts are always pushed in the foreground, because
+//! the wrapper swaps them out for itself as soon as it's pushed them.
+
+use std::{
+    io::Write as _,
+    os::fd::{AsRawFd as _, RawFd},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Context;
+use tempfile::TempDir;
+
+use crate::{cache::LocalCache, fail_point, session};
+
+const LOG_FILE_NAME: &str = "background-push.log";
+
+/// Whether we've left a thread running that might be holding a lock.
+static ABANDONED_THREAD: AtomicBool = AtomicBool::new(false);
+
+/// Note that a thread has been left to finish (or not) on its own,
+/// e.g. a pull that ran out of time, so that we don't fork while it
+/// might be holding a lock.
+pub fn note_abandoned_thread() {
+    ABANDONED_THREAD.store(true, Ordering::SeqCst);
+}
+
+/// Run `push` on the outputs in `departure_dir` in a child process,
+/// and return without waiting for it.
+///
+/// If there's no child to be had, just push now.
+pub fn run(
+    crate_unit_name: &str,
+    departure_dir: TempDir,
+    push: impl FnOnce(&Path) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if ABANDONED_THREAD.load(Ordering::SeqCst) {
+        eprintln!(
+            "Hope: Pushing {crate_unit_name} in the foreground, \
+             because a pull is still running in the background."
+        );
+        return push(departure_dir.path());
+    }
+    let cache_dir = LocalCache::dir_from_env()?;
+    std::fs::create_dir_all(&cache_dir).context("Failed to create cache dir")?;
+    let log_path = cache_dir.join(LOG_FILE_NAME);
+    let log_file = std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .with_context(|| format!("Failed to open {log_path:?}"))?;
+    let dev_null = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("Failed to open /dev/null")?;
+
+    // The child won't be able to find out who Cargo is once we've gone.
+    session::cargo_pid();
+    // And anything still buffered would get written twice.
+    std::io::stdout().flush()?;
+    std::io::stderr().flush()?;
+
+    // SAFETY: Only this thread carries on in the child, so it mustn't need
+    // a lock that another thread held at the time of the fork. There are no
+    // other threads by now: `signals::output` joins its stderr reader before
+    // it returns, and a pull with a budget joins its thread once it's done.
+    // The one thread we don't join is a pull that ran out of time, which
+    // could be part way through anything (e.g. holding an HTTP connection
+    // pool's lock that the push would then wait on forever), so we don't
+    // fork at all after one of those (see above). Signal handlers only
+    // touch atomics.
+    match unsafe { libc::fork() } {
+        -1 => {
+            let err = std::io::Error::last_os_error();
+            eprintln!("Hope couldn't push {crate_unit_name} in the background: {err}");
+            push(departure_dir.path())
+        }
+        0 => {
+            // SAFETY: `setsid` has no preconditions, and both files
+            // stay open for the rest of this process.
+            let detached = unsafe {
+                libc::setsid() != -1
+                    && redirect(dev_null.as_raw_fd(), libc::STDIN_FILENO)
+                    && redirect(dev_null.as_raw_fd(), libc::STDOUT_FILENO)
+                    && redirect(log_file.as_raw_fd(), libc::STDERR_FILENO)
+            };
+            // Exit without unwinding, so that nothing the parent still
+            // owns (like temporary dirs) gets cleaned up from here. The
+            // departure dir is ours now, though.
+            if !detached {
+                let _ = std::fs::remove_dir_all(departure_dir.path());
+                std::process::exit(1);
+            }
+            let pushed =
+                fail_point::check("background_push").and_then(|()| push(departure_dir.path()));
+            let _ = std::fs::remove_dir_all(departure_dir.path());
+            match pushed {
+                Ok(()) => std::process::exit(0),
+                Err(err) => {
+                    eprintln!(
+                        "{}: Hope failed to push {crate_unit_name}: {err:#}",
+                        chrono::Utc::now().to_rfc3339()
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => {
+            // The child removes it once it's pushed everything.
+            let _ = departure_dir.into_path();
+            Ok(())
+        }
+    }
+}
+
+/// # Safety
+///
+/// `from` must be an open file descriptor.
+unsafe fn redirect(from: RawFd, to: RawFd) -> bool {
+    libc::dup2(from, to) != -1
+}
@@ -0,0 +1,351 @@
+//! Keeping a cache in a Bazel remote cache, so that infrastructure that's
+//! already there for Bazel (e.g. `bazel-remote`, or BuildBuddy) can store
+//! hope's entries too.
+//!
+//! Use it by setting `HOPE_CACHE_URL` to e.g. "bazel+http://cache:8080" or
+//! "bazel+https://cache.example.com/some/prefix". We speak the HTTP flavour
+//! of the protocol, which has two halves:
+//!
+//! - The CAS ("content-addressable store"), where each blob lives at
+//!   "cas/{sha256 of its content}".
+//! - The action cache, where each action's result lives at
+//!   "ac/{sha256 of the action}".
+//!
+//! Our blobs are looked up by key rather than by content, so each one goes in
+//! the CAS, and then an action result goes in the action cache at the hash
+//! of its key, naming the blob as its only output file. That's a well-formed
+//! action result, so servers that check what's put in the action cache are
+//! happy with it, and they'll keep the blob around for as long as the result
+//! that points to it.
+//!
+//! Requests carry the cache's credential, if it has one (see the
+//! `credentials` module), as a bearer token, which is what BuildBuddy
+//! and `bazel-remote` (behind a proxy) expect.
+
+use std::{io::Read as _, time::Duration};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::{RemoteBlobStore, SCHEMA_VERSION},
+    chunks::BlobStore,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct BazelBlobStore {
+    /// e.g. "http://cache:8080/", with a trailing slash.
+    base_url: String,
+    /// Sent as "Authorization: Bearer {token}", if there is one.
+    token: Option<String>,
+    agent: ureq::Agent,
+}
+
+/// Where a blob is in the CAS.
+struct CasDigest {
+    hash: String,
+    size: u64,
+}
+
+impl BazelBlobStore {
+    pub fn from_url(url: &str) -> anyhow::Result<Self> {
+        let base_url = url
+            .strip_prefix("bazel+")
+            .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+            .with_context(|| {
+                format!(
+                    "{url:?} isn't a Bazel remote cache URL; \
+                     expected e.g. \"bazel+http://cache:8080\""
+                )
+            })?;
+        Ok(Self {
+            base_url: format!("{}/", base_url.trim_end_matches('/')),
+            token: None,
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout_read(IO_TIMEOUT)
+                .timeout_write(IO_TIMEOUT)
+                .build(),
+        })
+    }
+
+    /// Authenticate every request with `token`.
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self.agent.request(method, url);
+        match &self.token {
+            Some(token) => request.set("authorization", &format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    /// Where the action result for a blob is. Keys are namespaced,
+    /// so they can't clash with Bazel's own actions.
+    fn action_url(&self, key: &str) -> String {
+        let action_hash = Sha256::digest(format!("hope-schema-{SCHEMA_VERSION}/{key}"));
+        format!("{}ac/{action_hash:x}", self.base_url)
+    }
+
+    fn cas_url(&self, hash: &str) -> String {
+        format!("{}cas/{hash}", self.base_url)
+    }
+
+    /// `None` if there's no such blob.
+    fn lookup(&self, key: &str) -> anyhow::Result<Option<CasDigest>> {
+        let response = match self.request("GET", &self.action_url(key)).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to look up {key:?} in {}", self.url()))
+            }
+        };
+        let action_result = read_body(response)
+            .with_context(|| format!("Failed to read {key:?} from {}", self.url()))?;
+        let digest = decode_action_result(&action_result, key)
+            .with_context(|| format!("Bad action result for {key:?} in {}", self.url()))?;
+        Ok(Some(digest))
+    }
+
+    fn get_cas(
+        &self,
+        key: &str,
+        digest: &CasDigest,
+        range: Option<&str>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut request = self.request("GET", &self.cas_url(&digest.hash));
+        if let Some(range) = range {
+            request = request.set("range", range);
+        }
+        let response = request
+            .call()
+            .with_context(|| format!("Failed to get {key:?} from {}", self.url()))?;
+        read_body(response).with_context(|| format!("Failed to read {key:?} from {}", self.url()))
+    }
+}
+
+impl BlobStore for BazelBlobStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let digest = self
+            .lookup(key)?
+            .with_context(|| format!("{key:?} isn't in {}", self.url()))?;
+        let bytes = self.get_cas(key, &digest, None)?;
+        anyhow::ensure!(
+            format!("{:x}", Sha256::digest(&bytes)) == digest.hash,
+            "Content of {key:?} in {} doesn't match its hash",
+            self.url()
+        );
+        Ok(bytes)
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        // Content first, so that the action result never points at nothing.
+        let digest = CasDigest {
+            hash: format!("{:x}", Sha256::digest(bytes)),
+            size: bytes.len() as u64,
+        };
+        self.request("PUT", &self.cas_url(&digest.hash))
+            .send_bytes(bytes)
+            .with_context(|| format!("Failed to put {key:?} to {}", self.url()))?;
+        self.request("PUT", &self.action_url(key))
+            .send_bytes(&encode_action_result(key, &digest))
+            .with_context(|| {
+                format!("Failed to put action result for {key:?} to {}", self.url())
+            })?;
+        Ok(())
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.lookup(key)?.is_some())
+    }
+}
+
+impl RemoteBlobStore for BazelBlobStore {
+    fn url(&self) -> String {
+        format!("bazel+{}", self.base_url)
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        Ok(self.lookup(key)?.map(|digest| digest.size))
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        let digest = self
+            .lookup(key)?
+            .with_context(|| format!("{key:?} isn't in {}", self.url()))?;
+        let bytes = self.get_cas(key, &digest, Some(&format!("bytes=-{len}")))?;
+        // Servers are allowed to ignore the range and send the whole thing.
+        Ok(bytes[bytes.len().saturating_sub(len)..].to_vec())
+    }
+}
+
+fn read_body(response: ureq::Response) -> std::io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    response.into_reader().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Field numbers from the protocol's `remote_execution.proto`.
+const ACTION_RESULT_OUTPUT_FILES: u64 = 2;
+const OUTPUT_FILE_PATH: u64 = 1;
+const OUTPUT_FILE_DIGEST: u64 = 2;
+const DIGEST_HASH: u64 = 1;
+const DIGEST_SIZE_BYTES: u64 = 2;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+/// An `ActionResult` protobuf with the blob as its one output file.
+fn encode_action_result(key: &str, digest: &CasDigest) -> Vec<u8> {
+    let mut digest_message = Vec::new();
+    put_len_field(&mut digest_message, DIGEST_HASH, digest.hash.as_bytes());
+    put_varint(&mut digest_message, DIGEST_SIZE_BYTES << 3 | WIRE_VARINT);
+    put_varint(&mut digest_message, digest.size);
+
+    let mut output_file = Vec::new();
+    put_len_field(&mut output_file, OUTPUT_FILE_PATH, key.as_bytes());
+    put_len_field(&mut output_file, OUTPUT_FILE_DIGEST, &digest_message);
+
+    let mut action_result = Vec::new();
+    put_len_field(&mut action_result, ACTION_RESULT_OUTPUT_FILES, &output_file);
+    action_result
+}
+
+/// The digest of the output file named `key` in an `ActionResult` protobuf.
+fn decode_action_result(action_result: &[u8], key: &str) -> anyhow::Result<CasDigest> {
+    for (field, value) in Fields::new(action_result) {
+        let FieldValue::Len(output_file) = value? else {
+            continue;
+        };
+        if field != ACTION_RESULT_OUTPUT_FILES {
+            continue;
+        }
+        let mut path = None;
+        let mut digest = None;
+        for (field, value) in Fields::new(output_file) {
+            match (field, value?) {
+                (OUTPUT_FILE_PATH, FieldValue::Len(bytes)) => path = Some(bytes),
+                (OUTPUT_FILE_DIGEST, FieldValue::Len(bytes)) => digest = Some(bytes),
+                _ => {}
+            }
+        }
+        if path != Some(key.as_bytes()) {
+            continue;
+        }
+        let mut hash = None;
+        let mut size = 0;
+        for (field, value) in Fields::new(digest.context("Output file has no digest")?) {
+            match (field, value?) {
+                (DIGEST_HASH, FieldValue::Len(bytes)) => {
+                    hash = Some(String::from_utf8(bytes.to_vec()).context("Hash isn't UTF-8")?)
+                }
+                (DIGEST_SIZE_BYTES, FieldValue::Varint(value)) => size = value,
+                _ => {}
+            }
+        }
+        return Ok(CasDigest {
+            hash: hash.context("Digest has no hash")?,
+            size,
+        });
+    }
+    anyhow::bail!("No output file for {key:?}")
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_len_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, field << 3 | WIRE_LEN);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+enum FieldValue<'a> {
+    Varint(u64),
+    Len(&'a [u8]),
+    /// Fixed-width fields, which we have no use for.
+    Fixed,
+}
+
+/// The fields of a protobuf message, in order.
+struct Fields<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn new(message: &'a [u8]) -> Self {
+        Self { rest: message }
+    }
+
+    fn varint(&mut self) -> anyhow::Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.rest.split_first().context("Truncated varint")?;
+            self.rest = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        anyhow::bail!("Varint too long")
+    }
+
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        anyhow::ensure!(len <= self.rest.len(), "Truncated field");
+        let (taken, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Ok(taken)
+    }
+
+    fn field(&mut self) -> anyhow::Result<(u64, FieldValue<'a>)> {
+        let tag = self.varint()?;
+        let value = match tag & 7 {
+            WIRE_VARINT => FieldValue::Varint(self.varint()?),
+            WIRE_LEN => {
+                let len = self.varint()?;
+                FieldValue::Len(self.take(usize::try_from(len).context("Field too long")?)?)
+            }
+            WIRE_FIXED64 => {
+                self.take(8)?;
+                FieldValue::Fixed
+            }
+            WIRE_FIXED32 => {
+                self.take(4)?;
+                FieldValue::Fixed
+            }
+            wire_type => anyhow::bail!("Unsupported wire type {wire_type}"),
+        };
+        Ok((tag >> 3, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = (u64, anyhow::Result<FieldValue<'a>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        Some(match self.field() {
+            Ok((field, value)) => (field, Ok(value)),
+            Err(err) => {
+                // Nothing after a bad field can be trusted.
+                self.rest = &[];
+                (0, Err(err))
+            }
+        })
+    }
+}
@@ -0,0 +1,147 @@
+//! A/B timing of builds with and without Hope.
+//!
+//! The point of this is to give people credible before/after numbers,
+//! so each scenario gets a fresh target dir, and the caching scenarios
+//! use a fresh cache dir rather than whatever the user has lying around.
+
+use std::{
+    env,
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use hope_cache_log::{read_log_if_any, CacheLogLine};
+use tempfile::tempdir;
+
+#[derive(Clone, Copy, Debug)]
+enum Scenario {
+    /// Plain old `rustc`, no wrapper at all.
+    WithoutHope,
+    /// Hope with an empty cache; this measures our overhead.
+    ColdCache,
+    /// Hope with a cache populated by the cold run.
+    WarmCache,
+}
+
+impl Scenario {
+    fn description(self) -> &'static str {
+        match self {
+            Self::WithoutHope => "without hope",
+            Self::ColdCache => "hope, cold cache",
+            Self::WarmCache => "hope, warm cache",
+        }
+    }
+}
+
+/// Time `cargo build` (plus any extra `cargo_args`) for the project in the
+/// current directory in each scenario, `runs` times over, and print a table.
+pub fn run(runs: usize, cargo_args: &[String]) -> anyhow::Result<()> {
+    anyhow::ensure!(runs > 0, "Need at least one run");
+
+    // Make sure downloading crates doesn't count towards any of the timings.
+    let status = cargo()
+        .arg("fetch")
+        .status()
+        .context("Failed to start Cargo")?;
+    anyhow::ensure!(status.success(), "'cargo fetch' failed");
+
+    let hope_path = env::current_exe().context("Failed to get path to 'hope' exe")?;
+    let scenarios = [
+        Scenario::WithoutHope,
+        Scenario::ColdCache,
+        Scenario::WarmCache,
+    ];
+    let mut timings: Vec<Vec<Duration>> = vec![Vec::new(); scenarios.len()];
+    for run in 1..=runs {
+        // Each run gets its own cache, shared by the cold and warm scenarios.
+        let cache_dir = tempdir().context("Failed to create temporary cache dir")?;
+        for (scenario, timings) in scenarios.iter().zip(&mut timings) {
+            eprintln!("Run {run}/{runs}: building {}...", scenario.description());
+            let elapsed = time_build(*scenario, &hope_path, cache_dir.path(), cargo_args)
+                .with_context(|| format!("Build {} failed", scenario.description()))?;
+            timings.push(elapsed);
+        }
+        // If the warm run didn't pull anything then it isn't measuring what
+        // it says it is, so make that easy to notice.
+        let pulls = read_log_if_any(cache_dir.path())?
+            .unwrap_or_default()
+            .iter()
+            .filter(|line| matches!(line, CacheLogLine::PulledCrateOutputs(_)))
+            .count();
+        eprintln!("Run {run}/{runs}: hope, warm cache pulled {pulls} units");
+    }
+
+    let baseline = mean(&timings[0]);
+    println!(
+        "{:<20} {:>10} {:>10} {:>12}",
+        "scenario", "mean (s)", "min (s)", "vs. without"
+    );
+    for (scenario, timings) in scenarios.iter().zip(&timings) {
+        let mean = mean(timings);
+        let min = timings.iter().min().copied().unwrap_or_default();
+        println!(
+            "{:<20} {:>10.2} {:>10.2} {:>11.0}%",
+            scenario.description(),
+            mean.as_secs_f64(),
+            min.as_secs_f64(),
+            mean.as_secs_f64() / baseline.as_secs_f64() * 100.0
+        );
+    }
+
+    Ok(())
+}
+
+fn time_build(
+    scenario: Scenario,
+    hope_path: &Path,
+    cache_dir: &Path,
+    cargo_args: &[String],
+) -> anyhow::Result<Duration> {
+    let target_dir = tempdir().context("Failed to create temporary target dir")?;
+    let mut command = cargo();
+    command
+        .arg("build")
+        .args(cargo_args)
+        .env("CARGO_TARGET_DIR", target_dir.path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    // Any Hope settings from the environment (a remote cache, observe mode,
+    // and so on) would skew the numbers, as would a workspace wrapper.
+    for (var, _) in env::vars_os() {
+        if var.to_str().is_some_and(|var| var.starts_with("HOPE_")) {
+            command.env_remove(var);
+        }
+    }
+    command.env_remove("RUSTC_WORKSPACE_WRAPPER");
+    match scenario {
+        Scenario::WithoutHope => {
+            // An empty value explicitly disables any wrapper from Cargo config.
+            command.env("RUSTC_WRAPPER", "");
+        }
+        Scenario::ColdCache | Scenario::WarmCache => {
+            command
+                .env("RUSTC_WRAPPER", hope_path)
+                .env("HOPE_CACHE_DIR", cache_dir);
+        }
+    }
+
+    let before = Instant::now();
+    let output = command.output().context("Failed to start Cargo")?;
+    let elapsed = before.elapsed();
+    anyhow::ensure!(
+        output.status.success(),
+        "Cargo build failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(elapsed)
+}
+
+fn cargo() -> Command {
+    Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+}
+
+fn mean(timings: &[Duration]) -> Duration {
+    timings.iter().sum::<Duration>() / timings.len() as u32
+}
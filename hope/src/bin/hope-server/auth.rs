@@ -0,0 +1,94 @@
+//! Who can use which namespaces.
+//!
+//! The tokens file is JSON, listing each token by its SHA-256 hash, so the
+//! file itself isn't worth stealing:
+//!
+//! ```json
+//! {
+//!   "tokens": [
+//!     {
+//!       "name": "ci",
+//!       "sha256": "9f86d0...",
+//!       "namespaces": { "my-project": "write", "shared": "read", "*": "read" }
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! "write" lets a token read too. A namespace of "*" covers any namespace
+//! not listed by name. `hope-server new-token` makes up a token and prints
+//! its entry, ready to paste in.
+
+use std::{collections::BTreeMap, io::Read as _, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Token {
+    pub name: String,
+    sha256: String,
+    namespaces: BTreeMap<String, Access>,
+}
+
+impl Token {
+    /// Can this token do `needed` in `namespace`?
+    pub fn allows(&self, namespace: &str, needed: Access) -> bool {
+        self.namespaces
+            .get(namespace)
+            .or_else(|| self.namespaces.get("*"))
+            .is_some_and(|access| *access >= needed)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Tokens {
+    tokens: Vec<Token>,
+}
+
+impl Tokens {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let json = std::fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+        serde_json::from_slice(&json).with_context(|| format!("Invalid tokens file {path:?}"))
+    }
+
+    /// The token with this (unhashed) value, if there is one.
+    pub fn find(&self, token: &str) -> Option<&Token> {
+        let hash = hash(token);
+        self.tokens.iter().find(|known| known.sha256 == hash)
+    }
+}
+
+fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token))
+}
+
+/// Print a brand new token, and its entry for the tokens file.
+pub fn new_token(name: &str) -> anyhow::Result<()> {
+    let mut random = [0u8; 32];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut random))
+        .context("Failed to read /dev/urandom")?;
+    let token: String = std::iter::once("hope_".to_owned())
+        .chain(random.iter().map(|byte| format!("{byte:02x}")))
+        .collect();
+    let entry = Token {
+        name: name.to_owned(),
+        sha256: hash(&token),
+        namespaces: BTreeMap::new(),
+    };
+    println!("{token}");
+    eprintln!(
+        "Add this to the tokens file, with the namespaces it can use:\n{}",
+        serde_json::to_string_pretty(&entry)?
+    );
+    Ok(())
+}
@@ -0,0 +1,204 @@
+//! A cache server for a whole organisation, so that every laptop and CI
+//! runner can share a cache without being given the keys to a bucket.
+//!
+//! It speaks the same dumb protocol as `hope serve` (see the `http_store`
+//! module in `hope` itself), but each project gets its own namespace, every
+//! request needs a token, and each token says which namespaces it can read
+//! and which it can write:
+//!
+//! ```text
+//! hope-server new-token --name ci   # prints a token, and its entry for tokens.json
+//! hope-server serve --listen 0.0.0.0:7777 --root /srv/hope --tokens tokens.json
+//! ```
+//!
+//! Builds then use it with e.g.
+//! `HOPE_CACHE_URL=https://hope.example.com/ns/my-project/blobs` and
+//! `HOPE_CACHE_TOKEN=hope_...`, or the token saved with `hope login`. It
+//! doesn't do TLS itself, so put it behind a proxy that does before sending
+//! tokens over anything but a trusted network.
+//!
+//! How much each namespace and token reads and writes is counted, and can be
+//! fetched as JSON from `/ns/{namespace}/usage`. See the `usage` module.
+
+mod auth;
+mod store;
+mod usage;
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use auth::{Access, Tokens};
+use store::NamespaceStore;
+use usage::UsageBook;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Multi-tenant cache server for Hope")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve caches from `--root` until killed.
+    Serve {
+        /// Address to listen on, e.g. "0.0.0.0:7777".
+        #[arg(long)]
+        listen: String,
+        /// Directory to keep every namespace's blobs in.
+        #[arg(long)]
+        root: PathBuf,
+        /// JSON file saying which tokens can use which namespaces; see the `auth` module.
+        #[arg(long)]
+        tokens: PathBuf,
+    },
+    /// Make up a new token, and print it along with its entry for the tokens file.
+    NewToken {
+        /// Who the token is for, e.g. "ci" or "alice"; usage is counted under this name.
+        #[arg(long)]
+        name: String,
+    },
+}
+
+/// How often to save usage counts.
+const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+fn main() -> anyhow::Result<()> {
+    match Cli::parse().command {
+        Command::Serve {
+            listen,
+            root,
+            tokens,
+        } => serve(&listen, &root, &tokens),
+        Command::NewToken { name } => auth::new_token(&name),
+    }
+}
+
+struct State {
+    tokens: Tokens,
+    store: NamespaceStore,
+    usage: UsageBook,
+}
+
+fn serve(listen: &str, root: &Path, tokens_path: &Path) -> anyhow::Result<()> {
+    let tokens = Tokens::load(tokens_path)?;
+    std::fs::create_dir_all(root).with_context(|| format!("Failed to create {root:?}"))?;
+    let state = Arc::new(State {
+        tokens,
+        store: NamespaceStore::new(root),
+        usage: UsageBook::load(root)?,
+    });
+    let server = Server::http(listen)
+        .map_err(|err| anyhow::anyhow!(err))
+        .with_context(|| format!("Failed to listen on {listen:?}"))?;
+    let local_addr = server
+        .server_addr()
+        .to_ip()
+        .context("Server isn't listening on an IP address")?;
+    // As for `hope serve`, so that scripts (and tests) can find the port.
+    println!("Listening on {local_addr}");
+
+    {
+        let state = Arc::clone(&state);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(USAGE_SAVE_INTERVAL);
+            if let Err(err) = state.usage.save() {
+                eprintln!("Failed to save usage: {err:#}");
+            }
+        });
+    }
+
+    for request in server.incoming_requests() {
+        let state = Arc::clone(&state);
+        std::thread::spawn(move || {
+            if let Err(err) = respond(&state, request) {
+                eprintln!("Failed to respond to request: {err:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn respond(state: &State, mut request: Request) -> anyhow::Result<()> {
+    // e.g. "/ns/my-project/blobs/chunks/abc" or "/ns/my-project/usage"
+    let Some((namespace, rest)) = request
+        .url()
+        .strip_prefix("/ns/")
+        .and_then(|path| path.split_once('/'))
+        .map(|(namespace, rest)| (namespace.to_owned(), rest.to_owned()))
+    else {
+        return Ok(request.respond(Response::empty(404))?);
+    };
+    if !store::is_valid_namespace(&namespace) {
+        return Ok(request.respond(Response::empty(400))?);
+    }
+
+    let bearer = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .map(str::to_owned);
+    let Some(token) = bearer.as_deref().and_then(|token| state.tokens.find(token)) else {
+        return Ok(request.respond(Response::empty(401))?);
+    };
+    let needed = match request.method() {
+        Method::Get | Method::Head => Access::Read,
+        Method::Put => Access::Write,
+        _ => return Ok(request.respond(Response::empty(405))?),
+    };
+    if !token.allows(&namespace, needed) {
+        return Ok(request.respond(Response::empty(403))?);
+    }
+
+    if rest == "usage" && *request.method() == Method::Get {
+        let report = state.usage.report(&namespace, &state.store)?;
+        let json = Header::from_bytes("content-type", "application/json").unwrap();
+        return Ok(
+            request.respond(Response::from_data(serde_json::to_vec(&report)?).with_header(json))?
+        );
+    }
+    let Some(key) = rest.strip_prefix("blobs/") else {
+        return Ok(request.respond(Response::empty(404))?);
+    };
+    if !store::is_valid_key(key) {
+        return Ok(request.respond(Response::empty(400))?);
+    }
+    let key = key.to_owned();
+
+    match request.method() {
+        Method::Put => {
+            let mut bytes = Vec::new();
+            request
+                .as_reader()
+                .read_to_end(&mut bytes)
+                .context("Failed to read request body")?;
+            state.store.put(&namespace, &key, &bytes)?;
+            state
+                .usage
+                .wrote(&namespace, &token.name, bytes.len() as u64);
+            request.respond(Response::empty(204))?;
+        }
+        _ => match state.store.get(&namespace, &key)? {
+            Some(bytes) => {
+                // `tiny_http` leaves out the body for `HEAD` requests,
+                // so those don't count as reads.
+                if *request.method() == Method::Get {
+                    state
+                        .usage
+                        .read(&namespace, &token.name, bytes.len() as u64);
+                }
+                request.respond(Response::from_data(bytes))?;
+            }
+            None => request.respond(Response::empty(404))?,
+        },
+    }
+    Ok(())
+}
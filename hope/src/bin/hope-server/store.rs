@@ -0,0 +1,87 @@
+//! Where each namespace's blobs live: "{root}/{namespace}/{key}".
+
+use std::{
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+pub struct NamespaceStore {
+    root: PathBuf,
+}
+
+impl NamespaceStore {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_owned(),
+        }
+    }
+
+    pub fn namespace_dir(&self, namespace: &str) -> PathBuf {
+        self.root.join(namespace)
+    }
+
+    /// `None` if there's no such blob.
+    pub fn get(&self, namespace: &str, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let path = self.namespace_dir(namespace).join(key);
+        match std::fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {path:?}")),
+        }
+    }
+
+    /// Write a blob atomically, so nobody ever reads half of one.
+    pub fn put(&self, namespace: &str, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let path = self.namespace_dir(namespace).join(key);
+        let dir = path.parent().context("Blob has no parent dir")?;
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+        let mut temp_file = tempfile::Builder::new()
+            .prefix(".incoming-")
+            .tempfile_in(dir)
+            .context("Failed to create temporary file")?;
+        temp_file.write_all(bytes)?;
+        temp_file
+            .persist(&path)
+            .with_context(|| format!("Failed to move blob into place at {path:?}"))?;
+        Ok(())
+    }
+
+    /// How many blobs there are in a namespace, and how big they are altogether.
+    pub fn size(&self, namespace: &str) -> anyhow::Result<(u64, u64)> {
+        let dir = self.namespace_dir(namespace);
+        let mut blobs = 0;
+        let mut bytes = 0;
+        if !dir.exists() {
+            return Ok((blobs, bytes));
+        }
+        for entry in walkdir::WalkDir::new(&dir) {
+            let entry = entry.with_context(|| format!("Failed to walk {dir:?}"))?;
+            if entry.file_type().is_file() {
+                blobs += 1;
+                bytes += entry.metadata()?.len();
+            }
+        }
+        Ok((blobs, bytes))
+    }
+}
+
+/// Namespaces are single path segments of letters, digits, '-', '_', and '.',
+/// not starting with a dot (which is for our own files).
+pub fn is_valid_namespace(namespace: &str) -> bool {
+    !namespace.is_empty()
+        && !namespace.starts_with('.')
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Keys are relative, slash-separated paths; don't let anyone wander
+/// outside their namespace, or touch our temporary files.
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .split('/')
+            .all(|segment| !segment.is_empty() && !segment.starts_with('.'))
+}
@@ -0,0 +1,134 @@
+//! Counting how much each namespace, and each token, reads and writes.
+//!
+//! Counts are kept in memory and saved to "{root}/.usage.json" every few
+//! seconds, so a crash loses at most the last few of them. `GET` on
+//! "/ns/{namespace}/usage" reports a namespace's counts, along with how
+//! much it's storing now and which tokens have used it.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::store::NamespaceStore;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub reads: u64,
+    pub bytes_read: u64,
+    pub writes: u64,
+    pub bytes_written: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counts {
+    /// By namespace, then by token name.
+    namespaces: BTreeMap<String, BTreeMap<String, Usage>>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    namespace: String,
+    stored_blobs: u64,
+    stored_bytes: u64,
+    total: Usage,
+    by_token: BTreeMap<String, Usage>,
+}
+
+pub struct UsageBook {
+    path: PathBuf,
+    counts: Mutex<Counts>,
+}
+
+impl UsageBook {
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(".usage.json");
+        let counts = match std::fs::read(&path) {
+            Ok(json) => {
+                serde_json::from_slice(&json).with_context(|| format!("Invalid {path:?}"))?
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Counts::default(),
+            Err(err) => return Err(err).with_context(|| format!("Failed to read {path:?}")),
+        };
+        Ok(Self {
+            path,
+            counts: Mutex::new(counts),
+        })
+    }
+
+    fn update(&self, namespace: &str, token_name: &str, update: impl FnOnce(&mut Usage)) {
+        let mut counts = self.counts.lock().unwrap();
+        update(
+            counts
+                .namespaces
+                .entry(namespace.to_owned())
+                .or_default()
+                .entry(token_name.to_owned())
+                .or_default(),
+        );
+        counts.dirty = true;
+    }
+
+    pub fn read(&self, namespace: &str, token_name: &str, bytes: u64) {
+        self.update(namespace, token_name, |usage| {
+            usage.reads += 1;
+            usage.bytes_read += bytes;
+        });
+    }
+
+    pub fn wrote(&self, namespace: &str, token_name: &str, bytes: u64) {
+        self.update(namespace, token_name, |usage| {
+            usage.writes += 1;
+            usage.bytes_written += bytes;
+        });
+    }
+
+    pub fn report(&self, namespace: &str, store: &NamespaceStore) -> anyhow::Result<Report> {
+        let by_token = self
+            .counts
+            .lock()
+            .unwrap()
+            .namespaces
+            .get(namespace)
+            .cloned()
+            .unwrap_or_default();
+        let mut total = Usage::default();
+        for usage in by_token.values() {
+            total.reads += usage.reads;
+            total.bytes_read += usage.bytes_read;
+            total.writes += usage.writes;
+            total.bytes_written += usage.bytes_written;
+        }
+        let (stored_blobs, stored_bytes) = store.size(namespace)?;
+        Ok(Report {
+            namespace: namespace.to_owned(),
+            stored_blobs,
+            stored_bytes,
+            total,
+            by_token,
+        })
+    }
+
+    /// Save the counts, if they've changed since last time.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let json = {
+            let mut counts = self.counts.lock().unwrap();
+            if !counts.dirty {
+                return Ok(());
+            }
+            counts.dirty = false;
+            serde_json::to_vec_pretty(&*counts)?
+        };
+        let temp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&temp_path, json)
+            .with_context(|| format!("Failed to write {temp_path:?}"))?;
+        std::fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("Failed to move usage into place at {:?}", self.path))
+    }
+}
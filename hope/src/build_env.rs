@@ -0,0 +1,93 @@
+//! Snapshotting the parts of the build environment that outputs might depend on.
+//!
+//! Most of this isn't part of the key, because we can't know for sure what
+//! matters to any given crate (and being too picky would make the cache
+//! useless). Instead we record it with each entry, so that humans can see
+//! what an entry was built with, and so that pulls can refuse entries that
+//! are known to be incompatible (e.g. built against a newer glibc).
+
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    rustc_args::Args,
+    toolchain::{NativeToolchain, RustcInfo},
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BuildEnvironment {
+    pub rustc_release: String,
+    #[serde(default)]
+    pub rustc_commit_hash: Option<String>,
+    /// From `-C linker`; `None` means `rustc`'s default for the target.
+    #[serde(default)]
+    pub linker: Option<String>,
+    #[serde(flatten)]
+    pub native_toolchain: NativeToolchain,
+    /// e.g. "linux (Debian GNU/Linux 12 (bookworm))"
+    pub os: String,
+    /// e.g. "glibc 2.36" or "musl 1.2.4"; Linux only.
+    #[serde(default)]
+    pub libc: Option<String>,
+}
+
+impl BuildEnvironment {
+    /// Snapshot the environment for a unit we're about to push.
+    ///
+    /// This runs a few external programs, so only do it when pushing.
+    pub fn capture(rustc: &RustcInfo, native_toolchain: &NativeToolchain, args: &Args) -> Self {
+        Self {
+            rustc_release: rustc.release.clone(),
+            rustc_commit_hash: rustc.commit_hash.clone(),
+            linker: args.codegen_option("linker").map(str::to_owned),
+            native_toolchain: native_toolchain.clone(),
+            os: os_description(),
+            libc: libc_version(),
+        }
+    }
+}
+
+fn os_description() -> String {
+    let os = std::env::consts::OS;
+    let pretty_name = std::fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|os_release| {
+            os_release.lines().find_map(|line| {
+                line.strip_prefix("PRETTY_NAME=")
+                    .map(|name| name.trim_matches('"').to_owned())
+            })
+        });
+    match pretty_name {
+        Some(pretty_name) => format!("{os} ({pretty_name})"),
+        None => os.to_owned(),
+    }
+}
+
+/// Which C library (and version) we're building against.
+///
+/// TODO: Ask the C library itself rather than `ldd`.
+fn libc_version() -> Option<String> {
+    if std::env::consts::OS != "linux" {
+        return None;
+    }
+    let output = Command::new("ldd").arg("--version").output().ok()?;
+    // musl's `ldd` prints its version to stderr (and exits unsuccessfully).
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let first_line = text.lines().next()?;
+    if first_line.contains("musl") {
+        let version = text
+            .lines()
+            .find_map(|line| line.strip_prefix("Version "))?;
+        Some(format!("musl {}", version.trim()))
+    } else if first_line.contains("GLIBC") || first_line.contains("GNU libc") {
+        let version = first_line.split_whitespace().last()?;
+        Some(format!("glibc {version}"))
+    } else {
+        None
+    }
+}
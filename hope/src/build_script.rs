@@ -18,35 +18,64 @@ use hope_cache_log::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::cache::{Cache, LocalCache};
+use crate::{
+    build_script_inputs::BuildScriptInputs,
+    cache::{self, LocalCache},
+    config, lockfile_index,
+    out_dir_layout::{self, BuildScriptRunDir},
+    remote_fallback, signals,
+};
 
 pub const BUILD_SCRIPT_INVOCATION_INFO_FILE_NAME: &str = "build-script-invocation-info.json";
 
+/// Symlink to the real build script, next to the copy of Hope
+/// that stands in for it.
+pub const REAL_BUILD_SCRIPT_SYMLINK_NAME: &str = "real-build-script";
+
+/// Written next to the build script executable when we compile it, so that the
+/// wrapper knows exactly which build of the build script it stands in for.
+pub const BUILD_SCRIPT_KEY_FILE_NAME: &str = "hope-build-script-key";
+
+/// With whole-package pulls, written next to the build script executable
+/// when we compile it rather than pulling it, and in the out dir when we
+/// run it rather than replaying its output. Either way, the next unit of
+/// the package gets built locally too.
+pub const BUILT_LOCALLY_FILE_NAME: &str = "hope-built-locally";
+
 pub fn run(called_as: &Path) -> anyhow::Result<()> {
     // Figure out where the real build script is.
     let build_script_build_dir = called_as
         .parent()
         .context("Build script didn't have parent dir")?;
     // TODO: See comments where this is created about wanting to not do "real-build-script" symlink.
-    let real_build_script_symlink_path = build_script_build_dir.join("real-build-script");
+    let real_build_script_symlink_path =
+        build_script_build_dir.join(REAL_BUILD_SCRIPT_SYMLINK_NAME);
 
-    // By convention, Cargo puts out dirs for build scripts under "target/debug/build/cratename-{metadata_hash}/out".
-    // (This is a private implementation detail, but in practice the Cargo maintainers have been very conservative
-    // about changing details like this, so it wouldn't be a big deal to adapt if they do occasionally change it.)
-    // We want the build script execution metadata hash.
+    // We want the build script execution metadata hash, which Cargo only
+    // tells us by way of where it puts the out dir. See the `out_dir_layout` module.
     let out_dir =
         env::var("OUT_DIR").context("Missing 'OUT_DIR' env var for build script execution")?;
     let out_dir =
         PathBuf::from_str(&out_dir).context("'OUT_DIR' env var contained invalid path")?;
-    let (crate_name, run_metadata_hash) = out_dir
-        .parent()
-        .context("Missing parent on out dir")?
-        .file_name()
-        .context("Missing file name on build dir")?
-        .to_str()
-        .context("Invalid UTF-8 in build dir name")?
-        .rsplit_once('-')
-        .context("Couldn't find '-' in build dir")?;
+    let run_dir = match BuildScriptRunDir::parse(&out_dir) {
+        Ok(run_dir) => run_dir,
+        Err(unrecognised) => {
+            // Just be the real build script, as if we weren't here.
+            unrecognised.report()?;
+            let (status, _) = signals::status(&mut Command::new(&real_build_script_symlink_path))
+                .with_context(|| {
+                format!("Failed to start real build script at {real_build_script_symlink_path:?}")
+            })?;
+            signals::check()?;
+            std::process::exit(
+                status
+                    .code()
+                    .context("Child build script process was terminated by a signal")?,
+            );
+        }
+    };
+    let crate_name = run_dir.package_name.as_str();
+    let run_metadata_hash = run_dir.metadata_hash.as_str();
 
     let cache_dir =
         LocalCache::dir_from_env().context("Failed to get local cache dir from environment")?;
@@ -58,23 +87,68 @@ pub fn run(called_as: &Path) -> anyhow::Result<()> {
         }),
     )?;
 
+    // Cargo's hash for the run doesn't necessarily change when only the way
+    // the build script was compiled changes (e.g. `[profile.dev.build-override]`),
+    // but its output might, so key the output on the build script's own cache key too.
+    let build_script_key =
+        std::fs::read_to_string(build_script_build_dir.join(BUILD_SCRIPT_KEY_FILE_NAME))
+            .context("Failed to read build script cache key file")?;
+    let stdout_key = format!(
+        "{crate_name}-{run_metadata_hash}-{}",
+        build_script_key.trim()
+    );
+
+    // Cargo runs build scripts from the package dir, which is also what
+    // relative `rerun-if-changed` paths are relative to.
+    let package_dir = env::current_dir().context("Couldn't get working dir")?;
+
     // Can we find the stdout of this build script execution in cache?
-    let cache = LocalCache::from_env()?;
-    if let Ok(build_script_stdout) = cache.get_build_script_stdout(run_metadata_hash) {
-        let build_script_stdout = str::from_utf8(&build_script_stdout)
-            .context("Cached build script output contained invalid UTF-8")?;
+    // It's only any good if what the build script said it depends on
+    // hasn't changed since.
+    let profile_dir = out_dir_layout::profile_dir(&out_dir);
+    let cache = remote_fallback::cache_from_env(profile_dir)?;
+    let whole_package_pulls = config::whole_package_pulls();
+    let build_script_built_locally = whole_package_pulls
+        && build_script_build_dir
+            .join(BUILT_LOCALLY_FILE_NAME)
+            .exists();
+    let cached_stdout = match cache.get_build_script_stdout(&stdout_key) {
+        Ok(_) if build_script_built_locally => {
+            eprintln!("Hope: Build script for {crate_name} was built locally, so running it too.");
+            None
+        }
+        Ok(stdout) => {
+            let stdout = String::from_utf8(stdout)
+                .context("Cached build script output contained invalid UTF-8")?;
+            let recorded_inputs =
+                BuildScriptInputs::load(&*cache, &stdout_key)?.unwrap_or_default();
+            (recorded_inputs == BuildScriptInputs::observe(&stdout, &package_dir)?)
+                .then_some(stdout)
+        }
+        // TODO: Care about the specific error.
+        Err(_) => None,
+    };
+    if let Some(build_script_stdout) = cached_stdout {
         // We found the build script output in cache. We need to emit a copy of its output
         // so that Cargo knows what flags to use when invoking `rustc` for building the main crate.
         // (Most of them don't matter, but some things get a bit wonky if we don't emit the same thing
         // that the real build script does.)
         for line in build_script_stdout.lines() {
-            if line.starts_with("cargo:rerun-if-") {
-                // Skip output lines that would cause Cargo to consider
-                // the build script as dirty just because we don't actually run it.
+            if let Some(("rerun-if-changed", path)) = directive(line) {
+                // Skip watched files that the real build script would have
+                // generated, because Cargo would consider the build script
+                // dirty just because we didn't actually run it.
+                //
+                // Anything else we pass on, so that Cargo reruns (the wrapper
+                // for) the build script in exactly the same cases as it would
+                // have for the real one. Likewise `rerun-if-env-changed`.
                 //
                 // (We store the full output in the cache because it's easier for debugging
                 // and tweaking the rules here if we do it on the way _out_.)
-                continue;
+                let path = package_dir.join(path);
+                if !path.exists() || path.starts_with(&out_dir) {
+                    continue;
+                }
             }
 
             // TODO: See if there are any lines in the stdout that need to have, e.g., paths mangled.
@@ -96,25 +170,24 @@ pub fn run(called_as: &Path) -> anyhow::Result<()> {
                 .read_link()
                 .context("Failed to read symlink to real build script")?,
             env_vars: env::vars().collect(),
-            work_dir: env::current_dir().context("Couldn't get working dir")?,
+            work_dir: package_dir,
         };
         let invocation_info_file =
             File::create(out_dir.join(BUILD_SCRIPT_INVOCATION_INFO_FILE_NAME))
                 .context("Failed to create build script invocation info file")?;
         serde_json::to_writer(invocation_info_file, &invocation_info)
             .context("Failed to write build script invocation info file")?;
+        remove_if_exists(&out_dir.join(BUILT_LOCALLY_FILE_NAME))?;
     } else {
-        // TODO: Care about the specific error.
-
         // We couldn't find the build script output in cache, so we need to run it eagerly ourselves.
-        let output = Command::new(&real_build_script_symlink_path)
-            .output()
+        let (output, usage) = signals::output(&mut Command::new(&real_build_script_symlink_path))
             .with_context(|| {
-                format!(
-                    "Failed to start real build script at {:?}",
-                    real_build_script_symlink_path
-                )
-            })?;
+            format!(
+                "Failed to start real build script at {:?}",
+                real_build_script_symlink_path
+            )
+        })?;
+        signals::check()?;
         if !output.status.success() {
             std::process::exit(
                 output
@@ -129,6 +202,7 @@ pub fn run(called_as: &Path) -> anyhow::Result<()> {
             CacheLogLine::RanBuildScript(BuildScriptRunEvent {
                 crate_name: crate_name.to_string(),
                 ran_at: Utc::now(),
+                usage: Some(usage),
             }),
         )?;
 
@@ -137,15 +211,109 @@ pub fn run(called_as: &Path) -> anyhow::Result<()> {
         std::io::stdout().write_all(&output.stdout)?;
         std::io::stdout().write_all(&output.stderr)?;
 
+        // If an earlier run was only pretend (and the crate never got compiled,
+        // e.g. because the build was interrupted) then its notes are out of date.
+        remove_if_exists(&out_dir.join(BUILD_SCRIPT_INVOCATION_INFO_FILE_NAME))?;
+        if whole_package_pulls {
+            std::fs::write(out_dir.join(BUILT_LOCALLY_FILE_NAME), "")
+                .context("Failed to write note that build script ran locally")?;
+        } else {
+            remove_if_exists(&out_dir.join(BUILT_LOCALLY_FILE_NAME))?;
+        }
+
         // Finally, we need to store the build script output for other builds to find!
-        cache
-            .put_build_script_stdout(run_metadata_hash, &output.stdout)
-            .context("Failed to store build script output")?;
+        let inputs =
+            BuildScriptInputs::observe(&String::from_utf8_lossy(&output.stdout), &package_dir)?;
+        let stored = inputs.store(&*cache, &stdout_key).and_then(|()| {
+            cache
+                .put_build_script_stdout(&stdout_key, &output.stdout)
+                .context("Failed to store build script output")
+        });
+        // Nor is a remote cache failing; see the `remote_fallback` module.
+        match (stored, profile_dir) {
+            (Err(err), Some(profile_dir)) if cache.is_remote() => {
+                eprintln!(
+                    "Hope failed to store build script output for {crate_name} in {}: {err:#}",
+                    cache.location()
+                );
+                remote_fallback::note_error(
+                    &cache_dir,
+                    profile_dir,
+                    crate_name,
+                    &cache.location(),
+                    &err,
+                )?;
+            }
+            (Err(err), _) => return Err(err),
+            (Ok(()), Some(profile_dir)) if cache.is_remote() => {
+                remote_fallback::note_answer(profile_dir)?;
+            }
+            (Ok(()), _) => {}
+        }
+        // Other caches only get what the main one does, and any of them
+        // failing is no reason to fail the build.
+        let fell_back = remote_fallback::has_fallen_back(profile_dir)?;
+        for push_target in cache::also_push_to_from_env()? {
+            if fell_back && push_target.is_remote() {
+                continue;
+            }
+            if let Err(err) = inputs
+                .store(&*push_target, &stdout_key)
+                .and_then(|()| push_target.put_build_script_stdout(&stdout_key, &output.stdout))
+            {
+                eprintln!(
+                    "Hope failed to store build script output for {crate_name} in {}: {err:#}",
+                    push_target.location()
+                );
+            }
+        }
     }
+    lockfile_index::record_build_script(&LocalCache::from_env()?, &stdout_key)?;
 
     Ok(())
 }
 
+/// `rustc-env` values from the output of the build script for the crate
+/// being compiled, if it has one.
+///
+/// Cargo passes these to `rustc` as environment variables rather than
+/// arguments, so they need to go into the cache key separately.
+pub fn rustc_env_for_crate() -> anyhow::Result<Vec<String>> {
+    // Cargo only sets this for crates with build scripts.
+    let Some(out_dir) = env::var_os("OUT_DIR") else {
+        return Ok(Vec::new());
+    };
+    let out_dir = PathBuf::from(out_dir);
+    let output_path = BuildScriptRunDir::parse(&out_dir)?.stdout_path();
+    let output = match std::fs::read_to_string(&output_path) {
+        Ok(output) => output,
+        // Somebody might have set `OUT_DIR` themselves.
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read build script output at {output_path:?}"))
+        }
+    };
+    Ok(output
+        .lines()
+        .filter_map(|line| match directive(line) {
+            // Don't let where the target dir is make a difference.
+            Some(("rustc-env", var)) => Some(var.replace(&*out_dir.to_string_lossy(), "$OUT_DIR")),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Split a line of build script output into its directive and value,
+/// e.g. `("rustc-env", "FOO=bar")` for "cargo:rustc-env=FOO=bar".
+///
+/// Cargo accepts both the old "cargo:" and new "cargo::" prefixes.
+pub fn directive(line: &str) -> Option<(&str, &str)> {
+    line.strip_prefix("cargo::")
+        .or_else(|| line.strip_prefix("cargo:"))?
+        .split_once('=')
+}
+
 pub fn append_moved_build_script_suffix(build_script_path: &Path) -> anyhow::Result<PathBuf> {
     let build_script_file_name = build_script_path
         .file_name()
@@ -169,17 +337,61 @@ pub struct BuildScriptInvocationInfo {
 }
 
 impl BuildScriptInvocationInfo {
+    /// Load invocation info, unless it's stale, in which case remove it.
+    pub fn load_unless_stale(path: &Path) -> anyhow::Result<Option<Self>> {
+        match Self::load_checked(path) {
+            Ok(info) => Ok(Some(info)),
+            Err(stale_reason) => {
+                eprintln!(
+                    "Hope: Removing stale build script invocation info {path:?}: {stale_reason}"
+                );
+                remove_if_exists(path)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Load invocation info, or say why it's stale.
+    ///
+    /// It's stale if the build script or package it refers to has gone away,
+    /// or if it isn't in the out dir it was written for (e.g. because the
+    /// target dir was moved); we couldn't run the build script properly then.
+    pub fn load_checked(path: &Path) -> Result<Self, String> {
+        let json =
+            std::fs::read_to_string(path).map_err(|err| format!("couldn't read it: {err}"))?;
+        let info: Self =
+            serde_json::from_str(&json).map_err(|err| format!("couldn't parse it: {err}"))?;
+        match info.stale_reason(path) {
+            Some(stale_reason) => Err(stale_reason),
+            None => Ok(info),
+        }
+    }
+
+    fn stale_reason(&self, path: &Path) -> Option<String> {
+        if !self.real_build_script_path.exists() {
+            return Some(format!(
+                "real build script {:?} no longer exists",
+                self.real_build_script_path
+            ));
+        }
+        if !self.work_dir.is_dir() {
+            return Some(format!("package dir {:?} no longer exists", self.work_dir));
+        }
+        match self.out_dir() {
+            Ok(out_dir) if path.parent() == Some(out_dir.as_path()) => None,
+            Ok(out_dir) => Some(format!("it was written for out dir {out_dir:?}")),
+            Err(err) => Some(format!("{err:#}")),
+        }
+    }
+
     /// Get the invoked timestamp for when Cargo originally
     /// attempted to run the build script.
     ///
     /// See comments on `get_invoked_timestamp_for_crate_build_unit` for more detail.
     pub fn get_invoked_timestamp(&self) -> anyhow::Result<filetime::FileTime> {
-        let out_dir = self.out_dir()?;
-        let build_script_invocation_build_dir = out_dir
-            .parent()
-            .context("Out dir missing parent; can't find invoked timestamp for build script run")?;
-        // Now read the mtime of the "invoked.timestamp" file for this build script execution unit.
-        let invoked_timestamp_path = build_script_invocation_build_dir.join("invoked.timestamp");
+        // Read the mtime of the "invoked.timestamp" file for this build script execution unit.
+        let invoked_timestamp_path =
+            BuildScriptRunDir::parse(&self.out_dir()?)?.invoked_timestamp_path();
         let invoked_timestamp_file_metadata = std::fs::metadata(invoked_timestamp_path).context(
             "Failed to get metadata for \"invoked.timestamp\" file; maybe it doesn't exist?",
         )?;
@@ -197,3 +409,13 @@ impl BuildScriptInvocationInfo {
             .context("Build script invocation info 'OUT_DIR' env var contained invalid path")
     }
 }
+
+/// Remove a file (or symlink) if it's there.
+pub fn remove_if_exists(path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Failed to remove {path:?}"))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,87 @@
+//! Comparing cached build script output between two runs.
+//!
+//! Build script output controls how the main crate gets compiled
+//! (`cargo:rustc-cfg`, `cargo:rustc-link-lib`, etc.), so when upgrading
+//! something changes the flags for a crate and everything downstream stops
+//! hitting the cache, this is a quick way to see what changed.
+
+use anyhow::Context;
+
+use crate::cache::{Cache, LocalCache};
+
+/// Diff the cached stdout of two runs of `package`'s build script.
+///
+/// Each run is picked out by a prefix of what comes after the package name
+/// in its key (usually just the start of Cargo's metadata hash for the run).
+pub fn run(package: &str, run_a: &str, run_b: &str) -> anyhow::Result<()> {
+    let cache = LocalCache::from_env()?;
+    let runs = cache.build_script_executions(package)?;
+    let key_a = find_run(&runs, package, run_a)?;
+    let key_b = find_run(&runs, package, run_b)?;
+    let stdout = |key: &str| -> anyhow::Result<String> {
+        String::from_utf8(cache.get_build_script_stdout(key)?)
+            .context("Cached build script output contained invalid UTF-8")
+    };
+    let stdout_a = stdout(key_a)?;
+    let stdout_b = stdout(key_b)?;
+
+    println!("--- {key_a}");
+    println!("+++ {key_b}");
+    let lines_a: Vec<&str> = stdout_a.lines().collect();
+    let lines_b: Vec<&str> = stdout_b.lines().collect();
+    for (change, line) in diff_lines(&lines_a, &lines_b) {
+        println!("{change}{line}");
+    }
+    Ok(())
+}
+
+fn find_run<'a>(runs: &'a [String], package: &str, run: &str) -> anyhow::Result<&'a str> {
+    let matches: Vec<&String> = runs
+        .iter()
+        .filter(|key| key[package.len() + 1..].starts_with(run))
+        .collect();
+    match matches.as_slice() {
+        [key] => Ok(key),
+        [] if runs.is_empty() => anyhow::bail!("No cached build script output for {package}"),
+        [] => anyhow::bail!(
+            "No cached build script output for {package} matching {run:?}; found:\n{}",
+            runs.join("\n")
+        ),
+        _ => anyhow::bail!("{run:?} is ambiguous for {package}; give more of the hash"),
+    }
+}
+
+/// A minimal line diff: longest common subsequence, then walk it.
+///
+/// Build script output is rarely more than a few dozen lines,
+/// so the quadratic table is fine.
+fn diff_lines<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<(char, &'a str)> {
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            diff.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            diff.push(('-', a[i]));
+            i += 1;
+        } else {
+            diff.push(('+', b[j]));
+            j += 1;
+        }
+    }
+    diff.extend(a[i..].iter().map(|line| ('-', *line)));
+    diff.extend(b[j..].iter().map(|line| ('+', *line)));
+    diff
+}
@@ -0,0 +1,131 @@
+//! What a build script's output depends on, according to its own
+//! `rerun-if-changed` and `rerun-if-env-changed` directives.
+//!
+//! Cargo's metadata hash for a build script execution covers the package,
+//! features, profile, etc., but not environment variables or files that
+//! the build script reads. So when we store a build script's stdout,
+//! we also record what those were, and only replay it if they still match.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    build_script::directive,
+    chunks::{self, BlobStore},
+};
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildScriptInputs {
+    /// Values of variables named by `rerun-if-env-changed` (`None` if unset).
+    env: BTreeMap<String, Option<String>>,
+    /// Hashes of paths named by `rerun-if-changed`, relative to the
+    /// package dir (`None` if missing).
+    files: BTreeMap<String, Option<String>>,
+}
+
+impl BuildScriptInputs {
+    /// Look up the current state of everything the output says it depends on.
+    ///
+    /// Relative paths are resolved against `package_dir`, same as Cargo.
+    pub fn observe(stdout: &str, package_dir: &Path) -> anyhow::Result<Self> {
+        let mut inputs = Self::default();
+        for line in stdout.lines() {
+            match directive(line) {
+                Some(("rerun-if-env-changed", name)) if !is_set_by_cargo(name) => {
+                    inputs.env.insert(name.to_owned(), std::env::var(name).ok());
+                }
+                // Files outside the package are either generated (and so
+                // will be regenerated), or belong to other packages (which
+                // we assume don't change, same as this one's other files).
+                Some(("rerun-if-changed", path))
+                    if package_dir.join(path).starts_with(package_dir) =>
+                {
+                    inputs
+                        .files
+                        .insert(path.to_owned(), hash_path(&package_dir.join(path))?);
+                }
+                _ => {}
+            }
+        }
+        Ok(inputs)
+    }
+
+    pub fn blob_key(build_script_execution_key: &str) -> String {
+        format!("build-script-{build_script_execution_key}-inputs.json")
+    }
+
+    pub fn load(
+        store: &(impl BlobStore + ?Sized),
+        build_script_execution_key: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let blob_key = Self::blob_key(build_script_execution_key);
+        if !store.has_blob(&blob_key)? {
+            return Ok(None);
+        }
+        let bytes = store.get_blob(&blob_key)?;
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .with_context(|| format!("Invalid build script inputs {blob_key:?}"))
+    }
+
+    pub fn store(
+        &self,
+        store: &(impl BlobStore + ?Sized),
+        build_script_execution_key: &str,
+    ) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(self).context("Failed to serialize build script inputs")?;
+        store.put_blob(&Self::blob_key(build_script_execution_key), &bytes)
+    }
+}
+
+/// Is this one of the variables Cargo sets for build scripts?
+///
+/// Some build scripts watch these too, but they're either covered by
+/// Cargo's metadata hash or specific to the target dir (e.g. `OUT_DIR`),
+/// so recording them would only stop output being shared between projects.
+fn is_set_by_cargo(name: &str) -> bool {
+    const VARS: &[&str] = &[
+        "OUT_DIR",
+        "TARGET",
+        "HOST",
+        "NUM_JOBS",
+        "OPT_LEVEL",
+        "DEBUG",
+        "PROFILE",
+        "RUSTC",
+        "RUSTDOC",
+        "RUSTC_LINKER",
+        "RUSTC_WRAPPER",
+        "RUSTC_WORKSPACE_WRAPPER",
+    ];
+    name.starts_with("CARGO") || name.starts_with("DEP_") || VARS.contains(&name)
+}
+
+/// Hash a file, or everything under a directory (which Cargo also
+/// accepts for `rerun-if-changed`).
+fn hash_path(path: &Path) -> anyhow::Result<Option<String>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    if path.is_file() {
+        let content =
+            std::fs::read(path).with_context(|| format!("Failed to read {path:?} to hash it"))?;
+        return Ok(Some(chunks::hash_bytes(&content)));
+    }
+    let mut listing = String::new();
+    for entry in walkdir::WalkDir::new(path).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("Failed to walk {path:?}"))?;
+        if entry.file_type().is_file() {
+            let content = std::fs::read(entry.path())
+                .with_context(|| format!("Failed to read {:?} to hash it", entry.path()))?;
+            listing.push_str(&format!(
+                "{} {}\n",
+                chunks::hash_bytes(&content),
+                entry.path().strip_prefix(path)?.display()
+            ));
+        }
+    }
+    Ok(Some(chunks::hash_bytes(listing.as_bytes())))
+}
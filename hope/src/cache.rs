@@ -1,9 +1,11 @@
 use std::{
+    collections::{BTreeSet, HashMap},
     fs::File,
-    io::Write as _,
+    io::{Read as _, Seek as _, SeekFrom, Write as _},
     path::{Path, PathBuf},
     str::FromStr,
-    time::Instant,
+    sync::Arc,
+    time::{Instant, SystemTime},
 };
 
 use anyhow::Context;
@@ -11,13 +13,41 @@ use chrono::Utc;
 use directories::ProjectDirs;
 use hope_cache_log::{write_log_line, CacheLogLine, PullCrateOutputsEvent, PushCrateOutputsEvent};
 
-use crate::OutputDefn;
+use crate::{
+    bazel::BazelBlobStore,
+    chunks::{self, BlobStore},
+    clean, clock,
+    compression::{self, Codec, CompressionPolicy},
+    config, credentials,
+    daemon_socket::DaemonBlobStore,
+    entry_manifest::{EntryAlias, EntryManifest},
+    fail_point,
+    gha::GhaBlobStore,
+    http_store::HttpBlobStore,
+    key::CacheKey,
+    permissions::PermissionPolicy,
+    redis::RedisBlobStore,
+    s3::S3BlobStore,
+    sharded_store::ShardedBlobStore,
+    signals,
+    tiered_cache::TieredCache,
+    transfer_limits, OutputDefn,
+};
+
+/// Version of the cache's layout: how entries, manifests, and build script
+/// output are named and stored. Bump it whenever that changes in a way that
+/// older versions of Hope wouldn't understand.
+pub const SCHEMA_VERSION: u32 = 1;
 
 /// Cache implementations are not responsible for modifying
 /// content to be stored/retrieved (e.g. changing paths);
 /// that is the responsibility of the caller.
-pub trait Cache {
-    /// Unit name is of the form "{crate name}-{metadata hash}".
+///
+/// Entry manifests, aliases, and so on are stored as blobs
+/// alongside the outputs, so every cache is also a blob store.
+pub trait Cache: BlobStore + Send {
+    /// Outputs are stored under the key's storage name, but should arrive
+    /// named for the key's unit name (which is what Cargo expects).
     ///
     /// The `arrival_dir` should be a temporary directory.
     /// Once files are placed in that directory, it is the caller's
@@ -26,46 +56,319 @@ pub trait Cache {
     /// (at least try to clean up if you get a failure part-way through).
     fn pull_crate(
         &self,
-        unit_name: &str,
+        key: &CacheKey,
         output_defns: &[OutputDefn],
         arrival_dir: &Path,
     ) -> anyhow::Result<()>;
 
-    /// Unit name is of the form "{crate name}-{metadata hash}".
+    /// Files in `departure_dir` are named for the key's unit name.
+    /// The manifest is stored alongside them.
     ///
     /// TODO: List things that must be placed into this dir,
     /// and provide a helper to assert that they are there!
     fn push_crate(
         &self,
-        unit_name: &str,
+        key: &CacheKey,
+        manifest: &EntryManifest,
         output_defns: &[OutputDefn],
         departure_dir: &Path,
     ) -> anyhow::Result<()>;
 
     /// Get stdout of a build script execution from the cache.
     ///
-    /// (We don't have a great source for the main crate name when we
-    /// need to look this up, so just go by the execution's metadata hash alone.)
+    /// The key is "{package name}-{execution's metadata hash}-{build script's
+    /// own cache key}". (The package name is just to help humans find things;
+    /// see `hope diff-build-script`.)
     ///
     /// If this is present, then we can assume that the whole crate
     /// output is cached, so we can just emit the cached stdout to control
     /// arguments to `rustc` for the build of the main crate, but without
     /// actually building or running the build script itself.
-    fn get_build_script_stdout(
-        &self,
-        build_script_execution_metadata_hash: &str,
-    ) -> anyhow::Result<Vec<u8>>;
+    fn get_build_script_stdout(&self, build_script_execution_key: &str) -> anyhow::Result<Vec<u8>>;
 
     /// Put stdout of a build script execution into the cache.
     fn put_build_script_stdout(
         &self,
-        build_script_execution_metadata_hash: &str,
+        build_script_execution_key: &str,
         stdout: &[u8],
     ) -> anyhow::Result<()>;
+
+    /// Total size of the outputs that pulling this key would write,
+    /// if there's an entry for it.
+    fn pull_size(&self, key: &CacheKey, output_defns: &[OutputDefn])
+        -> anyhow::Result<Option<u64>>;
+
+    /// How the log says where pulls came from and pushes went to,
+    /// e.g. "local cache" or "s3://bucket/prefix/".
+    fn location(&self) -> String;
+
+    /// Somewhere on this machine that pushing writes to, if any,
+    /// so that we can check there's room.
+    fn local_dir(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Does this cache live somewhere other than this machine?
+    ///
+    /// Some limits (e.g. on artifact size) only make sense
+    /// when pushing over the network.
+    fn is_remote(&self) -> bool {
+        false
+    }
+
+    /// Where to find the outputs for a key, following its alias if it has one.
+    fn resolve_storage_name(&self, key: &CacheKey) -> anyhow::Result<String> {
+        if let Some(alias_name) = key.alias_name() {
+            if let Some(alias) = EntryAlias::load(self, &alias_name)? {
+                return Ok(alias.target);
+            }
+        }
+        // Even without an alias, an exact match is still a match.
+        Ok(key.storage_name())
+    }
+}
+
+/// The cache that builds pull from and push to: wherever `HOPE_CACHE_URL`
+/// says, or the local cache if it's not set.
+///
+/// The URL's scheme picks the backend, so switching between them is only
+/// ever a matter of changing the URL.
+///
+/// With `HOPE_DAEMON_SOCKET` set, the remote cache is reached through
+/// `hope daemon` instead; see the `daemon_socket` module.
+///
+/// With `HOPE_ALSO_PULL_FROM` set, pulls that miss fall back to those caches
+/// in turn (and with `HOPE_BACKFILL`, fill in the ones that missed), and
+/// then to the one in `HOPE_PUBLIC_CACHE_URL`; see the `tiered_cache` module.
+///
+/// With `HOPE_MAX_REMOTE_PUSH_SIZE` set too, units too big for the remote
+/// cache are pushed to the local cache instead, so pulls that miss the
+/// remote cache try the local cache before any of those.
+pub fn from_env() -> anyhow::Result<Arc<dyn Cache>> {
+    let main: Arc<dyn Cache> = match config::daemon_socket() {
+        Some(socket) => Arc::new(RemoteCache::new(DaemonBlobStore::new(&socket))?),
+        None => match remote_store_from_env()? {
+            Some(store) => Arc::new(RemoteCache::new(store)?),
+            None => Arc::new(LocalCache::from_env()?),
+        },
+    };
+    let mut also_pull_from = config::also_pull_from()
+        .iter()
+        .map(|target| from_target(target))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .context("Invalid 'HOPE_ALSO_PULL_FROM' environment variable")?;
+    if main.is_remote() && config::max_remote_push_size()?.is_some() {
+        also_pull_from.insert(0, Arc::new(LocalCache::from_env()?));
+    }
+    if let Some(url) = config::public_cache_url() {
+        also_pull_from.push(
+            public_cache_from_url(&url)
+                .context("Invalid 'HOPE_PUBLIC_CACHE_URL' environment variable")?,
+        );
+    }
+    if also_pull_from.is_empty() {
+        return Ok(main);
+    }
+    Ok(Arc::new(TieredCache::new(
+        main,
+        also_pull_from,
+        config::backfill(),
+    )))
+}
+
+/// Where `HOPE_CACHE_URL` or `HOPE_CACHE_SHARDS` says the remote cache is,
+/// or `None` if it's the local cache.
+pub fn remote_store_from_env() -> anyhow::Result<Option<Box<dyn RemoteBlobStore>>> {
+    let shards = config::cache_shards();
+    Ok(match config::cache_url() {
+        Some(_) if !shards.is_empty() => {
+            anyhow::bail!("Set one of 'HOPE_CACHE_URL' and 'HOPE_CACHE_SHARDS', not both")
+        }
+        Some(url) => {
+            Some(store_from_url(&url).context("Invalid 'HOPE_CACHE_URL' environment variable")?)
+        }
+        None if !shards.is_empty() => {
+            let shards = shards
+                .iter()
+                .map(|url| store_from_url(url))
+                .collect::<anyhow::Result<_>>()
+                .context("Invalid 'HOPE_CACHE_SHARDS' environment variable")?;
+            Some(Box::new(ShardedBlobStore::new(shards)?))
+        }
+        None => None,
+    })
+}
+
+/// Caches that pushes also go to, besides the one from `from_env`,
+/// as listed in `HOPE_ALSO_PUSH_TO`.
+///
+/// Pulls only ever come from the main cache; these are just
+/// for populating other caches along the way.
+pub fn also_push_to_from_env() -> anyhow::Result<Vec<Arc<dyn Cache>>> {
+    config::also_push_to()
+        .iter()
+        .map(|target| from_target(target))
+        .collect::<anyhow::Result<_>>()
+        .context("Invalid 'HOPE_ALSO_PUSH_TO' environment variable")
+}
+
+/// What pushes call the main cache (from `from_env`), as opposed to
+/// the caches in `HOPE_ALSO_PUSH_TO`, which go by their URLs.
+pub const MAIN_CACHE: &str = "main";
+
+/// Names for the caches that pushes go to, in the same order as
+/// `from_env` and then `also_push_to_from_env`, for remembering
+/// where a push was headed (see the `upload_queue` module).
+pub fn push_target_names() -> Vec<String> {
+    std::iter::once(MAIN_CACHE.to_owned())
+        .chain(config::also_push_to())
+        .collect()
+}
+
+/// The cache that one of [`push_target_names`] names.
+pub fn push_target_from_name(name: &str) -> anyhow::Result<Arc<dyn Cache>> {
+    if name == MAIN_CACHE {
+        from_env()
+    } else {
+        from_target(name)
+    }
+}
+
+/// A cache URL, or "local" for the local cache.
+fn from_target(target: &str) -> anyhow::Result<Arc<dyn Cache>> {
+    if target == "local" {
+        Ok(Arc::new(LocalCache::from_env()?))
+    } else {
+        from_url(target)
+    }
+}
+
+fn from_url(url: &str) -> anyhow::Result<Arc<dyn Cache>> {
+    Ok(Arc::new(RemoteCache::new(store_from_url(url)?)?))
+}
+
+/// A cache that anyone can pull from, so we mustn't send it credentials.
+fn public_cache_from_url(url: &str) -> anyhow::Result<Arc<dyn Cache>> {
+    anyhow::ensure!(
+        url.starts_with("http://") || url.starts_with("https://"),
+        "Public cache URL {url:?} should be http:// or https://; \
+         for a public bucket, that's its (or its CDN's) https:// URL"
+    );
+    let store = transfer_limits::limit_from_env(Box::new(HttpBlobStore::public(url)))?;
+    Ok(Arc::new(RemoteCache::new(store)?))
+}
+
+/// Where a cache URL (or "local", for the local cache) keeps its blobs,
+/// for working with them directly, e.g. in `hope sync`.
+pub fn blob_store_from_target(target: &str) -> anyhow::Result<Box<dyn RemoteBlobStore>> {
+    if target == "local" {
+        Ok(Box::new(LocalCache::from_env()?))
+    } else {
+        store_from_url(target)
+    }
+}
+
+/// The blob store for a cache URL, with transfers limited as
+/// the environment says (see the `transfer_limits` module).
+pub fn store_from_url(url: &str) -> anyhow::Result<Box<dyn RemoteBlobStore>> {
+    transfer_limits::limit_from_env(unlimited_store_from_url(url)?)
+}
+
+fn unlimited_store_from_url(url: &str) -> anyhow::Result<Box<dyn RemoteBlobStore>> {
+    Ok(if let Some(path) = url.strip_prefix("file://") {
+        // A cache dir that's shared (e.g. on a network drive) is as good
+        // as on another machine, and can be raced on just the same.
+        let path = path.strip_prefix("localhost").unwrap_or(path);
+        anyhow::ensure!(
+            path.starts_with('/'),
+            "Cache URL {url:?} should have an absolute path, e.g. \"file:///mnt/hope\""
+        );
+        let mut shared_cache = LocalCache::new(path);
+        shared_cache.permissions = config::permissions()?;
+        shared_cache
+            .permissions
+            .create_dir_all(Path::new(path))
+            .with_context(|| format!("Failed to create cache dir for {url:?}"))?;
+        Box::new(shared_cache)
+    } else if url.starts_with("s3://") {
+        Box::new(S3BlobStore::from_url(url, credentials::for_url(url)?)?)
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        Box::new(HttpBlobStore::new(url).with_token(credentials::for_url(url)?))
+    } else if url.starts_with("bazel+") {
+        Box::new(BazelBlobStore::from_url(url)?.with_token(credentials::for_url(url)?))
+    } else if url.starts_with("gha://") {
+        Box::new(GhaBlobStore::from_url(url, credentials::for_url(url)?)?)
+    } else if url.starts_with("redis://") {
+        Box::new(RedisBlobStore::from_url(
+            url,
+            config::redis_ttl()?,
+            credentials::for_url(url)?,
+        )?)
+    } else {
+        anyhow::bail!(
+            "Unsupported cache URL {url:?}; \
+             expected file://, s3://, http://, https://, bazel+http(s)://, redis://, or gha://"
+        )
+    })
+}
+
+/// A blob store on another machine, that a [`RemoteCache`] can keep entries in.
+pub trait RemoteBlobStore: BlobStore + Send {
+    /// Where the blobs are, for messages and the log.
+    fn url(&self) -> String;
+
+    /// Size of a blob, or `None` if there's no such blob.
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>>;
+
+    /// The last `len` bytes of a blob, without fetching the rest of it.
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>>;
+
+    /// Keys of the blobs at the top level (i.e. not under any "directory"),
+    /// which is where manifests and the like are, if the store can list them.
+    fn list_blobs(&self) -> anyhow::Result<Vec<String>> {
+        anyhow::bail!("Can't list what's in {}", self.url())
+    }
+}
+
+impl<S: BlobStore + ?Sized> BlobStore for Box<S> {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        (**self).get_blob(key)
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        (**self).put_blob(key, bytes)
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        (**self).has_blob(key)
+    }
+}
+
+impl<S: RemoteBlobStore + ?Sized> RemoteBlobStore for Box<S> {
+    fn url(&self) -> String {
+        (**self).url()
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        (**self).blob_size(key)
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        (**self).get_blob_tail(key, len)
+    }
+
+    fn list_blobs(&self) -> anyhow::Result<Vec<String>> {
+        (**self).list_blobs()
+    }
 }
 
+#[derive(Clone)]
 pub struct LocalCache {
     root: PathBuf,
+    // If set, then store artifacts in chunks of (at most) this size.
+    chunk_size: Option<u64>,
+    compression: CompressionPolicy,
+    permissions: PermissionPolicy,
 }
 
 impl LocalCache {
@@ -74,15 +377,27 @@ impl LocalCache {
     /// If you want that, then call `from_env`, which ensures
     /// the directory exists.
     pub fn new(root: impl Into<PathBuf>) -> Self {
-        Self { root: root.into() }
+        Self {
+            root: root.into(),
+            chunk_size: None,
+            compression: CompressionPolicy::default(),
+            permissions: PermissionPolicy::default(),
+        }
     }
 
     pub fn from_env() -> anyhow::Result<Self> {
         let cache_dir = Self::dir_from_env().context("Couldn't infer cache directory")?;
+        let permissions = config::permissions()?;
         if !cache_dir.exists() {
-            std::fs::create_dir_all(&cache_dir).context("Failed to create cache dir")?;
+            permissions
+                .create_dir_all(&cache_dir)
+                .context("Failed to create cache dir")?;
         }
-        Ok(Self::new(cache_dir))
+        let mut cache = Self::new(cache_dir);
+        cache.chunk_size = config::chunk_size()?;
+        cache.compression = config::compression()?;
+        cache.permissions = permissions;
+        Ok(cache)
     }
 
     pub fn dir_from_env() -> anyhow::Result<PathBuf> {
@@ -97,19 +412,342 @@ impl LocalCache {
     }
 }
 
+impl LocalCache {
+    /// Every entry that has a manifest, by storage name.
+    pub fn entries(&self) -> anyhow::Result<Vec<(String, EntryManifest)>> {
+        let mut entries = Vec::new();
+        for dir_entry in std::fs::read_dir(&self.root).context("Failed to read cache dir")? {
+            let file_name = dir_entry
+                .context("Failed to read cache dir entry")?
+                .file_name();
+            let Some(storage_name) = file_name
+                .to_str()
+                .and_then(EntryManifest::storage_name_for_blob_key)
+            else {
+                continue;
+            };
+            if let Some(manifest) = EntryManifest::load(self, storage_name)? {
+                entries.push((storage_name.to_owned(), manifest));
+            }
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(entries)
+    }
+
+    /// Keys of every cached execution of the package's build script.
+    pub fn build_script_executions(&self, package: &str) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for dir_entry in std::fs::read_dir(&self.root).context("Failed to read cache dir")? {
+            let file_name = dir_entry
+                .context("Failed to read cache dir entry")?
+                .file_name();
+            let Some(key) = file_name
+                .to_str()
+                .and_then(build_script_execution_key_for_file_name)
+            else {
+                continue;
+            };
+            // Package names can contain hyphens, so check that what's left
+            // is exactly the two hashes.
+            if key
+                .strip_prefix(package)
+                .and_then(|hashes| hashes.strip_prefix('-'))
+                .is_some_and(|hashes| hashes.split('-').count() == 2)
+            {
+                keys.push(key.to_owned());
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Every given entry's files, found in one pass over the cache dir.
+    ///
+    /// Output file names all embed the storage name, which ends in a hash,
+    /// so files are matched to entries by that hash rather than checked
+    /// against every entry in turn.
+    pub fn entry_files<'a>(
+        &self,
+        storage_names: impl IntoIterator<Item = &'a str>,
+    ) -> anyhow::Result<HashMap<String, EntryFiles>> {
+        let mut by_hash: HashMap<&[u8], Vec<&str>> = HashMap::new();
+        let mut entry_files = HashMap::new();
+        for storage_name in storage_names {
+            let hash = &storage_name.as_bytes()[storage_name.len().saturating_sub(HASH_LEN)..];
+            by_hash.entry(hash).or_default().push(storage_name);
+            entry_files.insert(storage_name.to_owned(), EntryFiles::default());
+        }
+        for dir_entry in std::fs::read_dir(&self.root).context("Failed to read cache dir")? {
+            let dir_entry = dir_entry.context("Failed to read cache dir entry")?;
+            let Some(file_name) = dir_entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            let owners: BTreeSet<&str> = file_name
+                .as_bytes()
+                .windows(HASH_LEN)
+                .filter_map(|window| by_hash.get(window))
+                .flatten()
+                .copied()
+                .filter(|storage_name| file_name.contains(storage_name))
+                .collect();
+            if owners.is_empty() {
+                continue;
+            }
+            let size = dir_entry.metadata()?.len();
+            let chunks = if file_name.ends_with(".chunks.json") {
+                chunks::ChunkManifest::parse(&std::fs::read(dir_entry.path())?)
+                    .with_context(|| format!("Invalid chunk manifest {file_name:?}"))?
+                    .chunks
+            } else {
+                Vec::new()
+            };
+            for storage_name in owners {
+                let files = entry_files
+                    .get_mut(storage_name)
+                    .expect("Owners should all be entries we're looking for");
+                files.file_names.push(file_name.clone());
+                files.size += size;
+                files
+                    .chunks
+                    .extend(chunks.iter().map(|chunk| chunk.hash.clone()));
+            }
+        }
+        Ok(entry_files)
+    }
+
+    /// Every stored chunk, by hash, with its size and when it was written.
+    pub fn chunks(&self) -> anyhow::Result<HashMap<String, (u64, SystemTime)>> {
+        let chunks_dir = self.root.join("chunks");
+        let read_dir = match std::fs::read_dir(&chunks_dir) {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(err) => return Err(err).context("Failed to read chunks dir"),
+        };
+        let mut chunks = HashMap::new();
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry.context("Failed to read chunks dir entry")?;
+            let Some(hash) = dir_entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            let metadata = dir_entry.metadata()?;
+            chunks.insert(hash, (metadata.len(), metadata.modified()?));
+        }
+        Ok(chunks)
+    }
+
+    /// Where the blob with this key lives.
+    ///
+    /// Keys can come from other machines (e.g. in a lockfile index or entry
+    /// manifest fetched from a remote cache), so refuse any that would
+    /// reach outside the cache dir.
+    fn blob_path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        anyhow::ensure!(
+            is_valid_key(key),
+            "Refusing blob key {key:?}, which isn't a relative path within the cache dir."
+        );
+        Ok(self.root.join(key))
+    }
+
+    /// How big a stored output file will be once it's pulled,
+    /// however it was stored, if it's here at all.
+    pub fn stored_file_size(&self, file_name: &str) -> anyhow::Result<Option<u64>> {
+        let chunk_manifest_key = chunks::manifest_key(file_name);
+        if self.has_blob(&chunk_manifest_key)? {
+            let manifest = chunks::ChunkManifest::parse(&self.get_blob(&chunk_manifest_key)?)
+                .with_context(|| format!("Invalid chunk manifest for {file_name:?}"))?;
+            return Ok(Some(manifest.total_size));
+        }
+        let compressed_key = compression::compressed_key(file_name);
+        if self.has_blob(&compressed_key)? {
+            return compression::uncompressed_size(&self.blob_path(&compressed_key)?).map(Some);
+        }
+        match std::fs::metadata(self.blob_path(file_name)?) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to stat {file_name:?}")),
+        }
+    }
+
+    /// Append a line to a blob, creating it if need be.
+    ///
+    /// Small appends are atomic, so concurrent `rustc`s can safely
+    /// add to the same blob without any locking.
+    pub fn append_line(&self, key: &str, line: &str) -> anyhow::Result<()> {
+        let path = self.blob_path(key)?;
+        if let Some(parent) = path.parent() {
+            self.permissions
+                .create_dir_all(parent)
+                .with_context(|| format!("Failed to create parent dir for {key:?}."))?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {key:?} in local cache."))?;
+        self.permissions.apply_if_ours(&path);
+        file.write_all(format!("{line}\n").as_bytes())
+            .with_context(|| format!("Failed to append to {key:?} in local cache."))
+    }
+
+    /// File to lock while pushing an entry.
+    ///
+    /// These live in their own directory, and are never removed, because
+    /// removing a lock file that somebody else has open splits the lock.
+    fn entry_lock_file(&self, storage_name: &str) -> anyhow::Result<File> {
+        let locks_dir = self.root.join("locks");
+        self.permissions
+            .create_dir_all(&locks_dir)
+            .context("Failed to create locks dir")?;
+        let lock_path = locks_dir.join(format!("{storage_name}.lock"));
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open lock file for entry {storage_name:?}."))?;
+        self.permissions.apply_if_ours(&lock_path);
+        Ok(file)
+    }
+
+    /// Copy a file into the cache under the given key.
+    ///
+    /// Like `put_blob`, this moves the finished copy into place, because two
+    /// `rustc`s can push the same entry at once (e.g. from two projects
+    /// building in parallel), and a third may already be pulling it.
+    fn copy_in(&self, key: &str, from_path: &Path) -> anyhow::Result<()> {
+        let path = self.blob_path(key)?;
+        let temp_file = tempfile::NamedTempFile::new_in(&self.root)
+            .with_context(|| format!("Failed to create temporary file for {key:?}."))?;
+        std::fs::copy(from_path, temp_file.path())
+            .with_context(|| format!("Failed to copy file {key:?} to local cache."))?;
+        self.permissions.apply_to_file(temp_file.as_file())?;
+        temp_file
+            .persist(path)
+            .with_context(|| format!("Failed to move {key:?} into place in local cache."))?;
+        Ok(())
+    }
+
+    /// Remove a single blob, if it exists.
+    pub fn remove_blob(&self, key: &str) -> anyhow::Result<()> {
+        match std::fs::remove_file(self.blob_path(key)?) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("Failed to remove {key:?} from local cache."))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove everything stored for an entry (apart from shared chunks).
+    ///
+    /// Chunks that nothing refers to any more are left for `hope gc` to sweep.
+    pub fn remove_entry(&self, storage_name: &str) -> anyhow::Result<()> {
+        let files = self
+            .entry_files([storage_name])?
+            .remove(storage_name)
+            .unwrap_or_default();
+        self.remove_entry_files(&files)
+    }
+
+    /// Remove an entry's own files, as found by `entry_files`.
+    ///
+    /// Output file names all embed the storage name, which ends in a hash,
+    /// so there's no danger of removing anything belonging to another entry.
+    pub fn remove_entry_files(&self, files: &EntryFiles) -> anyhow::Result<()> {
+        for file_name in &files.file_names {
+            self.remove_blob(file_name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Length of the hash at the end of every storage name; see `CacheKey::storage_name`.
+const HASH_LEN: usize = 16;
+
+/// What's stored in the local cache for an entry; see `LocalCache::entry_files`.
+#[derive(Debug, Default)]
+pub struct EntryFiles {
+    /// Top-level files, including chunk manifests.
+    pub file_names: Vec<String>,
+    /// Space taken up by those files (not counting chunks).
+    pub size: u64,
+    /// Hashes of the chunks its chunk manifests refer to.
+    pub chunks: BTreeSet<String>,
+}
+
 impl Cache for LocalCache {
+    fn pull_size(
+        &self,
+        key: &CacheKey,
+        output_defns: &[OutputDefn],
+    ) -> anyhow::Result<Option<u64>> {
+        let storage_name = self.resolve_storage_name(key)?;
+        let mut total = 0;
+        for output_defn in output_defns {
+            let Some(size) = self.stored_file_size(&output_defn.file_name(&storage_name))? else {
+                return Ok(None);
+            };
+            total += size;
+        }
+        Ok(Some(total))
+    }
+
+    fn location(&self) -> String {
+        "local cache".to_string()
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
     fn pull_crate(
         &self,
-        unit_name: &str,
+        key: &CacheKey,
         output_defns: &[OutputDefn],
         arrival_dir: &Path,
     ) -> anyhow::Result<()> {
         let before = Instant::now();
 
+        fail_point::check("pull_crate")?;
+
+        let storage_name = self.resolve_storage_name(key)?;
+        if let Some(manifest) = EntryManifest::load(self, &storage_name)? {
+            // We'd rather build it again than end up with outputs
+            // that are misnamed or just plain useless for our target.
+            anyhow::ensure!(
+                manifest.target == key.target.triple(),
+                "Cache entry {storage_name:?} was built for target {:?}, not {:?}.",
+                manifest.target,
+                key.target.triple()
+            );
+            if let Some(pushed_at) = manifest.pushed_at {
+                clock::check(
+                    &self.root,
+                    &key.unit_name,
+                    pushed_at,
+                    "Push time of cache entry",
+                )?;
+            }
+        }
         for output_defn in output_defns {
-            let file_name = output_defn.file_name(unit_name);
-            let from_path = self.root.join(&file_name);
-            let to_path = arrival_dir.join(&file_name);
+            signals::check()?;
+            let file_name = output_defn.file_name(&storage_name);
+            let to_path = arrival_dir.join(output_defn.file_name(&key.unit_name));
+            // Whether it was chunked depends on the settings in effect when it was pushed,
+            // not now, so check for a chunk manifest regardless.
+            if self.has_blob(&chunks::manifest_key(&file_name))? {
+                chunks::get_chunked(self, &file_name, &to_path).with_context(|| {
+                    format!("Failed to reassemble file {file_name:?} from local cache.")
+                })?;
+                continue;
+            }
+            // Likewise for compression.
+            if self.has_blob(&compression::compressed_key(&file_name))? {
+                let content = compression::get_compressed(self, &file_name)?;
+                std::fs::write(&to_path, content)
+                    .with_context(|| format!("Failed to write {to_path:?}"))?;
+                continue;
+            }
+            let from_path = self.blob_path(&file_name)?;
             // Copy it to from cache dir.
             std::fs::copy(from_path, &to_path)
                 .with_context(|| format!("Failed to copy file {file_name:?} from local cache."))?;
@@ -119,9 +757,9 @@ impl Cache for LocalCache {
         write_log_line(
             &self.root,
             CacheLogLine::PulledCrateOutputs(PullCrateOutputsEvent {
-                crate_unit_name: unit_name.to_owned(),
+                crate_unit_name: key.unit_name.clone(),
                 copied_at: Utc::now(),
-                copied_from: "local cache".to_string(),
+                copied_from: self.location(),
                 duration_secs: before.elapsed().as_secs_f64(),
             }),
         )?;
@@ -131,28 +769,75 @@ impl Cache for LocalCache {
 
     fn push_crate(
         &self,
-        unit_name: &str,
+        key: &CacheKey,
+        manifest: &EntryManifest,
         output_defns: &[OutputDefn],
         departure_dir: &Path,
     ) -> anyhow::Result<()> {
         let before = Instant::now();
 
-        for output_defn in output_defns {
-            let file_name = output_defn.file_name(unit_name);
-            let from_path = departure_dir.join(&file_name);
-            let to_path = self.root.join(&file_name);
-            // Copy it to the cache dir.
-            std::fs::copy(from_path, to_path)
-                .with_context(|| format!("Failed to copy file {file_name:?} to local cache."))?;
+        let storage_name = key.storage_name();
+        // Builds elsewhere can miss on the same unit at the same time (e.g.
+        // two projects building in parallel). Their outputs aren't identical
+        // (dep info has absolute paths in it, for a start) so only one of
+        // them gets to store them; otherwise we could end up with a mixture
+        // of files that doesn't match either's manifest.
+        let mut entry_lock = fd_lock::RwLock::new(self.entry_lock_file(&storage_name)?);
+        let _entry_guard = entry_lock
+            .write()
+            .with_context(|| format!("Failed to lock entry {storage_name:?} for pushing."))?;
+        // If an equivalent build got there first (under some other policy, or
+        // while we were waiting for the lock) there's no need to store the
+        // same outputs again.
+        if EntryManifest::load(self, &storage_name)?.is_none() {
+            let mut manifest = manifest.clone();
+            for output_defn in output_defns {
+                // Stopping here leaves some of the files without a manifest,
+                // which is fine; the next push of this entry will replace them.
+                signals::check()?;
+                let file_name = output_defn.file_name(&storage_name);
+                let from_path = departure_dir.join(output_defn.file_name(&key.unit_name));
+                let content = std::fs::read(&from_path)
+                    .with_context(|| format!("Failed to read {from_path:?} to hash it."))?;
+                manifest
+                    .files
+                    .insert(file_name.clone(), chunks::hash_bytes(&content));
+                if let Some(chunk_size) = self.chunk_size {
+                    chunks::put_chunked(self, &file_name, &from_path, chunk_size).with_context(
+                        || format!("Failed to store file {file_name:?} in chunks in local cache."),
+                    )?;
+                    continue;
+                }
+                match self
+                    .compression
+                    .codec_for(crate_name(&key.unit_name), &file_name)
+                {
+                    Codec::None => self.copy_in(&file_name, &from_path)?,
+                    Codec::Gzip { level } => {
+                        compression::put_compressed(self, &file_name, &content, level)?
+                    }
+                }
+            }
+            fail_point::check("push_crate")?;
+            manifest
+                .store(self, &storage_name)
+                .context("Failed to store entry manifest in local cache.")?;
+        }
+        if let Some(alias_name) = key.alias_name() {
+            EntryAlias {
+                target: storage_name,
+            }
+            .store(self, &alias_name)
+            .context("Failed to store entry alias in local cache.")?;
         }
 
         // Write out a log line describing where we pushed the unit to.
         write_log_line(
             &self.root,
             CacheLogLine::PushedCrateOutputs(PushCrateOutputsEvent {
-                crate_unit_name: unit_name.to_owned(),
+                crate_unit_name: key.unit_name.clone(),
                 copied_at: Utc::now(),
-                copied_from: "local cache".to_string(),
+                copied_from: self.location(),
                 duration_secs: before.elapsed().as_secs_f64(),
             }),
         )?;
@@ -160,12 +845,9 @@ impl Cache for LocalCache {
         Ok(())
     }
 
-    fn get_build_script_stdout(
-        &self,
-        build_script_execution_metadata_hash: &str,
-    ) -> anyhow::Result<Vec<u8>> {
-        let stdout_file_name = build_script_stdout_file_name(build_script_execution_metadata_hash);
-        let stdout_path = self.root.join(&stdout_file_name);
+    fn get_build_script_stdout(&self, build_script_execution_key: &str) -> anyhow::Result<Vec<u8>> {
+        let stdout_file_name = build_script_stdout_file_name(build_script_execution_key);
+        let stdout_path = self.blob_path(&stdout_file_name)?;
         let content = std::fs::read_to_string(stdout_path).with_context(|| {
             format!("Failed to read build script stdout file \"{stdout_file_name}\".")
         })?;
@@ -174,27 +856,403 @@ impl Cache for LocalCache {
 
     fn put_build_script_stdout(
         &self,
-        build_script_execution_metadata_hash: &str,
+        build_script_execution_key: &str,
         stdout: &[u8],
     ) -> anyhow::Result<()> {
-        let stdout_file_name = build_script_stdout_file_name(build_script_execution_metadata_hash);
-        let stdout_path = self.root.join(stdout_file_name);
-
-        let mut stdout_file =
-            File::create(stdout_path).context("Failed to create file for build script stdout")?;
-        stdout_file
-            .write_all(stdout)
-            .context("Failed to write build script stdout to file")?;
+        self.put_blob(
+            &build_script_stdout_file_name(build_script_execution_key),
+            stdout,
+        )
+        .context("Failed to write build script stdout to local cache")
+    }
+}
+
+impl BlobStore for LocalCache {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        std::fs::read(self.blob_path(key)?)
+            .with_context(|| format!("Failed to read {key:?} from local cache."))
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let path = self.blob_path(key)?;
+        if let Some(parent) = path.parent() {
+            self.permissions
+                .create_dir_all(parent)
+                .with_context(|| format!("Failed to create parent dir for {key:?}."))?;
+        }
+        // Write to a temporary file and then move it into place, so that
+        // nobody reading concurrently (e.g. via `hope serve`) sees half a blob.
+        let parent = path.parent().unwrap_or(&self.root);
+        let mut temp_file = tempfile::NamedTempFile::new_in(parent)
+            .with_context(|| format!("Failed to create temporary file for {key:?}."))?;
+        temp_file
+            .write_all(bytes)
+            .with_context(|| format!("Failed to write {key:?} to local cache."))?;
+        self.permissions.apply_to_file(temp_file.as_file())?;
+        temp_file
+            .persist(&path)
+            .with_context(|| format!("Failed to move {key:?} into place in local cache."))?;
         Ok(())
     }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.blob_path(key)?.exists())
+    }
 }
 
-/// We don't have a great source for the main crate name when we
-/// need to look this up, so just go by the execution's metadata hash alone.
-pub fn build_script_stdout_file_name(build_script_execution_metadata_hash: &str) -> String {
+/// A cache dir can also be shared, e.g. on a network drive, by giving it
+/// to other machines as a "file://" URL; see [`from_env`].
+impl RemoteBlobStore for LocalCache {
+    /// e.g. "file:///mnt/shared/hope/".
+    fn url(&self) -> String {
+        format!("file://{}/", self.root.display())
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        match std::fs::metadata(self.blob_path(key)?) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to check size of {key:?}")),
+        }
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut file = File::open(self.blob_path(key)?)
+            .with_context(|| format!("Failed to open {key:?} in {}", self.url()))?;
+        let size = file.metadata()?.len();
+        file.seek(SeekFrom::Start(size.saturating_sub(len as u64)))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .with_context(|| format!("Failed to read {key:?} from {}", self.url()))?;
+        Ok(bytes)
+    }
+
+    fn list_blobs(&self) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for dir_entry in std::fs::read_dir(&self.root).context("Failed to read cache dir")? {
+            let dir_entry = dir_entry.context("Failed to read cache dir entry")?;
+            if !dir_entry.file_type()?.is_file() {
+                continue;
+            }
+            // Half-written blobs are dot files, and never have a
+            // key; see `put_blob`.
+            if let Some(key) = dir_entry
+                .file_name()
+                .to_str()
+                .filter(|key| !key.starts_with('.'))
+            {
+                keys.push(key.to_owned());
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// A cache on another machine, for sharing between machines.
+///
+/// Use one by setting `HOPE_CACHE_URL` to e.g. "file:///mnt/shared/hope"
+/// (a cache dir on a shared drive), "s3://bucket/prefix"
+/// (see the `s3` module for where credentials come from), to the URL
+/// of any HTTP server that will take `PUT`s (see the `http_store` module),
+/// to e.g. "bazel+http://cache:8080" for a Bazel remote cache (see the
+/// `bazel` module), to e.g. "redis://localhost:6379" (see the `redis`
+/// module), or to "gha://" on GitHub Actions (see the `gha` module).
+/// Entries are laid out just like in the local cache, so chunking and
+/// compression work the same way.
+///
+/// There's no way to lock an entry while pushing it, so the manifest goes
+/// last, and we only pull entries that have one. Racing pushes can still
+/// leave an entry with files from both, so pulls also check every file
+/// against the hash in the manifest.
+///
+/// Blobs that `hope clean --prestage` fetched ahead of time are read from
+/// the local cache dir instead; see the `clean` module.
+pub struct RemoteCache<S> {
+    store: S,
+    staged: Option<LocalCache>,
+    /// The log stays on this machine, in the local cache dir.
+    log_dir: PathBuf,
+    chunk_size: Option<u64>,
+    compression: CompressionPolicy,
+}
+
+impl<S: RemoteBlobStore> RemoteCache<S> {
+    pub fn new(store: S) -> anyhow::Result<Self> {
+        let log_dir = LocalCache::dir_from_env().context("Couldn't infer cache directory")?;
+        std::fs::create_dir_all(&log_dir).context("Failed to create cache dir")?;
+        Ok(Self {
+            staged: clean::staged_store(&log_dir, &store.url()),
+            store,
+            log_dir,
+            chunk_size: config::chunk_size()?,
+            compression: config::compression()?,
+        })
+    }
+}
+
+impl<S: RemoteBlobStore> Cache for RemoteCache<S> {
+    fn pull_size(
+        &self,
+        key: &CacheKey,
+        output_defns: &[OutputDefn],
+    ) -> anyhow::Result<Option<u64>> {
+        let storage_name = self.resolve_storage_name(key)?;
+        if EntryManifest::load(self, &storage_name)?.is_none() {
+            return Ok(None);
+        }
+        let mut total: u64 = 0;
+        for output_defn in output_defns {
+            let file_name = output_defn.file_name(&storage_name);
+            let chunk_manifest_key = chunks::manifest_key(&file_name);
+            let compressed_key = compression::compressed_key(&file_name);
+            let size = if self.has_blob(&chunk_manifest_key)? {
+                let manifest =
+                    chunks::ChunkManifest::parse(&self.get_blob(&chunk_manifest_key)?)
+                        .with_context(|| format!("Invalid chunk manifest for {file_name:?}"))?;
+                manifest.total_size
+            } else if self.has_blob(&compressed_key)? {
+                compression::size_in_trailer(&self.store.get_blob_tail(&compressed_key, 4)?)?
+            } else {
+                match self.store.blob_size(&file_name)? {
+                    Some(size) => size,
+                    None => return Ok(None),
+                }
+            };
+            total = total
+                .checked_add(size)
+                .with_context(|| format!("Size of entry {storage_name:?} overflows"))?;
+        }
+        Ok(Some(total))
+    }
+
+    fn location(&self) -> String {
+        self.store.url()
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+
+    fn pull_crate(
+        &self,
+        key: &CacheKey,
+        output_defns: &[OutputDefn],
+        arrival_dir: &Path,
+    ) -> anyhow::Result<()> {
+        let before = Instant::now();
+
+        fail_point::check("pull_crate")?;
+
+        let storage_name = self.resolve_storage_name(key)?;
+        let manifest = EntryManifest::load(self, &storage_name)?.with_context(|| {
+            format!(
+                "No complete cache entry {storage_name:?} in {}",
+                self.store.url()
+            )
+        })?;
+        anyhow::ensure!(
+            manifest.target == key.target.triple(),
+            "Cache entry {storage_name:?} was built for target {:?}, not {:?}.",
+            manifest.target,
+            key.target.triple()
+        );
+        if let Some(pushed_at) = manifest.pushed_at {
+            clock::check(
+                &self.log_dir,
+                &key.unit_name,
+                pushed_at,
+                "Push time of cache entry",
+            )?;
+        }
+        for output_defn in output_defns {
+            signals::check()?;
+            let file_name = output_defn.file_name(&storage_name);
+            let to_path = arrival_dir.join(output_defn.file_name(&key.unit_name));
+            let content = if self.has_blob(&chunks::manifest_key(&file_name))? {
+                chunks::get_chunked(self, &file_name, &to_path).with_context(|| {
+                    format!(
+                        "Failed to reassemble file {file_name:?} from {}.",
+                        self.store.url()
+                    )
+                })?;
+                std::fs::read(&to_path).with_context(|| format!("Failed to read {to_path:?}"))?
+            } else {
+                let content = if self.has_blob(&compression::compressed_key(&file_name))? {
+                    compression::get_compressed(self, &file_name)?
+                } else {
+                    self.get_blob(&file_name)?
+                };
+                std::fs::write(&to_path, &content)
+                    .with_context(|| format!("Failed to write {to_path:?}"))?;
+                content
+            };
+            if let Some(expected_hash) = manifest.files.get(&file_name) {
+                anyhow::ensure!(
+                    chunks::hash_bytes(&content) == *expected_hash,
+                    "File {file_name:?} in {} doesn't match its entry manifest; \
+                     was it pushed by two builds at once?",
+                    self.store.url()
+                );
+            }
+        }
+
+        write_log_line(
+            &self.log_dir,
+            CacheLogLine::PulledCrateOutputs(PullCrateOutputsEvent {
+                crate_unit_name: key.unit_name.clone(),
+                copied_at: Utc::now(),
+                copied_from: self.location(),
+                duration_secs: before.elapsed().as_secs_f64(),
+            }),
+        )?;
+
+        Ok(())
+    }
+
+    fn push_crate(
+        &self,
+        key: &CacheKey,
+        manifest: &EntryManifest,
+        output_defns: &[OutputDefn],
+        departure_dir: &Path,
+    ) -> anyhow::Result<()> {
+        let before = Instant::now();
+
+        let storage_name = key.storage_name();
+        if EntryManifest::load(self, &storage_name)?.is_none() {
+            let mut manifest = manifest.clone();
+            for output_defn in output_defns {
+                signals::check()?;
+                let file_name = output_defn.file_name(&storage_name);
+                let from_path = departure_dir.join(output_defn.file_name(&key.unit_name));
+                let content = std::fs::read(&from_path)
+                    .with_context(|| format!("Failed to read {from_path:?} to push it."))?;
+                manifest
+                    .files
+                    .insert(file_name.clone(), chunks::hash_bytes(&content));
+                if let Some(chunk_size) = self.chunk_size {
+                    chunks::put_chunked(self, &file_name, &from_path, chunk_size).with_context(
+                        || {
+                            format!(
+                                "Failed to store file {file_name:?} in chunks in {}.",
+                                self.store.url()
+                            )
+                        },
+                    )?;
+                    continue;
+                }
+                match self
+                    .compression
+                    .codec_for(crate_name(&key.unit_name), &file_name)
+                {
+                    Codec::None => self.put_blob(&file_name, &content)?,
+                    Codec::Gzip { level } => {
+                        compression::put_compressed(self, &file_name, &content, level)?
+                    }
+                }
+            }
+            fail_point::check("push_crate")?;
+            manifest.store(self, &storage_name).with_context(|| {
+                format!("Failed to store entry manifest in {}.", self.store.url())
+            })?;
+        }
+        if let Some(alias_name) = key.alias_name() {
+            EntryAlias {
+                target: storage_name,
+            }
+            .store(self, &alias_name)
+            .with_context(|| format!("Failed to store entry alias in {}.", self.store.url()))?;
+        }
+
+        write_log_line(
+            &self.log_dir,
+            CacheLogLine::PushedCrateOutputs(PushCrateOutputsEvent {
+                crate_unit_name: key.unit_name.clone(),
+                copied_at: Utc::now(),
+                copied_from: self.location(),
+                duration_secs: before.elapsed().as_secs_f64(),
+            }),
+        )?;
+
+        Ok(())
+    }
+
+    fn get_build_script_stdout(&self, build_script_execution_key: &str) -> anyhow::Result<Vec<u8>> {
+        self.get_blob(&build_script_stdout_file_name(build_script_execution_key))
+    }
+
+    fn put_build_script_stdout(
+        &self,
+        build_script_execution_key: &str,
+        stdout: &[u8],
+    ) -> anyhow::Result<()> {
+        self.put_blob(
+            &build_script_stdout_file_name(build_script_execution_key),
+            stdout,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to write build script stdout to {}",
+                self.store.url()
+            )
+        })
+    }
+}
+
+impl<S: RemoteBlobStore> BlobStore for RemoteCache<S> {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        if let Some(staged) = &self.staged {
+            if staged.has_blob(key)? {
+                return staged.get_blob(key);
+            }
+        }
+        self.store.get_blob(key)
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.store.put_blob(key, bytes)
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        if let Some(staged) = &self.staged {
+            if staged.has_blob(key)? {
+                return Ok(true);
+            }
+        }
+        self.store.has_blob(key)
+    }
+}
+
+/// Crate name part of a unit name, e.g. "serde" for "serde-0123456789abcdef".
+fn crate_name(unit_name: &str) -> &str {
+    unit_name
+        .rsplit_once('-')
+        .map_or(unit_name, |(crate_name, _hash)| crate_name)
+}
+
+/// Keys are relative, slash-separated paths, none of whose segments
+/// are empty or start with a dot.
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.contains('\\')
+        && key
+            .split('/')
+            .all(|segment| !segment.is_empty() && !segment.starts_with('.'))
+}
+
+/// File name for a build script execution's stdout; see `Cache::get_build_script_stdout`.
+pub fn build_script_stdout_file_name(build_script_execution_key: &str) -> String {
     // NOTE: This is different to what Cargo calls it ("output").
     // I flip-flopped a bit on this, but ultimately decided that
     // I preferred calling it this in my own file names to clarify exactly what it is.
     // (Yeah, I know: big deal, right?)
-    format!("build-script-{build_script_execution_metadata_hash}-stdout.txt")
+    format!("build-script-{build_script_execution_key}-stdout.txt")
+}
+
+/// If this is the file name of a build script execution's stdout,
+/// get the execution's key.
+pub fn build_script_execution_key_for_file_name(file_name: &str) -> Option<&str> {
+    file_name
+        .strip_prefix("build-script-")?
+        .strip_suffix("-stdout.txt")
 }
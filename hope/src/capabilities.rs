@@ -0,0 +1,100 @@
+//! Telling other tools what this build of Hope can do.
+//!
+//! Orchestration tooling (and clients of `hope serve`) shouldn't have to
+//! guess from version numbers which backends, codecs, and cache layouts
+//! a given `hope` understands, so `hope capabilities --json` reports them,
+//! and `hope serve` answers the same report at [`CAPABILITIES_PATH`].
+//!
+//! Only ever add to the report; tools written against an older one
+//! should keep working.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::SCHEMA_VERSION, compression};
+
+pub const CAPABILITIES_PATH: &str = "/capabilities";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Version of the `hope` crate, e.g. "0.0.1".
+    pub version: String,
+    /// Version of the on-disk cache layout; see `cache::SCHEMA_VERSION`.
+    pub schema_version: u32,
+    /// Where caches can live: "local" is a directory, "file" is a directory
+    /// shared with other machines, "http" is another machine running
+    /// `hope serve` (or any server that takes `PUT`s), "s3" is an S3 (or
+    /// S3-compatible) bucket, "bazel" is a Bazel remote cache, "redis" is
+    /// Redis or Valkey, and "gha" is the GitHub Actions cache.
+    pub backends: Vec<String>,
+    /// How stored blobs can be compressed.
+    pub compression_codecs: Vec<String>,
+    pub log_formats: Vec<String>,
+    /// Values accepted by `HOPE_KEY_POLICY`.
+    pub key_policies: Vec<String>,
+    pub platform: Platform,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Platform {
+    /// e.g. "linux", as in `std::env::consts::OS`.
+    pub os: String,
+    /// e.g. "x86_64", as in `std::env::consts::ARCH`.
+    pub arch: String,
+    /// Optional behaviour that depends on how `hope` was built,
+    /// or where it's running.
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn current() -> Self {
+        let strings = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        let mut features = vec![
+            "daemon-socket",
+            "free-space-checks",
+            "symlinked-build-scripts",
+        ];
+        if cfg!(debug_assertions) {
+            features.push("fail-points");
+        }
+        if cfg!(feature = "wasm-plugins") {
+            features.push("wasm-plugins");
+        }
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            schema_version: SCHEMA_VERSION,
+            backends: strings(&["local", "file", "http", "s3", "bazel", "redis", "gha"]),
+            compression_codecs: strings(compression::CODEC_NAMES),
+            log_formats: strings(&["jsonl", "cbor"]),
+            key_policies: strings(&["strict", "relaxed", "custom"]),
+            platform: Platform {
+                os: std::env::consts::OS.to_owned(),
+                arch: std::env::consts::ARCH.to_owned(),
+                features: strings(&features),
+            },
+        }
+    }
+}
+
+pub fn run(json: bool) -> anyhow::Result<()> {
+    let capabilities = Capabilities::current();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&capabilities)?);
+        return Ok(());
+    }
+    println!("hope {}", capabilities.version);
+    println!("cache schema version: {}", capabilities.schema_version);
+    println!("backends: {}", capabilities.backends.join(", "));
+    println!(
+        "compression codecs: {}",
+        capabilities.compression_codecs.join(", ")
+    );
+    println!("log formats: {}", capabilities.log_formats.join(", "));
+    println!("key policies: {}", capabilities.key_policies.join(", "));
+    println!(
+        "platform: {} {} ({})",
+        capabilities.platform.os,
+        capabilities.platform.arch,
+        capabilities.platform.features.join(", ")
+    );
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//! Storing big artifacts as a bunch of smaller chunks.
+//!
+//! Some storage backends have per-object size limits, or just
+//! perform badly with very large objects. For those, we split each
+//! artifact into fixed-size chunks stored under their own hash, plus a small
+//! manifest listing the chunks in order. On the way back out, chunks are
+//! fetched in parallel and reassembled.
+//!
+//! Because chunks are content-addressed, identical chunks shared by
+//! different artifacts are only stored once.
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Maximum number of chunks we'll fetch at once for a single artifact.
+const MAX_PARALLEL_CHUNK_FETCHES: usize = 8;
+
+/// Biggest artifact we'll believe a chunk manifest about.
+///
+/// Manifests can come from other machines, so a corrupt one
+/// shouldn't be able to make us try to allocate all the memory in the world.
+const MAX_CHUNKED_ARTIFACT_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Somewhere we can stash opaque blobs by key.
+///
+/// Keys are relative, slash-separated paths.
+pub trait BlobStore: Sync {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>>;
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()>;
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub total_size: u64,
+    pub chunks: Vec<ChunkRef>,
+}
+
+impl ChunkManifest {
+    /// Parse a manifest read from a store, and check that
+    /// its chunk sizes add up to a believable total.
+    pub fn parse(json: &[u8]) -> anyhow::Result<Self> {
+        let manifest: Self = serde_json::from_slice(json)?;
+        let chunks_size = manifest
+            .chunks
+            .iter()
+            .try_fold(0u64, |total, chunk| total.checked_add(chunk.size))
+            .context("Chunk sizes overflow")?;
+        anyhow::ensure!(
+            chunks_size == manifest.total_size,
+            "Chunk sizes add up to {chunks_size} bytes, but the manifest says {} bytes",
+            manifest.total_size
+        );
+        anyhow::ensure!(
+            chunks_size <= MAX_CHUNKED_ARTIFACT_SIZE,
+            "Chunked artifact of {chunks_size} bytes is implausibly big"
+        );
+        Ok(manifest)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkRef {
+    /// Hex-encoded SHA-256 of the chunk content.
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Key for the manifest describing how the named artifact was chunked.
+pub fn manifest_key(file_name: &str) -> String {
+    format!("{file_name}.chunks.json")
+}
+
+pub fn chunk_key(hash: &str) -> String {
+    format!("chunks/{hash}")
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Split the file at `from_path` into chunks of at most `chunk_size` bytes,
+/// and store them along with a manifest for `file_name`.
+pub fn put_chunked(
+    store: &(impl BlobStore + ?Sized),
+    file_name: &str,
+    from_path: &Path,
+    chunk_size: u64,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(chunk_size > 0, "Chunk size must be greater than zero");
+    let content = std::fs::read(from_path)
+        .with_context(|| format!("Failed to read {from_path:?} for chunking"))?;
+
+    let mut manifest = ChunkManifest {
+        total_size: content.len() as u64,
+        chunks: Vec::new(),
+    };
+    for chunk in content.chunks(chunk_size as usize) {
+        let hash = hash_bytes(chunk);
+        let key = chunk_key(&hash);
+        // Chunks are content-addressed, so if it's already there then
+        // there's nothing more to do.
+        if !store.has_blob(&key)? {
+            store
+                .put_blob(&key, chunk)
+                .with_context(|| format!("Failed to store chunk {hash} of {file_name:?}"))?;
+        }
+        manifest.chunks.push(ChunkRef {
+            hash,
+            size: chunk.len() as u64,
+        });
+    }
+
+    // Write the manifest last, so that anyone who can see it
+    // can also see all of its chunks.
+    let manifest_json = serde_json::to_vec(&manifest)?;
+    store
+        .put_blob(&manifest_key(file_name), &manifest_json)
+        .with_context(|| format!("Failed to store chunk manifest for {file_name:?}"))?;
+    Ok(())
+}
+
+/// Reassemble the artifact `file_name` from its chunks, and write it to `to_path`.
+pub fn get_chunked(
+    store: &(impl BlobStore + ?Sized),
+    file_name: &str,
+    to_path: &Path,
+) -> anyhow::Result<()> {
+    let manifest_json = store
+        .get_blob(&manifest_key(file_name))
+        .with_context(|| format!("Failed to get chunk manifest for {file_name:?}"))?;
+    let manifest = ChunkManifest::parse(&manifest_json)
+        .with_context(|| format!("Invalid chunk manifest for {file_name:?}"))?;
+    let total_size = usize::try_from(manifest.total_size)
+        .with_context(|| format!("{file_name:?} is too big to reassemble"))?;
+
+    // Fetch chunks in parallel; each worker grabs the next chunk index
+    // that nobody has started on yet.
+    let next_index = AtomicUsize::new(0);
+    let fetched: Mutex<Vec<Option<Vec<u8>>>> = Mutex::new(vec![None; manifest.chunks.len()]);
+    let worker_count = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .clamp(1, MAX_PARALLEL_CHUNK_FETCHES)
+        .min(manifest.chunks.len().max(1));
+    std::thread::scope(|scope| -> anyhow::Result<()> {
+        let workers: Vec<_> = (0..worker_count)
+            .map(|_| {
+                scope.spawn(|| -> anyhow::Result<()> {
+                    loop {
+                        let index = next_index.fetch_add(1, Ordering::Relaxed);
+                        let Some(chunk_ref) = manifest.chunks.get(index) else {
+                            return Ok(());
+                        };
+                        let chunk = store
+                            .get_blob(&chunk_key(&chunk_ref.hash))
+                            .with_context(|| format!("Failed to fetch chunk {}", chunk_ref.hash))?;
+                        anyhow::ensure!(
+                            hash_bytes(&chunk) == chunk_ref.hash,
+                            "Chunk {} is corrupt",
+                            chunk_ref.hash
+                        );
+                        fetched.lock().expect("Chunk fetcher panicked")[index] = Some(chunk);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().expect("Chunk fetcher panicked")?;
+        }
+        Ok(())
+    })?;
+
+    let mut content = Vec::with_capacity(total_size);
+    for chunk in fetched.into_inner().expect("Chunk fetcher panicked") {
+        content.extend(chunk.context("Chunk fetcher finished without fetching every chunk")?);
+    }
+    anyhow::ensure!(
+        content.len() == total_size,
+        "Reassembled {file_name:?} has the wrong size"
+    );
+    std::fs::write(to_path, content)
+        .with_context(|| format!("Failed to write reassembled {file_name:?}"))?;
+    Ok(())
+}
@@ -0,0 +1,254 @@
+//! A `cargo clean` that knows what the cache can give back.
+//!
+//! Cleaning the target dir is the usual cure for a build that's gone weird,
+//! but it throws everything away, including what the cache already has.
+//! `hope clean` runs `cargo clean` (with whatever arguments it's given),
+//! but first:
+//!
+//! - pushes whatever in the push backlog (see the `push_backlog` module)
+//!   has its outputs in this target dir, as they won't be there to push
+//!   afterwards (unless `HOPE_READ_ONLY` is set);
+//! - says which units will come back from the cache on the next build,
+//!   and which will be compiled again because they never went into it;
+//! - with `--prestage`, fetches the entries that will come back from the
+//!   remote cache into "staged/{hash of its URL}" in the local cache dir,
+//!   which the remote cache reads through, so that the next build pulls them
+//!   from local disk instead. Staging again for the same remote cache
+//!   replaces what was staged before, and `hope gc` removes it all.
+//!
+//! To know which entries a target dir's units came from or went to, builds
+//! note each one in ".hope-entries" in the profile dir, one
+//! "{crate unit name} {storage name} [{alias name}]" per line. Like lockfile
+//! indexes (see the `lockfile_index` module), it's only ever appended to,
+//! so concurrent `rustc`s don't need to coordinate, and the last line
+//! for a unit wins.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write as _,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+
+use crate::{
+    cache::{self, LocalCache},
+    chunks, clean_project, config,
+    entry_manifest::EntryAlias,
+    key::CacheKey,
+    push_backlog,
+    sync::Copier,
+};
+
+const ENTRIES_FILE_NAME: &str = ".hope-entries";
+
+/// Dir in the local cache dir that staged blobs go in.
+const STAGED_DIR_NAME: &str = "staged";
+
+/// A cache entry that a unit came from or went to.
+#[derive(Debug)]
+struct RecordedEntry {
+    storage_name: String,
+    alias_name: Option<String>,
+}
+
+/// Note that the unit with this key, built in `profile_dir`,
+/// came from or went to the cache.
+pub fn record_entry(profile_dir: &Path, key: &CacheKey) -> anyhow::Result<()> {
+    let mut line = format!("{} {}", key.unit_name, key.storage_name());
+    if let Some(alias_name) = key.alias_name() {
+        line.push(' ');
+        line.push_str(&alias_name);
+    }
+    line.push('\n');
+    let path = profile_dir.join(ENTRIES_FILE_NAME);
+    let mut file = std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {path:?}"))?;
+    // All in one write, so that lines from concurrent units don't interleave.
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("Failed to append to {path:?}"))
+}
+
+/// Blobs that `hope clean --prestage` fetched from the remote cache at
+/// `url`, if there are any.
+pub fn staged_store(cache_dir: &Path, url: &str) -> Option<LocalCache> {
+    let dir = staged_dir(cache_dir, url);
+    dir.exists().then(|| LocalCache::new(dir))
+}
+
+fn staged_dir(cache_dir: &Path, url: &str) -> PathBuf {
+    cache_dir
+        .join(STAGED_DIR_NAME)
+        .join(chunks::hash_bytes(url.as_bytes()))
+}
+
+/// Remove everything that's been staged, for any remote cache.
+pub fn remove_staged(cache_dir: &Path) -> anyhow::Result<()> {
+    let dir = cache_dir.join(STAGED_DIR_NAME);
+    match std::fs::remove_dir_all(&dir) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Failed to remove {dir:?}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn run(dry_run: bool, prestage: bool, cargo_args: &[String]) -> anyhow::Result<()> {
+    let target_dir = clean_project::target_dir()?;
+    let mut cached = BTreeMap::new();
+    let mut uncached = BTreeSet::new();
+    for profile_dir in profile_dirs(&target_dir)? {
+        let mut recorded = recorded_entries(&profile_dir)?;
+        for crate_unit_name in built_units(&profile_dir)? {
+            match recorded.remove(&crate_unit_name) {
+                Some(entry) => {
+                    cached.insert(crate_unit_name, entry);
+                }
+                None => {
+                    uncached.insert(crate_unit_name);
+                }
+            }
+        }
+    }
+    println!(
+        "{} units in {} will come back from the cache; {} will be compiled again:",
+        cached.len(),
+        target_dir.display(),
+        uncached.len()
+    );
+    for crate_unit_name in &uncached {
+        println!("    {crate_unit_name}");
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    if config::read_only() {
+        println!("Not pushing the push backlog, as 'HOPE_READ_ONLY' is set.");
+    } else {
+        push_backlog::push_from(&target_dir)?;
+    }
+    if prestage {
+        stage(cached.values())?;
+    }
+
+    let status = Command::new("cargo")
+        .arg("clean")
+        .args(cargo_args)
+        .status()
+        .context("Failed to start Cargo")?;
+    anyhow::ensure!(status.success(), "'cargo clean' failed");
+    Ok(())
+}
+
+/// Fetch these entries from the remote cache into its staged dir.
+fn stage<'a>(entries: impl Iterator<Item = &'a RecordedEntry>) -> anyhow::Result<()> {
+    let Some(remote) = cache::remote_store_from_env()? else {
+        println!("The cache is local, so there's nothing to prestage.");
+        return Ok(());
+    };
+    let url = remote.url();
+    let dir = staged_dir(&LocalCache::dir_from_env()?, &url);
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove {dir:?}"))?;
+    }
+    let staged = LocalCache::new(dir);
+    let mut copier = Copier::new(&remote, &staged);
+    let mut staged_entries = 0;
+    for entry in entries {
+        let mut storage_name = entry.storage_name.clone();
+        if let Some(alias_name) = &entry.alias_name {
+            if let Some(alias) = EntryAlias::load(&remote, alias_name)? {
+                storage_name = alias.target;
+            }
+            copier.optional_blob(&EntryAlias::blob_key(alias_name))?;
+        }
+        copier.entry(&storage_name)?;
+        staged_entries += 1;
+    }
+    println!(
+        "Staged {} blobs for {staged_entries} entries from {url}.",
+        copier.copied
+    );
+    if !copier.missing.is_empty() {
+        println!(
+            "{} blobs were missing from {url}, so some units will have to be built:",
+            copier.missing.len()
+        );
+        for key in &copier.missing {
+            println!("    {key}");
+        }
+    }
+    Ok(())
+}
+
+/// Profile dirs are "{target dir}/{profile}" or, when cross-compiling,
+/// "{target dir}/{target}/{profile}", and have a ".fingerprint" dir.
+fn profile_dirs(target_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut profile_dirs = Vec::new();
+    for entry in walkdir::WalkDir::new(target_dir)
+        .min_depth(1)
+        .max_depth(2)
+        .into_iter()
+        .filter_entry(|entry| entry.file_type().is_dir())
+    {
+        let entry = entry.context("Couldn't read dir entry in target dir")?;
+        if entry.path().join(".fingerprint").is_dir() {
+            profile_dirs.push(entry.into_path());
+        }
+    }
+    Ok(profile_dirs)
+}
+
+/// Crate unit names of everything built in the profile dir, going by
+/// their dep info files: "deps/{crate unit name}.d" for crates, and
+/// "build/{package}-{hash}/{crate unit name}.d" for build scripts.
+fn built_units(profile_dir: &Path) -> anyhow::Result<BTreeSet<String>> {
+    let mut units = BTreeSet::new();
+    for entry in walkdir::WalkDir::new(profile_dir)
+        .min_depth(2)
+        .max_depth(3)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() > 1 || matches!(entry.file_name().to_str(), Some("deps" | "build"))
+        })
+    {
+        let entry = entry.context("Couldn't read dir entry in profile dir")?;
+        let path = entry.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("d") {
+            continue;
+        }
+        if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+            units.insert(stem.to_owned());
+        }
+    }
+    Ok(units)
+}
+
+fn recorded_entries(profile_dir: &Path) -> anyhow::Result<BTreeMap<String, RecordedEntry>> {
+    let path = profile_dir.join(ENTRIES_FILE_NAME);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {path:?}")),
+    };
+    let mut entries = BTreeMap::new();
+    for line in content.lines() {
+        let mut fields = line.split(' ');
+        let (Some(crate_unit_name), Some(storage_name)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        entries.insert(
+            crate_unit_name.to_owned(),
+            RecordedEntry {
+                storage_name: storage_name.to_owned(),
+                alias_name: fields.next().map(str::to_owned),
+            },
+        );
+    }
+    Ok(entries)
+}
@@ -0,0 +1,82 @@
+//! Tidying up what Hope leaves in a project's target dir.
+//!
+//! To defer build scripts, we leave a "real-build-script" symlink next to
+//! each build script, and a note on how to run it in its out dir for
+//! whichever crate turns out to need it. Those normally get used up or
+//! replaced, but an interrupted build (or somebody deleting bits of the
+//! target dir by hand) can leave them referring to things that are gone.
+//! Builds ignore and remove stale notes as they come across them;
+//! `hope clean-project` finds and removes all of them at once.
+
+use std::{path::PathBuf, process::Command};
+
+use anyhow::Context;
+
+use crate::build_script::{
+    self, BuildScriptInvocationInfo, BUILD_SCRIPT_INVOCATION_INFO_FILE_NAME,
+    REAL_BUILD_SCRIPT_SYMLINK_NAME,
+};
+
+pub fn run(dry_run: bool) -> anyhow::Result<()> {
+    let target_dir = target_dir()?;
+    let mut checked = 0;
+    let mut stale = Vec::new();
+    // Build script dirs are "{target dir}[/{target}]/{profile}/build/{package}-{hash}".
+    for entry in walkdir::WalkDir::new(&target_dir).max_depth(6) {
+        let entry = entry.context("Couldn't read dir entry in target dir")?;
+        let path = entry.path();
+        if entry.file_name() == REAL_BUILD_SCRIPT_SYMLINK_NAME && entry.path_is_symlink() {
+            checked += 1;
+            // `exists` follows the symlink.
+            if !path.exists() {
+                stale.push((
+                    path.to_owned(),
+                    "real build script no longer exists".to_owned(),
+                ));
+            }
+        } else if entry.file_name() == BUILD_SCRIPT_INVOCATION_INFO_FILE_NAME {
+            checked += 1;
+            if let Err(reason) = BuildScriptInvocationInfo::load_checked(path) {
+                stale.push((path.to_owned(), reason));
+            }
+        }
+    }
+
+    for (path, reason) in &stale {
+        if dry_run {
+            println!("Would remove {}: {reason}", path.display());
+        } else {
+            build_script::remove_if_exists(path)?;
+            println!("Removed {}", path.display());
+        }
+    }
+    println!(
+        "Checked {checked} deferred build script files in {}; {} stale.",
+        target_dir.display(),
+        stale.len()
+    );
+    Ok(())
+}
+
+/// Ask Cargo where the current project's target dir is, so that we respect
+/// `CARGO_TARGET_DIR`, `build.target-dir`, workspaces, etc.
+pub fn target_dir() -> anyhow::Result<PathBuf> {
+    cargo_metadata()?["target_directory"]
+        .as_str()
+        .map(PathBuf::from)
+        .context("'cargo metadata' didn't say where the target dir is")
+}
+
+/// What Cargo says about the current project's workspace.
+pub fn cargo_metadata() -> anyhow::Result<serde_json::Value> {
+    let output = Command::new("cargo")
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .output()
+        .context("Failed to start Cargo")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "'cargo metadata' failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    serde_json::from_slice(&output.stdout).context("Invalid output from 'cargo metadata'")
+}
@@ -0,0 +1,553 @@
+//! Subcommands for humans running `hope` directly,
+//! as opposed to Cargo running it as a `rustc` wrapper.
+
+use std::{collections::HashMap, io::Write as _, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use clap::{CommandFactory, Parser, Subcommand};
+use hope_cache_log::{read_log, write_log_lines, LogFormat};
+
+use crate::{
+    attestation, bench, build_script_diff, cache::LocalCache, capabilities, chunks, clean,
+    clean_project, config, credentials, daemon, determinism, explain, install_wrapper,
+    lockfile_index, mtime, observe, print_key, push_backlog, replay, serve, stats, sync,
+    toolchain::Channel, upload_queue, verify, why_rebuild,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "hope",
+    about = "A WIP rustc wrapper for caching build artifacts."
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Dump the cache log.
+    Log {
+        /// Format to write the log out in, regardless of how it is stored.
+        #[arg(long, default_value_t = LogFormat::Jsonl)]
+        export: LogFormat,
+    },
+    /// Build the current project twice from scratch and report which
+    /// cached crates didn't come out the same both times.
+    DeterminismReport {
+        /// Only rebuild and compare this many randomly chosen crate units.
+        #[arg(long)]
+        sample: Option<usize>,
+        /// Cargo profile to build with, e.g. "release".
+        #[arg(long, default_value = "dev")]
+        profile: String,
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Time building the current project without hope, and with hope using
+    /// a cold and then warm cache, each in a fresh target dir.
+    BenchCompare {
+        /// How many times to build in each scenario.
+        #[arg(long, default_value_t = 1)]
+        runs: usize,
+        /// Extra arguments for `cargo build`, e.g. `-- --release`.
+        #[arg(last = true)]
+        cargo_args: Vec<String>,
+    },
+    /// Build the current project without using the cache (but filling it),
+    /// and estimate how much time the cache would have saved.
+    Observe {
+        /// Extra arguments for `cargo build`, e.g. `-- --release`.
+        #[arg(last = true)]
+        cargo_args: Vec<String>,
+    },
+    /// List entries in the local cache.
+    Ls {
+        /// Also show the build environment each entry was built in.
+        #[arg(long, short)]
+        verbose: bool,
+    },
+    /// Show cache hits and compile times per crate.
+    Stats {
+        /// Print an anonymized JSON summary suitable for sharing publicly
+        /// (just crate names and numbers).
+        #[arg(long)]
+        export_aggregate: bool,
+        /// Print overall stats laid out like `sccache --show-stats`, for
+        /// existing dashboards and scripts.
+        #[arg(long, conflicts_with = "export_aggregate")]
+        sccache_compat: bool,
+        /// Report on the logs in these cache dirs (e.g. collected from
+        /// CI runners) all together, instead of on the local cache.
+        #[arg(long, num_args = 1.., value_name = "DIR", conflicts_with = "sccache_compat")]
+        merge: Vec<PathBuf>,
+    },
+    /// Compare the environment that cached entries for a crate were built in
+    /// against this machine's, to help work out why they aren't being used.
+    ///
+    /// Run from a workspace to also compare features, and see what
+    /// turns on any extra ones there.
+    Explain {
+        /// Crate name, e.g. "libc".
+        crate_name: String,
+        /// Target to compare against; defaults to the host.
+        #[arg(long)]
+        target: Option<String>,
+    },
+    /// Explain why Cargo rebuilt a package in the current project's target
+    /// dir (or would, if built now), from Cargo's fingerprints and Hope's log,
+    /// to tell rebuilds that Hope caused apart from Cargo's own.
+    WhyRebuild {
+        /// Package name, e.g. "libc".
+        crate_name: String,
+        /// Target it was built for, if cross-compiled.
+        #[arg(long)]
+        target: Option<String>,
+        /// Profile dir it was built in, e.g. "release".
+        #[arg(long, default_value = "debug")]
+        profile: String,
+    },
+    /// Print the cache key for a `rustc` invocation, component by component,
+    /// without building anything, e.g. `hope key -- rustc --crate-name foo ...`.
+    ///
+    /// Set the environment as Cargo would (e.g. `CARGO_PKG_NAME` and `OUT_DIR`)
+    /// to get the same key that a build would.
+    Key {
+        /// Print the key as JSON.
+        #[arg(long)]
+        json: bool,
+        /// Arguments for `rustc`, optionally preceded by the path to `rustc`.
+        #[arg(last = true, required = true)]
+        rustc_args: Vec<String>,
+    },
+    /// Show how a package's build script output differs between two cached runs.
+    DiffBuildScript {
+        /// Package name, e.g. "libc".
+        package: String,
+        /// Start of the hash identifying each run (as in the cache's
+        /// "build-script-{package}-{hash}-{key}-stdout.txt" file names).
+        run_a: String,
+        run_b: String,
+    },
+    /// Fetch every cache entry that builds of the project with the given
+    /// `Cargo.lock` are known to use, from a cache shared with `hope serve`.
+    ///
+    /// Entries are recorded against lockfiles by building with
+    /// `HOPE_LOCKFILE_INDEX` set.
+    WarmUp {
+        /// URL of the shared cache, e.g. "http://build-box:7777".
+        #[arg(long)]
+        from: String,
+        #[arg(long, default_value = "Cargo.lock")]
+        lockfile: PathBuf,
+    },
+    /// Copy cache entries that one cache has and another doesn't, e.g. to
+    /// seed a new region's cache, or to move to another storage provider.
+    ///
+    /// Caches are given as for `HOPE_CACHE_URL`, or "local" for the local
+    /// cache. Only the local cache, "file://", and "s3://" can be synced from.
+    Sync {
+        /// e.g. "local", or "s3://bucket/prefix".
+        #[arg(long)]
+        from: String,
+        /// e.g. "s3://other-bucket/prefix".
+        #[arg(long)]
+        to: String,
+        /// Only copy entries for this crate (or build script output for this
+        /// package); can be given more than once.
+        #[arg(long = "filter", value_name = "CRATE")]
+        crate_names: Vec<String>,
+    },
+    /// Upload pushes queued by builds with `HOPE_ASYNC_PUSH` set.
+    Flush,
+    /// Do the remote pushes that builds skipped for being over budget
+    /// or read-only, from wherever their outputs still are.
+    PushBacklog,
+    /// Remove the least recently pushed cache entries until the cache
+    /// is no bigger than the given size, and anything staged by
+    /// `hope clean --prestage`.
+    Gc {
+        /// e.g. "10G"
+        #[arg(long, value_parser = config::parse_size)]
+        max_size: u64,
+    },
+    /// Work with the provenance attestations stored alongside cache entries.
+    Attest {
+        #[command(subcommand)]
+        command: AttestCommand,
+    },
+    /// Check cache entries for missing or corrupt files.
+    Verify {
+        /// Fix problems: restore damaged files from identical copies in
+        /// other entries where possible, and otherwise remove the entry.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Run `cargo clean`, first pushing anything in the push backlog from
+    /// the target dir, and saying what the next build will get back from
+    /// the cache.
+    Clean {
+        /// Only say what would come back from the cache; don't clean.
+        #[arg(long)]
+        dry_run: bool,
+        /// Fetch what will come back from the remote cache now, so that
+        /// the next build gets it from local disk.
+        #[arg(long)]
+        prestage: bool,
+        /// Extra arguments for `cargo clean`, e.g. `-- --release`.
+        #[arg(last = true)]
+        cargo_args: Vec<String>,
+    },
+    /// Remove leftovers from interrupted builds (notes on how to run deferred
+    /// build scripts that can no longer be run) from the current project's
+    /// target dir.
+    CleanProject {
+        /// Only list what would be removed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Remove cache entries built by a given `rustc` release channel.
+    ///
+    /// Handy for nightly entries, which pile up fast and are rarely reused.
+    Purge {
+        #[arg(long)]
+        channel: Channel,
+    },
+    /// Report what this build of Hope supports (backends, codecs, cache
+    /// schema version, and so on), for tools that drive it.
+    Capabilities {
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check how mtimes behave on this machine, which decides how Hope makes
+    /// pulled files look fresh to Cargo.
+    MtimeProbe {
+        /// Directory on the filesystem to check; defaults to the current dir.
+        #[arg(long)]
+        dir: Option<PathBuf>,
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Copy this binary to a stable place and make it Cargo's `rustc`
+    /// wrapper, e.g. to bootstrap a CI container from a downloaded release.
+    InstallWrapper {
+        /// Where to put the binary [default: "$CARGO_HOME/bin"].
+        #[arg(long)]
+        bin_dir: Option<PathBuf>,
+        /// Cargo's home dir, whose config gets `build.rustc-wrapper`
+        /// [default: "$CARGO_HOME", or "~/.cargo"].
+        #[arg(long)]
+        cargo_home: Option<PathBuf>,
+    },
+    /// Print a shell completion script, e.g. `hope completions bash > /etc/bash_completion.d/hope`.
+    Completions { shell: clap_complete::Shell },
+    /// Print a manpage, in roff format.
+    Manpage,
+    /// Run a `rustc` invocation saved by `HOPE_RECORD_INVOCATIONS` again.
+    Replay {
+        /// The recording, e.g. "recordings/cfg_if-20250101T120000.000000-1234.json".
+        file: PathBuf,
+    },
+    /// Save a credential for a remote cache in the OS keyring, e.g. a token
+    /// for `hope-server`. It's read from stdin (or asked for, if that's a terminal).
+    Login {
+        /// The cache's URL, as in `HOPE_CACHE_URL`.
+        url: String,
+    },
+    /// Remove a credential saved by `hope login` from the OS keyring.
+    Logout {
+        /// The cache's URL, as in `HOPE_CACHE_URL`.
+        url: String,
+    },
+    /// Share the local cache with other machines over HTTP.
+    ///
+    /// There's no authentication, so only do this on a network you trust.
+    Serve {
+        /// Address to listen on, e.g. "0.0.0.0:7777".
+        #[arg(long)]
+        listen: String,
+        /// Take pushes from others too, rather than only sharing what's here.
+        #[arg(long)]
+        allow_push: bool,
+        /// Fetch (and keep) blobs this cache doesn't have from the cache
+        /// at this URL, like `HOPE_CACHE_URL`.
+        #[arg(long)]
+        upstream: Option<String>,
+    },
+    /// Run in the background, serving cache stats and health as JSON
+    /// for dashboards and CI sidecars, and blobs to local builds.
+    Daemon {
+        /// Port to serve `/status`, `/health`, and `/blobs/` on (localhost only).
+        #[arg(long)]
+        status_port: u16,
+        /// How much of the most recently used blobs to keep in memory, e.g. "1G".
+        #[arg(long, value_parser = config::parse_size, default_value = "256M")]
+        memory_cache_size: u64,
+        /// Also serve the remote cache on a unix socket here, for builds
+        /// with `HOPE_DAEMON_SOCKET` set to use.
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AttestCommand {
+    /// Check that entries' files are exactly what their attestations
+    /// say was built. (Attestations aren't signed, so this doesn't
+    /// tell you who built them.)
+    Verify {
+        /// Only check entries for this crate.
+        crate_name: Option<String>,
+    },
+    /// Print attestations as JSON Lines.
+    Export {
+        /// Only export attestations for this crate.
+        crate_name: Option<String>,
+    },
+}
+
+/// Does this look like one of our own subcommands?
+///
+/// When run as a `rustc` wrapper, the first argument is instead
+/// the path to the real `rustc`, so there's no ambiguity in practice.
+pub fn is_subcommand(arg: &str) -> bool {
+    Cli::command().find_subcommand(arg).is_some()
+}
+
+pub fn run(args: impl IntoIterator<Item = String>) -> anyhow::Result<()> {
+    let cli = Cli::parse_from(args);
+    credentials::allow_keyring();
+    match cli.command {
+        Command::Log { export } => export_log(export),
+        Command::DeterminismReport {
+            sample,
+            profile,
+            json,
+        } => determinism::run(sample, &profile, json),
+        Command::BenchCompare { runs, cargo_args } => bench::run(runs, &cargo_args),
+        Command::Observe { cargo_args } => observe::run(&cargo_args),
+        Command::Ls { verbose } => list_entries(verbose),
+        Command::Stats {
+            export_aggregate,
+            sccache_compat,
+            merge,
+        } => stats::run(export_aggregate, sccache_compat, &merge),
+        Command::Explain { crate_name, target } => explain::run(&crate_name, target.as_deref()),
+        Command::WhyRebuild {
+            crate_name,
+            target,
+            profile,
+        } => why_rebuild::run(&crate_name, target.as_deref(), &profile),
+        Command::Key { json, rustc_args } => print_key::run(&rustc_args, json),
+        Command::DiffBuildScript {
+            package,
+            run_a,
+            run_b,
+        } => build_script_diff::run(&package, &run_a, &run_b),
+        Command::WarmUp { from, lockfile } => lockfile_index::warm_up(&from, &lockfile),
+        Command::Sync {
+            from,
+            to,
+            crate_names,
+        } => sync::run(&from, &to, &crate_names),
+        Command::Flush => upload_queue::flush(),
+        Command::PushBacklog => push_backlog::push(),
+        Command::Gc { max_size } => gc(max_size),
+        Command::Attest { command } => match command {
+            AttestCommand::Verify { crate_name } => attestation::verify(crate_name.as_deref()),
+            AttestCommand::Export { crate_name } => attestation::export(crate_name.as_deref()),
+        },
+        Command::Verify { repair } => verify::run(repair),
+        Command::Clean {
+            dry_run,
+            prestage,
+            cargo_args,
+        } => clean::run(dry_run, prestage, &cargo_args),
+        Command::CleanProject { dry_run } => clean_project::run(dry_run),
+        Command::Purge { channel } => purge(channel),
+        Command::Capabilities { json } => capabilities::run(json),
+        Command::MtimeProbe { dir, json } => mtime::run(dir.as_deref(), json),
+        Command::InstallWrapper {
+            bin_dir,
+            cargo_home,
+        } => install_wrapper::run(bin_dir.as_deref(), cargo_home.as_deref()),
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "hope", &mut std::io::stdout());
+            Ok(())
+        }
+        Command::Manpage => {
+            clap_mangen::Man::new(Cli::command()).render(&mut std::io::stdout())?;
+            Ok(())
+        }
+        Command::Replay { file } => replay::run(&file),
+        Command::Login { url } => credentials::login(&url),
+        Command::Logout { url } => credentials::logout(&url),
+        Command::Serve {
+            listen,
+            allow_push,
+            upstream,
+        } => serve::run(&listen, allow_push, upstream.as_deref()),
+        Command::Daemon {
+            status_port,
+            memory_cache_size,
+            socket,
+        } => daemon::run(status_port, memory_cache_size, socket.as_deref()),
+    }
+}
+
+fn export_log(format: LogFormat) -> anyhow::Result<()> {
+    let cache_dir =
+        LocalCache::dir_from_env().context("Failed to get local cache dir from environment")?;
+    let log = read_log(&cache_dir).context("Failed to read cache log")?;
+    let mut stdout = std::io::stdout().lock();
+    write_log_lines(&mut stdout, format, &log).context("Failed to write log to stdout")?;
+    stdout.flush()?;
+    Ok(())
+}
+
+fn purge(channel: Channel) -> anyhow::Result<()> {
+    let cache = LocalCache::from_env()?;
+    let mut purged = 0;
+    for (storage_name, manifest) in cache.entries()? {
+        if manifest.channel == Some(channel) {
+            cache.remove_entry(&storage_name)?;
+            purged += 1;
+        }
+    }
+    println!("Purged {purged} {channel} entries.");
+    Ok(())
+}
+
+/// How long to leave chunks nothing refers to yet, in case their
+/// entry is still being pushed.
+const CHUNK_SWEEP_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Evict entries in the order they were pushed, oldest first.
+///
+/// Chunks can be shared between entries, so each one is only counted once,
+/// and only removed once no remaining entry refers to it.
+///
+/// TODO: Evict by when entries were last _pulled_, which is what matters.
+fn gc(max_size: u64) -> anyhow::Result<()> {
+    clean::remove_staged(&LocalCache::dir_from_env()?)?;
+    let cache = LocalCache::from_env()?;
+    let entries = cache.entries()?;
+    let entry_files = cache.entry_files(
+        entries
+            .iter()
+            .map(|(storage_name, _)| storage_name.as_str()),
+    )?;
+    let mut chunk_refs: HashMap<&str, usize> = HashMap::new();
+    for files in entry_files.values() {
+        for hash in &files.chunks {
+            *chunk_refs.entry(hash).or_default() += 1;
+        }
+    }
+
+    // Sweep chunks that nothing refers to, e.g. left behind by `hope purge`.
+    // Pushes store chunks before their manifests, so leave recent ones be.
+    let mut removed_chunks = 0;
+    let chunks = cache.chunks()?;
+    for (hash, (_, written_at)) in &chunks {
+        let is_recent = written_at
+            .elapsed()
+            .is_ok_and(|age| age < CHUNK_SWEEP_GRACE);
+        if !chunk_refs.contains_key(hash.as_str()) && !is_recent {
+            cache.remove_blob(&chunks::chunk_key(hash))?;
+            removed_chunks += 1;
+        }
+    }
+
+    let chunk_size = |hash: &str| chunks.get(hash).map_or(0, |(size, _)| *size);
+    let mut total_size = entry_files.values().map(|files| files.size).sum::<u64>()
+        + chunk_refs.keys().map(|hash| chunk_size(hash)).sum::<u64>();
+    let mut entries: Vec<_> = entries
+        .iter()
+        .map(|(storage_name, manifest)| (manifest.pushed_at, storage_name))
+        .collect();
+    // Entries without a push time are from older versions of Hope, so go first.
+    entries.sort();
+    let mut removed = 0;
+    for (_, storage_name) in entries {
+        if total_size <= max_size {
+            break;
+        }
+        let files = &entry_files[storage_name];
+        cache.remove_entry_files(files)?;
+        total_size -= files.size;
+        removed += 1;
+        for hash in &files.chunks {
+            let refs = chunk_refs
+                .get_mut(hash.as_str())
+                .expect("Every chunk should have been counted");
+            *refs -= 1;
+            if *refs == 0 {
+                cache.remove_blob(&chunks::chunk_key(hash))?;
+                total_size -= chunk_size(hash);
+                removed_chunks += 1;
+            }
+        }
+    }
+    println!(
+        "Removed {removed} entries and {removed_chunks} chunks; \
+         the cache now holds {total_size} bytes of entries."
+    );
+    Ok(())
+}
+
+fn list_entries(verbose: bool) -> anyhow::Result<()> {
+    let cache = LocalCache::from_env()?;
+    for (storage_name, manifest) in cache.entries()? {
+        println!("{storage_name} {}", manifest.target);
+        if !verbose {
+            continue;
+        }
+        if let Some(key_policy) = &manifest.key_policy {
+            println!("    key policy: {key_policy}");
+        }
+        let Some(environment) = &manifest.environment else {
+            println!("    (no build environment recorded)");
+            continue;
+        };
+        let unknown = || "unknown".to_string();
+        println!(
+            "    rustc: {} ({})",
+            environment.rustc_release,
+            environment
+                .rustc_commit_hash
+                .clone()
+                .unwrap_or_else(unknown)
+        );
+        println!(
+            "    linker: {}",
+            environment
+                .linker
+                .clone()
+                .unwrap_or_else(|| "default".to_string())
+        );
+        let native_toolchain = &environment.native_toolchain;
+        if let Some(cc_version) = &native_toolchain.cc_version {
+            println!("    cc: {cc_version}");
+        }
+        if let Some(ar_version) = &native_toolchain.ar_version {
+            println!("    ar: {ar_version}");
+        }
+        if let Some(deployment_target) = &native_toolchain.deployment_target {
+            println!("    deployment target: {deployment_target}");
+        }
+        if let Some(sdk_version) = &native_toolchain.sdk_version {
+            println!("    sdk: {sdk_version}");
+        }
+        if let Some(ndk_version) = &native_toolchain.ndk_version {
+            println!("    ndk: {ndk_version}");
+        }
+        println!("    os: {}", environment.os);
+        if let Some(libc) = &environment.libc {
+            println!("    libc: {libc}");
+        }
+    }
+    Ok(())
+}
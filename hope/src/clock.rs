@@ -0,0 +1,53 @@
+//! Noticing when clocks disagree.
+//!
+//! We never copy timestamps from other machines onto pulled artifacts;
+//! their mtimes always come from Cargo's own `invoked.timestamp` files in
+//! the local target dir (see `get_invoked_timestamp_for_crate_build_unit`),
+//! so builds are ordered relative to each other rather than by wall clock.
+//!
+//! But clocks that are way off (on another machine sharing the cache, or
+//! on a network filesystem holding the target dir) still tend to show up
+//! as mysterious constant rebuilds, so we at least try to point them out.
+
+use std::{path::Path, time::SystemTime};
+
+use chrono::{DateTime, Utc};
+use hope_cache_log::{write_log_line, CacheLogLine, ClockSkewEvent};
+
+/// How far apart clocks can be before we consider it worth mentioning.
+const TOLERANCE_SECS: f64 = 5.0 * 60.0;
+
+/// Complain if `observed` (which should be in the past) is well into the future.
+///
+/// `source` describes where the observed time came from, for humans.
+pub fn check(
+    cache_dir: &Path,
+    crate_unit_name: &str,
+    observed: DateTime<Utc>,
+    source: &str,
+) -> anyhow::Result<()> {
+    let now = Utc::now();
+    let skew_secs = (observed - now).num_milliseconds() as f64 / 1000.0;
+    if skew_secs <= TOLERANCE_SECS {
+        return Ok(());
+    }
+    eprintln!(
+        "Hope: {source} for {crate_unit_name} is {skew_secs:.0}s in the future; \
+         check for clock skew if you're seeing constant rebuilds."
+    );
+    write_log_line(
+        cache_dir,
+        CacheLogLine::DetectedClockSkew(ClockSkewEvent {
+            crate_unit_name: crate_unit_name.to_owned(),
+            detected_at: now,
+            skew_secs,
+            source: source.to_owned(),
+        }),
+    )
+}
+
+/// Convert a filesystem timestamp for comparison with wall clock time.
+pub fn file_time_to_utc(file_time: filetime::FileTime) -> DateTime<Utc> {
+    DateTime::from_timestamp(file_time.unix_seconds(), file_time.nanoseconds())
+        .unwrap_or_else(|| SystemTime::UNIX_EPOCH.into())
+}
@@ -0,0 +1,220 @@
+//! Compressing stored artifacts, with per-crate overrides.
+//!
+//! Artifacts are stored as is by default. Set `HOPE_COMPRESSION` to a codec
+//! (see [`Codec`]) to compress everything, and `HOPE_COMPRESSION_RULES` to
+//! override that for some crates or kinds of file. Not everything is worth
+//! the CPU time: static libraries from `*-sys` crates are often compressed
+//! already, while big `.rmeta` files that get pulled all the time are
+//! worth squeezing as hard as possible.
+//!
+//! Rules are separated by commas, and each one is
+//! "{crate glob}[/{file glob}]={codec}", e.g. "*-sys=none,*/*.rmeta=gzip:9".
+//! The first rule that matches wins, and `HOPE_COMPRESSION` applies if
+//! none do. In globs, `*` matches any run of characters. Crate names match
+//! with hyphens and underscores treated the same (Cargo turns one into the
+//! other), and file globs match stored file names.
+//!
+//! A compressed artifact is stored under its usual name plus [`GZIP_SUFFIX`].
+//! As with chunks, whether an artifact was compressed depends on the settings
+//! in effect when it was pushed, so readers check for both. Chunked artifacts
+//! are never compressed.
+
+use std::{
+    fmt,
+    io::{Read as _, Seek as _, SeekFrom, Write as _},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::Context;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use crate::chunks::BlobStore;
+
+pub const GZIP_SUFFIX: &str = ".gz";
+
+/// Names of every codec we support.
+pub const CODEC_NAMES: &[&str] = &["none", "gzip"];
+
+/// "none", or "gzip" with an optional level from 0 to 9, e.g. "gzip:9".
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    None,
+    Gzip {
+        level: u32,
+    },
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match s.trim().split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s.trim(), None),
+        };
+        match (name, level) {
+            ("none", None) => Ok(Self::None),
+            ("gzip", None) => Ok(Self::Gzip { level: 6 }),
+            ("gzip", Some(level)) => {
+                let level = level
+                    .parse()
+                    .ok()
+                    .filter(|level| *level <= 9)
+                    .with_context(|| format!("Invalid gzip level {level:?}; expected 0 to 9"))?;
+                Ok(Self::Gzip { level })
+            }
+            _ => anyhow::bail!("Unrecognised compression codec {s:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("none"),
+            Self::Gzip { level } => write!(f, "gzip:{level}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompressionPolicy {
+    default: Codec,
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    crate_glob: String,
+    file_glob: Option<String>,
+    codec: Codec,
+}
+
+impl CompressionPolicy {
+    /// Parse the values of `HOPE_COMPRESSION` and `HOPE_COMPRESSION_RULES`.
+    pub fn parse(default: Option<&str>, rules: Option<&str>) -> anyhow::Result<Self> {
+        let default = default.map_or(Ok(Codec::None), str::parse)?;
+        let rules = rules
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let (pattern, codec) = rule
+                    .split_once('=')
+                    .with_context(|| format!("Expected '{{glob}}={{codec}}', got {rule:?}"))?;
+                let (crate_glob, file_glob) = match pattern.split_once('/') {
+                    Some((crate_glob, file_glob)) => (crate_glob, Some(file_glob.to_owned())),
+                    None => (pattern, None),
+                };
+                Ok(Rule {
+                    crate_glob: crate_glob.to_owned(),
+                    file_glob,
+                    codec: codec.parse()?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { default, rules })
+    }
+
+    /// How to store the file `file_name` from the crate `crate_name`.
+    pub fn codec_for(&self, crate_name: &str, file_name: &str) -> Codec {
+        let crate_name = crate_name.replace('-', "_");
+        self.rules
+            .iter()
+            .find(|rule| {
+                glob_matches(&rule.crate_glob.replace('-', "_"), &crate_name)
+                    && match &rule.file_glob {
+                        Some(file_glob) => glob_matches(file_glob, file_name),
+                        None => true,
+                    }
+            })
+            .map_or(self.default, |rule| rule.codec)
+    }
+}
+
+/// Does `text` match `pattern`, where `*` in the pattern matches
+/// any run of characters (including none)?
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Where to resume if what followed the last `*` doesn't work out.
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, star_t)) = backtrack {
+            // Let that `*` swallow one more character.
+            p = star + 1;
+            t = star_t + 1;
+            backtrack = Some((star, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Key for the compressed copy of the named artifact.
+pub fn compressed_key(file_name: &str) -> String {
+    format!("{file_name}{GZIP_SUFFIX}")
+}
+
+/// Compress `content` and store it for `file_name`.
+pub fn put_compressed(
+    store: &(impl BlobStore + ?Sized),
+    file_name: &str,
+    content: &[u8],
+    level: u32,
+) -> anyhow::Result<()> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(content)?;
+    let compressed = encoder
+        .finish()
+        .with_context(|| format!("Failed to compress {file_name:?}"))?;
+    store.put_blob(&compressed_key(file_name), &compressed)
+}
+
+/// Decompress the artifact `file_name`.
+pub fn get_compressed(
+    store: &(impl BlobStore + ?Sized),
+    file_name: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let compressed = store.get_blob(&compressed_key(file_name))?;
+    let mut content = Vec::new();
+    GzDecoder::new(compressed.as_slice())
+        .read_to_end(&mut content)
+        .with_context(|| format!("Failed to decompress {file_name:?}"))?;
+    Ok(content)
+}
+
+/// Size of a gzipped file once decompressed, according to its trailer,
+/// without decompressing it.
+///
+/// The trailer only has room for the size modulo 4GiB,
+/// but no artifact we store should be that big.
+pub fn uncompressed_size(path: &Path) -> anyhow::Result<u64> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    file.seek(SeekFrom::End(-4))
+        .with_context(|| format!("{path:?} is too short to be gzipped"))?;
+    let mut trailer = [0; 4];
+    file.read_exact(&mut trailer)
+        .with_context(|| format!("Failed to read gzip trailer of {path:?}"))?;
+    size_in_trailer(&trailer)
+}
+
+/// Size of a gzipped blob once decompressed, given its last four bytes;
+/// see `uncompressed_size`.
+pub fn size_in_trailer(trailer: &[u8]) -> anyhow::Result<u64> {
+    let trailer: [u8; 4] = trailer
+        .try_into()
+        .context("Gzip trailer should be four bytes")?;
+    Ok(u32::from_le_bytes(trailer).into())
+}
@@ -0,0 +1,649 @@
+//! Knobs that can be tweaked through environment variables.
+//!
+//! Everything here has a sensible default, so none of these
+//! need to be set for normal use.
+
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::Context;
+
+use crate::{
+    compression::CompressionPolicy,
+    key_policy::{self, KeyPolicy},
+    permissions::{self, PermissionPolicy},
+    plugin,
+};
+
+/// Largest single artifact we'll push to a remote cache, in bytes.
+///
+/// Set with `HOPE_MAX_REMOTE_PUSH_SIZE`, e.g. "500M". Units with
+/// any artifact bigger than this are still cached locally; we just don't
+/// ship them off to a remote (and possibly metered) cache.
+pub fn max_remote_push_size() -> anyhow::Result<Option<u64>> {
+    let Ok(size) = std::env::var("HOPE_MAX_REMOTE_PUSH_SIZE") else {
+        return Ok(None);
+    };
+    parse_size(&size)
+        .map(Some)
+        .context("Invalid 'HOPE_MAX_REMOTE_PUSH_SIZE' environment variable")
+}
+
+/// Where to cache things, if not in the local cache dir.
+///
+/// Set with `HOPE_CACHE_URL`, e.g. "file:///mnt/shared/hope",
+/// "s3://bucket/prefix", "https://build-box/hope-cache",
+/// "bazel+http://cache:8080", "redis://localhost:6379", or "gha://".
+/// See `cache::from_env` for what's supported.
+pub fn cache_url() -> Option<String> {
+    std::env::var("HOPE_CACHE_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+}
+
+/// A public cache to pull from without any credentials, e.g. a CDN in front
+/// of the bucket that an open-source project's CI pushes to (with
+/// `HOPE_CACHE_URL`), so that anyone building the project can pull from it.
+///
+/// Set with `HOPE_PUBLIC_CACHE_URL`, to an http:// or https:// URL.
+/// See the `tiered_cache` module for details.
+pub fn public_cache_url() -> Option<String> {
+    std::env::var("HOPE_PUBLIC_CACHE_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+}
+
+/// Credential for every remote cache, e.g. a bearer token for `hope-server`.
+///
+/// Set with `HOPE_CACHE_TOKEN`. It's sent to every remote cache, so only
+/// set it when they're all yours. See the `credentials` module for details.
+pub fn cache_token() -> Option<String> {
+    std::env::var("HOPE_CACHE_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+/// Where to find credentials for remote caches, if not in
+/// "credentials" in Hope's config dir.
+///
+/// Set with `HOPE_CREDENTIALS_FILE`. See the `credentials` module for details.
+pub fn credentials_file() -> Option<PathBuf> {
+    std::env::var_os("HOPE_CREDENTIALS_FILE")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Look in the OS keyring for credentials during builds, not just in
+/// Hope's own commands.
+///
+/// Set `HOPE_KEYRING_IN_BUILDS=1` to enable. It's off by default because
+/// every unit looks, and a locked keyring can make each one wait for it to be
+/// unlocked. See the `credentials` module for details.
+pub fn keyring_in_builds() -> bool {
+    env_flag("HOPE_KEYRING_IN_BUILDS")
+}
+
+/// Other caches to push to, as well as the main one (see [`cache_url`]),
+/// e.g. to fill both the local cache and a team's shared cache.
+///
+/// Set `HOPE_ALSO_PUSH_TO` to a comma-separated list of cache URLs,
+/// or "local" for the local cache dir. Pushes to each of them are skipped
+/// or fail without affecting the others.
+pub fn also_push_to() -> Vec<String> {
+    cache_list("HOPE_ALSO_PUSH_TO")
+}
+
+/// Stores to spread the main cache across, by key, instead of [`cache_url`].
+///
+/// Set `HOPE_CACHE_SHARDS` to a comma-separated list of cache URLs.
+/// See the `sharded_store` module for details.
+pub fn cache_shards() -> Vec<String> {
+    cache_list("HOPE_CACHE_SHARDS")
+}
+
+/// Socket of a `hope daemon` to reach the remote cache through, instead of
+/// talking to it directly.
+///
+/// Set with `HOPE_DAEMON_SOCKET`. See the `daemon_socket` module for details.
+pub fn daemon_socket() -> Option<PathBuf> {
+    std::env::var_os("HOPE_DAEMON_SOCKET")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Other caches to pull from when the main one (see [`cache_url`]) doesn't
+/// have an entry, in the order to try them, e.g. a team's cache and then
+/// a public one.
+///
+/// Set `HOPE_ALSO_PULL_FROM` to a comma-separated list of cache URLs,
+/// or "local" for the local cache dir. See the `tiered_cache` module for details.
+pub fn also_pull_from() -> Vec<String> {
+    cache_list("HOPE_ALSO_PULL_FROM")
+}
+
+/// When a pull comes from one of [`also_pull_from`], push it to the caches
+/// in front of that one too, so they have it next time.
+///
+/// Set `HOPE_BACKFILL=1` to enable. See the `tiered_cache` module for details.
+pub fn backfill() -> bool {
+    env_flag("HOPE_BACKFILL")
+}
+
+/// Queue pushes to remote caches on disk for `hope flush` to upload,
+/// instead of uploading them as each unit is built.
+///
+/// Set `HOPE_ASYNC_PUSH=1` to enable. See the `upload_queue` module for details.
+pub fn async_push() -> bool {
+    env_flag("HOPE_ASYNC_PUSH")
+}
+
+/// Push each freshly compiled unit from a detached process, so that
+/// Cargo can get on with the build instead of waiting for the push.
+///
+/// Set `HOPE_BACKGROUND_PUSH=1` to enable. See the `background_push` module for details.
+pub fn background_push() -> bool {
+    env_flag("HOPE_BACKGROUND_PUSH")
+}
+
+/// Where to find an S3-compatible store other than AWS itself,
+/// e.g. MinIO or Ceph.
+///
+/// Set with `HOPE_S3_ENDPOINT`, e.g. "http://minio.internal:9000".
+/// Otherwise `AWS_ENDPOINT_URL_S3` or `AWS_ENDPOINT_URL` are used if set,
+/// as they would be by the AWS CLI.
+pub fn s3_endpoint() -> Option<String> {
+    [
+        "HOPE_S3_ENDPOINT",
+        "AWS_ENDPOINT_URL_S3",
+        "AWS_ENDPOINT_URL",
+    ]
+    .into_iter()
+    .find_map(|name| std::env::var(name).ok().filter(|url| !url.is_empty()))
+}
+
+/// Put the bucket name in the path of S3 requests ("host/bucket/key")
+/// rather than in the host name ("bucket.host/key").
+///
+/// Set `HOPE_S3_ADDRESSING_STYLE` to "path" or "virtual". Defaults to
+/// "path" with a custom [`s3_endpoint`], because that's what self-hosted
+/// stores handle without any DNS set up for them, and "virtual" otherwise.
+pub fn s3_path_style() -> anyhow::Result<bool> {
+    match std::env::var("HOPE_S3_ADDRESSING_STYLE").as_deref() {
+        Err(_) => Ok(s3_endpoint().is_some()),
+        Ok("path") => Ok(true),
+        Ok("virtual") => Ok(false),
+        Ok(style) => anyhow::bail!(
+            "Invalid 'HOPE_S3_ADDRESSING_STYLE' environment variable {style:?}; \
+             expected \"path\" or \"virtual\""
+        ),
+    }
+}
+
+/// Region to sign S3 requests for, in place of the one the AWS CLI
+/// would use (see the `s3` module).
+///
+/// Set with `HOPE_S3_REGION`. Self-hosted stores often accept any region,
+/// but some insist on the one they were set up with.
+pub fn s3_region() -> Option<String> {
+    std::env::var("HOPE_S3_REGION")
+        .ok()
+        .filter(|region| !region.is_empty())
+}
+
+/// How long blobs stored in a Redis cache live for, unless they're used.
+///
+/// Set with `HOPE_REDIS_TTL_SECS`. Fetching a blob starts its time over,
+/// so whatever's still being used stays cached. Blobs never expire if unset
+/// (which leaves it to Redis's `maxmemory-policy`).
+pub fn redis_ttl() -> anyhow::Result<Option<Duration>> {
+    let Ok(secs) = std::env::var("HOPE_REDIS_TTL_SECS") else {
+        return Ok(None);
+    };
+    let secs: u64 = secs
+        .trim()
+        .parse()
+        .context("Invalid 'HOPE_REDIS_TTL_SECS' environment variable")?;
+    anyhow::ensure!(secs > 0, "'HOPE_REDIS_TTL_SECS' must be greater than zero");
+    Ok(Some(Duration::from_secs(secs)))
+}
+
+/// Split artifacts into chunks of at most this many bytes when storing them.
+///
+/// Set with `HOPE_CHUNK_SIZE`, e.g. "8M". Artifacts are stored whole if unset.
+/// See the `chunks` module for details.
+pub fn chunk_size() -> anyhow::Result<Option<u64>> {
+    let Ok(size) = std::env::var("HOPE_CHUNK_SIZE") else {
+        return Ok(None);
+    };
+    let size = parse_size(&size).context("Invalid 'HOPE_CHUNK_SIZE' environment variable")?;
+    anyhow::ensure!(size > 0, "'HOPE_CHUNK_SIZE' must be greater than zero");
+    Ok(Some(size))
+}
+
+/// How to compress artifacts when storing them.
+///
+/// Set `HOPE_COMPRESSION` to a codec, e.g. "gzip:9" (the default is "none"),
+/// and `HOPE_COMPRESSION_RULES` to override it for particular crates.
+/// See the `compression` module for details.
+pub fn compression() -> anyhow::Result<CompressionPolicy> {
+    CompressionPolicy::parse(
+        std::env::var("HOPE_COMPRESSION").ok().as_deref(),
+        std::env::var("HOPE_COMPRESSION_RULES").ok().as_deref(),
+    )
+    .context("Invalid 'HOPE_COMPRESSION' or 'HOPE_COMPRESSION_RULES' environment variable")
+}
+
+/// Give up on pulling a unit if it takes longer than this, and build it instead.
+///
+/// Set with `HOPE_PULL_BUDGET_MS`. This is mostly for remote caches, so that
+/// a slow or flaky network can never make a warm build slower than a cold one.
+/// No limit if unset.
+pub fn pull_budget() -> anyhow::Result<Option<Duration>> {
+    let Ok(millis) = std::env::var("HOPE_PULL_BUDGET_MS") else {
+        return Ok(None);
+    };
+    let millis: u64 = millis
+        .trim()
+        .parse()
+        .context("Invalid 'HOPE_PULL_BUDGET_MS' environment variable")?;
+    Ok(Some(Duration::from_millis(millis)))
+}
+
+/// Most time that pulls and pushes can take, altogether, in one Cargo build;
+/// after that, the rest of the build compiles everything instead.
+///
+/// Set with `HOPE_SESSION_BUDGET_SECS`. It's for days when the remote cache
+/// is slow, so that CI doesn't take longer than it would have without it.
+/// No limit if unset. See the `session_budget` module for details.
+pub fn session_budget() -> anyhow::Result<Option<Duration>> {
+    let Ok(secs) = std::env::var("HOPE_SESSION_BUDGET_SECS") else {
+        return Ok(None);
+    };
+    let secs: f64 = secs
+        .trim()
+        .parse()
+        .context("Invalid 'HOPE_SESSION_BUDGET_SECS' environment variable")?;
+    Duration::try_from_secs_f64(secs)
+        .map(Some)
+        .context("Invalid 'HOPE_SESSION_BUDGET_SECS' environment variable")
+}
+
+/// Most that one Cargo build can pull, in bytes, before the rest of it
+/// compiles everything instead.
+///
+/// Set with `HOPE_SESSION_PULL_BUDGET`, e.g. "2G", for metered connections.
+/// No limit if unset. See the `session_budget` module for details.
+pub fn session_pull_budget() -> anyhow::Result<Option<u64>> {
+    let Ok(size) = std::env::var("HOPE_SESSION_PULL_BUDGET") else {
+        return Ok(None);
+    };
+    parse_size(&size)
+        .map(Some)
+        .context("Invalid 'HOPE_SESSION_PULL_BUDGET' environment variable")
+}
+
+/// Most blobs that all the wrappers running on this machine (sharing
+/// a cache dir) can be uploading to remote caches at once.
+///
+/// Set with `HOPE_MAX_UPLOADS`. No limit if unset, unless
+/// `HOPE_UPLOAD_LIMIT` is set. See the `transfer_limits` module for details.
+pub fn max_uploads() -> anyhow::Result<Option<u32>> {
+    transfer_count("HOPE_MAX_UPLOADS")
+}
+
+/// Most blobs that all the wrappers running on this machine (sharing
+/// a cache dir) can be downloading from remote caches at once.
+///
+/// Set with `HOPE_MAX_DOWNLOADS`. No limit if unset, unless
+/// `HOPE_DOWNLOAD_LIMIT` is set. See the `transfer_limits` module for details.
+pub fn max_downloads() -> anyhow::Result<Option<u32>> {
+    transfer_count("HOPE_MAX_DOWNLOADS")
+}
+
+fn transfer_count(var: &str) -> anyhow::Result<Option<u32>> {
+    let Ok(count) = std::env::var(var) else {
+        return Ok(None);
+    };
+    let count: u32 = count
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid '{var}' environment variable"))?;
+    anyhow::ensure!(count > 0, "'{var}' must be greater than zero");
+    Ok(Some(count))
+}
+
+/// Most bytes per second that uploads to remote caches
+/// can add up to, across the whole machine.
+///
+/// Set with `HOPE_UPLOAD_LIMIT`, e.g. "5M". No limit if unset.
+/// See the `transfer_limits` module for details.
+pub fn upload_limit() -> anyhow::Result<Option<u64>> {
+    bandwidth_limit("HOPE_UPLOAD_LIMIT")
+}
+
+/// Most bytes per second that downloads from remote caches
+/// can add up to, across the whole machine.
+///
+/// Set with `HOPE_DOWNLOAD_LIMIT`, e.g. "20M". No limit if unset.
+/// See the `transfer_limits` module for details.
+pub fn download_limit() -> anyhow::Result<Option<u64>> {
+    bandwidth_limit("HOPE_DOWNLOAD_LIMIT")
+}
+
+fn bandwidth_limit(var: &str) -> anyhow::Result<Option<u64>> {
+    let Ok(limit) = std::env::var(var) else {
+        return Ok(None);
+    };
+    let limit =
+        parse_size(&limit).with_context(|| format!("Invalid '{var}' environment variable"))?;
+    anyhow::ensure!(limit > 0, "'{var}' must be greater than zero");
+    Ok(Some(limit))
+}
+
+/// How many times in a row the remote cache can fail to answer (or take
+/// too long to) in one Cargo build before the rest of the build uses just
+/// the local cache instead.
+///
+/// Set with `HOPE_REMOTE_ERROR_LIMIT`; defaults to 3, and "0" means never
+/// give up on it. See the `remote_fallback` module for details.
+pub fn remote_error_limit() -> anyhow::Result<u32> {
+    let Ok(limit) = std::env::var("HOPE_REMOTE_ERROR_LIMIT") else {
+        return Ok(3);
+    };
+    limit
+        .trim()
+        .parse()
+        .context("Invalid 'HOPE_REMOTE_ERROR_LIMIT' environment variable")
+}
+
+/// Pull straight into the target directory, and rename files into place,
+/// rather than going via a temp dir elsewhere and copying them.
+///
+/// Set `HOPE_DIRECT_PULL=1` to enable. This halves the I/O for pulls,
+/// but leaves hidden ".hope-arrival-*" dirs in the target directory
+/// if Hope is killed part way through one.
+pub fn direct_pull() -> bool {
+    env_flag("HOPE_DIRECT_PULL")
+}
+
+/// Only pull a package's crate if its build script (if it has one) was
+/// pulled too, and only replay a build script's output if the build script
+/// itself was pulled.
+///
+/// Set `HOPE_WHOLE_PACKAGE_PULLS=1` to enable. Otherwise each unit is pulled
+/// if it can be, regardless of the rest of its package, which is faster but
+/// can leave a crate built against the output of a build script that ran here.
+/// See `build_script::BUILT_LOCALLY_FILE_NAME`.
+pub fn whole_package_pulls() -> bool {
+    env_flag("HOPE_WHOLE_PACKAGE_PULLS")
+}
+
+/// Build everything with the real `rustc` and push it as usual, but never pull,
+/// just note which units could have been pulled.
+///
+/// Set `HOPE_OBSERVE=1` to enable, or use `hope observe`, which also
+/// estimates how much time the cache would save. See the `observe` module.
+pub fn observe() -> bool {
+    env_flag("HOPE_OBSERVE")
+}
+
+/// Only cache this percentage of packages, for trying Hope out
+/// on a fraction of a big build before enabling it everywhere.
+///
+/// Set with `HOPE_ROLLOUT_PERCENT`, e.g. "25"; defaults to 100.
+/// Which packages are included is decided by a hash of their names,
+/// so it's the same from one build (and machine) to the next.
+pub fn rollout_percent() -> anyhow::Result<u8> {
+    let Ok(percent) = std::env::var("HOPE_ROLLOUT_PERCENT") else {
+        return Ok(100);
+    };
+    let percent: u8 = percent
+        .trim()
+        .trim_end_matches('%')
+        .parse()
+        .context("Invalid 'HOPE_ROLLOUT_PERCENT' environment variable")?;
+    anyhow::ensure!(
+        percent <= 100,
+        "'HOPE_ROLLOUT_PERCENT' must be between 0 and 100"
+    );
+    Ok(percent)
+}
+
+/// Always keep at least this many bytes free on the filesystems we write to.
+///
+/// Set with `HOPE_MIN_FREE_SPACE`, e.g. "2G"; defaults to 256M.
+/// See the `disk_space` module for details.
+pub fn min_free_space() -> anyhow::Result<u64> {
+    let Ok(size) = std::env::var("HOPE_MIN_FREE_SPACE") else {
+        return Ok(256 << 20);
+    };
+    parse_size(&size).context("Invalid 'HOPE_MIN_FREE_SPACE' environment variable")
+}
+
+/// Check registry sources against their `.crate` archives before pushing.
+///
+/// Set `HOPE_VERIFY_SOURCES=1` to enable. See the `sources` module for details.
+pub fn verify_sources() -> bool {
+    env_flag("HOPE_VERIFY_SOURCES")
+}
+
+/// Before pulling an entry, check that the package's source files here
+/// match the ones it was built from.
+///
+/// Set `HOPE_AUDIT_SOURCES=1` to enable. See `sources::audit` for details.
+pub fn audit_sources() -> bool {
+    env_flag("HOPE_AUDIT_SOURCES")
+}
+
+/// Only bother with the cache for small units if they're used directly
+/// by the workspace's own crates.
+///
+/// Set `HOPE_LEAF_ONLY=1` to enable. Deeper dependencies are still cached
+/// if their outputs add up to at least [`leaf_only_min_size`]; below that,
+/// they're quicker to build than to fetch. See the `leaf` module for details.
+pub fn leaf_only() -> bool {
+    env_flag("HOPE_LEAF_ONLY")
+}
+
+/// Smallest total output size worth caching for deeper dependencies
+/// when [`leaf_only`] is on.
+///
+/// Set with `HOPE_LEAF_ONLY_MIN_SIZE`, e.g. "4M"; defaults to 1 MiB.
+pub fn leaf_only_min_size() -> anyhow::Result<u64> {
+    let Ok(size) = std::env::var("HOPE_LEAF_ONLY_MIN_SIZE") else {
+        return Ok(1 << 20);
+    };
+    parse_size(&size).context("Invalid 'HOPE_LEAF_ONLY_MIN_SIZE' environment variable")
+}
+
+/// Don't pull or push crates that have been building quicker here than
+/// pulls from the cache in use have been taking.
+///
+/// Set `HOPE_SKIP_CHEAP_UNITS=1` to enable. It goes by the history in the
+/// log, so it only kicks in once there's been a few pulls to measure.
+/// See the `value` module for details.
+pub fn skip_cheap_units() -> bool {
+    env_flag("HOPE_SKIP_CHEAP_UNITS")
+}
+
+/// Let everyone in the cache dir's group use it, not just its owner.
+///
+/// Set `HOPE_GROUP_WRITABLE=1` to enable. What we write to the cache is made
+/// group-writable (see [`permissions`]), and a cache dir owned by somebody else
+/// is used as long as it's group-writable. See the `ownership` module for details.
+pub fn group_writable() -> bool {
+    env_flag("HOPE_GROUP_WRITABLE")
+}
+
+/// Modes for the files and directories we write to cache dirs.
+///
+/// Set `HOPE_FILE_MODE` and `HOPE_DIR_MODE` in octal, e.g. "664" and "2775".
+/// With [`group_writable`] on, those are the defaults; otherwise it's up to
+/// the umask. See the `permissions` module for details.
+pub fn permissions() -> anyhow::Result<PermissionPolicy> {
+    let mode = |name: &str, group_writable_mode: u32| -> anyhow::Result<Option<u32>> {
+        match std::env::var(name) {
+            Ok(mode) => permissions::parse_mode(&mode)
+                .map(Some)
+                .with_context(|| format!("Invalid '{name}' environment variable")),
+            Err(_) => Ok(group_writable().then_some(group_writable_mode)),
+        }
+    };
+    Ok(PermissionPolicy::new(
+        mode("HOPE_FILE_MODE", 0o664)?,
+        mode("HOPE_DIR_MODE", 0o2775)?,
+    ))
+}
+
+/// When running as root with a cache dir that belongs to somebody else,
+/// use it anyway, and give them anything we create in it.
+///
+/// Set `HOPE_ADOPT_CACHE_OWNER=1` to enable. Without it, Hope leaves a cache
+/// that isn't yours alone. See the `ownership` module for details.
+pub fn adopt_cache_owner() -> bool {
+    env_flag("HOPE_ADOPT_CACHE_OWNER")
+}
+
+/// Don't push to remote caches at all, e.g. for builds of untrusted pull
+/// requests, but remember what would have been pushed (see the
+/// `push_backlog` module).
+///
+/// Set `HOPE_READ_ONLY=1` to enable. Pushes to the local cache still happen.
+pub fn read_only() -> bool {
+    env_flag("HOPE_READ_ONLY")
+}
+
+/// Refuse to push artifacts containing machine-specific absolute paths
+/// to remote caches, unless `--remap-path-prefix` is in use.
+///
+/// Set `HOPE_REQUIRE_PORTABLE=1` to enable. See the `portability` module for details.
+pub fn require_portable_remote_pushes() -> bool {
+    env_flag("HOPE_REQUIRE_PORTABLE")
+}
+
+/// Shell command to run when things happen in the cache.
+///
+/// Set with `HOPE_HOOK`. See the `hooks` module for details.
+pub fn hook() -> Option<String> {
+    std::env::var("HOPE_HOOK")
+        .ok()
+        .filter(|hook| !hook.is_empty())
+}
+
+/// Directory to save each `rustc` invocation in, for replaying later.
+///
+/// Set with `HOPE_RECORD_INVOCATIONS`. See the `replay` module for details.
+pub fn record_invocations() -> Option<PathBuf> {
+    std::env::var_os("HOPE_RECORD_INVOCATIONS")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// WebAssembly module that decides what to cache, pull, and put in keys.
+///
+/// Set with `HOPE_POLICY_PLUGIN`. See the `plugin` module for details.
+pub fn policy_plugin() -> Option<PathBuf> {
+    std::env::var_os("HOPE_POLICY_PLUGIN")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Record which cache entries the project with this `Cargo.lock` uses.
+///
+/// Set `HOPE_LOCKFILE_INDEX` to the absolute path of the project's
+/// `Cargo.lock`. See the `lockfile_index` module for details.
+pub fn lockfile_index() -> anyhow::Result<Option<PathBuf>> {
+    let Some(path) = std::env::var_os("HOPE_LOCKFILE_INDEX").filter(|path| !path.is_empty()) else {
+        return Ok(None);
+    };
+    let path = PathBuf::from(path);
+    // `rustc` runs in each package's own dir, so relative paths are no good.
+    anyhow::ensure!(
+        path.is_absolute(),
+        "'HOPE_LOCKFILE_INDEX' must be an absolute path; got {path:?}"
+    );
+    Ok(Some(path))
+}
+
+/// Builder identity to record in attestations for entries we push.
+///
+/// Set with `HOPE_BUILDER_ID`, e.g. to a CI system's job URL;
+/// defaults to "hope@{hostname}". See the `attestation` module.
+pub fn builder_id() -> Option<String> {
+    std::env::var("HOPE_BUILDER_ID")
+        .ok()
+        .filter(|builder_id| !builder_id.is_empty())
+}
+
+/// Program to offer cache misses to before compiling them locally.
+///
+/// Set with `HOPE_REMOTE_BUILDER`. See the `remote_build` module for details.
+pub fn remote_builder() -> Option<String> {
+    std::env::var("HOPE_REMOTE_BUILDER")
+        .ok()
+        .filter(|builder| !builder.is_empty())
+}
+
+/// Which parts of a unit's build go into its cache key.
+///
+/// Set `HOPE_KEY_POLICY` to "strict" (the default), "relaxed", or "custom".
+/// The custom policy ignores the components listed in `HOPE_KEY_IGNORE`,
+/// separated by commas. See the `key_policy` module for details.
+///
+/// A policy plugin (see [`policy_plugin`]) can leave out more components.
+pub fn key_policy() -> anyhow::Result<Box<dyn KeyPolicy>> {
+    let policy = std::env::var("HOPE_KEY_POLICY").unwrap_or_default();
+    let policy: Box<dyn KeyPolicy> = match policy.as_str() {
+        "" | "strict" => Box::new(key_policy::Strict),
+        "relaxed" => Box::new(key_policy::Relaxed),
+        "custom" => {
+            let ignored = std::env::var("HOPE_KEY_IGNORE")
+                .context("'HOPE_KEY_POLICY=custom' requires 'HOPE_KEY_IGNORE'")?;
+            Box::new(
+                key_policy::Custom::new(
+                    ignored
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|component| !component.is_empty())
+                        .map(str::to_owned)
+                        .collect(),
+                )
+                .context("Invalid 'HOPE_KEY_IGNORE' environment variable")?,
+            )
+        }
+        _ => anyhow::bail!("Unrecognised key policy {policy:?} in 'HOPE_KEY_POLICY'"),
+    };
+    plugin::key_policy(policy)
+}
+
+/// Comma-separated cache URLs (or "local") in the named environment variable.
+fn cache_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|target| !target.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Is the named environment variable set to something truthy?
+fn env_flag(name: &str) -> bool {
+    matches!(std::env::var(name).as_deref(), Ok("1" | "true"))
+}
+
+/// Parse a byte count with an optional "K", "M", or "G" suffix (powers of 1024).
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let count: u64 = digits
+        .parse()
+        .with_context(|| format!("Bad size \"{s}\""))?;
+    count
+        .checked_mul(multiplier)
+        .with_context(|| format!("Size \"{s}\" is too big"))
+}
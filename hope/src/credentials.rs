@@ -0,0 +1,316 @@
+//! Finding the secret that a remote cache wants, in the same places
+//! whatever kind of cache it is.
+//!
+//! A cache's credential is a single string, looked for in order:
+//!
+//! - `HOPE_CACHE_TOKEN`, which goes to every remote cache, so it's only
+//!   any good when they all want the same thing (e.g. in CI).
+//! - The credentials file ("credentials" in Hope's config dir, e.g.
+//!   "~/.config/hope/credentials", or wherever `HOPE_CREDENTIALS_FILE`
+//!   says), which has a section for each cache, named by its URL or the
+//!   start of it, where the longest match wins:
+//!
+//!   ```text
+//!   [https://build-box/hope-cache]
+//!   token = 0123abcd
+//!
+//!   [s3://team-bucket]
+//!   token = AKIAEXAMPLE:wJalrXUtnFEMIEXAMPLEKEY
+//!   ```
+//!
+//!   It has secrets in it, so it mustn't be readable by anyone else.
+//! - The OS keyring, where `hope login <url>` saves them, under the
+//!   exact URL of the cache. That's the login keychain on macOS, and the
+//!   Secret Service (e.g. GNOME Keyring or KWallet) elsewhere, by way of
+//!   `security` and `secret-tool` respectively, so that we needn't link
+//!   against either.
+//!
+//!   Hope's own commands always look there, but builds only do with
+//!   `HOPE_KEYRING_IN_BUILDS` set (see `config::keyring_in_builds`): they
+//!   look up credentials for every unit, and a locked keyring can hold
+//!   each of them up with a prompt to unlock it.
+//!
+//! What the credential means is up to the kind of cache:
+//!
+//! - http(s):// and bazel+http(s)://: a bearer token.
+//! - s3://: "ACCESS_KEY_ID:SECRET_ACCESS_KEY", optionally followed by
+//!   ":SESSION_TOKEN", used instead of looking for AWS credentials.
+//! - redis://: "[USERNAME:]PASSWORD", unless the URL has a password.
+//! - gha://: a token to use instead of `ACTIONS_RUNTIME_TOKEN`.
+//!
+//! file:// caches don't have credentials, and nor do public caches
+//! (see `config::public_cache_url`), whatever their URL.
+
+use std::{
+    io::{BufRead as _, IsTerminal as _, Write as _},
+    os::unix::fs::PermissionsExt as _,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Context;
+use directories::ProjectDirs;
+
+use crate::config;
+
+/// What the keyring files our credentials under.
+const KEYRING_SERVICE: &str = "hope";
+
+/// Whether this process is one of Hope's own commands; see `allow_keyring`.
+static KEYRING_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+/// Look in the keyring for credentials from now on, even without
+/// `HOPE_KEYRING_IN_BUILDS`, because this isn't a build.
+pub fn allow_keyring() {
+    KEYRING_ALLOWED.store(true, Ordering::Relaxed);
+}
+
+/// The credential for the cache at `url`, if there is one.
+pub fn for_url(url: &str) -> anyhow::Result<Option<String>> {
+    if let Some(token) = config::cache_token() {
+        return Ok(Some(token));
+    }
+    if let Some(token) = from_file(url)? {
+        return Ok(Some(token));
+    }
+    if !KEYRING_ALLOWED.load(Ordering::Relaxed) && !config::keyring_in_builds() {
+        return Ok(None);
+    }
+    Ok(keyring_get(&keyring_account(url)))
+}
+
+fn credentials_file() -> anyhow::Result<PathBuf> {
+    if let Some(path) = config::credentials_file() {
+        return Ok(path);
+    }
+    let project_dirs =
+        ProjectDirs::from("", "", "Hope").context("Couldn't get project dirs for Hope")?;
+    Ok(project_dirs.config_dir().join("credentials"))
+}
+
+/// The token in the credentials file's section for `url`, if any.
+fn from_file(url: &str) -> anyhow::Result<Option<String>> {
+    let path = credentials_file()?;
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {path:?}")),
+    };
+    let mode = std::fs::metadata(&path)
+        .with_context(|| format!("Failed to read permissions of {path:?}"))?
+        .permissions()
+        .mode();
+    anyhow::ensure!(
+        mode & 0o077 == 0,
+        "{path:?} has credentials in it, but other users can read it; `chmod 600` it"
+    );
+
+    // The longest section that `url` starts with, and its token.
+    let mut best: Option<(&str, Option<&str>)> = None;
+    let mut in_best = false;
+    for (i, line) in content.lines().map(str::trim).enumerate() {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(section) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            let section = section.trim();
+            in_best = covers(section, url)
+                && match best {
+                    Some((best, _)) => section.len() > best.len(),
+                    None => true,
+                };
+            if in_best {
+                best = Some((section, None));
+            }
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("Line {} of {path:?} isn't \"key = value\"", i + 1))?;
+        if in_best && key.trim() == "token" {
+            best = best.map(|(section, _)| (section, Some(value.trim())));
+        }
+    }
+    Ok(best.and_then(|(_, token)| token).map(str::to_owned))
+}
+
+/// Does a credentials file section for `prefix` cover the cache at `url`?
+/// Only whole path segments count, so "http://a/b" doesn't cover "http://a/bc".
+fn covers(prefix: &str, url: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    url.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Caches are filed in the keyring under their URLs, give or take a slash.
+fn keyring_account(url: &str) -> String {
+    url.trim_end_matches('/').to_owned()
+}
+
+/// Save a credential for the cache at `url` in the keyring,
+/// reading it from stdin.
+pub fn login(url: &str) -> anyhow::Result<()> {
+    let token = read_token(url)?;
+    anyhow::ensure!(!token.is_empty(), "No credential given for {url}");
+    keyring_set(&keyring_account(url), &token)?;
+    println!("Saved the credential for {url} in the keyring.");
+    Ok(())
+}
+
+/// Remove the credential for the cache at `url` from the keyring.
+pub fn logout(url: &str) -> anyhow::Result<()> {
+    keyring_delete(&keyring_account(url))?;
+    println!("Removed the credential for {url} from the keyring.");
+    Ok(())
+}
+
+/// Read a line from stdin, asking for it (without echoing it) if that's a terminal.
+fn read_token(url: &str) -> anyhow::Result<String> {
+    let stdin = std::io::stdin();
+    let echo = stdin.is_terminal().then(|| {
+        eprint!("Credential for {url}: ");
+        let _ = std::io::stderr().flush();
+        turn_off_echo()
+    });
+    let mut token = String::new();
+    let read = stdin.lock().read_line(&mut token);
+    if let Some(Some(termios)) = echo {
+        // SAFETY: Putting back what `turn_off_echo` found.
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+        eprintln!();
+    }
+    read.context("Failed to read credential from stdin")?;
+    Ok(token.trim().to_owned())
+}
+
+/// Stop the terminal echoing what's typed, returning what it was like before.
+fn turn_off_echo() -> Option<libc::termios> {
+    // SAFETY: `termios` is plain old data, and `tcgetattr` fills it in.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return None;
+        }
+        let before = termios;
+        termios.c_lflag &= !libc::ECHO;
+        (libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) == 0).then_some(before)
+    }
+}
+
+/// Look up a credential in the keyring. Not having a keyring
+/// (as on most CI machines) is the same as it not having one.
+fn keyring_get(account: &str) -> Option<String> {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args([
+            "find-generic-password",
+            "-w",
+            "-s",
+            KEYRING_SERVICE,
+            "-a",
+            account,
+        ]);
+        command
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(["lookup", "service", KEYRING_SERVICE, "url", account]);
+        command
+    };
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let token = String::from_utf8(output.stdout).ok()?;
+    let token = token.trim_end_matches('\n');
+    (!token.is_empty()).then(|| token.to_owned())
+}
+
+fn keyring_set(account: &str, token: &str) -> anyhow::Result<()> {
+    if cfg!(target_os = "macos") {
+        // `security` only takes the password as an argument, which would
+        // show it to anyone looking at `ps`, so give it the whole command
+        // on stdin instead.
+        let mut command = Command::new("security");
+        command.arg("-i");
+        let line = [
+            "add-generic-password",
+            "-U",
+            "-s",
+            KEYRING_SERVICE,
+            "-a",
+            account,
+            "-w",
+            token,
+        ]
+        .map(security_quote)
+        .join(" ");
+        return run_keyring_tool(command, Some(&format!("{line}\n")));
+    }
+    let mut command = Command::new("secret-tool");
+    command.args(["store", "--label"]);
+    command.arg(format!("Hope cache credential for {account}"));
+    command.args(["service", KEYRING_SERVICE, "url", account]);
+    run_keyring_tool(command, Some(token))
+}
+
+/// Quote an argument for a command given to `security -i`.
+fn security_quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn keyring_delete(account: &str) -> anyhow::Result<()> {
+    let command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args([
+            "delete-generic-password",
+            "-s",
+            KEYRING_SERVICE,
+            "-a",
+            account,
+        ]);
+        command
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(["clear", "service", KEYRING_SERVICE, "url", account]);
+        command
+    };
+    run_keyring_tool(command, None)
+}
+
+fn run_keyring_tool(mut command: Command, stdin: Option<&str>) -> anyhow::Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = match command
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => anyhow::bail!(
+            "Couldn't find '{program}' to talk to the keyring; \
+             put the credential in the credentials file instead (see {:?})",
+            credentials_file()?
+        ),
+        Err(err) => return Err(err).with_context(|| format!("Failed to run '{program}'")),
+    };
+    if let Some(input) = stdin {
+        let mut pipe = child.stdin.take().context("Missing stdin pipe")?;
+        pipe.write_all(input.as_bytes())
+            .with_context(|| format!("Failed to write to '{program}'"))?;
+    }
+    let status = child
+        .wait()
+        .with_context(|| format!("Failed to wait for '{program}'"))?;
+    anyhow::ensure!(status.success(), "'{program}' failed ({status})");
+    Ok(())
+}
@@ -0,0 +1,205 @@
+//! A long-running companion process for the local cache.
+//!
+//! It answers questions about the cache over HTTP, so CI sidecars and
+//! dashboards can keep an eye on it without parsing the log themselves:
+//!
+//! - `GET /health` is 200 if the cache is usable, or 503 if it's degraded
+//!   (see the `disk_space` module), with a small JSON body either way.
+//! - `GET /status` is a JSON [`Status`], worked out fresh for each request.
+//!
+//! It also serves the cache's blobs, just like `hope serve`, but with the
+//! most recently used ones kept in memory (see the `memory_cache` module).
+//! Point local builds at it with `HOPE_CACHE_URL=http://127.0.0.1:{port}/blobs`
+//! to share hot artifacts between lots of them without touching the disk.
+//!
+//! With `--socket PATH`, it also fronts the remote cache (from its own
+//! `HOPE_CACHE_URL` or `HOPE_CACHE_SHARDS`) on a unix socket, for builds
+//! with `HOPE_DAEMON_SOCKET=PATH` to use instead of each opening their own
+//! connections; see the `daemon_socket` module.
+//!
+//! It only listens on localhost; put a proxy in front of it if something
+//! elsewhere needs to scrape it.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use hope_cache_log::{read_log, CacheLogLine};
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    cache::{self, LocalCache, RemoteBlobStore},
+    daemon_socket, disk_space,
+    memory_cache::{MemoryCache, MemoryCacheStats},
+    serve::{self, BLOB_PATH_PREFIX},
+};
+
+#[derive(Debug, Serialize)]
+struct Health {
+    healthy: bool,
+    /// Why the cache is degraded, if it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    started_at: DateTime<Utc>,
+    uptime_secs: i64,
+    cache_dir: String,
+    entries: usize,
+    /// Everything in the cache dir, including chunks and our own bookkeeping.
+    size_bytes: u64,
+    free_bytes: u64,
+    degraded: Option<String>,
+    /// Counted from the cache log, which covers every build that used this
+    /// cache, not just the ones since the daemon started.
+    pulls: u64,
+    compiles: u64,
+    pushes: u64,
+    /// Pulls as a fraction of all units we could have pulled, or `None`
+    /// if there haven't been any yet.
+    hit_rate: Option<f64>,
+    memory_cache: MemoryCacheStats,
+    /// Blobs from the remote cache, if serving it on a socket.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_memory_cache: Option<MemoryCacheStats>,
+}
+
+struct Daemon {
+    local_cache: LocalCache,
+    /// The same cache, as served to builds.
+    cache: MemoryCache<LocalCache>,
+    /// The remote cache, as served on the socket.
+    remote_cache: Option<Arc<MemoryCache<Box<dyn RemoteBlobStore>>>>,
+    cache_dir: PathBuf,
+    started_at: DateTime<Utc>,
+}
+
+/// Serve status and blobs on localhost at `status_port` until killed,
+/// keeping up to `memory_cache_size` bytes of blobs in memory, and
+/// the remote cache on `socket`, if given (with as much again in memory).
+pub fn run(status_port: u16, memory_cache_size: u64, socket: Option<&Path>) -> anyhow::Result<()> {
+    let local_cache = LocalCache::from_env()?;
+    let remote_cache = match socket {
+        Some(socket) => {
+            let store = cache::remote_store_from_env()?.context(
+                "Set 'HOPE_CACHE_URL' or 'HOPE_CACHE_SHARDS' for the daemon to serve on its socket",
+            )?;
+            let remote_cache = Arc::new(MemoryCache::new(store, memory_cache_size));
+            // Bind before saying we're listening, so builds can't beat us to it.
+            let listener = daemon_socket::bind(socket)?;
+            let served = Arc::clone(&remote_cache);
+            std::thread::spawn(move || {
+                if let Err(err) = daemon_socket::serve(listener, served) {
+                    eprintln!("Stopped serving on socket: {err:#}");
+                    std::process::exit(1);
+                }
+            });
+            Some(remote_cache)
+        }
+        None => None,
+    };
+    let daemon = Arc::new(Daemon {
+        cache: MemoryCache::new(local_cache.clone(), memory_cache_size),
+        remote_cache,
+        local_cache,
+        cache_dir: LocalCache::dir_from_env()?,
+        started_at: Utc::now(),
+    });
+    let listen = format!("127.0.0.1:{status_port}");
+    let server = Server::http(&listen)
+        .map_err(|err| anyhow::anyhow!(err))
+        .with_context(|| format!("Failed to listen on {listen:?}"))?;
+    let local_addr = server
+        .server_addr()
+        .to_ip()
+        .context("Server isn't listening on an IP address")?;
+    // Like `hope serve`, say which port we got in case it was 0.
+    println!("Status endpoint listening on {local_addr}");
+
+    // Pulls from many builds at once shouldn't have to queue up.
+    for request in server.incoming_requests() {
+        let daemon = Arc::clone(&daemon);
+        std::thread::spawn(move || {
+            if let Err(err) = respond(&daemon, request) {
+                eprintln!("Failed to respond to request: {err:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn respond(daemon: &Daemon, request: Request) -> anyhow::Result<()> {
+    if request.url().starts_with(BLOB_PATH_PREFIX) {
+        return serve::respond_with_blob(&daemon.cache, request);
+    }
+    if *request.method() != Method::Get {
+        return Ok(request.respond(Response::empty(405))?);
+    }
+    let (status_code, body) = match request.url() {
+        "/health" => {
+            let reason = disk_space::degraded_reason(&daemon.cache_dir);
+            let health = Health {
+                healthy: reason.is_none(),
+                reason,
+            };
+            let status_code = if health.healthy { 200 } else { 503 };
+            (status_code, serde_json::to_vec(&health)?)
+        }
+        "/status" => (200, serde_json::to_vec(&status(daemon)?)?),
+        _ => return Ok(request.respond(Response::empty(404))?),
+    };
+    let content_type = Header::from_bytes("Content-Type", "application/json")
+        .expect("Content-Type header should be valid");
+    request.respond(
+        Response::from_data(body)
+            .with_status_code(status_code)
+            .with_header(content_type),
+    )?;
+    Ok(())
+}
+
+fn status(daemon: &Daemon) -> anyhow::Result<Status> {
+    let cache_dir = &daemon.cache_dir;
+    let mut size_bytes = 0;
+    for dir_entry in walkdir::WalkDir::new(cache_dir) {
+        let dir_entry = dir_entry.context("Failed to read cache dir entry")?;
+        if dir_entry.file_type().is_file() {
+            size_bytes += dir_entry.metadata()?.len();
+        }
+    }
+
+    // There's no log at all until something has been cached.
+    let log = read_log(cache_dir).unwrap_or_default();
+    let (mut pulls, mut compiles, mut pushes) = (0, 0, 0);
+    for line in &log {
+        match line {
+            CacheLogLine::PulledCrateOutputs(_) => pulls += 1,
+            CacheLogLine::CompiledCrate(_) => compiles += 1,
+            CacheLogLine::PushedCrateOutputs(_) => pushes += 1,
+            _ => {}
+        }
+    }
+
+    let now = Utc::now();
+    Ok(Status {
+        started_at: daemon.started_at,
+        uptime_secs: (now - daemon.started_at).num_seconds(),
+        cache_dir: cache_dir.display().to_string(),
+        entries: daemon.local_cache.entries()?.len(),
+        size_bytes,
+        free_bytes: disk_space::free_bytes(cache_dir)?,
+        degraded: disk_space::degraded_reason(cache_dir),
+        pulls,
+        compiles,
+        pushes,
+        hit_rate: (pulls + compiles > 0).then(|| pulls as f64 / (pulls + compiles) as f64),
+        memory_cache: daemon.cache.stats(),
+        remote_memory_cache: daemon.remote_cache.as_ref().map(|cache| cache.stats()),
+    })
+}
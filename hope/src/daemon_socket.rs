@@ -0,0 +1,191 @@
+//! Talking to the remote cache through `hope daemon`, over a unix socket.
+//!
+//! Every rustc invocation is a new process, so without the daemon each one
+//! looks up credentials and opens its own connections (TLS and all) to the
+//! remote cache, hundreds of times per build. With `hope daemon --socket PATH`
+//! running, and `HOPE_DAEMON_SOCKET=PATH` set for builds, they hand their blob
+//! reads and writes to the daemon instead, which keeps its connections and
+//! credentials around, and the most recently used blobs in memory.
+//!
+//! The daemon talks to whatever `HOPE_CACHE_URL` (or `HOPE_CACHE_SHARDS`)
+//! says in its own environment; builds don't need to know.
+//!
+//! Each request gets its own connection, which is next to free for a unix
+//! socket. A request is one line, "{op} {key} {arg}", followed by `arg` bytes
+//! for "put". The response is one line, "ok {len}" (followed by `len` bytes),
+//! "missing", or "error {message}".
+
+use std::{
+    io::{BufRead as _, BufReader, Read as _, Write as _},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+
+use crate::{cache::RemoteBlobStore, chunks::BlobStore};
+
+/// The blobs that a `hope daemon` has at the other end of `socket`.
+pub struct DaemonBlobStore {
+    socket: PathBuf,
+}
+
+impl DaemonBlobStore {
+    pub fn new(socket: &Path) -> Self {
+        Self {
+            socket: socket.to_owned(),
+        }
+    }
+
+    /// `None` if there's no such blob.
+    fn request(
+        &self,
+        op: &str,
+        key: &str,
+        arg: &str,
+        body: &[u8],
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let stream = UnixStream::connect(&self.socket)
+            .with_context(|| format!("Failed to connect to hope daemon at {:?}", self.socket))?;
+        (&stream).write_all(format!("{op} {key} {arg}\n").as_bytes())?;
+        (&stream).write_all(body)?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .with_context(|| format!("No response from hope daemon at {:?}", self.socket))?;
+        let line = line.trim_end();
+        if line == "missing" {
+            return Ok(None);
+        }
+        if let Some(message) = line.strip_prefix("error ") {
+            anyhow::bail!("hope daemon failed to {op} {key:?}: {message}");
+        }
+        let len: usize = line
+            .strip_prefix("ok ")
+            .and_then(|len| len.parse().ok())
+            .with_context(|| format!("Unexpected response from hope daemon: {line:?}"))?;
+        let mut bytes = vec![0; len];
+        reader
+            .read_exact(&mut bytes)
+            .with_context(|| format!("Failed to read {key:?} from hope daemon"))?;
+        Ok(Some(bytes))
+    }
+}
+
+impl BlobStore for DaemonBlobStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.request("get", key, "", &[])?
+            .with_context(|| format!("No blob {key:?} in {}", self.url()))
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.request("put", key, &bytes.len().to_string(), bytes)?;
+        Ok(())
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.request("has", key, "", &[])?.is_some())
+    }
+}
+
+impl RemoteBlobStore for DaemonBlobStore {
+    fn url(&self) -> String {
+        format!("unix:{}", self.socket.display())
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        let Some(size) = self.request("size", key, "", &[])? else {
+            return Ok(None);
+        };
+        let size = String::from_utf8(size).context("Invalid size from hope daemon")?;
+        Ok(Some(size.parse().context("Invalid size from hope daemon")?))
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.request("tail", key, &len.to_string(), &[])?
+            .with_context(|| format!("No blob {key:?} in {}", self.url()))
+    }
+
+    fn list_blobs(&self) -> anyhow::Result<Vec<String>> {
+        // Keys never have spaces, so a placeholder will do.
+        let keys = self.request("list", "-", "", &[])?.unwrap_or_default();
+        let keys = String::from_utf8(keys).context("Invalid keys from hope daemon")?;
+        Ok(keys.lines().map(str::to_owned).collect())
+    }
+}
+
+/// Listen on a unix socket at `path`, replacing one left behind
+/// by a daemon that's gone away.
+pub fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    if UnixStream::connect(path).is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    UnixListener::bind(path).with_context(|| format!("Failed to listen on {path:?}"))
+}
+
+/// Answer requests on `listener` from `store`, until killed.
+pub fn serve<S: RemoteBlobStore + 'static>(
+    listener: UnixListener,
+    store: Arc<S>,
+) -> anyhow::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream.context("Failed to accept connection")?;
+        let store = Arc::clone(&store);
+        std::thread::spawn(move || {
+            if let Err(err) = respond(&*store, stream) {
+                eprintln!("Failed to respond to request: {err:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn respond(store: &dyn RemoteBlobStore, stream: UnixStream) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut words = line.trim_end_matches('\n').splitn(3, ' ');
+    let (op, key, arg) = (
+        words.next().unwrap_or_default(),
+        words.next().unwrap_or_default(),
+        words.next().unwrap_or_default(),
+    );
+    let result = match op {
+        "get" => store.get_blob(key).map(Some),
+        "has" => store.has_blob(key).map(|has| has.then(Vec::new)),
+        "size" => store
+            .blob_size(key)
+            .map(|size| size.map(|size| size.to_string().into_bytes())),
+        "tail" => arg
+            .parse()
+            .context("Invalid length")
+            .and_then(|len| store.get_blob_tail(key, len))
+            .map(Some),
+        "list" => store
+            .list_blobs()
+            .map(|keys| Some(keys.join("\n").into_bytes())),
+        "put" => arg.parse().context("Invalid length").and_then(|len| {
+            let mut bytes = vec![0; len];
+            reader.read_exact(&mut bytes)?;
+            store.put_blob(key, &bytes).map(|()| Some(Vec::new()))
+        }),
+        _ => Err(anyhow::anyhow!("Unknown request {op:?}")),
+    };
+    let mut stream = &stream;
+    match result {
+        Ok(Some(bytes)) => {
+            stream.write_all(format!("ok {}\n", bytes.len()).as_bytes())?;
+            stream.write_all(&bytes)?;
+        }
+        Ok(None) => stream.write_all(b"missing\n")?,
+        Err(err) => {
+            // Keep it to one line; the whole chain is in the daemon's output.
+            eprintln!("Failed to {op} {key:?}: {err:#}");
+            stream
+                .write_all(format!("error {}\n", err.to_string().replace('\n', " ")).as_bytes())?;
+        }
+    }
+    Ok(())
+}
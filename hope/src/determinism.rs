@@ -0,0 +1,301 @@
+//! Checking whether cached crates actually build reproducibly.
+//!
+//! Hope assumes that building the same crate unit twice gives you
+//! the same bytes (or at least interchangeable ones). That isn't always true,
+//! so this builds the current project (or just a sample of its packages)
+//! twice in fresh target directories, and compares the outputs for
+//! crate units that we've cached.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use hope_cache_log::{read_log, CacheLogLine};
+use serde::Serialize;
+use tempfile::{tempdir, TempDir};
+
+use crate::{cache::LocalCache, target::Target, toolchain::RustcInfo, CrateType, OutputDefn};
+
+#[derive(Debug, Serialize)]
+struct Report {
+    compared_units: usize,
+    non_reproducible: Vec<Finding>,
+}
+
+#[derive(Debug, Serialize)]
+struct Finding {
+    crate_unit_name: String,
+    file_name: String,
+    reason: Reason,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "kind")]
+enum Reason {
+    /// The output contains the absolute path of the target directory
+    /// it was built in, so it will never match across projects.
+    EmbedsTargetDir,
+    SizeDiffers {
+        first: u64,
+        second: u64,
+    },
+    ContentDiffers {
+        first_difference_at: usize,
+    },
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmbedsTargetDir => write!(f, "embeds the absolute path of its target dir"),
+            Self::SizeDiffers { first, second } => {
+                write!(f, "size differs ({first} vs {second} bytes)")
+            }
+            Self::ContentDiffers {
+                first_difference_at,
+            } => write!(f, "content differs from byte {first_difference_at}"),
+        }
+    }
+}
+
+/// Build the project in the current directory twice, with the given Cargo
+/// profile, and report on which cached crate units came out differently.
+///
+/// If `sample` is set, then we only rebuild the packages of that many
+/// (randomly chosen) cached units, rather than the whole dependency graph.
+/// We can only tell which package a unit belongs to from its entry manifest,
+/// so units pushed by older versions of Hope are never sampled.
+pub fn run(sample: Option<usize>, profile: &str, json: bool) -> anyhow::Result<()> {
+    let cache_dir =
+        LocalCache::dir_from_env().context("Failed to get local cache dir from environment")?;
+    let log = read_log(&cache_dir).context("Failed to read cache log")?;
+    let mut cached_units: Vec<String> = log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::PushedCrateOutputs(push_event) => {
+                Some(push_event.crate_unit_name.clone())
+            }
+            _ => None,
+        })
+        .collect();
+    cached_units.sort();
+    cached_units.dedup();
+
+    // Package ID specs to build, or all of them.
+    let mut packages = Vec::new();
+    if let Some(sample) = sample {
+        let unit_packages = unit_packages(&LocalCache::from_env()?)?;
+        let project_packages = project_package_ids()?;
+        cached_units.retain(|unit_name| {
+            unit_packages
+                .get(unit_name)
+                .is_some_and(|package_id| project_packages.contains(package_id))
+        });
+        fastrand::shuffle(&mut cached_units);
+        cached_units.truncate(sample);
+        cached_units.sort();
+        packages = cached_units
+            .iter()
+            .map(|unit_name| unit_packages[unit_name].clone())
+            .collect();
+        packages.sort();
+        packages.dedup();
+    }
+
+    // These builds are always for the host.
+    let rustc = RustcInfo::query_default().context("Failed to identify local `rustc`")?;
+    let target = Target::from_arg(None, &rustc.host);
+
+    let mut report = Report {
+        compared_units: 0,
+        non_reproducible: Vec::new(),
+    };
+    // Building with no packages named would build everything.
+    if sample.is_none() || !packages.is_empty() {
+        let first = build_in_fresh_target_dir(profile, &packages).context("First build failed")?;
+        let second =
+            build_in_fresh_target_dir(profile, &packages).context("Second build failed")?;
+        for unit_name in cached_units {
+            // Only units that this build actually built are interesting.
+            let file_names: Vec<String> = candidate_file_names(&unit_name, &target)
+                .into_iter()
+                .filter(|file_name| first.deps_dir().join(file_name).exists())
+                .collect();
+            if file_names.is_empty() {
+                continue;
+            }
+            report.compared_units += 1;
+            for file_name in file_names {
+                if let Some(reason) = compare(&first, &second, &file_name)? {
+                    report.non_reproducible.push(Finding {
+                        crate_unit_name: unit_name.clone(),
+                        file_name,
+                        reason,
+                    });
+                }
+            }
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!(
+            "Compared {} cached crate units; {} output(s) were not reproducible.",
+            report.compared_units,
+            report.non_reproducible.len()
+        );
+        for finding in &report.non_reproducible {
+            println!(
+                "  {} ({}): {}",
+                finding.crate_unit_name, finding.file_name, finding.reason
+            );
+        }
+    }
+
+    Ok(())
+}
+
+struct Build {
+    target_dir: TempDir,
+    profile_dir_name: String,
+}
+
+impl Build {
+    fn deps_dir(&self) -> PathBuf {
+        self.target_dir
+            .path()
+            .join(&self.profile_dir_name)
+            .join("deps")
+    }
+}
+
+/// Build the given packages (or the whole project, if none are given)
+/// with the given profile.
+fn build_in_fresh_target_dir(profile: &str, packages: &[String]) -> anyhow::Result<Build> {
+    let target_dir = tempdir().context("Failed to create temporary target dir")?;
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut command = Command::new(cargo);
+    command.args(["build", "--profile", profile]);
+    for package in packages {
+        command.args(["--package", package]);
+    }
+    // Explicitly disable ourselves; we want to know what `rustc` actually produces.
+    let status = command
+        .env("CARGO_TARGET_DIR", target_dir.path())
+        .env("RUSTC_WRAPPER", "")
+        .status()
+        .context("Failed to start Cargo")?;
+    anyhow::ensure!(status.success(), "Cargo build failed");
+    Ok(Build {
+        target_dir,
+        profile_dir_name: profile_dir_name(profile).to_owned(),
+    })
+}
+
+/// Where Cargo puts outputs for a profile, under the target dir.
+fn profile_dir_name(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        custom => custom,
+    }
+}
+
+/// Cargo's package ID for each cached unit that has one recorded,
+/// by unit name; see `entry_manifest::package_id`.
+fn unit_packages(cache: &LocalCache) -> anyhow::Result<HashMap<String, String>> {
+    let mut unit_packages = HashMap::new();
+    for (storage_name, manifest) in cache.entries()? {
+        // Storage names are "{unit name}-{hash}".
+        let (Some((unit_name, _)), Some(package_id)) =
+            (storage_name.rsplit_once('-'), manifest.package_id)
+        else {
+            continue;
+        };
+        unit_packages.insert(unit_name.to_owned(), package_id);
+    }
+    Ok(unit_packages)
+}
+
+/// Cargo's IDs for every package in the current project's dependency graph.
+fn project_package_ids() -> anyhow::Result<HashSet<String>> {
+    let output = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .args(["metadata", "--format-version", "1"])
+        .output()
+        .context("Failed to start Cargo")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "'cargo metadata' failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let metadata: serde_json::Value =
+        serde_json::from_slice(&output.stdout).context("Invalid output from 'cargo metadata'")?;
+    Ok(metadata["packages"]
+        .as_array()
+        .context("'cargo metadata' didn't list any packages")?
+        .iter()
+        .filter_map(|package| package["id"].as_str().map(str::to_owned))
+        .collect())
+}
+
+/// Output files worth comparing for a unit.
+///
+/// We skip dep info files, because they always contain absolute paths
+/// and we rewrite them on pull anyway.
+fn candidate_file_names(unit_name: &str, target: &Target) -> Vec<String> {
+    [
+        OutputDefn::Metadata,
+        OutputDefn::Link(CrateType::Rlib, target.link_naming(CrateType::Rlib)),
+        OutputDefn::Link(
+            CrateType::ProcMacro,
+            target.link_naming(CrateType::ProcMacro),
+        ),
+    ]
+    .iter()
+    .map(|output_defn| output_defn.file_name(unit_name))
+    .collect()
+}
+
+fn compare(first: &Build, second: &Build, file_name: &str) -> anyhow::Result<Option<Reason>> {
+    let first_bytes = read(&first.deps_dir().join(file_name))?;
+    let second_bytes = read(&second.deps_dir().join(file_name))?;
+    if first_bytes == second_bytes {
+        return Ok(None);
+    }
+
+    if contains_path(&first_bytes, first.target_dir.path())
+        || contains_path(&second_bytes, second.target_dir.path())
+    {
+        return Ok(Some(Reason::EmbedsTargetDir));
+    }
+    if first_bytes.len() != second_bytes.len() {
+        return Ok(Some(Reason::SizeDiffers {
+            first: first_bytes.len() as u64,
+            second: second_bytes.len() as u64,
+        }));
+    }
+    let first_difference_at = first_bytes
+        .iter()
+        .zip(&second_bytes)
+        .position(|(a, b)| a != b)
+        .unwrap_or_default();
+    Ok(Some(Reason::ContentDiffers {
+        first_difference_at,
+    }))
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Failed to read build output {path:?}"))
+}
+
+fn contains_path(haystack: &[u8], path: &Path) -> bool {
+    let needle = path.as_os_str().as_encoded_bytes();
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
@@ -0,0 +1,115 @@
+//! Making sure there's room for what we're about to write.
+//!
+//! Running out of space part way through a build tends to leave a mess:
+//! half-written artifacts, a target dir that Cargo no longer trusts, and
+//! an error message from deep inside `rustc` or the linker. So before pulling
+//! or pushing a unit, we check that its outputs will fit (leaving some room
+//! for everything else), and skip the pull or push with a clear warning if
+//! they won't.
+//!
+//! With a lockfile index (see the `lockfile_index` module), we also warn
+//! at the start of a build if everything it lists won't fit in the target
+//! dir, before any of it is pulled.
+//!
+//! If the cache's disk is nearly full (less than the minimum free space
+//! left), then we're in "degraded" mode: we still pull, but never push,
+//! and the log stops growing beyond a cap. Each build warns about it once,
+//! and we leave a marker file in the cache dir while that's the case,
+//! so that `hope stats` can mention it too.
+
+use std::path::Path;
+
+use chrono::Utc;
+
+use anyhow::Context;
+
+use crate::{cache::LocalCache, config, lockfile_index};
+
+/// Free space available to us on the filesystem holding `path`, in bytes.
+pub fn free_bytes(path: &Path) -> anyhow::Result<u64> {
+    let stat = rustix::fs::statvfs(path)
+        .with_context(|| format!("Failed to get free space for {path:?}"))?;
+    Ok(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+/// If writing `needed` more bytes at `path` would eat into the space we're
+/// meant to keep free, explain why, for humans.
+pub fn shortfall(path: &Path, needed: u64) -> anyhow::Result<Option<String>> {
+    let min_free = config::min_free_space()?;
+    let free = free_bytes(path)?;
+    if free >= needed.saturating_add(min_free) {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "{path:?} has {free} bytes free, but we need {needed} bytes \
+         and want to keep {min_free} bytes free"
+    )))
+}
+
+/// At the start of a build, warn if pulling everything the lockfile index
+/// lists (see the `lockfile_index` module) wouldn't fit in the target dir,
+/// rather than only finding out one unit at a time, part way through.
+pub fn warn_if_projected_pulls_wont_fit(profile_dir: &Path) -> anyhow::Result<()> {
+    let Some(needed) =
+        lockfile_index::projected_pull_size(&LocalCache::from_env()?)?.filter(|needed| *needed > 0)
+    else {
+        return Ok(());
+    };
+    if let Some(shortfall) = shortfall(profile_dir, needed)? {
+        eprintln!(
+            "Hope: This build's lockfile index projects {needed} bytes of pulls, \
+             which won't all fit: {shortfall}. Units that don't fit will be built \
+             instead, if there's room for that."
+        );
+    }
+    Ok(())
+}
+
+/// Present in the cache dir while it's nearly full.
+const DEGRADED_MARKER_FILE_NAME: &str = "hope-degraded";
+
+/// How big we let the log get while in degraded mode.
+pub const DEGRADED_LOG_SIZE_CAP: u64 = 16 << 20;
+
+/// Is the cache's disk nearly full? Marks the cache as degraded while it is,
+/// and clears the condition again once there's space.
+///
+/// This doesn't warn, because it runs for every unit; the first unit of
+/// each build warns instead, with `warn_if_degraded`.
+pub fn check_degraded(cache_dir: &Path) -> anyhow::Result<bool> {
+    let marker_path = cache_dir.join(DEGRADED_MARKER_FILE_NAME);
+    let Some(shortfall) = shortfall(cache_dir, 0)? else {
+        if marker_path.exists() {
+            std::fs::remove_file(&marker_path)
+                .with_context(|| format!("Failed to remove {marker_path:?}"))?;
+        }
+        return Ok(false);
+    };
+    if !marker_path.exists() {
+        std::fs::write(&marker_path, format!("{}\n{shortfall}\n", Utc::now()))
+            .with_context(|| format!("Failed to write {marker_path:?}"))?;
+    }
+    Ok(true)
+}
+
+/// At the start of a build, explain that the cache is in degraded mode,
+/// if it is, so it doesn't just quietly stop pushing.
+pub fn warn_if_degraded(cache_dir: &Path) {
+    if let Some(reason) = degraded_reason(cache_dir) {
+        eprintln!("Hope: {reason}");
+    }
+}
+
+/// If the cache is in degraded mode, explain why and what to do about it.
+pub fn degraded_reason(cache_dir: &Path) -> Option<String> {
+    let marker = std::fs::read_to_string(cache_dir.join(DEGRADED_MARKER_FILE_NAME)).ok()?;
+    let shortfall = marker.lines().nth(1).unwrap_or("the disk is nearly full");
+    Some(degraded_notice(shortfall))
+}
+
+fn degraded_notice(shortfall: &str) -> String {
+    format!(
+        "The cache is nearly full ({shortfall}), so it's only being pulled from \
+         until there's more room. Run `hope gc --max-size <SIZE>` to free some space."
+    )
+}
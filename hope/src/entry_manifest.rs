@@ -0,0 +1,173 @@
+//! Describing what a cache entry is, beyond its outputs.
+//!
+//! The key tells us whether an entry _should_ be what we want, but a small
+//! manifest stored alongside the outputs lets us double check before
+//! pulling (and helps anyone poking around in a cache to make sense of it).
+//!
+//! Keys that don't name entries directly name alias records instead,
+//! which point at an entry that's known to be equivalent.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{build_env::BuildEnvironment, chunks::BlobStore, key::CacheKey, toolchain::Channel};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EntryManifest {
+    /// Target triple that the outputs were built for.
+    pub target: String,
+    /// Rust edition the crate was compiled with.
+    ///
+    /// This is informational only; we don't care what editions
+    /// crates use, as long as the key accounts for it.
+    #[serde(default)]
+    pub edition: Option<String>,
+    /// Release channel of the `rustc` that built it.
+    #[serde(default)]
+    pub channel: Option<Channel>,
+    /// Wall clock time on the pushing machine; only used to spot clock skew.
+    #[serde(default)]
+    pub pushed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub environment: Option<BuildEnvironment>,
+    /// Key policy the entry was pushed under; see the `key_policy` module.
+    #[serde(default)]
+    pub key_policy: Option<String>,
+    /// SHA-256 of each stored output file (whole, even if it's chunked),
+    /// by stored file name. Filled in by the cache when pushing.
+    ///
+    /// This lets `hope verify` spot corruption, and repair it from
+    /// identical files belonging to other entries.
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+    /// SHA-256 of each source file in the package that the build read
+    /// (according to its dep info), by path relative to the package dir.
+    ///
+    /// See `sources::audit` for what these are for.
+    #[serde(default)]
+    pub sources: BTreeMap<String, String>,
+    /// Cargo's ID for the package the unit belongs to; see `package_id`.
+    ///
+    /// Crate unit names are only meant to be unique per package, so we
+    /// check this before pulling rather than risk serving the wrong crate.
+    #[serde(default)]
+    pub package_id: Option<String>,
+    /// Features the crate was built with, so that `hope explain` can
+    /// point out when that's all that's different.
+    #[serde(default)]
+    pub features: Option<BTreeSet<String>>,
+}
+
+const BLOB_KEY_SUFFIX: &str = ".manifest.json";
+
+impl EntryManifest {
+    /// Manifest for an entry we're pushing right now.
+    pub fn new(key: &CacheKey, environment: BuildEnvironment) -> Self {
+        Self {
+            target: key.target.triple().to_owned(),
+            edition: key.edition.clone(),
+            channel: Some(key.channel),
+            pushed_at: Some(Utc::now()),
+            environment: Some(environment),
+            key_policy: Some(key.policy.clone()),
+            files: BTreeMap::new(),
+            sources: BTreeMap::new(),
+            package_id: None,
+            features: None,
+        }
+    }
+
+    pub fn blob_key(storage_name: &str) -> String {
+        format!("{storage_name}{BLOB_KEY_SUFFIX}")
+    }
+
+    /// If this is the key of an entry manifest, get the storage name of the entry.
+    pub fn storage_name_for_blob_key(blob_key: &str) -> Option<&str> {
+        blob_key.strip_suffix(BLOB_KEY_SUFFIX)
+    }
+
+    /// Load the manifest for an entry, if it has one.
+    ///
+    /// Entries pushed by older versions of Hope won't.
+    pub fn load(
+        store: &(impl BlobStore + ?Sized),
+        storage_name: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let blob_key = Self::blob_key(storage_name);
+        if !store.has_blob(&blob_key)? {
+            return Ok(None);
+        }
+        let bytes = store.get_blob(&blob_key)?;
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .with_context(|| format!("Invalid entry manifest {blob_key:?}"))
+    }
+
+    pub fn store(
+        &self,
+        store: &(impl BlobStore + ?Sized),
+        storage_name: &str,
+    ) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(self).context("Failed to serialize entry manifest")?;
+        store.put_blob(&Self::blob_key(storage_name), &bytes)
+    }
+}
+
+/// Cargo's package ID for a package from crates.io, e.g.
+/// "registry+https://github.com/rust-lang/crates.io-index#anyhow@1.0.86".
+///
+/// Two units from different packages can end up with the same unit name
+/// (e.g. from different registries, or with renamed libs), so this is what
+/// we actually compare. We only cache crates from crates.io for now,
+/// so that's the only source we need to name.
+pub fn package_id(package_name: &str, package_version: &str) -> String {
+    // Cargo's index dir names include a hash that has changed between
+    // versions of Cargo, so use the canonical source ID instead.
+    format!(
+        "registry+https://github.com/rust-lang/crates.io-index#{package_name}@{package_version}"
+    )
+}
+
+/// Points a non-strict key at an entry that satisfies it.
+///
+/// See `CacheKey::alias_name`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EntryAlias {
+    /// Storage name of the entry.
+    pub target: String,
+}
+
+const ALIAS_BLOB_KEY_SUFFIX: &str = ".alias.json";
+
+impl EntryAlias {
+    pub fn blob_key(alias_name: &str) -> String {
+        format!("{alias_name}{ALIAS_BLOB_KEY_SUFFIX}")
+    }
+
+    /// If this is the key of an entry alias, get the alias name.
+    pub fn alias_name_for_blob_key(blob_key: &str) -> Option<&str> {
+        blob_key.strip_suffix(ALIAS_BLOB_KEY_SUFFIX)
+    }
+
+    pub fn load(
+        store: &(impl BlobStore + ?Sized),
+        alias_name: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let blob_key = Self::blob_key(alias_name);
+        if !store.has_blob(&blob_key)? {
+            return Ok(None);
+        }
+        let bytes = store.get_blob(&blob_key)?;
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .with_context(|| format!("Invalid entry alias {blob_key:?}"))
+    }
+
+    pub fn store(&self, store: &(impl BlobStore + ?Sized), alias_name: &str) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(self).context("Failed to serialize entry alias")?;
+        store.put_blob(&Self::blob_key(alias_name), &bytes)
+    }
+}
@@ -0,0 +1,269 @@
+//! Explaining why cached entries for a crate might not be usable here.
+//!
+//! Most of what goes into a key comes from `rustc` arguments, which we can't
+//! see outside of a build. But the parts that come from the environment
+//! (toolchain versions, SDKs, and so on) are recorded in entry manifests,
+//! so we can at least compare those against this machine.
+//!
+//! Entries also record which features they were built with. Run from a
+//! workspace, we ask Cargo which features it would build the crate with
+//! there, and what turns each one on, because feature unification (e.g.
+//! after `cargo add` of something that wants an extra feature of a crate
+//! you already had) is an easy way to miss the cache without knowing why.
+
+use std::{
+    collections::BTreeMap,
+    process::{Command, Stdio},
+};
+
+use anyhow::Context;
+
+use crate::{
+    cache::LocalCache,
+    config,
+    entry_manifest::EntryManifest,
+    target::Target,
+    toolchain::{NativeToolchain, RustcInfo},
+};
+
+pub fn run(package_name: &str, target: Option<&str>) -> anyhow::Result<()> {
+    let cache = LocalCache::from_env()?;
+    let crate_name = package_name.replace('-', "_");
+    let rustc = RustcInfo::query_default().context("Failed to identify local `rustc`")?;
+    let target = Target::from_arg(target, &rustc.host);
+    // We don't know whether the crate links native code, so assume it does;
+    // we only compare what the entry recorded anyway.
+    let native_toolchain = NativeToolchain::detect(&target, true);
+    let key_policy = config::key_policy()?;
+    // Crate names use underscores, but package names more often use hyphens.
+    let workspace_features = workspace_features(package_name, &target)
+        .or_else(|| workspace_features(&package_name.replace('_', "-"), &target));
+
+    let mut found = 0;
+    for (storage_name, manifest) in cache.entries()? {
+        // "{crate name}-{extra filename hash}-{key digest}"
+        if storage_name.rsplitn(3, '-').nth(2) != Some(crate_name.as_str()) {
+            continue;
+        }
+        found += 1;
+        println!("{storage_name}");
+
+        let mut mismatches = Vec::new();
+        mismatches.extend(mismatch(
+            "target",
+            Some(&manifest.target),
+            Some(target.triple()),
+        ));
+        // Entries from before key policies existed were all strict.
+        mismatches.extend(mismatch(
+            "key policy",
+            Some(manifest.key_policy.as_deref().unwrap_or("strict")),
+            Some(&key_policy.name()),
+        ));
+        if let Some(workspace_features) = &workspace_features {
+            mismatches.extend(feature_mismatches(&manifest, workspace_features));
+        }
+        let Some(environment) = &manifest.environment else {
+            mismatches.push("(no build environment recorded)".to_string());
+            print_mismatches(&mismatches);
+            continue;
+        };
+        mismatches.extend(mismatch(
+            "rustc",
+            Some(&environment.rustc_release),
+            Some(&rustc.release),
+        ));
+        mismatches.extend(mismatch(
+            "rustc commit",
+            environment.rustc_commit_hash.as_deref(),
+            rustc.commit_hash.as_deref(),
+        ));
+        let theirs = &environment.native_toolchain;
+        // These are only recorded for entries that they matter for.
+        if theirs.cc_version.is_some() {
+            mismatches.extend(mismatch(
+                "cc",
+                theirs.cc_version.as_deref(),
+                native_toolchain.cc_version.as_deref(),
+            ));
+        }
+        if theirs.ar_version.is_some() {
+            mismatches.extend(mismatch(
+                "ar",
+                theirs.ar_version.as_deref(),
+                native_toolchain.ar_version.as_deref(),
+            ));
+        }
+        if theirs.sdk_version.is_some() {
+            mismatches.extend(mismatch(
+                "sdk",
+                theirs.sdk_version.as_deref(),
+                native_toolchain.sdk_version.as_deref(),
+            ));
+        }
+        if theirs.ndk_version.is_some() {
+            mismatches.extend(mismatch(
+                "ndk",
+                theirs.ndk_version.as_deref(),
+                native_toolchain.ndk_version.as_deref(),
+            ));
+        }
+        if Target::from_arg(Some(&manifest.target), &rustc.host).is_apple() {
+            mismatches.extend(mismatch(
+                "deployment target",
+                theirs.deployment_target.as_deref(),
+                native_toolchain.deployment_target.as_deref(),
+            ));
+        }
+
+        print_mismatches(&mismatches);
+    }
+
+    if found == 0 {
+        println!("No cache entries for {crate_name}.");
+    } else {
+        println!();
+        if workspace_features.is_none() {
+            println!(
+                "Couldn't ask Cargo which features {crate_name} would have here; \
+                 run this from the workspace to compare those too."
+            );
+        }
+        println!(
+            "Entries can also differ in other `rustc` arguments (profile \
+             settings, etc.) which aren't shown here."
+        );
+    }
+    Ok(())
+}
+
+fn print_mismatches(mismatches: &[String]) {
+    if mismatches.is_empty() {
+        println!("    no differences from this machine's environment");
+    }
+    for mismatch in mismatches {
+        println!("    {mismatch}");
+    }
+}
+
+fn mismatch(what: &str, theirs: Option<&str>, ours: Option<&str>) -> Option<String> {
+    (theirs != ours).then(|| {
+        format!(
+            "{what}: {} (here: {})",
+            theirs.unwrap_or("none"),
+            ours.unwrap_or("none")
+        )
+    })
+}
+
+/// A package's features as Cargo resolves them for the workspace we're in.
+struct WorkspaceFeatures {
+    version: String,
+    /// What turns on each feature: dependents (e.g. "tokio v1.38.0"),
+    /// or other features (e.g. `serde feature "derive"`).
+    enabled_by: BTreeMap<String, Vec<String>>,
+}
+
+fn workspace_features(package_name: &str, target: &Target) -> Option<WorkspaceFeatures> {
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = Command::new(cargo)
+        .args([
+            "tree",
+            "--edges",
+            "normal,build,features",
+            "--prefix",
+            "depth",
+        ])
+        .args(["--invert", package_name, "--target", target.triple()])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_inverted_feature_tree(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Make sense of `cargo tree --invert {package} --edges features --prefix depth`,
+/// where each node is followed by what depends on it (one deeper), e.g.:
+///
+/// ```text
+/// 0serde v1.0.203
+/// 1serde feature "default"
+/// 2my-app v0.1.0 (/home/me/my-app)
+/// 1serde feature "serde_derive"
+/// 2serde feature "derive"
+/// 3my-app v0.1.0 (/home/me/my-app) (*)
+/// 1serde feature "derive" (*)
+/// ```
+///
+/// Nodes marked "(*)" were already shown, along with what depends on them.
+fn parse_inverted_feature_tree(tree: &str) -> Option<WorkspaceFeatures> {
+    let mut lines = tree.lines();
+    let (package_name, version) = lines.next()?.strip_prefix('0')?.split_once(" v")?;
+    let feature_prefix = format!("{package_name} feature \"");
+    let feature_name = |node: &str| -> Option<String> {
+        Some(
+            node.strip_prefix(&feature_prefix)?
+                .strip_suffix('"')?
+                .to_owned(),
+        )
+    };
+    let mut enabled_by: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    // The node at each depth on the way to the current line.
+    let mut ancestors: Vec<&str> = vec![package_name];
+    // A package built for both the host and the target gets a tree for each,
+    // but we only care about the first.
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let node = line.trim_start_matches(|c: char| c.is_ascii_digit());
+        let depth: usize = line[..line.len() - node.len()].parse().ok()?;
+        let node = node
+            .trim_end_matches(" (*)")
+            .trim_end_matches(" (command-line)");
+        ancestors.truncate(depth);
+        if let Some(name) = feature_name(node) {
+            enabled_by.entry(name).or_default();
+        }
+        if let Some(enabled) = ancestors.last().and_then(|parent| feature_name(parent)) {
+            enabled_by.entry(enabled).or_default().push(node.to_owned());
+        }
+        ancestors.push(node);
+    }
+    Some(WorkspaceFeatures {
+        version: version.split(' ').next()?.to_owned(),
+        enabled_by,
+    })
+}
+
+fn feature_mismatches(manifest: &EntryManifest, ours: &WorkspaceFeatures) -> Vec<String> {
+    // Features of a different version aren't comparable.
+    let theirs_version = manifest
+        .package_id
+        .as_deref()
+        .and_then(|package_id| package_id.rsplit_once('@'))
+        .map(|(_, version)| version);
+    if let Some(version_mismatch) = mismatch("version", theirs_version, Some(ours.version.as_str()))
+    {
+        return vec![version_mismatch];
+    }
+    let Some(theirs) = &manifest.features else {
+        return vec!["(no features recorded)".to_string()];
+    };
+    let mut mismatches = Vec::new();
+    for (name, enabled_by) in &ours.enabled_by {
+        if !theirs.contains(name) && enabled_by.is_empty() {
+            mismatches.push(format!("feature \"{name}\": off (here: on)"));
+        } else if !theirs.contains(name) {
+            mismatches.push(format!(
+                "feature \"{name}\": off (here: on, enabled by {})",
+                enabled_by.join(", ")
+            ));
+        }
+    }
+    for name in theirs {
+        if !ours.enabled_by.contains_key(name) {
+            mismatches.push(format!("feature \"{name}\": on (here: off)"));
+        }
+    }
+    mismatches
+}
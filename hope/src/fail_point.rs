@@ -0,0 +1,52 @@
+//! Deliberately failing at specific points, for testing.
+//!
+//! Set `HOPE_FAIL_POINT` to a comma-separated list of `{name}:{action}`
+//! pairs, e.g. "push_crate:io_error", to make the named fail point fail.
+//! Actions are:
+//!
+//! - `io_error`: return an I/O error, as if the disk or network had failed.
+//! - `panic`: panic.
+//! - `delay_ms={n}`: sleep for `n` milliseconds, then carry on as normal.
+//!
+//! This lets integration tests exercise error handling paths
+//! deterministically, without elaborate filesystem tricks.
+//! Fail points are compiled out of release builds.
+
+#[cfg(debug_assertions)]
+use anyhow::Context;
+
+/// Fail if `HOPE_FAIL_POINT` says this fail point should.
+#[cfg(debug_assertions)]
+pub fn check(name: &str) -> anyhow::Result<()> {
+    let Ok(fail_points) = std::env::var("HOPE_FAIL_POINT") else {
+        return Ok(());
+    };
+    for fail_point in fail_points.split(',') {
+        let Some((fail_point_name, action)) = fail_point.split_once(':') else {
+            anyhow::bail!("Invalid fail point {fail_point:?}; expected '{{name}}:{{action}}'");
+        };
+        if fail_point_name != name {
+            continue;
+        }
+        if let Some(millis) = action.strip_prefix("delay_ms=") {
+            let millis: u64 = millis
+                .parse()
+                .with_context(|| format!("Bad delay in fail point {fail_point:?}"))?;
+            std::thread::sleep(std::time::Duration::from_millis(millis));
+            continue;
+        }
+        match action {
+            "io_error" => {
+                return Err(std::io::Error::other(format!("Injected failure at {name:?}")).into())
+            }
+            "panic" => panic!("Injected panic at {name:?}"),
+            _ => anyhow::bail!("Unrecognised fail point action {action:?}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(debug_assertions))]
+pub fn check(_name: &str) -> anyhow::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,242 @@
+//! Talking to the GitHub Actions cache service, so that builds on
+//! GitHub-hosted runners can share a cache without any storage of their own.
+//!
+//! Use it by setting `HOPE_CACHE_URL` to "gha://", or "gha://{scope}" to keep
+//! separate caches (e.g. per workflow) apart. The service's URL and a token
+//! for it come from `ACTIONS_CACHE_URL` and `ACTIONS_RUNTIME_TOKEN`, which
+//! the runner only gives to actions, not to `run` steps; an action like
+//! `crazy-max/ghaction-github-runtime` can pass them on. A credential for
+//! the cache's URL (see the `credentials` module) takes the token's place.
+//!
+//! Each blob is its own cache entry. Entries can't be replaced once they're
+//! committed, so the first push of a key wins, and later ones are quietly
+//! dropped. Almost every blob's content is fixed by its key anyway; the
+//! exception is entry aliases, which keep pointing at whichever entry was
+//! pushed for them first. GitHub evicts entries that haven't been used for
+//! a week, and the oldest ones once a repository's cache is full.
+
+use std::{io::Read as _, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::{RemoteBlobStore, SCHEMA_VERSION},
+    chunks::BlobStore,
+};
+
+const API_VERSION: &str = "application/json;api-version=6.0-preview.1";
+
+/// Uploads are sent in pieces of at most this size, as the service wants.
+const UPLOAD_CHUNK_SIZE: usize = 32 << 20;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct GhaBlobStore {
+    /// e.g. "https://acghubeus1.actions.githubusercontent.com/abc123/",
+    /// with a trailing slash.
+    service_url: String,
+    token: String,
+    /// Prepended to every key; either empty or ending in a slash.
+    scope: String,
+    /// The service also matches on a "version", which is meant to tell apart
+    /// caches made by incompatible tools; we use our cache schema version.
+    version: String,
+    agent: ureq::Agent,
+}
+
+/// What the service tells us about a cache entry it found.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    cache_key: String,
+    /// Where to download the entry from (a pre-signed URL).
+    archive_location: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReserveRequest<'a> {
+    key: &'a str,
+    version: &'a str,
+    cache_size: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReservedEntry {
+    cache_id: u64,
+}
+
+#[derive(Serialize)]
+struct CommitRequest {
+    size: usize,
+}
+
+impl GhaBlobStore {
+    pub fn from_url(url: &str, credential: Option<String>) -> anyhow::Result<Self> {
+        let scope = url
+            .strip_prefix("gha://")
+            .with_context(|| format!("{url:?} isn't a GitHub Actions cache URL"))?
+            .trim_matches('/');
+        let service_url = std::env::var("ACTIONS_CACHE_URL").context(
+            "Using the GitHub Actions cache needs 'ACTIONS_CACHE_URL', \
+             which GitHub only gives to actions; see the `gha` module docs",
+        )?;
+        let token = match credential {
+            Some(token) => token,
+            None => std::env::var("ACTIONS_RUNTIME_TOKEN")
+                .context("Using the GitHub Actions cache needs 'ACTIONS_RUNTIME_TOKEN'")?,
+        };
+        Ok(Self {
+            service_url: format!("{}/", service_url.trim_end_matches('/')),
+            token,
+            scope: if scope.is_empty() {
+                String::new()
+            } else {
+                format!("{scope}/")
+            },
+            version: format!(
+                "{:x}",
+                Sha256::digest(format!("hope-schema-{SCHEMA_VERSION}"))
+            ),
+            agent: ureq::AgentBuilder::new().timeout(IO_TIMEOUT).build(),
+        })
+    }
+
+    fn api_request(&self, method: &str, path: &str) -> ureq::Request {
+        self.agent
+            .request(
+                method,
+                &format!("{}_apis/artifactcache/{path}", self.service_url),
+            )
+            .set("authorization", &format!("Bearer {}", self.token))
+            .set("accept", API_VERSION)
+    }
+
+    /// The cache entry for a blob, if there is one.
+    fn lookup(&self, key: &str) -> anyhow::Result<Option<CacheEntry>> {
+        let cache_key = format!("hope/{}{key}", self.scope);
+        let response = self
+            .api_request("GET", "cache")
+            .query("keys", &cache_key)
+            .query("version", &self.version)
+            .call()
+            .with_context(|| format!("Failed to look up {key:?} in {}", self.url()))?;
+        // No content means no match.
+        if response.status() == 204 {
+            return Ok(None);
+        }
+        let entry: CacheEntry = serde_json::from_reader(response.into_reader())
+            .with_context(|| format!("Bad response looking up {key:?} in {}", self.url()))?;
+        // Keys are matched by prefix, like an action's `restore-keys`.
+        Ok((entry.cache_key == cache_key).then_some(entry))
+    }
+
+    fn download(&self, key: &str, range: Option<String>) -> anyhow::Result<Vec<u8>> {
+        let entry = self
+            .lookup(key)?
+            .with_context(|| format!("{key:?} isn't in {}", self.url()))?;
+        let mut request = self.agent.get(&entry.archive_location);
+        if let Some(range) = &range {
+            request = request.set("range", range);
+        }
+        let response = request
+            .call()
+            .with_context(|| format!("Failed to download {key:?} from {}", self.url()))?;
+        let mut bytes = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut bytes)
+            .with_context(|| format!("Failed to read {key:?} from {}", self.url()))?;
+        Ok(bytes)
+    }
+}
+
+impl BlobStore for GhaBlobStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.download(key, None)
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let cache_key = format!("hope/{}{key}", self.scope);
+        let reserved = self
+            .api_request("POST", "caches")
+            .set("content-type", "application/json")
+            .send_string(&serde_json::to_string(&ReserveRequest {
+                key: &cache_key,
+                version: &self.version,
+                cache_size: bytes.len(),
+            })?);
+        let cache_id = match reserved {
+            Ok(response) => {
+                let reserved: ReservedEntry = serde_json::from_reader(response.into_reader())
+                    .with_context(|| format!("Bad response reserving {key:?}"))?;
+                reserved.cache_id
+            }
+            // Somebody else already pushed (or is pushing) this blob.
+            Err(ureq::Error::Status(409, _)) => return Ok(()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to reserve {key:?} in {}", self.url()))
+            }
+        };
+
+        let path = format!("caches/{cache_id}");
+        for (index, chunk) in bytes.chunks(UPLOAD_CHUNK_SIZE).enumerate() {
+            let start = index * UPLOAD_CHUNK_SIZE;
+            self.api_request("PATCH", &path)
+                .set("content-type", "application/octet-stream")
+                .set(
+                    "content-range",
+                    &format!("bytes {start}-{}/*", start + chunk.len() - 1),
+                )
+                .send_bytes(chunk)
+                .with_context(|| format!("Failed to upload {key:?} to {}", self.url()))?;
+        }
+        self.api_request("POST", &path)
+            .set("content-type", "application/json")
+            .send_string(&serde_json::to_string(&CommitRequest {
+                size: bytes.len(),
+            })?)
+            .with_context(|| format!("Failed to commit {key:?} to {}", self.url()))?;
+        Ok(())
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.lookup(key)?.is_some())
+    }
+}
+
+impl RemoteBlobStore for GhaBlobStore {
+    /// e.g. "gha://scope/".
+    fn url(&self) -> String {
+        format!("gha://{}", self.scope)
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        let Some(entry) = self.lookup(key)? else {
+            return Ok(None);
+        };
+        let response = self
+            .agent
+            .head(&entry.archive_location)
+            .call()
+            .with_context(|| format!("Failed to check size of {key:?} in {}", self.url()))?;
+        match response
+            .header("content-length")
+            .and_then(|size| size.parse().ok())
+        {
+            Some(size) => Ok(Some(size)),
+            // In case it's sent chunked.
+            None => Ok(Some(self.get_blob(key)?.len() as u64)),
+        }
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        let bytes = self.download(key, Some(format!("bytes=-{len}")))?;
+        // In case the range was ignored, and we got the whole thing.
+        Ok(bytes[bytes.len().saturating_sub(len)..].to_vec())
+    }
+}
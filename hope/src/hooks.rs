@@ -0,0 +1,103 @@
+//! Running a site-specific command when things happen in the cache.
+//!
+//! Set `HOPE_HOOK` to a shell command, and Hope will run it for each
+//! [`HookEvent`], with the event as a single line of JSON on its stdin
+//! and its name in `HOPE_HOOK_EVENT`. That's enough to, say, tell a
+//! dashboard about misses, or kick off a job to warm a shared cache,
+//! without teaching Hope about any of it.
+//!
+//! Hooks run synchronously, once per unit, so keep them quick (or have
+//! them hand off to something in the background). Their stdout is thrown
+//! away, because Cargo is listening to ours.
+//!
+//! The `before-*` hooks can veto what's about to happen by failing:
+//! we build the unit instead of pulling it, or skip pushing it. If any
+//! other hook fails, we just warn about it.
+
+use std::{
+    io::Write as _,
+    process::{Command, Stdio},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::config;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum HookEvent<'a> {
+    /// About to look for a unit in the cache.
+    BeforePull {
+        crate_unit_name: &'a str,
+        storage_name: &'a str,
+    },
+    Pulled {
+        crate_unit_name: &'a str,
+        storage_name: &'a str,
+    },
+    /// Couldn't (or wouldn't) pull a unit, so we're building it instead.
+    Missed {
+        crate_unit_name: &'a str,
+        storage_name: &'a str,
+        reason: String,
+    },
+    /// Built a unit, and about to push it to the cache.
+    BeforePush {
+        crate_unit_name: &'a str,
+        storage_name: &'a str,
+    },
+    Pushed {
+        crate_unit_name: &'a str,
+        storage_name: &'a str,
+    },
+}
+
+impl HookEvent<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::BeforePull { .. } => "before-pull",
+            Self::Pulled { .. } => "pulled",
+            Self::Missed { .. } => "missed",
+            Self::BeforePush { .. } => "before-push",
+            Self::Pushed { .. } => "pushed",
+        }
+    }
+}
+
+/// Run the hook (if there is one) for an event, and fail if it does.
+pub fn run(event: &HookEvent) -> anyhow::Result<()> {
+    let Some(hook) = config::hook() else {
+        return Ok(());
+    };
+    let mut line = serde_json::to_vec(event).context("Failed to serialize hook event")?;
+    line.push(b'\n');
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&hook)
+        .env("HOPE_HOOK_EVENT", event.name())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to start hook {hook:?}"))?;
+    let mut stdin = child.stdin.take().expect("Stdin should be piped");
+    // Hooks that don't care about the details might not read them,
+    // so a broken pipe here isn't a problem.
+    let _ = stdin.write_all(&line);
+    drop(stdin);
+    let status = child.wait().context("Failed to wait for hook")?;
+    anyhow::ensure!(
+        status.success(),
+        "Hook {hook:?} failed for {:?} event ({status})",
+        event.name()
+    );
+    Ok(())
+}
+
+/// Run the hook for an event that it can't do anything about,
+/// and only warn if it fails.
+pub fn notify(event: &HookEvent) {
+    if let Err(err) = run(event) {
+        eprintln!("Hope: {err:#}");
+    }
+}
@@ -0,0 +1,189 @@
+//! Talking to a cache over plain HTTP.
+//!
+//! The protocol is as dumb as it gets: each blob lives at a URL made by
+//! appending its key to a base URL, `GET` fetches it, `HEAD` checks for it,
+//! `PUT` stores it, and a 404 means it isn't there. That's what `hope serve`
+//! speaks (see the `serve` module), but so does just about any web server
+//! that accepts uploads, e.g. nginx with `dav_methods PUT` (plus
+//! `create_full_put_path on`, because some keys have slashes in them).
+//!
+//! If the cache has a credential (see the `credentials` module), every request
+//! carries it as a bearer token, which is what `hope-server` wants (see
+//! `src/bin/hope-server`).
+//!
+//! Public caches (see `config::public_cache_url`) never get a credential, and
+//! a 403 from one means the blob isn't there too, because that's what S3
+//! (and CDNs in front of it) say to anonymous requests for missing objects,
+//! rather than let them find out what's in the bucket. A bucket keeps blobs
+//! at "{prefix}{key}", just as it would at "{base URL}/{key}" over HTTP, so
+//! a public cache can be the same bucket that `s3://` pushes go to.
+//!
+//! A 403 for a push means the server doesn't take pushes (as with `hope serve`
+//! without `--allow-push`), which is a [`PushRefused`] error, so the build
+//! can stop trying rather than count it against the server's health.
+
+use std::{fmt, io::Read as _, time::Duration};
+
+use anyhow::Context;
+
+use crate::{cache::RemoteBlobStore, chunks::BlobStore, serve::BLOB_PATH_PREFIX};
+
+/// A server that stops responding shouldn't hang the build; timing out
+/// instead lets the circuit breaker in `remote_fallback` do its job.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The server turned a push away with a 403.
+#[derive(Debug)]
+pub struct PushRefused {
+    blobs_url: String,
+}
+
+impl fmt::Display for PushRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} doesn't take pushes (403 Forbidden)", self.blobs_url)
+    }
+}
+
+impl std::error::Error for PushRefused {}
+
+pub struct HttpBlobStore {
+    /// e.g. "http://build-box:7777/blobs/", with a trailing slash.
+    blobs_url: String,
+    /// Sent as "Authorization: Bearer {token}", if there is one.
+    token: Option<String>,
+    /// Anonymous, so a 403 means there's no such blob.
+    public: bool,
+    /// Keeps connections open between requests, which matters most
+    /// in `hope daemon`, where one store lasts for many builds.
+    agent: ureq::Agent,
+}
+
+impl HttpBlobStore {
+    /// Blobs directly under `url`, e.g. "http://build-box/hope-cache".
+    pub fn new(url: &str) -> Self {
+        Self {
+            blobs_url: format!("{}/", url.trim_end_matches('/')),
+            token: None,
+            public: false,
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout_read(IO_TIMEOUT)
+                .timeout_write(IO_TIMEOUT)
+                .build(),
+        }
+    }
+
+    /// A public cache at `url`, read without credentials.
+    pub fn public(url: &str) -> Self {
+        Self {
+            public: true,
+            ..Self::new(url)
+        }
+    }
+
+    /// Authenticate every request with `token`.
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Blobs shared by `hope serve` at `base_url`, e.g. "http://build-box:7777".
+    pub fn serve(base_url: &str) -> Self {
+        Self::new(&format!(
+            "{}{BLOB_PATH_PREFIX}",
+            base_url.trim_end_matches('/')
+        ))
+    }
+
+    fn blob_url(&self, key: &str) -> String {
+        format!("{}{key}", self.blobs_url)
+    }
+
+    fn request(&self, method: &str, key: &str) -> ureq::Request {
+        let request = self.agent.request(method, &self.blob_url(key));
+        match &self.token {
+            Some(token) => request.set("authorization", &format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    /// `None` if there's no such blob.
+    fn head(&self, key: &str) -> anyhow::Result<Option<ureq::Response>> {
+        match self.request("HEAD", key).call() {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(ureq::Error::Status(403, _)) if self.public => Ok(None),
+            Err(err) => Err(err)
+                .with_context(|| format!("Failed to check for {key:?} on {}", self.blobs_url)),
+        }
+    }
+
+    fn read_body(&self, key: &str, response: ureq::Response) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut bytes)
+            .with_context(|| format!("Failed to read {key:?} from {}", self.blobs_url))?;
+        Ok(bytes)
+    }
+}
+
+impl BlobStore for HttpBlobStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let response = self
+            .request("GET", key)
+            .call()
+            .with_context(|| format!("Failed to get {key:?} from {}", self.blobs_url))?;
+        self.read_body(key, response)
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        match self.request("PUT", key).send_bytes(bytes) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(403, _)) => Err(PushRefused {
+                blobs_url: self.blobs_url.clone(),
+            }
+            .into()),
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to put {key:?} to {}", self.blobs_url))
+            }
+        }
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.head(key)?.is_some())
+    }
+}
+
+impl RemoteBlobStore for HttpBlobStore {
+    fn url(&self) -> String {
+        self.blobs_url.clone()
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        let Some(response) = self.head(key)? else {
+            return Ok(None);
+        };
+        match response
+            .header("content-length")
+            .and_then(|size| size.parse().ok())
+        {
+            Some(size) => Ok(Some(size)),
+            // Big blobs might be sent chunked, so we won't know
+            // how big they are until we've fetched them.
+            None => Ok(Some(self.get_blob(key)?.len() as u64)),
+        }
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        let response = self
+            .request("GET", key)
+            .set("range", &format!("bytes=-{len}"))
+            .call()
+            .with_context(|| format!("Failed to get {key:?} from {}", self.blobs_url))?;
+        let bytes = self.read_body(key, response)?;
+        // Servers are allowed to ignore the range and send the whole thing.
+        Ok(bytes[bytes.len().saturating_sub(len)..].to_vec())
+    }
+}
@@ -0,0 +1,159 @@
+//! Installing Hope as Cargo's `rustc` wrapper, from nothing but the binary.
+//!
+//! Ephemeral CI containers often have Cargo but no way to `cargo install`
+//! (or no toolchain at all until later), so the release builds are static
+//! musl binaries that run anywhere, and this puts one where it'll stay:
+//!
+//! ```text
+//! curl -L .../hope-x86_64-unknown-linux-musl -o hope
+//! chmod +x hope && ./hope install-wrapper
+//! ```
+//!
+//! That copies the binary to "$CARGO_HOME/bin/hope" and sets `rustc-wrapper`
+//! in "$CARGO_HOME/config.toml", so every later build uses it without
+//! anybody having to export `RUSTC_WRAPPER`.
+//!
+//! There's no TOML library here, so the config is edited line by line:
+//! anything else in it is left alone, and running it again just updates
+//! the path.
+
+use std::{
+    io::Write as _,
+    os::unix::fs::PermissionsExt as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+pub fn run(bin_dir: Option<&Path>, cargo_home: Option<&Path>) -> anyhow::Result<()> {
+    let cargo_home = match cargo_home {
+        Some(cargo_home) => cargo_home.to_owned(),
+        None => cargo_home_from_env()?,
+    };
+    let bin_dir = bin_dir
+        .map(Path::to_owned)
+        .unwrap_or_else(|| cargo_home.join("bin"));
+    let installed = install_binary(&bin_dir)?;
+    let config_path = set_rustc_wrapper(&cargo_home, &installed)?;
+    println!(
+        "Installed {} as the rustc wrapper in {}",
+        installed.display(),
+        config_path.display()
+    );
+    Ok(())
+}
+
+/// Where Cargo keeps its own config, as Cargo works it out.
+fn cargo_home_from_env() -> anyhow::Result<PathBuf> {
+    if let Some(cargo_home) = std::env::var_os("CARGO_HOME").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(cargo_home));
+    }
+    let base_dirs = directories::BaseDirs::new()
+        .context("Couldn't find home dir; set 'CARGO_HOME' or pass '--cargo-home'")?;
+    Ok(base_dirs.home_dir().join(".cargo"))
+}
+
+/// Copy the running binary to `bin_dir`, unless that's where it already is.
+fn install_binary(bin_dir: &Path) -> anyhow::Result<PathBuf> {
+    let current_exe = std::env::current_exe().context("Couldn't find our own binary")?;
+    std::fs::create_dir_all(bin_dir).with_context(|| format!("Failed to create {bin_dir:?}"))?;
+    let installed = bin_dir.join("hope");
+    let already_there = installed
+        .canonicalize()
+        .is_ok_and(|installed| current_exe.canonicalize().ok() == Some(installed));
+    if already_there {
+        return Ok(installed);
+    }
+    // Move a copy into place rather than writing over the old binary, which
+    // a build might still be running.
+    let mut temp_file = tempfile::Builder::new()
+        .prefix(".hope-")
+        .tempfile_in(bin_dir)
+        .context("Failed to create temporary file")?;
+    let mut binary = std::fs::File::open(&current_exe)
+        .with_context(|| format!("Failed to open {current_exe:?}"))?;
+    std::io::copy(&mut binary, &mut temp_file)
+        .with_context(|| format!("Failed to copy {current_exe:?}"))?;
+    temp_file.flush()?;
+    temp_file
+        .as_file()
+        .set_permissions(std::fs::Permissions::from_mode(0o755))?;
+    temp_file
+        .persist(&installed)
+        .with_context(|| format!("Failed to move binary into place at {installed:?}"))?;
+    Ok(installed)
+}
+
+/// Point `build.rustc-wrapper` in Cargo's config at `wrapper`,
+/// and say which file that was.
+fn set_rustc_wrapper(cargo_home: &Path, wrapper: &Path) -> anyhow::Result<PathBuf> {
+    // Cargo still reads the old name if that's all there is.
+    let legacy_path = cargo_home.join("config");
+    let path = if legacy_path.is_file() && !cargo_home.join("config.toml").exists() {
+        legacy_path
+    } else {
+        cargo_home.join("config.toml")
+    };
+    let config = match std::fs::read_to_string(&path) {
+        Ok(config) => config,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {path:?}")),
+    };
+    let wrapper = wrapper
+        .to_str()
+        .with_context(|| format!("Can't put {wrapper:?} in Cargo's config"))?;
+    std::fs::create_dir_all(cargo_home)
+        .with_context(|| format!("Failed to create {cargo_home:?}"))?;
+    std::fs::write(&path, with_rustc_wrapper(&config, wrapper))
+        .with_context(|| format!("Failed to write {path:?}"))?;
+    Ok(path)
+}
+
+fn with_rustc_wrapper(config: &str, wrapper: &str) -> String {
+    // `Debug` escaping is close enough to TOML's basic strings for paths.
+    let setting = format!("rustc-wrapper = {wrapper:?}");
+    let mut lines: Vec<String> = config.lines().map(str::to_owned).collect();
+    let mut section = String::new();
+    let mut build_header = None;
+    for (index, line) in lines.iter_mut().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            section = trimmed.to_owned();
+            if section == "[build]" {
+                build_header = Some(index);
+            }
+            continue;
+        }
+        let key = trimmed.split('=').next().unwrap_or_default().trim();
+        let replaces = match key {
+            "rustc-wrapper" => section == "[build]",
+            "build.rustc-wrapper" => section.is_empty(),
+            _ => false,
+        };
+        if replaces {
+            *line = if section.is_empty() {
+                format!("build.{setting}")
+            } else {
+                setting
+            };
+            return join_lines(&lines);
+        }
+    }
+    match build_header {
+        Some(index) => lines.insert(index + 1, setting),
+        None => {
+            if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push("[build]".to_owned());
+            lines.push(setting);
+        }
+    }
+    join_lines(&lines)
+}
+
+fn join_lines(lines: &[String]) -> String {
+    let mut config = lines.join("\n");
+    config.push('\n');
+    config
+}
@@ -0,0 +1,221 @@
+//! Cache keys.
+//!
+//! Cargo's `-C extra-filename` hash (which is baked into the crate unit name)
+//! already covers most of what matters: package ID, features, profile, target,
+//! `rustc` version, etc. But exactly what Cargo chooses to hash has changed
+//! over time, and profile overrides (e.g. `[profile.dev.package."*"]`) can
+//! change codegen for one package with no other obvious hint. So we don't
+//! let correctness hinge on that alone, and also hash every `rustc` argument
+//! that can affect what gets built.
+
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    key_policy::{self, KeyPolicy},
+    rustc_args::{Args, FlagOrKvPair},
+    target::Target,
+    toolchain::{Channel, NativeToolchain, RustcInfo},
+};
+
+/// Codegen options that don't affect the content of outputs,
+/// that we already account for via the unit name,
+/// or that get their own component below.
+const IGNORED_CODEGEN_OPTIONS: &[&str] = &["metadata", "extra-filename", "incremental"];
+
+/// Serializable so that skipped pushes can be done later; see the `push_backlog` module.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheKey {
+    /// "{crate name}{extra filename}", as used by Cargo for output file names.
+    pub unit_name: String,
+    /// What we're building for; also recorded in entry manifests.
+    pub target: Target,
+    /// Only recorded in entry manifests, to help with diagnosing problems.
+    /// (It's already part of the digest like any other argument.)
+    pub edition: Option<String>,
+    /// Recorded in entry manifests so that we can find
+    /// fast-churning nightly entries to clean up.
+    pub channel: Channel,
+    /// Name of the key policy used, for entry manifests.
+    pub policy: String,
+    digest: String,
+    /// What the digest would be under the strict policy,
+    /// if we're using some other policy.
+    strict_digest: Option<String>,
+}
+
+pub struct KeyComponent {
+    pub name: &'static str,
+    pub value: String,
+}
+
+impl KeyComponent {
+    /// How key policies refer to this component: just its name, except
+    /// for codegen options, which are "codegen:{option}" so that they
+    /// can be picked out individually.
+    pub fn selector(&self) -> Cow<'_, str> {
+        match self.name {
+            "codegen" => {
+                let option = self
+                    .value
+                    .split_once('=')
+                    .map_or(&*self.value, |(key, _)| key);
+                format!("codegen:{option}").into()
+            }
+            name => name.into(),
+        }
+    }
+}
+
+impl CacheKey {
+    pub fn new(
+        unit_name: &str,
+        target: &Target,
+        rustc: &RustcInfo,
+        native_toolchain: &NativeToolchain,
+        args: &Args,
+        build_script_env: &[String],
+        policy: &dyn KeyPolicy,
+    ) -> Self {
+        let components = components(target, rustc, native_toolchain, args, build_script_env);
+
+        let digest_with = |policy: &dyn KeyPolicy| {
+            let mut hasher = Sha256::new();
+            hasher.update(unit_name.as_bytes());
+            if !policy.is_strict() {
+                hasher.update(b"\npolicy=");
+                hasher.update(policy.name().as_bytes());
+            }
+            for component in components
+                .iter()
+                .filter(|component| policy.includes(component))
+            {
+                hasher.update(b"\n");
+                hasher.update(component.name.as_bytes());
+                hasher.update(b"=");
+                hasher.update(component.value.as_bytes());
+            }
+            format!("{:x}", hasher.finalize())
+        };
+        let digest = digest_with(policy);
+        let strict_digest = (!policy.is_strict()).then(|| digest_with(&key_policy::Strict));
+
+        Self {
+            unit_name: unit_name.to_owned(),
+            target: target.clone(),
+            edition: args.edition.clone(),
+            channel: rustc.channel,
+            policy: policy.name().into_owned(),
+            digest,
+            strict_digest,
+        }
+    }
+
+    /// Abbreviated digest; plenty to tell apart builds of the same unit.
+    pub fn short_digest(&self) -> &str {
+        &self.digest[..16]
+    }
+
+    /// Name to store this unit's outputs under in the cache.
+    ///
+    /// This keeps the unit name up front so that cache dirs are still
+    /// somewhat human-navigable.
+    ///
+    /// Outputs are always stored under their strict key, whatever the
+    /// policy, because they're exactly what a strict build would produce.
+    /// Other policies' keys only ever name aliases; see `alias_name`.
+    pub fn storage_name(&self) -> String {
+        let digest = self.strict_digest.as_ref().unwrap_or(&self.digest);
+        format!("{}-{}", self.unit_name, &digest[..16])
+    }
+
+    /// Name of the alias record for this key, if it's not strict.
+    ///
+    /// Non-strict keys cover a whole set of builds that the policy considers
+    /// equivalent (e.g. differing only in debug info level), so the alias
+    /// points at whichever one of those was pushed most recently.
+    pub fn alias_name(&self) -> Option<String> {
+        self.strict_digest
+            .is_some()
+            .then(|| format!("{}-{}", self.unit_name, self.short_digest()))
+    }
+}
+
+/// Everything that goes into a key (besides the unit name), in the order
+/// it's hashed. Policies can leave some of these out.
+pub fn components(
+    target: &Target,
+    rustc: &RustcInfo,
+    native_toolchain: &NativeToolchain,
+    args: &Args,
+    build_script_env: &[String],
+) -> Vec<KeyComponent> {
+    let mut components = Vec::new();
+    let mut push = |name, value: String| components.push(KeyComponent { name, value });
+
+    push("rustc-release", rustc.release.clone());
+    if let Some(commit_hash) = &rustc.commit_hash {
+        push("rustc-commit-hash", commit_hash.clone());
+    }
+    for (name, value) in native_toolchain.key_components() {
+        push(name, value.to_owned());
+    }
+
+    // This ends up in symbol names, so it matters even if
+    // it's not reflected in the unit name.
+    if let Some(metadata_hash) = args.metadata_hash() {
+        push("metadata", metadata_hash.to_owned());
+    }
+    for codegen_option in &args.codegen_options {
+        match codegen_option {
+            FlagOrKvPair::Flag(flag) => push("codegen", flag.clone()),
+            FlagOrKvPair::KvPair(kv_pair) => {
+                if !IGNORED_CODEGEN_OPTIONS.contains(&kv_pair.key.as_str()) {
+                    push("codegen", format!("{}={}", kv_pair.key, kv_pair.value));
+                }
+            }
+        }
+    }
+    if args.optimize {
+        push("optimize", "true".to_string());
+    }
+    if args.include_debug_info {
+        push("debug-info", "true".to_string());
+    }
+    if args.test {
+        push("test", "true".to_string());
+    }
+    for cfg in &args.cfg {
+        push("cfg", cfg.clone());
+    }
+    // Usually from build scripts, which can choose (e.g.) static or dynamic
+    // linking by environment, without Cargo noticing. The kind ends up in
+    // the rlib, and static libraries get bundled into it.
+    for link_to_native_lib in &args.link_to_native_libs {
+        push("link-native-lib", link_to_native_lib.clone());
+    }
+    for crate_type in &args.crate_types {
+        push("crate-type", crate_type.clone());
+    }
+    for emit in &args.emit {
+        push("emit", emit.clone());
+    }
+    if let Some(edition) = &args.edition {
+        push("edition", edition.clone());
+    }
+    // Even without `--target`, this is different on different hosts.
+    push("target", target.triple().to_owned());
+    for unstable_option in &args.unstable_options {
+        push("unstable-option", unstable_option.clone());
+    }
+    for remap_path_prefix in &args.remap_path_prefixes {
+        push("remap-path-prefix", remap_path_prefix.clone());
+    }
+    // Not arguments, but `env!` can bake them into the output.
+    for var in build_script_env {
+        push("build-script-env", var.clone());
+    }
+    components
+}
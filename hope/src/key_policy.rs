@@ -0,0 +1,115 @@
+//! Choosing which parts of a unit's build go into its cache key.
+//!
+//! The default (strict) policy includes everything that could possibly
+//! affect outputs. Some people would rather get more cache hits and accept
+//! occasionally pulling outputs that aren't exactly what they'd have built,
+//! e.g. with a different debug info level. That's a reasonable trade
+//! to make, but it should be an explicit one, so:
+//!
+//! - The policy has to be chosen with `HOPE_KEY_POLICY`.
+//! - Non-strict policies are part of the key themselves. Those keys only
+//!   name aliases pointing at entries stored under strict keys (see
+//!   `CacheKey::alias_name`), so anyone using the strict policy will only
+//!   ever pull exactly what they'd have built.
+//! - The policy is recorded in each entry's manifest.
+//! - Some components are in every key whatever the policy; see [`NEVER_IGNORED`].
+//!
+//! Note that Cargo bakes some of the same things into unit names
+//! (e.g. the profile's debug info level), and we can't do anything
+//! about that; policies only affect our own part of the key.
+
+use std::borrow::Cow;
+
+use crate::key::KeyComponent;
+
+pub trait KeyPolicy {
+    /// Short description for entry manifests, e.g. "relaxed".
+    fn name(&self) -> Cow<'_, str>;
+
+    /// Should this component be part of the key?
+    fn includes(&self, component: &KeyComponent) -> bool;
+
+    /// Is this the default policy? If not, its name is hashed into keys.
+    fn is_strict(&self) -> bool {
+        false
+    }
+}
+
+/// Everything that could affect outputs goes into the key.
+pub struct Strict;
+
+impl KeyPolicy for Strict {
+    fn name(&self) -> Cow<'_, str> {
+        "strict".into()
+    }
+
+    fn includes(&self, _component: &KeyComponent) -> bool {
+        true
+    }
+
+    fn is_strict(&self) -> bool {
+        true
+    }
+}
+
+/// Ignore differences that (usually) don't change how code behaves.
+pub struct Relaxed;
+
+/// Components that the relaxed policy ignores.
+const RELAXED_IGNORED: &[&str] = &["debug-info", "codegen:debuginfo", "codegen:split-debuginfo"];
+
+impl KeyPolicy for Relaxed {
+    fn name(&self) -> Cow<'_, str> {
+        "relaxed".into()
+    }
+
+    fn includes(&self, component: &KeyComponent) -> bool {
+        !RELAXED_IGNORED.contains(&component.selector().as_ref())
+    }
+}
+
+/// Components that no policy may ignore, because they decide whether outputs
+/// can be linked together at all, not just how they behave. E.g. `rustc` won't
+/// link a crate built with `-C panic=abort` into one that unwinds, and one
+/// built with a different `-C metadata` has different symbol names, and
+/// one linked to a native library statically can't stand in for one linked
+/// to it dynamically.
+pub const NEVER_IGNORED: &[&str] = &["codegen:panic", "metadata", "link-native-lib"];
+
+/// Ignore an explicit list of components, from `HOPE_KEY_IGNORE`.
+///
+/// Components are named as in `KeyComponent::selector`, e.g. "debug-info"
+/// or "codegen:opt-level".
+pub struct Custom {
+    ignored: Vec<String>,
+}
+
+impl Custom {
+    pub fn new(mut ignored: Vec<String>) -> anyhow::Result<Self> {
+        if let Some(component) = ignored
+            .iter()
+            .find(|component| NEVER_IGNORED.contains(&component.as_str()))
+        {
+            anyhow::bail!(
+                "Can't ignore {component:?}: builds that differ in it can't be linked together"
+            );
+        }
+        // So that the same list in any order makes the same key.
+        ignored.sort();
+        ignored.dedup();
+        Ok(Self { ignored })
+    }
+}
+
+impl KeyPolicy for Custom {
+    fn name(&self) -> Cow<'_, str> {
+        format!("custom(ignore {})", self.ignored.join(",")).into()
+    }
+
+    fn includes(&self, component: &KeyComponent) -> bool {
+        !self
+            .ignored
+            .iter()
+            .any(|ignored| *ignored == component.selector())
+    }
+}
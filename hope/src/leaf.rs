@@ -0,0 +1,88 @@
+//! Telling direct dependencies of the workspace apart from deeper ones.
+//!
+//! With `HOPE_LEAF_ONLY` set, we only always cache packages that the
+//! workspace's own crates depend on directly. Those tend to be the ones that
+//! are worth it; a deep dependency is often a tiny crate that builds in less
+//! time than a round trip to a remote cache takes. Deeper dependencies are
+//! still cached if they're big enough (see `config::leaf_only_min_size`).
+//!
+//! The workspace's `Cargo.lock` is next to its target dir (at least when
+//! that's in the usual place). Packages in it without a `source` are the workspace's
+//! own (or other path dependencies), and their `dependencies` are what
+//! they depend on directly.
+
+use std::path::Path;
+
+use anyhow::Context;
+
+/// Does a package of the workspace depend on this one directly?
+///
+/// If there's no lockfile to go by, then assume it does,
+/// so that it gets cached as usual.
+pub fn is_direct_dependency(
+    workspace_dir: &Path,
+    package_name: &str,
+    package_version: &str,
+) -> anyhow::Result<bool> {
+    let lockfile_path = workspace_dir.join("Cargo.lock");
+    let lockfile = match std::fs::read_to_string(&lockfile_path) {
+        Ok(lockfile) => lockfile,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {lockfile_path:?}")),
+    };
+    Ok(local_packages(&lockfile)
+        .iter()
+        .flat_map(|package| &package.dependencies)
+        .any(|dependency| {
+            // "name", "name version", or "name version (source)", depending
+            // on how much it takes to tell which package is meant.
+            let mut parts = dependency.split(' ');
+            parts.next() == Some(package_name)
+                && !matches!(parts.next(), Some(version) if version != package_version)
+        }))
+}
+
+#[derive(Default)]
+struct LockedPackage {
+    has_source: bool,
+    dependencies: Vec<String>,
+}
+
+/// Packages in a lockfile with no source.
+///
+/// This just picks out the lines we care about rather than parsing the whole
+/// lockfile as TOML, which is fine because Cargo always writes it the same way.
+fn local_packages(lockfile: &str) -> Vec<LockedPackage> {
+    let mut packages = Vec::new();
+    let mut current: Option<LockedPackage> = None;
+    let mut in_dependencies = false;
+    for line in lockfile.lines() {
+        let line = line.trim();
+        if line.starts_with('[') && !in_dependencies {
+            packages.extend(current.take());
+            if line == "[[package]]" {
+                current = Some(LockedPackage::default());
+            }
+            continue;
+        }
+        let Some(package) = current.as_mut() else {
+            continue;
+        };
+        if in_dependencies {
+            if line == "]" {
+                in_dependencies = false;
+            } else {
+                package
+                    .dependencies
+                    .push(line.trim_end_matches(',').trim_matches('"').to_owned());
+            }
+        } else if line.starts_with("source =") {
+            package.has_source = true;
+        } else if line == "dependencies = [" {
+            in_dependencies = true;
+        }
+    }
+    packages.extend(current);
+    packages.retain(|package| !package.has_source);
+    packages
+}
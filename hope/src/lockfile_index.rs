@@ -0,0 +1,157 @@
+//! Indexes of the cache entries used by a given `Cargo.lock`.
+//!
+//! With `HOPE_LOCKFILE_INDEX` set to a project's `Cargo.lock`, every entry
+//! (and build script output) pulled or pushed while building that project
+//! gets recorded in an index named for a hash of the lockfile's contents.
+//! A CI job that starts from an empty cache can then run `hope warm-up`
+//! to fetch that one index from a shared cache, and then everything it
+//! lists, before the build starts. (Build with `--locked`, so that the
+//! lockfile doesn't change part way through and split the index in two.)
+//!
+//! Indexes are plain text with one record per line, and are only ever
+//! appended to, so concurrent `rustc`s don't need to coordinate.
+//! Records may repeat; readers don't care.
+
+use std::{collections::BTreeSet, path::Path};
+
+use anyhow::Context;
+
+use crate::{
+    build_script_inputs::BuildScriptInputs,
+    cache::{build_script_stdout_file_name, Cache, LocalCache},
+    chunks::{self, BlobStore},
+    config,
+    entry_manifest::{EntryAlias, EntryManifest},
+    http_store::HttpBlobStore,
+    key::CacheKey,
+    sync::Copier,
+};
+
+const ENTRY: &str = "entry";
+const ALIAS: &str = "alias";
+const BUILD_SCRIPT: &str = "build-script";
+
+fn index_key(lockfile: &Path) -> anyhow::Result<String> {
+    let lockfile_content =
+        std::fs::read(lockfile).with_context(|| format!("Failed to read {lockfile:?}"))?;
+    Ok(format!(
+        "lockfiles/{}.index",
+        chunks::hash_bytes(&lockfile_content)
+    ))
+}
+
+/// Note that the entry for this key was used, if we're keeping an index.
+///
+/// Indexes always live in the local cache, even if entries come from elsewhere.
+pub fn record_entry(
+    local_cache: &LocalCache,
+    cache: &dyn Cache,
+    key: &CacheKey,
+) -> anyhow::Result<()> {
+    let Some(lockfile) = config::lockfile_index()? else {
+        return Ok(());
+    };
+    let index_key = index_key(&lockfile)?;
+    if let Some(alias_name) = key.alias_name() {
+        local_cache.append_line(&index_key, &format!("{ALIAS} {alias_name}"))?;
+    }
+    let storage_name = cache.resolve_storage_name(key)?;
+    local_cache.append_line(&index_key, &format!("{ENTRY} {storage_name}"))
+}
+
+/// How much pulling every entry in the index would write to the target dir,
+/// going by what's in the local cache, if we're keeping an index.
+///
+/// That's only a projection: entries that are already built (or are only
+/// in a remote cache) don't count, and the index may list entries that
+/// this build won't need any more.
+pub fn projected_pull_size(local_cache: &LocalCache) -> anyhow::Result<Option<u64>> {
+    let Some(lockfile) = config::lockfile_index()? else {
+        return Ok(None);
+    };
+    let index_key = index_key(&lockfile)?;
+    if !local_cache.has_blob(&index_key)? {
+        return Ok(None);
+    }
+    let index = String::from_utf8(local_cache.get_blob(&index_key)?)
+        .context("Lockfile index contained invalid UTF-8")?;
+    let storage_names: BTreeSet<&str> = index
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter(|(kind, _)| *kind == ENTRY)
+        .map(|(_, storage_name)| storage_name)
+        .collect();
+    let mut total = 0;
+    for storage_name in storage_names {
+        let Some(manifest) = EntryManifest::load(local_cache, storage_name)? else {
+            continue;
+        };
+        for file_name in manifest.files.keys() {
+            total += local_cache.stored_file_size(file_name)?.unwrap_or(0);
+        }
+    }
+    Ok(Some(total))
+}
+
+/// Note that this build script output was used, if we're keeping an index.
+pub fn record_build_script(
+    cache: &LocalCache,
+    build_script_execution_key: &str,
+) -> anyhow::Result<()> {
+    let Some(lockfile) = config::lockfile_index()? else {
+        return Ok(());
+    };
+    cache.append_line(
+        &index_key(&lockfile)?,
+        &format!("{BUILD_SCRIPT} {build_script_execution_key}"),
+    )
+}
+
+/// Fetch everything the lockfile's index lists from a `hope serve` cache
+/// into the local cache.
+pub fn warm_up(from: &str, lockfile: &Path) -> anyhow::Result<()> {
+    let remote = HttpBlobStore::serve(from);
+    let local = LocalCache::from_env()?;
+    let index_key = index_key(lockfile)?;
+    if !remote.has_blob(&index_key)? {
+        println!("{from} has no index for {lockfile:?}; nothing to do.");
+        return Ok(());
+    }
+    let index = String::from_utf8(remote.get_blob(&index_key)?)
+        .context("Lockfile index contained invalid UTF-8")?;
+    let records: BTreeSet<(&str, &str)> = index
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .collect();
+
+    let mut warm_up = Copier::new(&remote, &local);
+    for (kind, name) in &records {
+        match *kind {
+            ENTRY => warm_up.entry(name)?,
+            ALIAS => warm_up.blob(&EntryAlias::blob_key(name))?,
+            BUILD_SCRIPT => {
+                warm_up.blob(&build_script_stdout_file_name(name))?;
+                warm_up.optional_blob(&BuildScriptInputs::blob_key(name))?;
+            }
+            _ => {}
+        }
+    }
+
+    println!(
+        "Fetched {} blobs for {} records in the index.",
+        warm_up.copied,
+        records.len()
+    );
+    if !warm_up.missing.is_empty() {
+        println!(
+            "{} blobs were missing from {from}, so some units will have to be built:",
+            warm_up.missing.len()
+        );
+        for key in &warm_up.missing {
+            println!("    {key}");
+        }
+    }
+    // Keep our own copy of the index, so that this machine can serve it too.
+    local.put_blob(&index_key, index.as_bytes())?;
+    Ok(())
+}
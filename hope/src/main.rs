@@ -1,5 +1,69 @@
+mod attestation;
+mod background_push;
+mod bazel;
+mod bench;
+mod build_env;
 mod build_script;
+mod build_script_diff;
+mod build_script_inputs;
 mod cache;
+mod capabilities;
+mod chunks;
+mod clean;
+mod clean_project;
+mod cli;
+mod clock;
+mod compression;
+mod config;
+mod credentials;
+mod daemon;
+mod daemon_socket;
+mod determinism;
+mod disk_space;
+mod entry_manifest;
+mod explain;
+mod fail_point;
+mod gha;
+mod hooks;
+mod http_store;
+mod install_wrapper;
+mod key;
+mod key_policy;
+mod leaf;
+mod lockfile_index;
+mod memory_cache;
+mod mtime;
+mod observe;
+mod out_dir_layout;
+mod ownership;
+mod permissions;
+mod plugin;
+mod portability;
+mod print_key;
+mod push_backlog;
+mod redis;
+mod remote_build;
+mod remote_fallback;
+mod replay;
+mod rusage;
+mod rustc_args;
+mod s3;
+mod serve;
+mod session;
+mod session_budget;
+mod sharded_store;
+mod signals;
+mod sources;
+mod stats;
+mod sync;
+mod target;
+mod tiered_cache;
+mod toolchain;
+mod transfer_limits;
+mod upload_queue;
+mod value;
+mod verify;
+mod why_rebuild;
 
 use std::collections::HashSet;
 use std::env;
@@ -7,119 +71,46 @@ use std::fs::File;
 use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
-use std::{process::Command, str::FromStr};
+use std::sync::{mpsc::RecvTimeoutError, Arc};
+use std::time::{Duration, Instant};
+use std::{
+    process::{Command, Stdio},
+    str::FromStr,
+};
 
 use anyhow::Context;
+use attestation::BuildRecord;
+use build_env::BuildEnvironment;
 use build_script::{
     append_moved_build_script_suffix, BuildScriptInvocationInfo,
-    BUILD_SCRIPT_INVOCATION_INFO_FILE_NAME,
+    BUILD_SCRIPT_INVOCATION_INFO_FILE_NAME, BUILD_SCRIPT_KEY_FILE_NAME, BUILT_LOCALLY_FILE_NAME,
+    REAL_BUILD_SCRIPT_SYMLINK_NAME,
 };
 use cache::{Cache, LocalCache};
+use chrono::Utc;
 use clap::Parser;
+use entry_manifest::EntryManifest;
+use hooks::HookEvent;
+use hope_cache_log::{
+    write_log_line, AbandonPullEvent, CacheLogLine, CompileCrateEvent, PortabilityCheckEvent,
+    ResourceUsage, SkipPushEvent,
+};
+use key::CacheKey;
+use portability::PortabilityReport;
+use rustc_args::Args;
+use serde::{Deserialize, Serialize};
+use target::{FileNaming, Target};
 use tempfile::tempdir;
+use toolchain::{NativeToolchain, RustcInfo};
 
-// TODO: I don't like this. I'd instead like to be able to collect
-// the flags and kv-pairs into a custom collection.
-#[derive(Clone, Debug, PartialEq, Eq)]
-enum FlagOrKvPair {
-    Flag(String),
-    KvPair(KeyValuePair),
-}
-
-impl FromStr for FlagOrKvPair {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some((key, value)) = s.split_once('=') {
-            Ok(Self::KvPair(KeyValuePair {
-                key: key.to_owned(),
-                value: value.to_owned(),
-            }))
-        } else {
-            Ok(Self::Flag(s.to_owned()))
-        }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq, Eq)]
-struct KeyValuePair {
-    key: String,
-    value: String,
-}
-
-// Arguments here mirror the real `rustc` arguments.
-// I'm just using Clap to make it easier to inspect/modify the ones I care about.
-#[derive(Parser, Debug)]
-#[command(disable_version_flag = true, disable_help_flag = true)]
-struct Args {
-    // Not required if, e.g., passing `--version`.
-    input: Option<String>,
-    #[arg(long, value_delimiter = ',')]
-    cfg: Vec<String>,
-    #[arg(short = 'L', value_delimiter = ',')]
-    lib_search_paths: Vec<String>,
-    #[arg(short = 'l', value_delimiter = ',')]
-    link_to_native_libs: Vec<String>,
-    #[arg(long = "crate-type")]
-    crate_types: Vec<String>,
-    #[arg(long)]
-    crate_name: Option<String>,
-    #[arg(long)]
-    edition: Option<String>,
-    #[arg(long, value_delimiter = ',')]
-    emit: Vec<String>,
-    #[arg(long, value_delimiter = ',')]
-    print: Vec<String>,
-    #[arg(short = 'g')]
-    include_debug_info: bool,
-    #[arg(short = 'O')]
-    optimize: bool,
-    #[arg(short = 'o')]
-    out: Option<String>,
-    #[arg(long)]
-    out_dir: Option<String>,
-    #[arg(long)]
-    explain: bool,
-    #[arg(long)]
-    test: bool,
-    #[arg(long = "warn", short = 'W', value_delimiter = ',')]
-    warn_for_lints: Vec<String>,
-    #[arg(long = "force-warn", value_delimiter = ',')]
-    force_warn_for_lints: Vec<String>,
-    #[arg(long = "allow", short = 'A', value_delimiter = ',')]
-    allow_lints: Vec<String>,
-    #[arg(long = "deny", short = 'D', value_delimiter = ',')]
-    deny_lints: Vec<String>,
-    #[arg(long = "forbid", short = 'F', value_delimiter = ',')]
-    forbid_lints: Vec<String>,
-    #[arg(short = 'Z', value_delimiter = ',')]
-    unstable_options: Vec<String>,
-    #[arg(long)]
-    cap_lints: Option<String>,
-    #[arg(short = 'C', long = "codegen", value_delimiter = ',')]
-    codegen_options: Vec<FlagOrKvPair>,
-    #[arg(short = 'V', long)]
-    version: bool,
-    #[arg(short, long)]
-    verbose: bool,
-    #[arg(long = "extern", value_delimiter = ',')]
-    extern_: Vec<String>,
-    #[arg(long)]
-    sysroot: Option<String>,
-    #[arg(long)]
-    error_format: Option<String>,
-    #[arg(long)]
-    color: Option<String>,
-    #[arg(long)]
-    diagnostic_width: Option<u32>,
-    #[arg(long = "remap-path-prefix", value_delimiter = ',')]
-    remap_path_prefixes: Vec<String>,
-    #[arg(long, value_delimiter = ',')]
-    json: Vec<String>,
+fn main() -> anyhow::Result<()> {
+    let result = run();
+    // We've already cleaned up on the way out.
+    signals::reraise();
+    result
 }
 
-fn main() -> anyhow::Result<()> {
+fn run() -> anyhow::Result<()> {
     let mut args = std::env::args().peekable();
 
     let mut args_to_parse: Vec<String> = Vec::new();
@@ -128,16 +119,35 @@ fn main() -> anyhow::Result<()> {
         .next()
         .context("Missing argument for path to this executable")?;
 
-    // TODO: Non-hack way to get this! :P
-    if called_as.contains("/build/") && args.peek().is_none() {
+    if Path::new(&called_as)
+        .parent()
+        .is_some_and(out_dir_layout::is_build_script_out_dir)
+        && args.peek().is_none()
+    {
         // Looks like we're being run as a build script, because we moved
         // the actual build script out of the way and replaced it with a symlink
         // to this binary.
         let called_as = PathBuf::from_str(&called_as).context("Bad path in argv[0]")?;
+        signals::install()?;
         return build_script::run(&called_as);
     }
 
+    if args.peek().is_some_and(|arg| cli::is_subcommand(arg)) {
+        // Somebody is running us directly, rather than Cargo
+        // running us as a `rustc` wrapper.
+        return cli::run(std::iter::once(called_as).chain(args));
+    }
+
     args_to_parse.push(called_as);
+    signals::install()?;
+
+    let args: Vec<String> = args.collect();
+    if let Some(record_dir) = config::record_invocations() {
+        if let Err(err) = replay::record(&record_dir, &args) {
+            eprintln!("Hope failed to record this invocation: {err:#}");
+        }
+    }
+    let mut args = args.into_iter();
 
     let rustc_path = args
         .next()
@@ -172,6 +182,7 @@ fn main() -> anyhow::Result<()> {
 
     let out_dir = args
         .out_dir
+        .clone()
         .context("Missing out-dir; don't know where build artifacts are supposed to be")?;
     let out_dir = PathBuf::from_str(&out_dir).context("Invalid path in out-dir argument")?;
 
@@ -179,56 +190,105 @@ fn main() -> anyhow::Result<()> {
         .crate_name
         .clone()
         .context("Missing crate name argument")?;
-    // TODO: Dedup this stuff
     let extra_filename = args
-        .codegen_options
-        .iter()
-        .filter_map(|codegen_option| {
-            if let FlagOrKvPair::KvPair(kv_pair) = codegen_option {
-                Some(kv_pair)
-            } else {
-                None
-            }
-        })
-        .find(|kv_pair| kv_pair.key == "extra-filename")
-        .context("Missing extra-filename codegen option")?
-        .value
-        .clone();
-    let metadata_hash = args
-        .codegen_options
-        .iter()
-        .filter_map(|codegen_option| {
-            if let FlagOrKvPair::KvPair(kv_pair) = codegen_option {
-                Some(kv_pair)
-            } else {
-                None
-            }
-        })
-        .find(|kv_pair| kv_pair.key == "metadata")
-        .context("Missing metadata codegen option")?
-        .value
-        .clone();
+        .extra_filename()
+        .context("Missing extra-filename codegen option")?;
 
     let cargo_package_name =
         env::var("CARGO_PKG_NAME").context("Missing 'CARGO_PKG_NAME' env var")?;
+    let cargo_package_version = env::var("CARGO_PKG_VERSION").unwrap_or_default();
+    let plugin_unit = plugin::Unit {
+        crate_name: &crate_name,
+        package_name: &cargo_package_name,
+        package_version: &cargo_package_version,
+        storage_name: None,
+    };
+    if !in_rollout(&cargo_package_name, config::rollout_percent()?)
+        || !plugin::cacheable(&plugin_unit)?
+    {
+        // Leave this one alone, as if Hope weren't here at all.
+        return run_real_rustc(&rustc_path, pass_through_args);
+    }
+
+    // Don't leave files in somebody else's cache that they can't replace.
+    let _adopter = match ownership::check(&LocalCache::dir_from_env()?)? {
+        ownership::Ownership::Ours => None,
+        ownership::Ownership::Adopt(adopter) => Some(adopter),
+        ownership::Ownership::Theirs { owner_uid, our_uid } => {
+            eprintln!(
+                "Hope: cache dir belongs to uid {owner_uid}, not you (uid {our_uid}); \
+                 not caching this unit. Set HOPE_ADOPT_CACHE_OWNER=1 (as root) or \
+                 HOPE_GROUP_WRITABLE=1 (with a group-writable cache dir) to use it anyway."
+            );
+            return run_real_rustc(&rustc_path, pass_through_args);
+        }
+    };
+    hope_cache_log::set_file_mode(config::permissions()?.file_mode());
+
+    if let Err(unrecognised) = out_dir_layout::check_unit_out_dir(&out_dir) {
+        unrecognised.report()?;
+        return run_real_rustc(&rustc_path, pass_through_args);
+    }
 
     let crate_unit_name = format!("{crate_name}{extra_filename}");
 
-    let invoked_timestamp =
-        get_invoked_timestamp_for_crate_build_unit(&out_dir, &cargo_package_name, &metadata_hash)
-            .with_context(|| {
-            format!(
-                "Failed to get invoked timestamp for crate build unit '{crate_unit_name}' (Cargo package '{cargo_package_name}')"
+    // With checksum-based freshness, Cargo doesn't care about mtimes
+    // on our outputs, so we can skip the whole mtime equalization dance.
+    let invoked_timestamp = if uses_checksum_freshness(&args) {
+        None
+    } else {
+        Some(
+            get_invoked_timestamp_for_crate_build_unit(
+                &out_dir,
+                &cargo_package_name,
+                extra_filename.trim_start_matches('-'),
             )
-        })?;
+            .with_context(|| {
+                format!(
+                    "Failed to get invoked timestamp for crate build unit '{crate_unit_name}' (Cargo package '{cargo_package_name}')"
+                )
+            })?,
+        )
+    };
+
+    // Where copies get fresh mtimes anyway, we only need the invoked
+    // timestamp to check the clock.
+    let mtime_for_outputs = invoked_timestamp
+        .filter(|_| mtime::strategy(&mtime::EXPECTED) == mtime::Strategy::InvokedTimestamp);
+
+    if let Some(invoked_timestamp) = invoked_timestamp {
+        // If the target dir's filesystem has a clock way ahead of ours,
+        // then Cargo and `rustc` will disagree about what's fresh.
+        clock::check(
+            &LocalCache::dir_from_env()?,
+            &crate_unit_name,
+            clock::file_time_to_utc(invoked_timestamp),
+            "Cargo's invoked timestamp",
+        )?;
+    }
 
-    let cache = LocalCache::from_env()?;
+    let local_cache = LocalCache::from_env()?;
+    // Once this build has given up on the remote cache, it's local-only.
+    let cache = remote_fallback::cache_from_env(out_dir_layout::profile_dir(&out_dir))?;
+    let also_push_to = cache::also_push_to_from_env()?;
+    let push_targets = upload_queue::push_targets(&cache, &also_push_to)?;
+    // Pushes get skipped anyway when there's no room, but also keep
+    // the log from filling up the last of the disk.
+    if disk_space::check_degraded(&LocalCache::dir_from_env()?)? {
+        hope_cache_log::set_size_cap(disk_space::DEGRADED_LOG_SIZE_CAP);
+    }
 
     let mut crate_types = HashSet::new();
-    for crate_type_str in &args.crate_types {
-        let crate_type = CrateType::from_str(crate_type_str)
-            .context("Found unexpected output type in '--crate-type' argument")?;
-        crate_types.insert(crate_type);
+    if args.test {
+        // With `--test`, `rustc` builds a test harness executable
+        // regardless of any `--crate-type` arguments.
+        crate_types.insert(CrateType::Bin);
+    } else {
+        for crate_type_str in &args.crate_types {
+            let crate_type = CrateType::from_str(crate_type_str)
+                .context("Found unexpected output type in '--crate-type' argument")?;
+            crate_types.insert(crate_type);
+        }
     }
 
     let mut output_types = HashSet::new();
@@ -238,7 +298,22 @@ fn main() -> anyhow::Result<()> {
         output_types.insert(output_type);
     }
 
-    let output_defns = output_defns(&crate_types, &output_types);
+    let rustc_info =
+        RustcInfo::query_for_build(&rustc_path, out_dir_layout::profile_dir(&out_dir))?;
+    let target = Target::from_arg(args.target.as_deref(), &rustc_info.host);
+    if let Some(profile_dir) = out_dir_layout::profile_dir(&out_dir) {
+        match session::note_start(&LocalCache::dir_from_env()?, profile_dir, &rustc_info) {
+            Ok(true) => {
+                disk_space::warn_if_degraded(&LocalCache::dir_from_env()?);
+                if let Err(err) = disk_space::warn_if_projected_pulls_wont_fit(profile_dir) {
+                    eprintln!("Hope failed to project how much this build will pull: {err:#}");
+                }
+            }
+            Ok(false) => {}
+            Err(err) => eprintln!("Hope failed to note the start of this build: {err:#}"),
+        }
+    }
+    let output_defns = output_defns(&crate_types, &output_types, &target);
 
     // Try to pull from the cache.
     //
@@ -246,10 +321,176 @@ fn main() -> anyhow::Result<()> {
     // we need to the pulled files, and then copy them into the target directory.
     // (This is partly to help with testing, and partly to make it more obvious
     // what need cleaning up if there are failures.)
-    let arrival_dir = tempdir()
-        .with_context(|| format!("Failed to create arrival dir for crate {crate_unit_name}."))?;
-    match cache.pull_crate(&crate_unit_name, &output_defns, arrival_dir.path()) {
+    //
+    // With direct pulls, the arrival dir is inside the target directory, so
+    // files can be renamed into place instead of copied a second time.
+    // (Renaming is still atomic, so Cargo never sees a half-written file.)
+    let direct_pull = config::direct_pull();
+    let arrival_dir = if direct_pull {
+        tempfile::Builder::new()
+            .prefix(".hope-arrival-")
+            .tempdir_in(&out_dir)
+    } else {
+        tempdir()
+    }
+    .with_context(|| format!("Failed to create arrival dir for crate {crate_unit_name}."))?;
+    let native_toolchain = NativeToolchain::detect(
+        &target,
+        toolchain::links_native_code(&cargo_package_name, &args),
+    );
+    let cache_key = CacheKey::new(
+        &crate_unit_name,
+        &target,
+        &rustc_info,
+        &native_toolchain,
+        &args,
+        &build_script::rustc_env_for_crate()?,
+        config::key_policy()?.as_ref(),
+    );
+    let package_id = entry_manifest::package_id(&cargo_package_name, &cargo_package_version);
+    let storage_name = cache_key.storage_name();
+    // Small units deep in the dependency graph aren't worth the round trip.
+    // (Cargo runs us from each package's own dir, so find the workspace
+    // from the target dir instead.)
+    let workspace_dir =
+        out_dir_layout::profile_dir(&out_dir).and_then(out_dir_layout::workspace_dir);
+    let min_size_to_cache = if config::leaf_only()
+        && match workspace_dir {
+            Some(workspace_dir) => !leaf::is_direct_dependency(
+                workspace_dir,
+                &cargo_package_name,
+                &cargo_package_version,
+            )?,
+            // Can't tell, so cache it as usual.
+            None => false,
+        } {
+        Some(config::leaf_only_min_size()?)
+    } else {
+        None
+    };
+    let profile_dir = out_dir_layout::profile_dir(&out_dir);
+    // Nor are units that build quicker than they'd take to pull.
+    let cheap_reason = if config::skip_cheap_units() {
+        value::skip_reason(
+            &LocalCache::dir_from_env()?,
+            profile_dir,
+            &cache.location(),
+            &crate_unit_name,
+        )?
+    } else {
+        None
+    };
+    // Nor is anything, once this build has spent all it's allowed to on the cache.
+    let over_budget = |crate_unit_name: &str| match profile_dir {
+        Some(profile_dir) => {
+            session_budget::exhausted(&LocalCache::dir_from_env()?, profile_dir, crate_unit_name)
+        }
+        None => Ok(None),
+    };
+    let budget_reason = over_budget(&crate_unit_name)?;
+    let pull_started = Instant::now();
+    // When only observing, we still want to know whether we could have pulled.
+    let observe = config::observe();
+    // Not being able to tell is just a miss, though; it's no reason to fail the build.
+    let observed_hit = observe
+        && match cache.pull_size(&cache_key, &output_defns) {
+            Ok(size) => size.is_some(),
+            Err(err) => {
+                eprintln!("Hope failed to check whether {crate_unit_name} is cached: {err:#}");
+                false
+            }
+        };
+    let pull_result = if observe {
+        Err(anyhow::anyhow!("Only observing; not pulling"))
+    } else if let Some(cheap_reason) = &cheap_reason {
+        Err(anyhow::anyhow!("Not worth pulling: {cheap_reason}"))
+    } else if let Some(budget_reason) = &budget_reason {
+        Err(anyhow::anyhow!("{budget_reason}"))
+    } else {
+        match check_pull_size(
+            &*cache,
+            &cache_key,
+            &output_defns,
+            arrival_dir.path(),
+            &out_dir,
+            min_size_to_cache,
+        )
+        .and_then(|()| check_manifest_before_pull(&*cache, &cache_key, &input_path, &package_id))
+        .and_then(|()| check_build_script_before_pull(&crate_unit_name))
+        .and_then(|()| {
+            plugin::check_pull(&plugin::Unit {
+                storage_name: Some(&storage_name),
+                ..plugin_unit
+            })
+        })
+        .and_then(|()| {
+            hooks::run(&HookEvent::BeforePull {
+                crate_unit_name: &crate_unit_name,
+                storage_name: &storage_name,
+            })
+        }) {
+            Err(err) => Err(err),
+            Ok(()) => match config::pull_budget()? {
+                Some(budget) => pull_within_budget(
+                    &cache,
+                    &cache_key,
+                    &output_defns,
+                    arrival_dir.path(),
+                    budget,
+                ),
+                None => cache.pull_crate(&cache_key, &output_defns, arrival_dir.path()),
+            },
+        }
+    };
+    // Whatever went wrong with the pull, building it instead isn't what's wanted now.
+    signals::check()?;
+    // Whether or not the remote cache had it, it's only news if we asked.
+    let asked = !observe && cheap_reason.is_none() && budget_reason.is_none();
+    if let (true, true, Some(profile_dir)) = (asked, cache.is_remote(), profile_dir) {
+        match &pull_result {
+            Err(err) if err.downcast_ref::<remote_fallback::RemoteError>().is_some() => {
+                remote_fallback::note_error(
+                    &LocalCache::dir_from_env()?,
+                    profile_dir,
+                    &crate_unit_name,
+                    &cache.location(),
+                    err,
+                )?;
+            }
+            _ => remote_fallback::note_answer(profile_dir)?,
+        }
+    }
+    let pulled = pull_result.is_ok();
+    if let (Some(profile_dir), None) = (profile_dir, &budget_reason) {
+        let pulled_bytes = if pulled {
+            output_defns
+                .iter()
+                .filter_map(|output_defn| {
+                    std::fs::metadata(
+                        arrival_dir
+                            .path()
+                            .join(output_defn.file_name(&crate_unit_name)),
+                    )
+                    .ok()
+                })
+                .map(|metadata| metadata.len())
+                .sum()
+        } else {
+            0
+        };
+        session_budget::spend(profile_dir, pull_started.elapsed(), pulled_bytes)?;
+    }
+    match pull_result {
         Ok(_) => {
+            lockfile_index::record_entry(&local_cache, &*cache, &cache_key)?;
+            if let Some(profile_dir) = profile_dir {
+                clean::record_entry(profile_dir, &cache_key)?;
+            }
+            hooks::notify(&HookEvent::Pulled {
+                crate_unit_name: &crate_unit_name,
+                storage_name: &storage_name,
+            });
+
             // Modify files in the arrival dir, and then copy them over to the target dir.
             //
             // TODO: If anything in here fails, then try to clean up any files
@@ -258,11 +499,13 @@ fn main() -> anyhow::Result<()> {
                 let file_name = output_defn.file_name(&crate_unit_name);
                 let arrival_path = arrival_dir.path().join(&file_name);
 
-                // Set the staging copy's mtime.
-                // See comments on `get_invoked_timestamp_for_crate_build_unit` for why we do this.
-                filetime::set_file_mtime(&arrival_path, invoked_timestamp).with_context(|| {
-                    format!("Failed to update mtime for arrival file {file_name:?}.")
-                })?;
+                // Set the staging copy's mtime, if need be.
+                // See the `mtime` module for why we do this.
+                if let Some(invoked_timestamp) = mtime_for_outputs {
+                    filetime::set_file_mtime(&arrival_path, invoked_timestamp).with_context(
+                        || format!("Failed to update mtime for arrival file {file_name:?}."),
+                    )?;
+                }
 
                 if *output_defn == OutputDefn::DepInfo {
                     // We want to remove most stuff from dep info files because the
@@ -272,6 +515,16 @@ fn main() -> anyhow::Result<()> {
                     let mut file = File::create(&arrival_path)?;
                     for line in dep_info_text.lines() {
                         let line = line.trim();
+                        // Checksum comments (from `-Z checksum-hash-algorithm`) name source
+                        // files too, so drop the ones in the build dir for the same reason
+                        // as we drop them from the dep lines below.
+                        if line.starts_with("# checksum:")
+                            && line
+                                .split(' ')
+                                .any(|word| out_dir_layout::is_in_build_dir(Path::new(word)))
+                        {
+                            continue;
+                        }
                         if line.is_empty() || line.starts_with('#') {
                             // Write it out unmodified.
                             writeln!(file, "{}", line)?;
@@ -283,11 +536,11 @@ fn main() -> anyhow::Result<()> {
                             .split_once(':')
                             .with_context(|| format!("Couldn't find ':' in line: {line}"))?;
 
-                        // TODO: Proper way to determine that it's in the build dir!
-                        // We should have enough information in context,
-                        // but we're not doing the absolute path replacement yet
-                        // so I'm just going with this dirty hack for right now.
-                        if left_side.contains("/build/") {
+                        // These are paths in the target dir of whoever pushed this,
+                        // which could be anywhere; see `out_dir_layout::is_in_build_dir`.
+                        //
+                        // TODO: Replace them with placeholders when pushing instead.
+                        if out_dir_layout::is_in_build_dir(Path::new(left_side)) {
                             // Skip the whole line.
                             continue;
                         } else {
@@ -304,11 +557,7 @@ fn main() -> anyhow::Result<()> {
                             .map(str::to_owned);
 
                         for dep in deps {
-                            // TODO: Proper way to determine that it's in the build dir!
-                            // We should have enough information in context,
-                            // but we're not doing the absolute path replacement yet
-                            // so I'm just going with this dirty hack for right now.
-                            if !dep.contains("/build/") {
+                            if !out_dir_layout::is_in_build_dir(Path::new(&dep)) {
                                 // It's not in the build dir, so we can depend on it
                                 // without it causing Cargo to constantly rebuild.
 
@@ -328,33 +577,52 @@ fn main() -> anyhow::Result<()> {
                     // for our target dir.
                 }
 
+                signals::check()?;
                 let path_in_out_dir = out_dir.join(&file_name);
-                std::fs::copy(arrival_path, &path_in_out_dir).with_context(|| {
-                    format!("Failed to copy file {file_name:?} from arrival directory to target directory.")
-                })?;
+                if direct_pull {
+                    std::fs::rename(arrival_path, &path_in_out_dir).with_context(|| {
+                        format!("Failed to move file {file_name:?} from arrival directory to target directory.")
+                    })?;
+                } else {
+                    copy_into_place(&arrival_path, &path_in_out_dir).with_context(|| {
+                        format!("Failed to copy file {file_name:?} from arrival directory to target directory.")
+                    })?;
+                }
             }
         }
-        Err(_) => {
+        Err(err) => {
             // TODO: We should care about the specific error when pulling!
+            hooks::notify(&HookEvent::Missed {
+                crate_unit_name: &crate_unit_name,
+                storage_name: &storage_name,
+                reason: format!("{err:#}"),
+            });
 
             // We weren't able to pull from cache, so we have to ask the real rustc to build it.
             // But first, we will see if there is a deferred build script to run.
-            let build_script_invocation_info_path =
-                out_dir.join(BUILD_SCRIPT_INVOCATION_INFO_FILE_NAME);
-            if build_script_invocation_info_path.exists() {
-                // Yep, there's a build script to run. Load the description of how
-                // we're supposed to run it, and then run it!
-                let build_script_invocation_info_json =
-                    std::fs::read_to_string(build_script_invocation_info_path)
-                        .context("Failed to read build script invocation info file")?;
-                let build_script_invocation_info: BuildScriptInvocationInfo =
-                    serde_json::from_str(&build_script_invocation_info_json)
-                        .context("Failed to deserialize build script invocation JSON")?;
-                let status = Command::new(&build_script_invocation_info.real_build_script_path)
-                    .current_dir(&build_script_invocation_info.work_dir)
-                    .envs(&build_script_invocation_info.env_vars)
-                    .status()
-                    .context("Failed to start (real) build script")?;
+            //
+            // The wrapper leaves its notes in the build script's out dir, which
+            // Cargo tells us about because it's also where the crate finds
+            // anything the build script generates.
+            let build_script_invocation_info_path = env::var_os("OUT_DIR")
+                .map(|out_dir| PathBuf::from(out_dir).join(BUILD_SCRIPT_INVOCATION_INFO_FILE_NAME))
+                .filter(|path| path.exists());
+            let build_script_invocation_info = match &build_script_invocation_info_path {
+                Some(path) => BuildScriptInvocationInfo::load_unless_stale(path)?,
+                None => None,
+            };
+            if let Some(build_script_invocation_info) = build_script_invocation_info {
+                // Yep, there's a build script to run. We've loaded the description
+                // of how we're supposed to run it, so now run it!
+                let (status, _) = signals::status(
+                    Command::new(&build_script_invocation_info.real_build_script_path)
+                        .current_dir(&build_script_invocation_info.work_dir)
+                        .envs(&build_script_invocation_info.env_vars)
+                        // Cargo already has its output, from when the wrapper replayed it.
+                        .stdout(Stdio::null()),
+                )
+                .context("Failed to start (real) build script")?;
+                signals::check()?;
                 if !status.success() {
                     std::process::exit(
                         status.code().context(
@@ -363,11 +631,18 @@ fn main() -> anyhow::Result<()> {
                     );
                 }
 
+                // It's done its job now; if this crate gets rebuilt later without
+                // the build script being rerun, the build script's outputs are
+                // all still here.
+                if let Some(path) = &build_script_invocation_info_path {
+                    std::fs::remove_file(path)
+                        .context("Failed to remove build script invocation info file")?;
+                }
+
                 // Rewind the mtime of anything we find in the build script out dir
-                // to avoid spurious rebuilds.
-                //
-                // See comments on `get_invoked_timestamp_for_crate_build_unit` for
-                // why this is important.
+                // to avoid spurious rebuilds. Whatever the `mtime` strategy, these
+                // were written while building the crate that uses them, which is
+                // later than Cargo expects build script outputs to be from.
                 let build_script_out_dir = build_script_invocation_info.out_dir()?;
                 let build_script_invoked_timestamp =
                     build_script_invocation_info.get_invoked_timestamp()?;
@@ -381,13 +656,37 @@ fn main() -> anyhow::Result<()> {
             }
 
             // Now we can run the real rustc!
-            run_real_rustc(&rustc_path, pass_through_args)?;
+            let before = Instant::now();
+            let started_at = Utc::now();
+            let remote = config::remote_builder().is_some_and(|builder| {
+                remote_build::try_build(&builder, &rustc_path, &args, &pass_through_args, &out_dir)
+            });
+            let usage = if remote {
+                None
+            } else {
+                Some(measure_real_rustc(&rustc_path, pass_through_args.clone())?)
+            };
+            let finished_at = Utc::now();
+            write_log_line(
+                &LocalCache::dir_from_env()?,
+                CacheLogLine::CompiledCrate(CompileCrateEvent {
+                    crate_unit_name: crate_unit_name.clone(),
+                    compiled_at: Utc::now(),
+                    duration_secs: before.elapsed().as_secs_f64(),
+                    remote,
+                    observed_hit,
+                    usage,
+                }),
+            )?;
 
             // Attempt to push the result to cache, via departure dir.
+            //
+            // The outputs go there first, even when pushing in the background,
+            // so that what gets pushed is what was built, even if Cargo (or
+            // anyone else) changes the target dir as soon as we've exited.
             let departure_dir = tempdir().with_context(|| {
                 format!("Failed to create departure dir for crate {crate_unit_name}.")
             })?;
-
             for output_defn in &output_defns {
                 let file_name = output_defn.file_name(&crate_unit_name);
                 let path_in_out_dir = out_dir.join(&file_name);
@@ -400,20 +699,219 @@ fn main() -> anyhow::Result<()> {
                     format!("Failed to copy file {file_name:?} from target directory to departure directory.")
                 })?;
             }
-
-            cache
-                .push_crate(&crate_unit_name, &output_defns, departure_dir.path())
-                .context("Failed to push to cache")?;
+            let push = |departure_dir: &Path| -> anyhow::Result<()> {
+                let artifact_paths: Vec<PathBuf> = output_defns
+                    .iter()
+                    .filter(|output_defn| **output_defn != OutputDefn::DepInfo)
+                    .map(|output_defn| departure_dir.join(output_defn.file_name(&crate_unit_name)))
+                    .collect();
+                let portability = portability::check(&artifact_paths)
+                    .context("Failed to check portability of build outputs")?;
+                write_log_line(
+                    &LocalCache::dir_from_env()?,
+                    CacheLogLine::CheckedPortability(PortabilityCheckEvent {
+                        crate_unit_name: crate_unit_name.clone(),
+                        checked_at: Utc::now(),
+                        score: portability.score,
+                        leaked_prefixes: portability.leaked_prefixes.clone(),
+                    }),
+                )?;
+
+                let push_candidate = PushCandidate {
+                    input_path: &input_path,
+                    crate_unit_name: &crate_unit_name,
+                    output_defns: &output_defns,
+                    departure_dir,
+                    portability: &portability,
+                    remaps_path_prefixes: !args.remap_path_prefixes.is_empty(),
+                    min_size: min_size_to_cache,
+                    cheap_reason: cheap_reason.as_deref(),
+                };
+                let mut manifest = EntryManifest::new(
+                    &cache_key,
+                    BuildEnvironment::capture(&rustc_info, &native_toolchain, &args),
+                );
+                manifest.package_id = Some(package_id);
+                manifest.features = Some(args.features());
+                if output_defns.contains(&OutputDefn::DepInfo) {
+                    // So that pulls elsewhere can check they have the same sources.
+                    manifest.sources = sources::hash_listed_sources(
+                        &input_path,
+                        &departure_dir.join(OutputDefn::DepInfo.file_name(&crate_unit_name)),
+                    )
+                    .context("Failed to hash sources listed in dep info")?;
+                }
+                let build = BuildRecord {
+                    package_name: &cargo_package_name,
+                    package_version: &cargo_package_version,
+                    rustc_path: &rustc_path,
+                    rustc_release: &rustc_info.release,
+                    args: &pass_through_args,
+                    externs: &args.extern_,
+                    started_at,
+                    finished_at,
+                };
+                // Each cache gets its own chance; one being unreachable
+                // (or full) shouldn't stop the others getting the entry.
+                let budget_reason = over_budget(&crate_unit_name)?;
+                // Remote pushes we can't do now, but could do later.
+                let deferred_reason = if config::read_only() {
+                    Some("read-only mode".to_owned())
+                } else if budget_reason.is_some() {
+                    budget_reason.clone()
+                } else if remote_fallback::has_fallen_back(profile_dir)? {
+                    Some("Gave up on the remote cache for this build".to_owned())
+                } else {
+                    None
+                };
+                let push_started = Instant::now();
+                for (push_target, target_name) in
+                    push_targets.iter().zip(cache::push_target_names())
+                {
+                    // Units too big for a remote cache are still worth keeping locally,
+                    // and pulls look there too (see `cache::from_env`).
+                    let oversized_reason = if push_target.is_remote() {
+                        over_remote_push_limit(&output_defns, &crate_unit_name, departure_dir)?
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = &oversized_reason {
+                        write_log_line(
+                            &LocalCache::dir_from_env()?,
+                            CacheLogLine::SkippedPush(SkipPushEvent {
+                                crate_unit_name: crate_unit_name.clone(),
+                                skipped_at: Utc::now(),
+                                reason: format!("{reason}; cached locally instead"),
+                            }),
+                        )?;
+                    }
+                    let push_target: &dyn Cache = match oversized_reason {
+                        Some(_) => &local_cache,
+                        None => &**push_target,
+                    };
+                    let skip_reason = match push_skip_reason(push_target, &push_candidate)? {
+                        Some(reason) => Some(reason),
+                        None if push_target.is_remote()
+                            && remote_fallback::refuses_pushes(
+                                profile_dir,
+                                &push_target.location(),
+                            )? =>
+                        {
+                            Some(format!(
+                                "{} refused an earlier push this build",
+                                push_target.location()
+                            ))
+                        }
+                        None if push_target.is_remote() => match &deferred_reason {
+                            // Build scripts' outputs get replaced with Hope itself
+                            // (see below), so there'd be nothing left to push.
+                            Some(reason) if !out_dir_layout::is_build_script_out_dir(&out_dir) => {
+                                let recorded = push_backlog::record(&push_backlog::SkippedPush {
+                                    target: &target_name,
+                                    reason,
+                                    key: &cache_key,
+                                    manifest: &manifest,
+                                    crate_types: &crate_types,
+                                    output_types: &output_types,
+                                    out_dir: &out_dir,
+                                });
+                                match recorded {
+                                    Ok(()) => {
+                                        Some(format!("{reason}; saved for `hope push-backlog`"))
+                                    }
+                                    Err(err) => {
+                                        eprintln!(
+                                            "Hope failed to save {crate_unit_name} for later: {err:#}"
+                                        );
+                                        Some(reason.clone())
+                                    }
+                                }
+                            }
+                            reason => reason.clone(),
+                        },
+                        None => None,
+                    };
+                    if let Some(reason) = skip_reason {
+                        write_log_line(
+                            &LocalCache::dir_from_env()?,
+                            CacheLogLine::SkippedPush(SkipPushEvent {
+                                crate_unit_name: crate_unit_name.clone(),
+                                skipped_at: Utc::now(),
+                                reason,
+                            }),
+                        )?;
+                    } else if let Err(err) = signals::check()
+                        .and_then(|()| {
+                            hooks::run(&HookEvent::BeforePush {
+                                crate_unit_name: &crate_unit_name,
+                                storage_name: &storage_name,
+                            })
+                        })
+                        .and_then(|()| {
+                            push_target.push_crate(
+                                &cache_key,
+                                &manifest,
+                                &output_defns,
+                                departure_dir,
+                            )
+                        })
+                    {
+                        // The build itself worked, so don't fail it just because
+                        // we couldn't share the results (unless we're being stopped).
+                        signals::check()?;
+                        eprintln!(
+                            "Hope failed to push {crate_unit_name} to {}: {err:#}",
+                            push_target.location()
+                        );
+                        if let (true, Some(profile_dir)) = (push_target.is_remote(), profile_dir) {
+                            if err.downcast_ref::<http_store::PushRefused>().is_some() {
+                                remote_fallback::note_push_refused(
+                                    profile_dir,
+                                    &push_target.location(),
+                                )?;
+                            } else {
+                                remote_fallback::note_error(
+                                    &LocalCache::dir_from_env()?,
+                                    profile_dir,
+                                    &crate_unit_name,
+                                    &push_target.location(),
+                                    &err,
+                                )?;
+                            }
+                        }
+                    } else {
+                        if let (true, Some(profile_dir)) = (push_target.is_remote(), profile_dir) {
+                            remote_fallback::note_answer(profile_dir)?;
+                        }
+                        lockfile_index::record_entry(&local_cache, push_target, &cache_key)?;
+                        if let Some(profile_dir) = profile_dir {
+                            clean::record_entry(profile_dir, &cache_key)?;
+                        }
+                        hooks::notify(&HookEvent::Pushed {
+                            crate_unit_name: &crate_unit_name,
+                            storage_name: &storage_name,
+                        });
+                        if let Err(err) = attestation::attest(push_target, &cache_key, &build) {
+                            eprintln!("Hope failed to attest {crate_unit_name}: {err:#}");
+                        }
+                    }
+                }
+                if let (Some(profile_dir), None) = (profile_dir, &budget_reason) {
+                    session_budget::spend(profile_dir, push_started.elapsed(), 0)?;
+                }
+                Ok(())
+            };
+            // Build scripts' outputs get replaced with Hope itself (see below)
+            // as soon as we're done here, so they have to be pushed first.
+            if config::background_push() && !out_dir_layout::is_build_script_out_dir(&out_dir) {
+                background_push::run(&crate_unit_name, departure_dir, push)?;
+            } else {
+                push(departure_dir.path())?;
+            }
         }
     };
 
-    if out_dir.components().any(|component| {
-        component
-            .as_os_str()
-            .to_str()
-            .expect("Bad string in out dir component")
-            == "build"
-    }) {
+    if out_dir_layout::is_build_script_out_dir(&out_dir) {
         // This looks like a build script.
         //
         // Whether we pulled the build script from cache or build it ourselves,
@@ -426,9 +924,11 @@ fn main() -> anyhow::Result<()> {
         // the former right now (on the assumption that what I replace it
         // with will get copied just fine) but I should probably understand why
         // both exist.
-        //
-        // TODO: Apply binary extension here if relevant.
-        let build_script_path = out_dir.join(&crate_unit_name);
+        let build_script_path = out_dir.join(
+            target
+                .link_naming(CrateType::Bin)
+                .file_name(&crate_unit_name),
+        );
         let moved_build_script_path = append_moved_build_script_suffix(&build_script_path)
             .context("Failed to append moved build script path suffix")?;
         std::fs::rename(&build_script_path, &moved_build_script_path)
@@ -440,7 +940,9 @@ fn main() -> anyhow::Result<()> {
         // TODO: I'd prefer to not have to do this, but I'm not sure
         // how to accurately infer the name from the kebab-case "build-script-build"
         // that we get called as.
-        let real_build_script_symlink_path = out_dir.join("real-build-script");
+        let real_build_script_symlink_path = out_dir.join(REAL_BUILD_SCRIPT_SYMLINK_NAME);
+        // There may be one left over from an interrupted build.
+        build_script::remove_if_exists(&real_build_script_symlink_path)?;
         std::os::unix::fs::symlink(moved_build_script_path, real_build_script_symlink_path)
             .context("Failed to create symlink to the real build script")?;
 
@@ -453,26 +955,347 @@ fn main() -> anyhow::Result<()> {
         std::fs::copy(current_exe, &build_script_path)
             .context("Failed to copy 'hope' binary to where build script would have been built")?;
 
-        // Set the copy's mtime.
-        // See comments on `get_invoked_timestamp_for_crate_build_unit` for why we do this.
-        filetime::set_file_mtime(&build_script_path, invoked_timestamp)
-            .with_context(|| format!("Failed to update mtime for {build_script_path:?}."))?;
+        // Leave a note for the wrapper about which build of the build script this was.
+        std::fs::write(
+            out_dir.join(BUILD_SCRIPT_KEY_FILE_NAME),
+            cache_key.short_digest(),
+        )
+        .context("Failed to write build script cache key file")?;
+
+        // And whether it has to run for real, with whole-package pulls.
+        let built_locally_path = out_dir.join(BUILT_LOCALLY_FILE_NAME);
+        if config::whole_package_pulls() && !pulled {
+            std::fs::write(&built_locally_path, "")
+                .context("Failed to write note that build script was built locally")?;
+        } else {
+            build_script::remove_if_exists(&built_locally_path)?;
+        }
+
+        // Set the copy's mtime, if need be.
+        // See the `mtime` module for why we do this.
+        if let Some(invoked_timestamp) = mtime_for_outputs {
+            filetime::set_file_mtime(&build_script_path, invoked_timestamp)
+                .with_context(|| format!("Failed to update mtime for {build_script_path:?}."))?;
+        }
     }
 
     Ok(())
 }
 
-fn run_real_rustc(rustc_path: &Path, pass_through_args: Vec<String>) -> anyhow::Result<()> {
-    let before = Instant::now();
-    // dbg!(&pass_through_args[0..usize::min(pass_through_args.len(), 3)]);
+/// Refuse to pull an entry whose manifest says it isn't what we're building.
+fn check_manifest_before_pull(
+    cache: &dyn Cache,
+    key: &CacheKey,
+    input_path: &Path,
+    package_id: &str,
+) -> anyhow::Result<()> {
+    let storage_name = cache.resolve_storage_name(key)?;
+    let Some(manifest) = EntryManifest::load(cache, &storage_name)? else {
+        // Nothing to pull, so nothing to check.
+        return Ok(());
+    };
+    check_sources_before_pull(key, &storage_name, &manifest, input_path)?;
+    check_package_before_pull(key, &storage_name, &manifest, package_id)
+}
+
+/// With `HOPE_AUDIT_SOURCES`, refuse to pull an entry that was built from
+/// different sources to the ones in the package's checkout here.
+fn check_sources_before_pull(
+    key: &CacheKey,
+    storage_name: &str,
+    manifest: &EntryManifest,
+    input_path: &Path,
+) -> anyhow::Result<()> {
+    if !config::audit_sources() {
+        return Ok(());
+    }
+    if let Some(path) = sources::audit(input_path, manifest)? {
+        eprintln!(
+            "Hope: Not pulling {} because {path:?} is missing or differs from \
+             the source it was built from; building it instead.",
+            key.unit_name
+        );
+        anyhow::bail!("Source file {path:?} doesn't match cache entry {storage_name:?}");
+    }
+    Ok(())
+}
+
+/// Refuse to pull an entry that belongs to a different package
+/// that just happens to have the same crate unit name.
+fn check_package_before_pull(
+    key: &CacheKey,
+    storage_name: &str,
+    manifest: &EntryManifest,
+    package_id: &str,
+) -> anyhow::Result<()> {
+    // Entries pushed by older versions of Hope don't say.
+    match &manifest.package_id {
+        Some(entry_package_id) if entry_package_id != package_id => {
+            eprintln!(
+                "Hope: Not pulling {} because it was built for {entry_package_id:?}, \
+                 not {package_id:?}; building it instead.",
+                key.unit_name
+            );
+            anyhow::bail!("Cache entry {storage_name:?} belongs to a different package");
+        }
+        _ => Ok(()),
+    }
+}
+
+/// With whole-package pulls, refuse to pull a crate whose build script
+/// ran here rather than having its output replayed from the cache.
+fn check_build_script_before_pull(crate_unit_name: &str) -> anyhow::Result<()> {
+    if !config::whole_package_pulls() {
+        return Ok(());
+    }
+    // Cargo only sets this for crates with build scripts.
+    let Some(out_dir) = env::var_os("OUT_DIR") else {
+        return Ok(());
+    };
+    if Path::new(&out_dir).join(BUILT_LOCALLY_FILE_NAME).exists() {
+        eprintln!(
+            "Hope: Not pulling {crate_unit_name} because its build script ran locally; \
+             building it instead."
+        );
+        anyhow::bail!("Build script for {crate_unit_name} ran locally");
+    }
+    Ok(())
+}
 
-    // TODO: Yeah, I'd like an explicit event for this,
-    // especially so that I can start collecting timings. :)
+/// Check that a unit's outputs are worth pulling, and would fit.
+///
+/// Refuse to pull if the outputs wouldn't fit, rather than running out of
+/// space part way through. (Building it instead might not fit either,
+/// but at least the warning tells the user what's going on.)
+///
+/// Also refuse if they're smaller than `min_size`; see `config::leaf_only`.
+fn check_pull_size(
+    cache: &dyn Cache,
+    key: &CacheKey,
+    output_defns: &[OutputDefn],
+    arrival_dir: &Path,
+    out_dir: &Path,
+    min_size: Option<u64>,
+) -> anyhow::Result<()> {
+    let Some(needed) = cache
+        .pull_size(key, output_defns)
+        .context(remote_fallback::RemoteError)?
+    else {
+        // Nothing to pull, so nothing to check.
+        return Ok(());
+    };
+    if let Some(min_size) = min_size.filter(|min_size| needed < *min_size) {
+        anyhow::bail!("Only {needed} bytes to pull, which isn't worth it (under {min_size})");
+    }
+    for dir in [arrival_dir, out_dir] {
+        if let Some(shortfall) = disk_space::shortfall(dir, needed)? {
+            eprintln!(
+                "Hope: Not pulling {} because {shortfall}; building it instead.",
+                key.unit_name
+            );
+            anyhow::bail!("Not enough free space to pull: {shortfall}");
+        }
+    }
+    Ok(())
+}
+
+/// Pull a unit, but give up if that takes longer than `budget`.
+///
+/// There's no way to cancel a pull part way through, so an abandoned pull
+/// just carries on in the background until we exit. That's harmless:
+/// it only writes to its own arrival dir. (But see `background_push::run`.)
+fn pull_within_budget(
+    cache: &Arc<dyn Cache>,
+    key: &CacheKey,
+    output_defns: &[OutputDefn],
+    arrival_dir: &Path,
+    budget: Duration,
+) -> anyhow::Result<()> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let pull_thread = {
+        let cache = Arc::clone(cache);
+        let key = key.clone();
+        let output_defns = output_defns.to_vec();
+        let arrival_dir = arrival_dir.to_owned();
+        std::thread::spawn(move || {
+            // Nobody will be listening if we took too long.
+            let _ = sender.send(cache.pull_crate(&key, &output_defns, &arrival_dir));
+        })
+    };
+    match receiver.recv_timeout(budget) {
+        Ok(pull_result) => {
+            // It's all but done; make sure it is before anyone forks.
+            let _ = pull_thread.join();
+            pull_result
+        }
+        Err(RecvTimeoutError::Timeout) => {
+            background_push::note_abandoned_thread();
+            write_log_line(
+                &LocalCache::dir_from_env()?,
+                CacheLogLine::AbandonedPull(AbandonPullEvent {
+                    crate_unit_name: key.unit_name.clone(),
+                    abandoned_at: Utc::now(),
+                    budget_secs: budget.as_secs_f64(),
+                }),
+            )?;
+            Err(anyhow::anyhow!("Pull didn't finish within {budget:?}")
+                .context(remote_fallback::RemoteError))
+        }
+        Err(RecvTimeoutError::Disconnected) => anyhow::bail!("Pull thread panicked"),
+    }
+}
 
-    let status = Command::new(rustc_path)
-        .args(pass_through_args)
-        .status()
+/// Everything we know about a freshly built unit that we might push.
+struct PushCandidate<'a> {
+    input_path: &'a Path,
+    crate_unit_name: &'a str,
+    output_defns: &'a [OutputDefn],
+    departure_dir: &'a Path,
+    portability: &'a PortabilityReport,
+    remaps_path_prefixes: bool,
+    /// Don't bother caching it if it's smaller than this; see `config::leaf_only`.
+    min_size: Option<u64>,
+    /// Why it's not worth caching, if it isn't; see `config::skip_cheap_units`.
+    cheap_reason: Option<&'a str>,
+}
+
+/// Decide whether there's a good reason to _not_ push a freshly built unit,
+/// even though we could.
+fn push_skip_reason(
+    cache: &dyn Cache,
+    candidate: &PushCandidate,
+) -> anyhow::Result<Option<String>> {
+    if config::verify_sources() {
+        let dep_info_path = candidate
+            .output_defns
+            .contains(&OutputDefn::DepInfo)
+            .then(|| {
+                candidate
+                    .departure_dir
+                    .join(OutputDefn::DepInfo.file_name(candidate.crate_unit_name))
+            });
+        let modified_source =
+            sources::find_modified_source(candidate.input_path, dep_info_path.as_deref())
+                .context("Failed to verify registry sources")?;
+        if let Some(modified_source) = modified_source {
+            return Ok(Some(format!(
+                "source file {modified_source:?} doesn't match the registry's crate archive"
+            )));
+        }
+    }
+
+    if let Some(cheap_reason) = candidate.cheap_reason {
+        return Ok(Some(format!("not worth caching: {cheap_reason}")));
+    }
+
+    let mut needed = 0;
+    for output_defn in candidate.output_defns {
+        let file_name = output_defn.file_name(candidate.crate_unit_name);
+        needed += std::fs::metadata(candidate.departure_dir.join(&file_name))
+            .with_context(|| format!("Failed to get metadata for departing file {file_name:?}"))?
+            .len();
+    }
+
+    if let Some(min_size) = candidate.min_size.filter(|min_size| needed < *min_size) {
+        return Ok(Some(format!(
+            "not a direct dependency of the workspace, and only {needed} bytes \
+             (under the leaf-only minimum of {min_size} bytes)"
+        )));
+    }
+
+    if let Some(local_dir) = cache.local_dir() {
+        if let Some(shortfall) = disk_space::shortfall(local_dir, needed)? {
+            return Ok(Some(format!("not enough free space: {shortfall}")));
+        }
+    }
+
+    if !cache.is_remote() {
+        return Ok(None);
+    }
+
+    if config::require_portable_remote_pushes()
+        && !candidate.portability.is_portable()
+        && !candidate.remaps_path_prefixes
+    {
+        return Ok(Some(format!(
+            "outputs contain machine-specific paths ({}) and '--remap-path-prefix' isn't in use",
+            candidate.portability.leaked_prefixes.join(", ")
+        )));
+    }
+
+    Ok(None)
+}
+
+/// If any of a unit's outputs in `dir` is too big to push to a remote cache
+/// (see `config::max_remote_push_size`), say which.
+pub(crate) fn over_remote_push_limit(
+    output_defns: &[OutputDefn],
+    crate_unit_name: &str,
+    dir: &Path,
+) -> anyhow::Result<Option<String>> {
+    let Some(max_size) = config::max_remote_push_size()? else {
+        return Ok(None);
+    };
+    for output_defn in output_defns {
+        let file_name = output_defn.file_name(crate_unit_name);
+        let size = std::fs::metadata(dir.join(&file_name))
+            .with_context(|| format!("Failed to get metadata for file {file_name:?}"))?
+            .len();
+        if size > max_size {
+            return Ok(Some(format!(
+                "{file_name} is {size} bytes, which is over the remote push limit of {max_size} bytes"
+            )));
+        }
+    }
+    Ok(None)
+}
+
+/// Is caching enabled for this package, given the rollout percentage?
+///
+/// This goes by a hash of the package name, so the same packages
+/// are in (or out) on every build and every machine, and raising
+/// the percentage only ever adds packages. Going by package rather
+/// than unit means a build script always shares its library's fate.
+fn in_rollout(package_name: &str, percent: u8) -> bool {
+    let hash = chunks::hash_bytes(package_name.as_bytes());
+    let bucket = u64::from_str_radix(&hash[..16], 16).expect("Hash should be hex") % 100;
+    bucket < u64::from(percent)
+}
+
+/// Is Cargo using checksums rather than mtimes to decide what's fresh?
+///
+/// Under `-Z checksum-freshness`, Cargo asks `rustc` to record checksums
+/// of source files in the dep info file, which we can detect from the arguments.
+fn uses_checksum_freshness(args: &Args) -> bool {
+    args.unstable_options
+        .iter()
+        .any(|option| option.starts_with("checksum-hash-algorithm="))
+}
+
+/// Copy a file via a temporary file next to its destination, so that
+/// nobody ever sees (or gets left with) a partial copy.
+fn copy_into_place(from_path: &Path, to_path: &Path) -> anyhow::Result<()> {
+    let dir = to_path.parent().context("Destination has no parent dir")?;
+    let temp_file =
+        tempfile::NamedTempFile::new_in(dir).context("Failed to create temporary file")?;
+    std::fs::copy(from_path, temp_file.path())?;
+    temp_file.persist(to_path)?;
+    Ok(())
+}
+
+fn run_real_rustc(rustc_path: &Path, pass_through_args: Vec<String>) -> anyhow::Result<()> {
+    measure_real_rustc(rustc_path, pass_through_args)?;
+    Ok(())
+}
+
+/// Run the real `rustc`, and report what it used.
+fn measure_real_rustc(
+    rustc_path: &Path,
+    pass_through_args: Vec<String>,
+) -> anyhow::Result<ResourceUsage> {
+    let (status, usage) = signals::status(Command::new(rustc_path).args(pass_through_args))
         .context("Failed to start real `rustc`")?;
+    signals::check()?;
     if !status.success() {
         std::process::exit(
             status
@@ -480,19 +1303,13 @@ fn run_real_rustc(rustc_path: &Path, pass_through_args: Vec<String>) -> anyhow::
                 .context("Child `rustc` process was terminated by a signal")?,
         );
     }
-
-    // DEBUG: TODO: Put behind a verbose flag or something.
-    // Or just put it in the structured log.
-    // eprintln!("Real rustc took: {}", before.elapsed().as_secs_f32());
-    let _ = before;
-
-    Ok(())
+    Ok(usage)
 }
 
 /// Different types of crates that `rustc` can compile.
 ///
 /// These are selected with the `--crate-type` argument.
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 enum CrateType {
     // Assumed to be the same as rlib for now. But that's not guaranteed!
     Lib,
@@ -524,7 +1341,7 @@ impl FromStr for CrateType {
 /// Different types of outputs created by `rustc`.
 ///
 /// These are selected with the `--emit` argument.
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 enum OutputType {
     Asm,
     LlvmBc,
@@ -554,18 +1371,23 @@ impl FromStr for OutputType {
     }
 }
 
-/// Output type with crate type for the `Link` output type.
+/// Output type with crate type for the `Link` output type,
+/// plus any extra files that the target writes when linking.
 ///
 /// This is enough information to generate an output file name
 /// given a base name.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 enum OutputDefn {
     Asm,
     LlvmBc,
     LlvmIr,
     Obj,
     Metadata,
-    Link(CrateType),
+    Link(CrateType, FileNaming),
+    /// What Windows links against to use a DLL.
+    ImportLib(FileNaming),
+    /// MSVC debug info.
+    Pdb,
     DepInfo,
     Mir,
 }
@@ -578,23 +1400,8 @@ impl OutputDefn {
             Self::LlvmIr => format!("{crate_unit_name}.ll"),
             Self::Obj => format!("{crate_unit_name}.o"),
             Self::Metadata => format!("lib{crate_unit_name}.rmeta"),
-            Self::Link(crate_type) => {
-                // TODO: This should depend on platform for many of these types!
-                match crate_type {
-                    // Assume lib is rlib for now, but that is not necessarily going
-                    // to be true forever.
-                    CrateType::Lib => format!("lib{crate_unit_name}.rlib"),
-                    CrateType::Rlib => format!("lib{crate_unit_name}.rlib"),
-                    CrateType::Staticlib => todo!(),
-                    CrateType::Dylib => todo!(),
-                    CrateType::Cdylib => todo!(),
-                    CrateType::Bin => crate_unit_name.to_owned(),
-                    #[cfg(target_os = "linux")]
-                    CrateType::ProcMacro => format!("lib{crate_unit_name}.so"),
-                    #[cfg(target_os = "macos")]
-                    CrateType::ProcMacro => format!("lib{crate_unit_name}.dylib"),
-                }
-            }
+            Self::Link(_, naming) | Self::ImportLib(naming) => naming.file_name(crate_unit_name),
+            Self::Pdb => format!("{crate_unit_name}.pdb"),
             // TODO: This will need to be modified on push/pull to stop cargo from getting
             // confused and constantly trying to rebuild the crate.
             //
@@ -606,10 +1413,11 @@ impl OutputDefn {
 }
 
 /// Return a list of all the outputs we should be creating,
-/// based on the '--emit' and '--crate-type' flags.
+/// based on the '--emit' and '--crate-type' flags, and the target.
 fn output_defns(
     crate_types: &HashSet<CrateType>,
     output_types: &HashSet<OutputType>,
+    target: &Target,
 ) -> Vec<OutputDefn> {
     let mut output_defns = vec![];
     for output_type in output_types {
@@ -621,18 +1429,19 @@ fn output_defns(
             OutputType::Metadata => output_defns.push(OutputDefn::Metadata),
             OutputType::Link => {
                 for crate_type in crate_types {
-                    match crate_type {
-                        CrateType::Lib => output_defns.push(OutputDefn::Link(CrateType::Lib)),
-                        CrateType::Rlib => output_defns.push(OutputDefn::Link(CrateType::Rlib)),
-                        CrateType::Staticlib => {
-                            output_defns.push(OutputDefn::Link(CrateType::Staticlib))
-                        }
-                        CrateType::Dylib => output_defns.push(OutputDefn::Link(CrateType::Dylib)),
-                        CrateType::Cdylib => output_defns.push(OutputDefn::Link(CrateType::Cdylib)),
-                        CrateType::Bin => output_defns.push(OutputDefn::Link(CrateType::Bin)),
-                        CrateType::ProcMacro => {
-                            output_defns.push(OutputDefn::Link(CrateType::ProcMacro))
-                        }
+                    if !target.supports_crate_type(*crate_type) {
+                        continue;
+                    }
+                    output_defns.push(OutputDefn::Link(
+                        *crate_type,
+                        target.link_naming(*crate_type),
+                    ));
+                    if let Some(naming) = target.import_lib_naming(*crate_type) {
+                        output_defns.push(OutputDefn::ImportLib(naming));
+                    }
+                    // The PDB is named for the unit, so there's only ever one.
+                    if target.has_pdb(*crate_type) && !output_defns.contains(&OutputDefn::Pdb) {
+                        output_defns.push(OutputDefn::Pdb);
                     }
                 }
             }
@@ -651,36 +1460,23 @@ fn output_defns(
 /// thinks it started the build for this unit, and less than when Cargo
 /// thinks it started any _downstream_ unit.
 ///
-/// In my testing, it seems that:
+/// The fingerprint directory is keyed by the same hash that Cargo uses
+/// for `-C extra-filename` (which, in recent Cargo versions, is no longer
+/// the same as `-C metadata`).
 ///
-/// - This is necessary on macOS, because mtime isn't
-///   implicitly bumped there when using `std::fs::copy`. (TODO: Verify this!)
-///
-/// - If we do it, it has to come from an actual file,
-///   _not_ `FileTime::now`, because `SystemTime` doesn't
-///   agree with filesystem timestamps on Linux. I've observed
-///   a SystemTime fetched after modifying a file to be supposedly
-///   _before_ I modified the file. (TODO: It's just as likely that
-///   this is some huge misunderstanding of mine, so understand this better!)
+/// See the `mtime` module for when we need this, and why it has to come
+/// from a file rather than the system clock.
 fn get_invoked_timestamp_for_crate_build_unit(
     out_dir: &Path,
     cargo_package_name: &str,
-    metadata_hash: &str,
+    unit_hash: &str,
 ) -> anyhow::Result<filetime::FileTime> {
-    // First, walk up until we find a directory with a ".fingerprint" directory inside it.
-    let mut path = out_dir;
-    let fingerprint_dir_path = loop {
-        let fingerprint_dir_path = path.join(".fingerprint");
-        if fingerprint_dir_path.exists() {
-            break fingerprint_dir_path;
-        }
-        path = path
-            .parent()
-            .context("Reached root dir without finding \".fingerprint\" directory")?;
-    };
+    let fingerprint_dir_path = out_dir_layout::profile_dir(out_dir)
+        .context("Reached root dir without finding \".fingerprint\" directory")?
+        .join(".fingerprint");
     // Now read the mtime of the "invoked.timestamp" file for this crate build unit.
     let invoked_timestamp_path = fingerprint_dir_path
-        .join(format!("{cargo_package_name}-{metadata_hash}"))
+        .join(format!("{cargo_package_name}-{unit_hash}"))
         .join("invoked.timestamp");
     let invoked_timestamp_file_metadata = std::fs::metadata(invoked_timestamp_path).context(
         "Failed to get metadata for \"invoked.timestamp\" file; maybe it doesn't exist?",
@@ -0,0 +1,187 @@
+//! Keeping recently-used blobs in memory, in front of some other store.
+//!
+//! `hope daemon` serves the local cache through one of these, so that the
+//! hottest artifacts (think `serde` or `syn` metadata) come straight from
+//! memory when lots of local builds pull them over and over, like in a test
+//! matrix. It's bounded by total size, and forgets the least recently used
+//! blobs first. With `--socket`, it does the same for the remote cache (see
+//! the `daemon_socket` module).
+//!
+//! Blobs are only ever written through to the underlying store; memory is
+//! just a copy, so nothing is lost if the daemon goes away.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use serde::Serialize;
+
+use crate::{cache::RemoteBlobStore, chunks::BlobStore};
+
+/// Blobs bigger than this fraction of the capacity aren't kept,
+/// so that one huge artifact can't push out everything else.
+const MAX_BLOB_FRACTION: u64 = 8;
+
+pub struct MemoryCache<S> {
+    store: S,
+    /// Total size of blobs to keep, in bytes.
+    capacity: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Each blob, and when it was last used.
+    blobs: HashMap<String, (Vec<u8>, u64)>,
+    /// Keys by when they were last used, oldest first.
+    by_last_used: BTreeMap<u64, String>,
+    size: u64,
+    /// Counts up on every use; only the order matters.
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemoryCacheStats {
+    pub entries: usize,
+    pub size_bytes: u64,
+    pub capacity_bytes: u64,
+    /// Blobs served from memory since the daemon started.
+    pub hits: u64,
+    /// Blobs that had to be read from the underlying store.
+    pub misses: u64,
+}
+
+impl<S: BlobStore> MemoryCache<S> {
+    pub fn new(store: S, capacity: u64) -> Self {
+        Self {
+            store,
+            capacity,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn stats(&self) -> MemoryCacheStats {
+        let state = self.state.lock().expect("Memory cache was poisoned");
+        MemoryCacheStats {
+            entries: state.blobs.len(),
+            size_bytes: state.size,
+            capacity_bytes: self.capacity,
+            hits: state.hits,
+            misses: state.misses,
+        }
+    }
+}
+
+impl State {
+    fn touch(&mut self, key: &str) -> Option<&[u8]> {
+        self.clock += 1;
+        let (bytes, last_used) = self.blobs.get_mut(key)?;
+        self.by_last_used.remove(last_used);
+        *last_used = self.clock;
+        self.by_last_used.insert(self.clock, key.to_owned());
+        Some(bytes)
+    }
+
+    fn insert(&mut self, key: &str, bytes: Vec<u8>, capacity: u64) {
+        self.remove(key);
+        let size = bytes.len() as u64;
+        if size > capacity / MAX_BLOB_FRACTION {
+            return;
+        }
+        while self.size + size > capacity {
+            let Some((_, oldest)) = self.by_last_used.pop_first() else {
+                break;
+            };
+            if let Some((bytes, _)) = self.blobs.remove(&oldest) {
+                self.size -= bytes.len() as u64;
+            }
+        }
+        self.clock += 1;
+        self.size += size;
+        self.by_last_used.insert(self.clock, key.to_owned());
+        self.blobs.insert(key.to_owned(), (bytes, self.clock));
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((bytes, last_used)) = self.blobs.remove(key) {
+            self.by_last_used.remove(&last_used);
+            self.size -= bytes.len() as u64;
+        }
+    }
+}
+
+impl<S: BlobStore> BlobStore for MemoryCache<S> {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        {
+            let mut state = self.state.lock().expect("Memory cache was poisoned");
+            if let Some(bytes) = state.touch(key) {
+                let bytes = bytes.to_vec();
+                state.hits += 1;
+                return Ok(bytes);
+            }
+            state.misses += 1;
+        }
+        // Don't hold the lock while reading from the store,
+        // so that hits can still be served in the meantime.
+        let bytes = self.store.get_blob(key)?;
+        self.state
+            .lock()
+            .expect("Memory cache was poisoned")
+            .insert(key, bytes.clone(), self.capacity);
+        Ok(bytes)
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.store.put_blob(key, bytes)?;
+        // Most keys only ever have one value, but aliases get replaced.
+        self.state
+            .lock()
+            .expect("Memory cache was poisoned")
+            .remove(key);
+        Ok(())
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        if self
+            .state
+            .lock()
+            .expect("Memory cache was poisoned")
+            .blobs
+            .contains_key(key)
+        {
+            return Ok(true);
+        }
+        self.store.has_blob(key)
+    }
+}
+
+impl<S: RemoteBlobStore> RemoteBlobStore for MemoryCache<S> {
+    fn url(&self) -> String {
+        self.store.url()
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        let state = self.state.lock().expect("Memory cache was poisoned");
+        if let Some((bytes, _)) = state.blobs.get(key) {
+            return Ok(Some(bytes.len() as u64));
+        }
+        drop(state);
+        self.store.blob_size(key)
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        let state = self.state.lock().expect("Memory cache was poisoned");
+        if let Some((bytes, _)) = state.blobs.get(key) {
+            return Ok(bytes[bytes.len().saturating_sub(len)..].to_vec());
+        }
+        drop(state);
+        self.store.get_blob_tail(key, len)
+    }
+
+    fn list_blobs(&self) -> anyhow::Result<Vec<String>> {
+        self.store.list_blobs()
+    }
+}
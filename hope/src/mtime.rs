@@ -0,0 +1,147 @@
+//! How we make the files we put in the target dir look fresh to Cargo.
+//!
+//! Unless it's using checksums, Cargo decides whether a unit is fresh by
+//! comparing mtimes: a unit's outputs need to be no older than when Cargo
+//! started building it (the mtime of its `invoked.timestamp` file), and
+//! older than anything built after it. Two facts about mtimes decide how
+//! we arrange that, and `hope mtime-probe` checks them on the machine it
+//! runs on (as do the integration tests, on every platform CI covers):
+//!
+//! - **Copies keep the source's mtime on macOS.** `std::fs::copy` clones
+//!   the file on APFS, and a clone keeps the original's timestamps. So a
+//!   file copied out of the cache looks as old as when it was pushed,
+//!   and Cargo would rebuild everything that depends on it. On Linux, the
+//!   copy is a new file, so its mtime is when we wrote it.
+//!
+//! - **The filesystem's clock lags the system clock on Linux.** File
+//!   timestamps come from a coarse clock that only ticks every few
+//!   milliseconds, so a file written just _after_ reading `SystemTime::now`
+//!   usually has an mtime from just _before_. Mtimes we set ourselves
+//!   therefore have to come from another file, not from the system clock.
+//!
+//! Putting those together gives us [`Strategy::InvokedTimestamp`] where
+//! copies keep their mtime, and [`Strategy::AsWritten`] everywhere else.
+
+use std::{path::Path, time::SystemTime};
+
+use anyhow::Context;
+use serde::Serialize;
+
+/// How many files to write when checking for clock lag. The lag shows up
+/// on nearly every write where there is any, so this is plenty.
+const CLOCK_LAG_SAMPLES: usize = 100;
+
+/// What we know about mtimes on some platform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Findings {
+    /// Does `std::fs::copy` keep the source file's mtime?
+    pub copy_preserves_mtime: bool,
+    /// Can a file written after reading `SystemTime::now` have an older mtime?
+    pub fs_clock_lags_system_time: bool,
+}
+
+/// What we've found on the platform we're built for. Nobody has seen the
+/// filesystem clock lag on macOS, but nothing relies on it not doing so.
+pub const EXPECTED: Findings = Findings {
+    copy_preserves_mtime: cfg!(target_os = "macos"),
+    fs_clock_lags_system_time: cfg!(target_os = "linux"),
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Leave files with whatever mtime they got when we wrote them, which is
+    /// after Cargo started building the unit and before it starts anything
+    /// downstream.
+    AsWritten,
+    /// Set files' mtimes to that of the unit's `invoked.timestamp` file.
+    ///
+    /// Not to the current time, because the system clock can't be compared
+    /// with filesystem timestamps (see the module docs).
+    InvokedTimestamp,
+}
+
+/// Which strategy to use, given what we know about the platform.
+pub fn strategy(findings: &Findings) -> Strategy {
+    if findings.copy_preserves_mtime {
+        Strategy::InvokedTimestamp
+    } else {
+        Strategy::AsWritten
+    }
+}
+
+/// Check the facts that [`strategy`] depends on, using files in `dir`.
+pub fn probe(dir: &Path) -> anyhow::Result<Findings> {
+    let source_path = dir.join("source");
+    std::fs::write(&source_path, "source").context("Failed to write probe file")?;
+    // An hour ago is far enough back to be sure about, whatever the clocks are up to.
+    let an_hour_ago =
+        filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() - 60 * 60, 0);
+    filetime::set_file_mtime(&source_path, an_hour_ago)
+        .context("Failed to set mtime of probe file")?;
+    let copy_path = dir.join("copy");
+    std::fs::copy(&source_path, &copy_path).context("Failed to copy probe file")?;
+    let copy_mtime = filetime::FileTime::from_last_modification_time(
+        &std::fs::metadata(&copy_path).context("Failed to stat copied probe file")?,
+    );
+
+    let mut fs_clock_lags_system_time = false;
+    for sample in 0..CLOCK_LAG_SAMPLES {
+        let before = SystemTime::now();
+        let sample_path = dir.join(format!("sample-{sample}"));
+        std::fs::write(&sample_path, "sample").context("Failed to write probe file")?;
+        let mtime = std::fs::metadata(&sample_path)
+            .and_then(|metadata| metadata.modified())
+            .context("Failed to get mtime of probe file")?;
+        if mtime < before {
+            fs_clock_lags_system_time = true;
+            break;
+        }
+    }
+
+    Ok(Findings {
+        copy_preserves_mtime: copy_mtime == an_hour_ago,
+        fs_clock_lags_system_time,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    expected: Findings,
+    found: Findings,
+    strategy: Strategy,
+}
+
+/// Probe the filesystem holding `dir` (default: the current dir, which is
+/// usually where the target dir is) and report how it compares to what we
+/// expect, failing if our strategy won't work there.
+pub fn run(dir: Option<&Path>, json: bool) -> anyhow::Result<()> {
+    let dir = dir.unwrap_or(Path::new("."));
+    let probe_dir = tempfile::tempdir_in(dir)
+        .with_context(|| format!("Failed to create temp dir in {dir:?}"))?;
+    let report = Report {
+        expected: EXPECTED,
+        found: probe(probe_dir.path())?,
+        strategy: strategy(&EXPECTED),
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!(
+            "copy preserves mtime:      expected {}, found {}",
+            report.expected.copy_preserves_mtime, report.found.copy_preserves_mtime
+        );
+        println!(
+            "fs clock lags system time: expected {}, found {}",
+            report.expected.fs_clock_lags_system_time, report.found.fs_clock_lags_system_time
+        );
+        println!("strategy: {:?}", report.strategy);
+    }
+    // Setting mtimes ourselves works anywhere; leaving them alone only works
+    // if copies get fresh ones.
+    anyhow::ensure!(
+        report.strategy == Strategy::InvokedTimestamp || !report.found.copy_preserves_mtime,
+        "Copies keep their mtimes on this filesystem, so pulled files will look stale to Cargo"
+    );
+    Ok(())
+}
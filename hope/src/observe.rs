@@ -0,0 +1,121 @@
+//! Trying Hope out without trusting it yet.
+//!
+//! In observe mode (`HOPE_OBSERVE=1`) every unit is built by the real
+//! `rustc`, exactly as it would be without Hope, but is still pushed to
+//! the cache, and we note which units we could have pulled instead.
+//! `hope observe` runs a build that way and then adds up the compile times
+//! we recorded, to project how much time the cache would save, both for
+//! this build and for a rebuild with a warm cache, so evaluating Hope
+//! doesn't take a second run.
+//!
+//! Compile times are summed over all of Cargo's jobs, so with lots of
+//! parallelism the wall clock savings will be smaller. Time spent pulling
+//! isn't counted either, but that's usually small in comparison.
+
+use std::{collections::HashSet, env, process::Command};
+
+use anyhow::Context;
+use hope_cache_log::{read_log, CacheLogLine};
+
+use crate::cache::LocalCache;
+
+/// Run `cargo build` (plus any extra `cargo_args`) for the project in the
+/// current directory in observe mode, and print a projection of the savings.
+pub fn run(cargo_args: &[String]) -> anyhow::Result<()> {
+    let cache_dir = LocalCache::dir_from_env()?;
+    // There's no log at all until something has been cached.
+    let log_len_before = read_log(&cache_dir).map_or(0, |log| log.len());
+
+    let hope_path = env::current_exe().context("Failed to get path to 'hope' exe")?;
+    let status = Command::new("cargo")
+        .arg("build")
+        .args(cargo_args)
+        .env("RUSTC_WRAPPER", hope_path)
+        .env("HOPE_OBSERVE", "1")
+        .status()
+        .context("Failed to start Cargo")?;
+    anyhow::ensure!(status.success(), "'cargo build' failed");
+
+    let log = read_log(&cache_dir).unwrap_or_default();
+    // Anything else sharing the cache at the same time will muddy this
+    // a little, but it's only an estimate anyway.
+    let projection = Projection::from_log(log.get(log_len_before..).unwrap_or_default());
+    projection.print();
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct Projection {
+    /// Units we could have cached, and how long `rustc` took over them.
+    compiled: Tally,
+    /// Units we could have pulled this time.
+    hits: Tally,
+    /// Units that would come from the cache next time.
+    warm_hits: Tally,
+}
+
+#[derive(Debug, Default)]
+struct Tally {
+    units: usize,
+    secs: f64,
+}
+
+impl Tally {
+    fn add(&mut self, secs: f64) {
+        self.units += 1;
+        self.secs += secs;
+    }
+}
+
+impl Projection {
+    fn from_log(log: &[CacheLogLine]) -> Self {
+        let pushed: HashSet<&str> = log
+            .iter()
+            .filter_map(|line| match line {
+                CacheLogLine::PushedCrateOutputs(event) => Some(event.crate_unit_name.as_str()),
+                _ => None,
+            })
+            .collect();
+        let mut projection = Self::default();
+        for line in log {
+            let CacheLogLine::CompiledCrate(event) = line else {
+                continue;
+            };
+            projection.compiled.add(event.duration_secs);
+            if event.observed_hit {
+                projection.hits.add(event.duration_secs);
+            }
+            if event.observed_hit || pushed.contains(event.crate_unit_name.as_str()) {
+                projection.warm_hits.add(event.duration_secs);
+            }
+        }
+        projection
+    }
+
+    fn print(&self) {
+        println!(
+            "Compiled {} units that Hope could cache, taking {:.1}s of rustc time.",
+            self.compiled.units, self.compiled.secs
+        );
+        println!(
+            "    {} of them ({:.1}s, {:.0}%) were already cached, and would have been pulled.",
+            self.hits.units,
+            self.hits.secs,
+            self.percent_of_compiled(self.hits.secs)
+        );
+        println!(
+            "    {} of them ({:.1}s, {:.0}%) would be pulled when rebuilding with a warm cache.",
+            self.warm_hits.units,
+            self.warm_hits.secs,
+            self.percent_of_compiled(self.warm_hits.secs)
+        );
+    }
+
+    fn percent_of_compiled(&self, secs: f64) -> f64 {
+        if self.compiled.secs > 0.0 {
+            secs / self.compiled.secs * 100.0
+        } else {
+            0.0
+        }
+    }
+}
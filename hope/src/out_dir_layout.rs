@@ -0,0 +1,213 @@
+//! What we assume about how Cargo lays out its target dir.
+//!
+//! This is a private implementation detail of Cargo, but we rely on it to
+//! find out which build script run a build script's `OUT_DIR` belongs to,
+//! and where Cargo keeps that run's output. Cargo's maintainers have been
+//! very conservative about changing it, but it's not set in stone, so
+//! everything we assume about it lives here. We recognise two layouts:
+//!
+//! - The long-standing one, where a build script run's `OUT_DIR` is
+//!   "{profile dir}/build/{package}-{metadata hash}/out", and Cargo keeps
+//!   its stdout in "output" and its "invoked.timestamp" next to that.
+//!
+//! - Cargo's per-package build dir layout (`-Z build-dir-new-layout`), where
+//!   it's "{profile dir}/build/{package}/{metadata hash}/out", and those files
+//!   are in "run/stdout" and "run/invoked.timestamp" instead. Every unit gets
+//!   an out dir like that in this layout, which we don't support caching yet.
+//!
+//! Anything else is an `UnrecognisedLayout`, which we log and then get
+//! out of the way, rather than guess and risk breaking the build.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use hope_cache_log::{write_log_line, CacheLogLine, UnrecognisedLayoutEvent};
+
+use crate::cache::LocalCache;
+
+/// A build script run, as identified by its `OUT_DIR`.
+#[derive(Debug)]
+pub struct BuildScriptRunDir {
+    pub package_name: String,
+    pub metadata_hash: String,
+    /// Where Cargo keeps the run's stdout and "invoked.timestamp".
+    run_files_dir: PathBuf,
+    stdout_file_name: &'static str,
+}
+
+impl BuildScriptRunDir {
+    pub fn parse(out_dir: &Path) -> Result<Self, UnrecognisedLayout> {
+        let unrecognised = |problem: &str| UnrecognisedLayout {
+            path: out_dir.to_owned(),
+            problem: problem.to_owned(),
+        };
+        if out_dir.file_name().and_then(|name| name.to_str()) != Some("out") {
+            return Err(unrecognised("expected 'OUT_DIR' to be called \"out\""));
+        }
+        let run_dir = out_dir
+            .parent()
+            .ok_or_else(|| unrecognised("'OUT_DIR' has no parent"))?;
+        let run_dir_name = file_name(run_dir)
+            .ok_or_else(|| unrecognised("'OUT_DIR' parent has no (UTF-8) name"))?;
+
+        if is_package_and_hash(run_dir_name) {
+            let (package_name, metadata_hash) =
+                run_dir_name.rsplit_once('-').expect("Checked above");
+            return Ok(Self {
+                package_name: package_name.to_owned(),
+                metadata_hash: metadata_hash.to_owned(),
+                run_files_dir: run_dir.to_owned(),
+                stdout_file_name: "output",
+            });
+        }
+        // "{package}/{hash}"
+        if is_metadata_hash(run_dir_name) {
+            let package_name = run_dir
+                .parent()
+                .and_then(file_name)
+                .ok_or_else(|| unrecognised("expected a package dir above the hash dir"))?;
+            return Ok(Self {
+                package_name: package_name.to_owned(),
+                metadata_hash: run_dir_name.to_owned(),
+                run_files_dir: run_dir.join("run"),
+                stdout_file_name: "stdout",
+            });
+        }
+        Err(unrecognised(
+            "expected 'OUT_DIR' parent to be named \"{package}-{hash}\" or \"{hash}\"",
+        ))
+    }
+
+    /// Cargo's copy of what the build script printed.
+    pub fn stdout_path(&self) -> PathBuf {
+        self.run_files_dir.join(self.stdout_file_name)
+    }
+
+    /// Touched by Cargo just before it runs the build script.
+    pub fn invoked_timestamp_path(&self) -> PathBuf {
+        self.run_files_dir.join("invoked.timestamp")
+    }
+}
+
+/// Check that we understand the layout around an out dir
+/// that Cargo has given `rustc`, before we go caching anything in it.
+pub fn check_unit_out_dir(out_dir: &Path) -> Result<(), UnrecognisedLayout> {
+    if out_dir.file_name().and_then(|name| name.to_str()) == Some("out")
+        && out_dir
+            .parent()
+            .and_then(file_name)
+            .is_some_and(is_metadata_hash)
+    {
+        return Err(UnrecognisedLayout {
+            path: out_dir.to_owned(),
+            problem: "Cargo's per-package build dir layout isn't supported yet".to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Is this the out dir for compiling a build script,
+/// i.e. "{profile dir}/build/{package}-{hash}"?
+///
+/// This only looks at the end of the path, so it doesn't matter where the
+/// target dir is, even if that has a "build" dir in it (e.g. `--target-dir
+/// /build/target`).
+pub fn is_build_script_out_dir(out_dir: &Path) -> bool {
+    out_dir.parent().and_then(file_name) == Some("build")
+        && file_name(out_dir).is_some_and(is_package_and_hash)
+}
+
+/// The profile dir (e.g. "target/debug") that a unit's out dir is in,
+/// which is where Cargo keeps its ".fingerprint" dir.
+pub fn profile_dir(out_dir: &Path) -> Option<&Path> {
+    out_dir
+        .ancestors()
+        .find(|dir| dir.join(".fingerprint").exists())
+}
+
+/// The workspace root that a profile dir belongs to, assuming that the
+/// target dir is in the usual place there.
+///
+/// Cross-compiled units live in "{target dir}/{target}/{profile}" rather
+/// than "{target dir}/{profile}", and only the target dir itself gets a
+/// "CACHEDIR.TAG".
+pub fn workspace_dir(profile_dir: &Path) -> Option<&Path> {
+    let parent = profile_dir.parent()?;
+    let target_dir = if parent.join("CACHEDIR.TAG").exists() {
+        parent
+    } else {
+        parent.parent()?
+    };
+    target_dir.parent()
+}
+
+/// Is this path inside any build script's dir, i.e. somewhere under
+/// "{profile dir}/build/{package}-{hash}"?
+///
+/// Build scripts write generated code there, and dep info files for
+/// crates that include it name it, so we need to spot these paths even
+/// when they're from some other machine's target dir.
+pub fn is_in_build_dir(path: &Path) -> bool {
+    let components: Vec<&str> = path
+        .components()
+        .filter_map(|component| component.as_os_str().to_str())
+        .collect();
+    components
+        .windows(2)
+        .any(|pair| pair[0] == "build" && is_package_and_hash(pair[1]))
+}
+
+/// "{package}-{hash}"
+fn is_package_and_hash(s: &str) -> bool {
+    s.rsplit_once('-')
+        .is_some_and(|(package_name, hash)| !package_name.is_empty() && is_metadata_hash(hash))
+}
+
+fn file_name(path: &Path) -> Option<&str> {
+    path.file_name()?.to_str()
+}
+
+/// Cargo's metadata hashes are 16 lowercase hex digits, but don't be fussy
+/// about the length in case that changes too.
+fn is_metadata_hash(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+}
+
+#[derive(Debug)]
+pub struct UnrecognisedLayout {
+    path: PathBuf,
+    problem: String,
+}
+
+impl UnrecognisedLayout {
+    /// Tell the user (and the log) that we're getting out of the way.
+    pub fn report(&self) -> anyhow::Result<()> {
+        eprintln!("Hope: {self}; not caching this unit.");
+        write_log_line(
+            &LocalCache::dir_from_env()?,
+            CacheLogLine::UnrecognisedLayout(UnrecognisedLayoutEvent {
+                path: self.path.display().to_string(),
+                detected_at: Utc::now(),
+                problem: self.problem.clone(),
+            }),
+        )
+    }
+}
+
+impl fmt::Display for UnrecognisedLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unrecognised Cargo target dir layout at {:?} ({}); \
+             this version of Cargo may lay things out differently to what Hope expects",
+            self.path, self.problem
+        )
+    }
+}
+
+impl std::error::Error for UnrecognisedLayout {}
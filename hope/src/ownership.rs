@@ -0,0 +1,105 @@
+//! Making sure we don't leave a cache dir that its owner can't use.
+//!
+//! The usual way for this to go wrong is a `sudo cargo build` with the
+//! environment kept, so that root writes entries (and lock files, and the log)
+//! into a developer's cache dir, and the developer's next build can't replace
+//! or lock any of them. Other users on a shared dev server can do much the
+//! same thing.
+//!
+//! So before touching the cache, we check who owns the cache dir:
+//!
+//! - If it's us, carry on.
+//! - If it's somebody else, but `HOPE_GROUP_WRITABLE` is set and the dir is
+//!   group-writable, then the cache is meant to be shared; carry on.
+//! - If we're root and `HOPE_ADOPT_CACHE_OWNER` is set, carry on, and hand
+//!   anything we created back to the cache dir's owner when we're done.
+//! - Otherwise, build the unit without the cache.
+
+use std::{
+    os::unix::fs::{MetadataExt as _, PermissionsExt as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::config;
+
+pub enum Ownership {
+    /// Go ahead and use the cache.
+    Ours,
+    /// Go ahead and use the cache, then give anything we
+    /// created in it to whoever owns the cache dir.
+    Adopt(Adopter),
+    /// Leave the cache alone.
+    Theirs { owner_uid: u32, our_uid: u32 },
+}
+
+/// Check whether we should use the cache dir, as described in the module docs.
+///
+/// A cache dir that doesn't exist yet will be ours when we create it.
+pub fn check(cache_dir: &Path) -> anyhow::Result<Ownership> {
+    let metadata = match std::fs::metadata(cache_dir) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Ownership::Ours),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read metadata for {cache_dir:?}"))
+        }
+    };
+    let our_uid = rustix::process::geteuid().as_raw();
+    let owner_uid = metadata.uid();
+    if owner_uid == our_uid {
+        return Ok(Ownership::Ours);
+    }
+    if config::group_writable() && metadata.permissions().mode() & 0o020 != 0 {
+        return Ok(Ownership::Ours);
+    }
+    if our_uid == 0 && config::adopt_cache_owner() {
+        return Ok(Ownership::Adopt(Adopter {
+            cache_dir: cache_dir.to_owned(),
+            uid: owner_uid,
+            gid: metadata.gid(),
+        }));
+    }
+    Ok(Ownership::Theirs { owner_uid, our_uid })
+}
+
+/// Gives anything root created in the cache dir to the cache dir's owner,
+/// when dropped.
+pub struct Adopter {
+    cache_dir: PathBuf,
+    uid: u32,
+    gid: u32,
+}
+
+impl Drop for Adopter {
+    fn drop(&mut self) {
+        if let Err(err) = chown_ours(&self.cache_dir, self.uid, self.gid) {
+            eprintln!(
+                "Hope failed to hand files in {:?} back to uid {}: {err:#}",
+                self.cache_dir, self.uid
+            );
+        }
+    }
+}
+
+/// Everything under `dir` that root owns goes to `uid`/`gid` instead.
+///
+/// This walks the whole cache, but only when running as root with
+/// `HOPE_ADOPT_CACHE_OWNER` set, which should be rare.
+fn chown_ours(dir: &Path, uid: u32, gid: u32) -> anyhow::Result<()> {
+    for dir_entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {dir:?}"))? {
+        let dir_entry = dir_entry.with_context(|| format!("Failed to read entry in {dir:?}"))?;
+        let path = dir_entry.path();
+        let metadata = dir_entry
+            .metadata()
+            .with_context(|| format!("Failed to read metadata for {path:?}"))?;
+        if metadata.uid() == 0 {
+            std::os::unix::fs::lchown(&path, Some(uid), Some(gid))
+                .with_context(|| format!("Failed to change owner of {path:?}"))?;
+        }
+        if metadata.is_dir() {
+            chown_ours(&path, uid, gid)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! What mode the files and directories we write to a cache dir get.
+//!
+//! By default, entries are only readable by whoever pushed them, and
+//! directories get whatever the umask allows, which is right for a cache
+//! that belongs to one person. To share a cache dir between users on one
+//! machine, set `HOPE_FILE_MODE` and `HOPE_DIR_MODE` (e.g. "664" and
+//! "2775", so that everything stays in the cache dir's group), or just set
+//! `HOPE_GROUP_WRITABLE` for those same modes.
+//!
+//! Only a file's owner can change its mode, so for files and directories
+//! that might have been created by somebody else (lock files, shared
+//! directories), whoever created them first gets to decide.
+
+use std::{
+    fs::{File, Permissions},
+    os::unix::fs::PermissionsExt as _,
+    path::Path,
+};
+
+use anyhow::Context;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PermissionPolicy {
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+}
+
+impl PermissionPolicy {
+    pub fn new(file_mode: Option<u32>, dir_mode: Option<u32>) -> Self {
+        Self {
+            file_mode,
+            dir_mode,
+        }
+    }
+
+    /// Mode for files, if it's not just left up to whatever creates them.
+    pub fn file_mode(&self) -> Option<u32> {
+        self.file_mode
+    }
+
+    /// Set the mode of a file we've just created (e.g. before moving it into place).
+    pub fn apply_to_file(&self, file: &File) -> anyhow::Result<()> {
+        let Some(mode) = self.file_mode else {
+            return Ok(());
+        };
+        file.set_permissions(Permissions::from_mode(mode))
+            .context("Failed to set file mode")
+    }
+
+    /// Set the mode of a file or directory that might belong to somebody else,
+    /// if we can.
+    pub fn apply_if_ours(&self, path: &Path) {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return;
+        };
+        let mode = if metadata.is_dir() {
+            self.dir_mode
+        } else {
+            self.file_mode
+        };
+        if let Some(mode) = mode {
+            if metadata.permissions().mode() & 0o7777 != mode {
+                let _ = std::fs::set_permissions(path, Permissions::from_mode(mode));
+            }
+        }
+    }
+
+    /// Like `std::fs::create_dir_all`, but giving
+    /// any directories it creates our mode.
+    pub fn create_dir_all(&self, dir: &Path) -> std::io::Result<()> {
+        if self.dir_mode.is_none() {
+            return std::fs::create_dir_all(dir);
+        }
+        let missing: Vec<&Path> = dir
+            .ancestors()
+            .take_while(|ancestor| !ancestor.exists())
+            .collect();
+        std::fs::create_dir_all(dir)?;
+        // Deepest last, so that (e.g.) a setgid bit is
+        // in place before anything goes inside.
+        for created in missing.into_iter().rev() {
+            self.apply_if_ours(created);
+        }
+        Ok(())
+    }
+}
+
+/// Parse a mode given in octal, e.g. "664" or "2775".
+pub fn parse_mode(s: &str) -> anyhow::Result<u32> {
+    let mode = u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .with_context(|| format!("Invalid mode {s:?}; expected octal, e.g. \"664\""))?;
+    anyhow::ensure!(mode <= 0o7777, "Invalid mode {s:?}; too big");
+    Ok(mode)
+}
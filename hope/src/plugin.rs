@@ -0,0 +1,248 @@
+//! Letting a WebAssembly module make policy decisions, for when the
+//! environment variables don't cover what a site wants, and a `HOPE_HOOK`
+//! (see the `hooks` module) can't answer in time or can't say enough.
+//!
+//! Set `HOPE_POLICY_PLUGIN` to the path of a module (binary, or text
+//! format), and build Hope with the `wasm-plugins` feature. The module
+//! can't import anything, and must export its `memory` and:
+//!
+//! - `hope_alloc(len: i32) -> i32`, giving us somewhere to put `len` bytes
+//!   of input. (It's fine to hand out the same place every time.)
+//!
+//! And then any of these, which each get a JSON object as a pointer and a
+//! length, and return non-zero for "yes":
+//!
+//! - `hope_cacheable`: should we cache this unit at all? Gets a [`Unit`].
+//!   If not, it's left to the real `rustc`, as if Hope weren't there.
+//! - `hope_accept_pull`: should we pull this unit, now that we know where
+//!   it would come from? Gets a [`Unit`] with its `storage_name`.
+//!   If not, we build it instead (and push it, as for any other miss).
+//! - `hope_key_includes`: should this part of the key go into it? Gets a
+//!   [`KeyPart`]. Keys made this way are aliases, just as for the
+//!   non-strict key policies (see the `key_policy` module), named for the
+//!   module's content, so that changing the module changes them all.
+//!
+//! Each call gets a fixed amount of fuel, so a module that never returns
+//! can't hang the build. A module that traps (or runs out) counts as
+//! saying "no" for the first two, and "yes" for the last.
+
+use std::borrow::Cow;
+
+use serde::Serialize;
+
+use crate::{
+    key::KeyComponent,
+    key_policy::{KeyPolicy, NEVER_IGNORED},
+};
+
+/// What plugins get told about the unit being built.
+#[derive(Serialize)]
+pub struct Unit<'a> {
+    pub crate_name: &'a str,
+    pub package_name: &'a str,
+    pub package_version: &'a str,
+    /// Only for `hope_accept_pull`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_name: Option<&'a str>,
+}
+
+/// A part of a unit's key, for `hope_key_includes`.
+#[derive(Serialize)]
+struct KeyPart<'a> {
+    /// As in `KeyComponent::selector`, e.g. "codegen:opt-level".
+    component: &'a str,
+    value: &'a str,
+}
+
+/// Should we cache this unit? Yes, if there's no plugin to say otherwise.
+pub fn cacheable(unit: &Unit) -> anyhow::Result<bool> {
+    let Some(plugin) = imp::get()? else {
+        return Ok(true);
+    };
+    Ok(plugin.ask("hope_cacheable", unit, false))
+}
+
+/// Should we pull this unit? Fails (so that it's a miss) if not.
+pub fn check_pull(unit: &Unit) -> anyhow::Result<()> {
+    let Some(plugin) = imp::get()? else {
+        return Ok(());
+    };
+    anyhow::ensure!(
+        plugin.ask("hope_accept_pull", unit, false),
+        "Policy plugin rejected the pull"
+    );
+    Ok(())
+}
+
+/// Let the plugin (if there is one, and it cares) have a say in what goes into keys.
+pub fn key_policy(inner: Box<dyn KeyPolicy>) -> anyhow::Result<Box<dyn KeyPolicy>> {
+    match imp::get()? {
+        Some(plugin) if plugin.exports("hope_key_includes") => {
+            Ok(Box::new(PluginKeyPolicy { inner, plugin }))
+        }
+        _ => Ok(inner),
+    }
+}
+
+struct PluginKeyPolicy {
+    inner: Box<dyn KeyPolicy>,
+    plugin: &'static imp::PolicyPlugin,
+}
+
+impl KeyPolicy for PluginKeyPolicy {
+    fn name(&self) -> Cow<'_, str> {
+        format!("{}+plugin({})", self.inner.name(), self.plugin.digest()).into()
+    }
+
+    fn includes(&self, component: &KeyComponent) -> bool {
+        let selector = component.selector();
+        if NEVER_IGNORED.contains(&selector.as_ref()) {
+            return true;
+        }
+        let part = KeyPart {
+            component: &selector,
+            value: &component.value,
+        };
+        self.inner.includes(component) && self.plugin.ask("hope_key_includes", &part, true)
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+mod imp {
+    use std::sync::{Mutex, OnceLock};
+
+    use anyhow::Context;
+    use serde::Serialize;
+    use sha2::{Digest, Sha256};
+    use wasmtime::{Config, Engine, Instance, Module, Store};
+
+    use crate::config;
+
+    /// Enough for any reasonable policy, but not for a runaway loop.
+    const FUEL_PER_CALL: u64 = 100_000_000;
+
+    pub struct PolicyPlugin {
+        /// Short hash of the module, for naming keys.
+        digest: String,
+        instance: Instance,
+        store: Mutex<Store<()>>,
+    }
+
+    /// The plugin from `HOPE_POLICY_PLUGIN`, loaded the first time it's needed.
+    pub fn get() -> anyhow::Result<Option<&'static PolicyPlugin>> {
+        static PLUGIN: OnceLock<Option<PolicyPlugin>> = OnceLock::new();
+        if let Some(plugin) = PLUGIN.get() {
+            return Ok(plugin.as_ref());
+        }
+        let plugin = match config::policy_plugin() {
+            Some(path) => Some(
+                PolicyPlugin::load(&path)
+                    .with_context(|| format!("Failed to load policy plugin {path:?}"))?,
+            ),
+            None => None,
+        };
+        Ok(PLUGIN.get_or_init(|| plugin).as_ref())
+    }
+
+    impl PolicyPlugin {
+        fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+            let bytes = std::fs::read(path).context("Failed to read module")?;
+            let mut wasm_config = Config::new();
+            wasm_config.consume_fuel(true);
+            let engine = Engine::new(&wasm_config)?;
+            let module = Module::new(&engine, &bytes)?;
+            let mut store = Store::new(&engine, ());
+            let instance = Instance::new(&mut store, &module, &[])
+                .context("Failed to instantiate module; it can't import anything")?;
+            instance
+                .get_memory(&mut store, "memory")
+                .context("Module doesn't export its memory")?;
+            instance
+                .get_typed_func::<i32, i32>(&mut store, "hope_alloc")
+                .context("Module doesn't export 'hope_alloc'")?;
+            Ok(Self {
+                digest: format!("{:x}", Sha256::digest(&bytes))[..12].to_owned(),
+                instance,
+                store: Mutex::new(store),
+            })
+        }
+
+        pub fn digest(&self) -> &str {
+            &self.digest
+        }
+
+        pub fn exports(&self, function: &str) -> bool {
+            let mut store = self.store.lock().unwrap();
+            self.instance.get_func(&mut *store, function).is_some()
+        }
+
+        /// Ask the module a yes-or-no question, taking `default` as the answer
+        /// if it fails. Anything it doesn't export a function for is a yes.
+        pub fn ask(&self, function: &str, input: &impl Serialize, default: bool) -> bool {
+            match self.call(function, input) {
+                Ok(Some(answer)) => answer,
+                Ok(None) => true,
+                Err(err) => {
+                    eprintln!("Hope's policy plugin failed in '{function}': {err:#}");
+                    default
+                }
+            }
+        }
+
+        fn call(&self, function: &str, input: &impl Serialize) -> anyhow::Result<Option<bool>> {
+            let mut store = self.store.lock().unwrap();
+            let Some(func) = self.instance.get_func(&mut *store, function) else {
+                return Ok(None);
+            };
+            let func = func.typed::<(i32, i32), i32>(&*store)?;
+            let input = serde_json::to_vec(input).context("Failed to serialize input")?;
+            let len = i32::try_from(input.len()).context("Input too big")?;
+            store.set_fuel(FUEL_PER_CALL)?;
+            let alloc = self
+                .instance
+                .get_typed_func::<i32, i32>(&mut *store, "hope_alloc")?;
+            let ptr = alloc
+                .call(&mut *store, len)
+                .context("'hope_alloc' failed")?;
+            let memory = self
+                .instance
+                .get_memory(&mut *store, "memory")
+                .context("Module doesn't export its memory")?;
+            memory
+                .write(&mut *store, ptr as u32 as usize, &input)
+                .context("'hope_alloc' gave us somewhere that isn't in memory")?;
+            Ok(Some(func.call(&mut *store, (ptr, len))? != 0))
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+mod imp {
+    use serde::Serialize;
+
+    use crate::config;
+
+    pub enum PolicyPlugin {}
+
+    pub fn get() -> anyhow::Result<Option<&'static PolicyPlugin>> {
+        anyhow::ensure!(
+            config::policy_plugin().is_none(),
+            "'HOPE_POLICY_PLUGIN' is set, but Hope was built without the 'wasm-plugins' feature"
+        );
+        Ok(None)
+    }
+
+    impl PolicyPlugin {
+        pub fn digest(&self) -> &str {
+            match *self {}
+        }
+
+        pub fn exports(&self, _function: &str) -> bool {
+            match *self {}
+        }
+
+        pub fn ask(&self, _function: &str, _input: &impl Serialize, _default: bool) -> bool {
+            match *self {}
+        }
+    }
+}
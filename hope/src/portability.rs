@@ -0,0 +1,73 @@
+//! Spotting absolute paths from this machine baked into artifacts.
+//!
+//! `rustc` happily embeds absolute source paths in panic messages,
+//! debug info, and crate metadata. That's harmless for a cache that only
+//! one machine uses, but artifacts full of `/home/alice/...` are less useful
+//! (and a bit of an information leak) when shared with other machines.
+//! `--remap-path-prefix` is the cure, so we mainly want to flag
+//! entries that were built without it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+#[derive(Debug)]
+pub struct PortabilityReport {
+    /// Percentage of checked artifacts that contain no machine-specific paths.
+    pub score: u8,
+    /// Machine-specific path prefixes that turned up in at least one artifact.
+    pub leaked_prefixes: Vec<String>,
+}
+
+impl PortabilityReport {
+    pub fn is_portable(&self) -> bool {
+        self.leaked_prefixes.is_empty()
+    }
+}
+
+/// Scan the given artifacts for absolute paths specific to this machine.
+pub fn check(artifact_paths: &[PathBuf]) -> anyhow::Result<PortabilityReport> {
+    let prefixes = machine_specific_prefixes();
+    let mut leaked_prefixes: Vec<String> = Vec::new();
+    let mut clean_count = 0;
+    for artifact_path in artifact_paths {
+        let content = std::fs::read(artifact_path)
+            .with_context(|| format!("Failed to read {artifact_path:?} to check portability"))?;
+        let mut clean = true;
+        for prefix in &prefixes {
+            if memchr::memmem::find(&content, prefix.as_bytes()).is_some() {
+                clean = false;
+                if !leaked_prefixes.contains(prefix) {
+                    leaked_prefixes.push(prefix.clone());
+                }
+            }
+        }
+        if clean {
+            clean_count += 1;
+        }
+    }
+    let score = if artifact_paths.is_empty() {
+        100
+    } else {
+        (clean_count * 100 / artifact_paths.len()) as u8
+    };
+    Ok(PortabilityReport {
+        score,
+        leaked_prefixes,
+    })
+}
+
+/// Absolute paths that are likely to differ between machines.
+///
+/// TODO: Include the target dir, once we have a reliable way to find it.
+fn machine_specific_prefixes() -> Vec<String> {
+    let mut prefixes: Vec<String> = ["HOME", "CARGO_HOME"]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        // Don't go looking for "/" or similar; everything would match.
+        .filter(|prefix| Path::new(prefix).components().count() > 1)
+        .collect();
+    prefixes.sort();
+    prefixes.dedup();
+    prefixes
+}
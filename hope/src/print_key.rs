@@ -0,0 +1,127 @@
+//! Printing the cache key for a `rustc` invocation, without building anything.
+//!
+//! Handy for scripts, for checking what a key policy actually leaves out,
+//! and for bug reports: paste in a command line from `cargo build -v`, and
+//! run it with the same environment (`CARGO_PKG_NAME`, `OUT_DIR`, `CC`, and
+//! so on) that Cargo would have given the wrapper.
+
+use std::{ffi::OsStr, path::Path};
+
+use anyhow::Context;
+use clap::Parser as _;
+use serde::Serialize;
+
+use crate::{
+    build_script, config,
+    key::{self, CacheKey},
+    rustc_args::Args,
+    target::Target,
+    toolchain::{self, NativeToolchain, RustcInfo},
+};
+
+#[derive(Serialize)]
+struct KeyReport<'a> {
+    unit_name: String,
+    /// How Hope made sense of the command line.
+    args: &'a Args,
+    policy: String,
+    components: Vec<ComponentReport>,
+    storage_name: String,
+    alias_name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ComponentReport {
+    name: &'static str,
+    value: String,
+    /// Whether the key policy hashes this component.
+    included: bool,
+}
+
+/// `rustc_args` may start with the path to `rustc` itself, as Cargo
+/// prints them; otherwise we use `$RUSTC`, or whatever `rustc` is on the path.
+pub fn run(rustc_args: &[String], json: bool) -> anyhow::Result<()> {
+    let (rustc, rustc_args) = match rustc_args.split_first() {
+        Some((first, rest)) if !first.starts_with('-') && looks_like_rustc(first) => {
+            (RustcInfo::query(Path::new(first))?, rest)
+        }
+        _ => (
+            RustcInfo::query_default().context("Failed to identify local `rustc`")?,
+            rustc_args,
+        ),
+    };
+    let args =
+        Args::parse_from(std::iter::once("rustc").chain(rustc_args.iter().map(|arg| &**arg)));
+
+    let crate_name = args
+        .crate_name
+        .clone()
+        .context("Missing crate name argument")?;
+    let extra_filename = args
+        .extra_filename()
+        .context("Missing extra-filename codegen option")?;
+    let unit_name = format!("{crate_name}{extra_filename}");
+    let target = Target::from_arg(args.target.as_deref(), &rustc.host);
+    let cargo_package_name = std::env::var("CARGO_PKG_NAME").unwrap_or_default();
+    let native_toolchain = NativeToolchain::detect(
+        &target,
+        toolchain::links_native_code(&cargo_package_name, &args),
+    );
+    let build_script_env = build_script::rustc_env_for_crate()?;
+    let policy = config::key_policy()?;
+
+    let components = key::components(&target, &rustc, &native_toolchain, &args, &build_script_env);
+    let cache_key = CacheKey::new(
+        &unit_name,
+        &target,
+        &rustc,
+        &native_toolchain,
+        &args,
+        &build_script_env,
+        policy.as_ref(),
+    );
+    let report = KeyReport {
+        unit_name,
+        args: &args,
+        policy: cache_key.policy.clone(),
+        components: components
+            .into_iter()
+            .map(|component| ComponentReport {
+                included: policy.includes(&component),
+                name: component.name,
+                value: component.value,
+            })
+            .collect(),
+        storage_name: cache_key.storage_name(),
+        alias_name: cache_key.alias_name(),
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    println!("unit: {}", report.unit_name);
+    println!("key policy: {}", report.policy);
+    for component in &report.components {
+        let ignored = if component.included {
+            ""
+        } else {
+            " (ignored by policy)"
+        };
+        println!("    {}={}{ignored}", component.name, component.value);
+    }
+    println!("storage name: {}", report.storage_name);
+    if let Some(alias_name) = &report.alias_name {
+        println!("alias: {alias_name}");
+    }
+    Ok(())
+}
+
+/// e.g. "rustc", or "/home/me/.rustup/toolchains/stable-x86_64-unknown-linux-gnu/bin/rustc".
+fn looks_like_rustc(arg: &str) -> bool {
+    let path = Path::new(arg);
+    path.extension() != Some(OsStr::new("rs"))
+        && path
+            .file_stem()
+            .is_some_and(|stem| stem.to_string_lossy().starts_with("rustc"))
+}
@@ -0,0 +1,213 @@
+//! Doing pushes later that a build couldn't afford, or wasn't allowed, to do.
+//!
+//! Pushes to remote caches are skipped once a build is over its session
+//! budget (see the `session_budget` module), and altogether with
+//! `HOPE_READ_ONLY=1`. Rather than forget about them, each skipped push is
+//! noted in "push-backlog.jsonl" in the local cache dir: which cache it was
+//! for, its key and manifest, and where its outputs are in the target dir.
+//! `hope push-backlog` then pushes whichever of them are still there, once
+//! there's time (or permission) to, e.g. at the end of a CI job:
+//!
+//! ```text
+//! HOPE_SESSION_BUDGET_SECS=60 cargo build
+//! hope push-backlog
+//! ```
+//!
+//! Outputs are hashed when they're noted, so anything that's since been
+//! rebuilt differently (or cleaned away) is dropped rather than pushed
+//! under the wrong key. Pushes from the backlog don't get attestations,
+//! because the details of the build that made them are long gone.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{Read as _, Seek as _, Write as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{self, LocalCache},
+    chunks,
+    entry_manifest::EntryManifest,
+    key::CacheKey,
+    output_defns, CrateType, OutputType,
+};
+
+const BACKLOG_FILE_NAME: &str = "push-backlog.jsonl";
+
+/// A push that was skipped, as passed to [`record`].
+pub struct SkippedPush<'a> {
+    /// One of `cache::push_target_names`.
+    pub target: &'a str,
+    pub reason: &'a str,
+    pub key: &'a CacheKey,
+    pub manifest: &'a EntryManifest,
+    pub crate_types: &'a HashSet<CrateType>,
+    pub output_types: &'a HashSet<OutputType>,
+    /// Where the outputs are, named for the unit as Cargo expects.
+    pub out_dir: &'a Path,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BacklogEntry {
+    recorded_at: DateTime<Utc>,
+    target: String,
+    reason: String,
+    key: CacheKey,
+    manifest: EntryManifest,
+    crate_types: Vec<CrateType>,
+    output_types: Vec<OutputType>,
+    out_dir: PathBuf,
+    /// Hash of each output when the push was skipped, by file name.
+    hashes: BTreeMap<String, String>,
+}
+
+impl BacklogEntry {
+    fn file_names(&self) -> Vec<String> {
+        output_defns(
+            &self.crate_types.iter().copied().collect(),
+            &self.output_types.iter().copied().collect(),
+            &self.key.target,
+        )
+        .iter()
+        .map(|output_defn| output_defn.file_name(&self.key.unit_name))
+        .collect()
+    }
+}
+
+/// Note a skipped push, for [`push`] to do later.
+pub fn record(skipped: &SkippedPush) -> anyhow::Result<()> {
+    let mut entry = BacklogEntry {
+        recorded_at: Utc::now(),
+        target: skipped.target.to_owned(),
+        reason: skipped.reason.to_owned(),
+        key: skipped.key.clone(),
+        manifest: skipped.manifest.clone(),
+        crate_types: skipped.crate_types.iter().copied().collect(),
+        output_types: skipped.output_types.iter().copied().collect(),
+        out_dir: skipped.out_dir.to_owned(),
+        hashes: BTreeMap::new(),
+    };
+    for file_name in entry.file_names() {
+        let path = skipped.out_dir.join(&file_name);
+        let content =
+            std::fs::read(&path).with_context(|| format!("Failed to read {path:?} to hash it"))?;
+        entry.hashes.insert(file_name, chunks::hash_bytes(&content));
+    }
+    with_backlog(|backlog| {
+        backlog.seek(std::io::SeekFrom::End(0))?;
+        writeln!(backlog, "{}", serde_json::to_string(&entry)?)?;
+        Ok(())
+    })
+}
+
+/// Push everything in the backlog that's still in its target dir.
+///
+/// Pushes that fail stay in the backlog for next time.
+pub fn push() -> anyhow::Result<()> {
+    push_where(|_| true)
+}
+
+/// Push what's in the backlog from under `target_dir`, e.g. before it's
+/// cleaned, leaving the rest for [`push`].
+pub fn push_from(target_dir: &Path) -> anyhow::Result<()> {
+    push_where(|entry| entry.out_dir.starts_with(target_dir))
+}
+
+fn push_where(wanted: impl Fn(&BacklogEntry) -> bool) -> anyhow::Result<()> {
+    // Take the whole backlog, so builds can go on adding to it meanwhile.
+    let lines = with_backlog(|backlog| {
+        let mut lines = String::new();
+        backlog.read_to_string(&mut lines)?;
+        backlog.set_len(0)?;
+        Ok(lines)
+    })?;
+    let (mut pushed, mut gone) = (0, 0);
+    // Pushes that failed, and entries we weren't asked to push.
+    let mut kept = Vec::new();
+    let mut failed = 0;
+    for line in lines.lines().filter(|line| !line.trim().is_empty()) {
+        let entry: BacklogEntry =
+            serde_json::from_str(line).context("Invalid line in push backlog")?;
+        if !wanted(&entry) {
+            kept.push(line.to_owned());
+            continue;
+        }
+        match push_entry(&entry) {
+            Ok(true) => pushed += 1,
+            Ok(false) => gone += 1,
+            Err(err) => {
+                eprintln!(
+                    "Failed to push {} to {}: {err:#}",
+                    entry.key.unit_name, entry.target
+                );
+                kept.push(line.to_owned());
+                failed += 1;
+            }
+        }
+    }
+    if !kept.is_empty() {
+        with_backlog(|backlog| {
+            backlog.seek(std::io::SeekFrom::End(0))?;
+            for line in &kept {
+                writeln!(backlog, "{line}")?;
+            }
+            Ok(())
+        })?;
+    }
+    println!(
+        "Pushed {pushed} entries from the backlog; {gone} had changed or were gone \
+         from their target dirs, and {failed} are still waiting."
+    );
+    Ok(())
+}
+
+/// Push an entry, or say it's not there to push any more.
+fn push_entry(entry: &BacklogEntry) -> anyhow::Result<bool> {
+    let departure_dir = tempfile::tempdir().context("Failed to create departure dir")?;
+    for (file_name, hash) in &entry.hashes {
+        let Ok(content) = std::fs::read(entry.out_dir.join(file_name)) else {
+            return Ok(false);
+        };
+        if chunks::hash_bytes(&content) != *hash {
+            return Ok(false);
+        }
+        std::fs::write(departure_dir.path().join(file_name), content)
+            .with_context(|| format!("Failed to copy {file_name:?} to departure dir"))?;
+    }
+    let output_defns = output_defns(
+        &entry.crate_types.iter().copied().collect(),
+        &entry.output_types.iter().copied().collect(),
+        &entry.key.target,
+    );
+    let cache = cache::push_target_from_name(&entry.target)?;
+    cache.push_crate(
+        &entry.key,
+        &entry.manifest,
+        &output_defns,
+        departure_dir.path(),
+    )?;
+    Ok(true)
+}
+
+/// Run `f` on the backlog file with it locked, as builds run in parallel.
+fn with_backlog<T>(f: impl FnOnce(&mut std::fs::File) -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let cache_dir = LocalCache::dir_from_env()?;
+    std::fs::create_dir_all(&cache_dir).context("Failed to create cache dir")?;
+    let path = cache_dir.join(BACKLOG_FILE_NAME);
+    let file = std::fs::File::options()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {path:?}"))?;
+    let mut lock = fd_lock::RwLock::new(file);
+    let mut guard = lock
+        .write()
+        .with_context(|| format!("Failed to lock {path:?}"))?;
+    f(&mut guard).with_context(|| format!("Failed to update {path:?}"))
+}
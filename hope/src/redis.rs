@@ -0,0 +1,257 @@
+//! Talking to Redis (or Valkey), for small, hot caches.
+//!
+//! This suits CI runners that come and go but share a Redis sidecar: it's
+//! fast, but everything lives in memory, so it's best kept to smaller
+//! artifacts and build script output. `HOPE_MAX_REMOTE_PUSH_SIZE` keeps big
+//! artifacts out of it, `HOPE_CHUNK_SIZE` keeps each value small, and
+//! `HOPE_REDIS_TTL_SECS` lets entries nobody uses any more expire.
+//!
+//! We only need a handful of commands, so we speak the protocol (RESP2)
+//! ourselves rather than pull in a client library. The URL is
+//! "redis://[[username]:password@]host[:port][/database]", as for other
+//! clients, or the password can be kept out of it as a credential instead
+//! (see the `credentials` module). Keys are prefixed with [`KEY_PREFIX`] so that the cache can
+//! share a database with other things.
+//!
+//! TODO: TLS ("rediss://").
+
+use std::{
+    io::{BufRead as _, BufReader, Read as _, Write as _},
+    net::TcpStream,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{cache::RemoteBlobStore, chunks::BlobStore};
+
+const KEY_PREFIX: &str = "hope:";
+
+const DEFAULT_PORT: u16 = 6379;
+
+/// Give up on a Redis that's stopped answering, rather than hang the build.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct RedisBlobStore {
+    /// "host:port"
+    addr: String,
+    username: Option<String>,
+    password: Option<String>,
+    database: Option<u32>,
+    ttl: Option<Duration>,
+    /// Connected on first use, and dropped after any error,
+    /// so we never read the rest of a reply as the next one.
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+/// What Redis sends back for a command, less the kinds we never ask for.
+enum Reply {
+    Status,
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+impl RedisBlobStore {
+    pub fn from_url(
+        url: &str,
+        ttl: Option<Duration>,
+        credential: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let location = url
+            .strip_prefix("redis://")
+            .with_context(|| format!("{url:?} isn't a Redis URL"))?;
+        let (credentials, location) = match location.rsplit_once('@') {
+            Some((credentials, location)) => (Some(credentials), location),
+            None => (credential.as_deref(), location),
+        };
+        let (username, password) = match credentials {
+            Some(credentials) => match credentials.split_once(':') {
+                Some((username, password)) => (
+                    Some(username.to_owned()).filter(|username| !username.is_empty()),
+                    Some(password.to_owned()),
+                ),
+                None => (None, Some(credentials.to_owned())),
+            },
+            None => (None, None),
+        };
+        let (host_port, database) = location.split_once('/').unwrap_or((location, ""));
+        let database = match database.trim_end_matches('/') {
+            "" => None,
+            database => Some(
+                database
+                    .parse()
+                    .with_context(|| format!("Bad database number in Redis URL {url:?}"))?,
+            ),
+        };
+        anyhow::ensure!(!host_port.is_empty(), "Redis URL {url:?} has no host");
+        let addr = if host_port.rsplit_once(':').is_some_and(|(_, port)| {
+            !port.is_empty() && port.bytes().all(|byte| byte.is_ascii_digit())
+        }) {
+            host_port.to_owned()
+        } else {
+            format!("{host_port}:{DEFAULT_PORT}")
+        };
+        Ok(Self {
+            addr,
+            username,
+            password,
+            database,
+            ttl,
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Send a command and wait for its reply.
+    fn command(&self, args: &[&[u8]]) -> anyhow::Result<Reply> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Redis connection was poisoned"))?;
+        if connection.is_none() {
+            *connection = Some(self.connect()?);
+        }
+        let result = send(connection.as_mut().unwrap(), args);
+        if result.is_err() {
+            *connection = None;
+        }
+        result.with_context(|| format!("Redis command failed at {}", self.url()))
+    }
+
+    fn connect(&self) -> anyhow::Result<BufReader<TcpStream>> {
+        let stream = TcpStream::connect(&self.addr)
+            .with_context(|| format!("Failed to connect to Redis at {}", self.addr))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut connection = BufReader::new(stream);
+        if let Some(password) = &self.password {
+            let mut args: Vec<&[u8]> = vec![b"AUTH"];
+            args.extend(self.username.as_deref().map(str::as_bytes));
+            args.push(password.as_bytes());
+            send(&mut connection, &args)
+                .with_context(|| format!("Failed to log in to Redis at {}", self.addr))?;
+        }
+        if let Some(database) = self.database {
+            send(
+                &mut connection,
+                &[b"SELECT", database.to_string().as_bytes()],
+            )
+            .with_context(|| format!("Failed to select Redis database {database}"))?;
+        }
+        Ok(connection)
+    }
+
+    fn ttl_secs(&self) -> Option<String> {
+        self.ttl.map(|ttl| ttl.as_secs().to_string())
+    }
+}
+
+impl BlobStore for RedisBlobStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let redis_key = format!("{KEY_PREFIX}{key}");
+        // Using a blob keeps it around for another TTL.
+        let ttl_secs = self.ttl_secs();
+        let reply = match &ttl_secs {
+            Some(ttl_secs) => {
+                self.command(&[b"GETEX", redis_key.as_bytes(), b"EX", ttl_secs.as_bytes()])?
+            }
+            None => self.command(&[b"GET", redis_key.as_bytes()])?,
+        };
+        match reply {
+            Reply::Bulk(Some(bytes)) => Ok(bytes),
+            Reply::Bulk(None) => anyhow::bail!("{key:?} isn't in {}", self.url()),
+            _ => anyhow::bail!("Unexpected reply from Redis when getting {key:?}"),
+        }
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let redis_key = format!("{KEY_PREFIX}{key}");
+        let ttl_secs = self.ttl_secs();
+        let mut args: Vec<&[u8]> = vec![b"SET", redis_key.as_bytes(), bytes];
+        if let Some(ttl_secs) = &ttl_secs {
+            args.extend([&b"EX"[..], ttl_secs.as_bytes()]);
+        }
+        match self.command(&args)? {
+            Reply::Status => Ok(()),
+            _ => anyhow::bail!("Unexpected reply from Redis when setting {key:?}"),
+        }
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        let redis_key = format!("{KEY_PREFIX}{key}");
+        match self.command(&[b"EXISTS", redis_key.as_bytes()])? {
+            Reply::Integer(count) => Ok(count > 0),
+            _ => anyhow::bail!("Unexpected reply from Redis when checking for {key:?}"),
+        }
+    }
+}
+
+impl RemoteBlobStore for RedisBlobStore {
+    /// e.g. "redis://localhost:6379/0", without any credentials.
+    fn url(&self) -> String {
+        match self.database {
+            Some(database) => format!("redis://{}/{database}", self.addr),
+            None => format!("redis://{}", self.addr),
+        }
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        let redis_key = format!("{KEY_PREFIX}{key}");
+        let size = match self.command(&[b"STRLEN", redis_key.as_bytes()])? {
+            Reply::Integer(size) => size as u64,
+            _ => anyhow::bail!("Unexpected reply from Redis when sizing {key:?}"),
+        };
+        // Missing keys have a length of zero too.
+        if size == 0 && !self.has_blob(key)? {
+            return Ok(None);
+        }
+        Ok(Some(size))
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        let redis_key = format!("{KEY_PREFIX}{key}");
+        let start = format!("-{len}");
+        match self.command(&[b"GETRANGE", redis_key.as_bytes(), start.as_bytes(), b"-1"])? {
+            Reply::Bulk(Some(bytes)) => Ok(bytes),
+            _ => anyhow::bail!("Unexpected reply from Redis when getting {key:?}"),
+        }
+    }
+}
+
+fn send(connection: &mut BufReader<TcpStream>, args: &[&[u8]]) -> anyhow::Result<Reply> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend(*arg);
+        request.extend(b"\r\n");
+    }
+    connection.get_mut().write_all(&request)?;
+    read_reply(connection)
+}
+
+fn read_reply(connection: &mut BufReader<TcpStream>) -> anyhow::Result<Reply> {
+    let mut line = String::new();
+    connection.read_line(&mut line)?;
+    let line = line
+        .strip_suffix("\r\n")
+        .context("Redis closed the connection")?;
+    let (kind, rest) = line.split_at(line.len().min(1));
+    match kind {
+        "+" => Ok(Reply::Status),
+        "-" => anyhow::bail!("Redis said: {rest}"),
+        ":" => Ok(Reply::Integer(
+            rest.parse().context("Bad integer from Redis")?,
+        )),
+        "$" => {
+            let len: i64 = rest.parse().context("Bad bulk string length from Redis")?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut bytes = vec![0; len as usize + 2];
+            connection.read_exact(&mut bytes)?;
+            bytes.truncate(len as usize);
+            Ok(Reply::Bulk(Some(bytes)))
+        }
+        _ => anyhow::bail!("Unexpected reply from Redis: {line:?}"),
+    }
+}
@@ -0,0 +1,155 @@
+//! Handing compilation off to a remote build farm (experimental).
+//!
+//! When `HOPE_REMOTE_BUILDER` is set, cache misses are first offered to
+//! that program instead of being compiled locally. Hope works out what
+//! the unit's inputs are, writes them to a JSON request file, and runs
+//! `$HOPE_REMOTE_BUILDER {request file} {rustc} {args...}`. (The `rustc`
+//! command line is also in the request; it's repeated for the convenience
+//! of simple builders.) Everything else (shipping inputs,
+//! picking a machine, running `rustc` there, and bringing the outputs back
+//! into `out_dir`) is up to the builder, in the style of `icecc` or
+//! `sccache-dist`. The remote machine needs the same toolchain as this one.
+//!
+//! If the builder fails for any reason, we just compile locally instead,
+//! so a farm being unavailable never breaks a build. (That also means real
+//! compile errors get reported by the local `rustc`, as usual.)
+//!
+//! Outputs built remotely get pushed to the cache like any others.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use tempfile::tempdir;
+
+use crate::rustc_args::Args;
+
+#[derive(Debug, Serialize)]
+struct RemoteBuildRequest<'a> {
+    /// Bumped whenever the request format changes incompatibly.
+    format_version: u32,
+    /// Working directory to run `rustc` in.
+    cwd: PathBuf,
+    rustc: &'a Path,
+    args: &'a [String],
+    /// Where `rustc` should write its outputs; the builder has to leave
+    /// them here, too.
+    out_dir: &'a Path,
+    /// Files that `rustc` reads: sources (including `include!`d files),
+    /// and the dependencies named by `--extern`. Dependencies of
+    /// dependencies still need to be found through `-L dependency=...`.
+    inputs: Vec<PathBuf>,
+    /// Environment variables the crate reads at compile time (via `env!`).
+    env: BTreeMap<String, String>,
+}
+
+const REQUEST_FORMAT_VERSION: u32 = 1;
+
+/// Try to build the unit with the remote builder.
+///
+/// Returns whether that worked; if not, the caller should build locally.
+pub fn try_build(
+    builder: &str,
+    rustc_path: &Path,
+    args: &Args,
+    pass_through_args: &[String],
+    out_dir: &Path,
+) -> bool {
+    match build(builder, rustc_path, args, pass_through_args, out_dir) {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("Hope: Remote build failed; building locally instead: {err:#}");
+            false
+        }
+    }
+}
+
+fn build(
+    builder: &str,
+    rustc_path: &Path,
+    args: &Args,
+    pass_through_args: &[String],
+    out_dir: &Path,
+) -> anyhow::Result<()> {
+    let request_dir = tempdir().context("Failed to create temp dir for remote build request")?;
+    let (sources, env) = scan_dependencies(rustc_path, pass_through_args, request_dir.path())?;
+    let externs = args.extern_.iter().filter_map(|extern_| {
+        let (_name, path) = extern_.split_once('=')?;
+        Some(PathBuf::from(path))
+    });
+    let request = RemoteBuildRequest {
+        format_version: REQUEST_FORMAT_VERSION,
+        cwd: std::env::current_dir().context("Failed to get current dir")?,
+        rustc: rustc_path,
+        args: pass_through_args,
+        out_dir,
+        inputs: sources.into_iter().chain(externs).collect(),
+        env,
+    };
+    let request_path = request_dir.path().join("request.json");
+    std::fs::write(&request_path, serde_json::to_vec_pretty(&request)?)
+        .context("Failed to write remote build request")?;
+
+    let status = Command::new(builder)
+        .arg(&request_path)
+        .arg(rustc_path)
+        .args(pass_through_args)
+        .status()
+        .with_context(|| format!("Failed to start remote builder {builder:?}"))?;
+    anyhow::ensure!(status.success(), "Remote builder exited with {status}");
+    Ok(())
+}
+
+/// Ask `rustc` for just the dep info, to find out which files and
+/// environment variables the crate depends on.
+///
+/// This is our equivalent of preprocessing a C file: it expands macros,
+/// so it's not free, but it's much cheaper than actually compiling.
+fn scan_dependencies(
+    rustc_path: &Path,
+    pass_through_args: &[String],
+    scratch_dir: &Path,
+) -> anyhow::Result<(Vec<PathBuf>, BTreeMap<String, String>)> {
+    let dep_info_path = scratch_dir.join("deps.d");
+    let mut scan_args = Vec::new();
+    let mut args = pass_through_args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--emit" {
+            args.next();
+        } else if !arg.starts_with("--emit=") {
+            scan_args.push(arg.clone());
+        }
+    }
+    scan_args.push(format!("--emit=dep-info={}", dep_info_path.display()));
+    // Diagnostics come from the real build, wherever that happens.
+    let status = Command::new(rustc_path)
+        .args(&scan_args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("Failed to start `rustc` to scan dependencies")?;
+    anyhow::ensure!(status.success(), "Scanning dependencies failed");
+
+    let dep_info =
+        std::fs::read_to_string(&dep_info_path).context("Failed to read dep info file")?;
+    let mut sources = Vec::new();
+    let mut env = BTreeMap::new();
+    for line in dep_info.lines() {
+        if let Some(env_dep) = line.strip_prefix("# env-dep:") {
+            let (name, value) = env_dep.split_once('=').unwrap_or((env_dep, ""));
+            env.insert(name.to_owned(), value.to_owned());
+        } else if let Some((_target, deps)) = line.split_once(": ") {
+            // Every rule lists the same sources; just take the first.
+            //
+            // TODO: Handle escaped spaces in paths.
+            if sources.is_empty() {
+                sources.extend(deps.split_whitespace().map(PathBuf::from));
+            }
+        }
+    }
+    Ok((sources, env))
+}
@@ -0,0 +1,143 @@
+//! Carrying on without the remote cache when it isn't working.
+//!
+//! A remote cache that's down or misconfigured (or a network that is) would
+//! otherwise cost every unit of a build an error or a timeout before it
+//! compiled anyway. So it gets a circuit breaker: whenever the remote cache
+//! fails to answer, or takes longer than `HOPE_PULL_BUDGET_MS`, the unit adds
+//! that to a count of failures in a row for the build, kept in the profile
+//! dir (see `session::update_state`), and whenever it does answer (even if
+//! only to say it hasn't got something), the count goes back to zero.
+//!
+//! Once the count reaches `HOPE_REMOTE_ERROR_LIMIT`, the breaker trips, and
+//! the rest of the build (build scripts included) uses just the local cache,
+//! pulling from and pushing to that instead. Pushes to remote caches in
+//! `HOPE_ALSO_PUSH_TO` are saved for `hope push-backlog` (see the
+//! `push_backlog` module) rather than tried.
+//!
+//! The unit that trips it says so on stderr, and logs
+//! a [`RemoteFallbackEvent`], which `hope stats` reports.
+//! The next build tries the remote cache again.
+//!
+//! A remote cache that refuses pushes (see `http_store::PushRefused`) is
+//! answering just fine, so that doesn't count as a failure. Instead the
+//! rest of the build stops pushing to it, and keeps pulling from it.
+
+use std::{collections::BTreeSet, fmt, path::Path, sync::Arc};
+
+use chrono::Utc;
+use hope_cache_log::{write_log_line, CacheLogLine, RemoteFallbackEvent};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{self, Cache, LocalCache},
+    config, session,
+};
+
+/// How it's gone with the remote cache so far this build.
+const ERRORS_FILE_NAME: &str = ".hope-remote-errors";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Errors {
+    in_a_row: u32,
+    fell_back: bool,
+    /// Locations of remote caches that have refused a push.
+    refusing_pushes: BTreeSet<String>,
+}
+
+/// Context for errors that mean the remote cache couldn't be asked
+/// about an entry at all (or took too long to say), as opposed to it
+/// not having the entry.
+#[derive(Debug)]
+pub struct RemoteError;
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The cache didn't answer")
+    }
+}
+
+/// Has this build given up on the remote cache?
+///
+/// Units outside of any profile dir never do.
+pub fn has_fallen_back(profile_dir: Option<&Path>) -> anyhow::Result<bool> {
+    let Some(profile_dir) = profile_dir else {
+        return Ok(false);
+    };
+    session::update_state(profile_dir, ERRORS_FILE_NAME, |errors: &mut Errors| {
+        Ok(errors.fell_back)
+    })
+}
+
+/// The cache that the environment asks for, or the local
+/// cache if this build has given up on that.
+pub fn cache_from_env(profile_dir: Option<&Path>) -> anyhow::Result<Arc<dyn Cache>> {
+    if has_fallen_back(profile_dir)? {
+        return Ok(Arc::new(LocalCache::from_env()?));
+    }
+    cache::from_env()
+}
+
+/// Note that the remote cache answered, which resets the count.
+pub fn note_answer(profile_dir: &Path) -> anyhow::Result<()> {
+    session::update_state(profile_dir, ERRORS_FILE_NAME, |errors: &mut Errors| {
+        errors.in_a_row = 0;
+        Ok(())
+    })
+}
+
+/// Note that the remote cache at `location` refused a push, so that the
+/// rest of the build doesn't push to it. It answered, so that also resets
+/// the count.
+pub fn note_push_refused(profile_dir: &Path, location: &str) -> anyhow::Result<()> {
+    session::update_state(profile_dir, ERRORS_FILE_NAME, |errors: &mut Errors| {
+        errors.in_a_row = 0;
+        errors.refusing_pushes.insert(location.to_owned());
+        Ok(())
+    })
+}
+
+/// Has the remote cache at `location` refused a push this build?
+///
+/// Units outside of any profile dir never know.
+pub fn refuses_pushes(profile_dir: Option<&Path>, location: &str) -> anyhow::Result<bool> {
+    let Some(profile_dir) = profile_dir else {
+        return Ok(false);
+    };
+    session::update_state(profile_dir, ERRORS_FILE_NAME, |errors: &mut Errors| {
+        Ok(errors.refusing_pushes.contains(location))
+    })
+}
+
+/// Count a failure of the remote cache at `location` towards this build's
+/// limit, and give up on it if that's one too many in a row.
+pub fn note_error(
+    cache_dir: &Path,
+    profile_dir: &Path,
+    crate_unit_name: &str,
+    location: &str,
+    err: &anyhow::Error,
+) -> anyhow::Result<()> {
+    let limit = config::remote_error_limit()?;
+    session::update_state(profile_dir, ERRORS_FILE_NAME, |errors: &mut Errors| {
+        errors.in_a_row += 1;
+        if limit == 0 || errors.in_a_row < limit || errors.fell_back {
+            return Ok(());
+        }
+        errors.fell_back = true;
+        eprintln!(
+            "Hope: {location} has failed {} times in a row ({err:#}); \
+             using just the local cache for the rest of this build",
+            errors.in_a_row
+        );
+        write_log_line(
+            cache_dir,
+            CacheLogLine::FellBackToLocal(RemoteFallbackEvent {
+                crate_unit_name: crate_unit_name.to_owned(),
+                fell_back_at: Utc::now(),
+                location: location.to_owned(),
+                errors: errors.in_a_row,
+                last_error: format!("{err:#}"),
+            }),
+        )
+    })
+}
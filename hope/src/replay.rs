@@ -0,0 +1,110 @@
+//! Recording wrapper invocations, and running them again later.
+//!
+//! When something goes wrong deep inside a build, it's usually down to one
+//! `rustc` invocation that's awkward to reproduce by hand: Cargo passes a
+//! lot of arguments, and a lot of environment variables. So set
+//! `HOPE_RECORD_INVOCATIONS` to a directory, and each invocation we see
+//! as a `rustc` wrapper is saved there as JSON: its arguments, its working
+//! directory, and the environment variables that Cargo, `rustc`, or Hope
+//! care about. Then `hope replay <file>` runs Hope again exactly as it was
+//! run then, as many times as it takes, with whatever debugging you like.
+//!
+//! Recordings include `HOPE_*` variables, which may have credentials in
+//! them (e.g. in `HOPE_CACHE_URL`), so check before passing them on.
+//! Anything in the recording is set for the replay, except for `HOPE_*`
+//! variables that are already set, so that a replay can (e.g.) use a
+//! different cache dir from the original.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::signals;
+
+/// Environment variables worth recording: anything starting with one of these...
+const RECORDED_ENV_PREFIXES: &[&str] = &["CARGO", "RUST", "HOPE_", "DEP_"];
+
+/// ...or named exactly one of these.
+const RECORDED_ENV_NAMES: &[&str] = &[
+    "OUT_DIR", "TARGET", "HOST", "CC", "CXX", "AR", "CFLAGS", "CXXFLAGS",
+];
+
+/// Don't record replays of recordings.
+const RECORD_VAR: &str = "HOPE_RECORD_INVOCATIONS";
+
+#[derive(Serialize, Deserialize)]
+struct Invocation {
+    recorded_at: DateTime<Utc>,
+    /// Everything after our own path, starting with the real `rustc`'s.
+    args: Vec<String>,
+    cwd: PathBuf,
+    env: BTreeMap<String, String>,
+}
+
+/// Save an invocation with these arguments (not including our own path)
+/// into `dir`, named for the crate being built, if there is one.
+pub fn record(dir: &Path, args: &[String]) -> anyhow::Result<()> {
+    let invocation = Invocation {
+        recorded_at: Utc::now(),
+        args: args.to_vec(),
+        cwd: std::env::current_dir().context("Failed to get current dir")?,
+        env: std::env::vars()
+            .filter(|(name, _)| {
+                name != RECORD_VAR
+                    && (RECORDED_ENV_NAMES.contains(&name.as_str())
+                        || RECORDED_ENV_PREFIXES
+                            .iter()
+                            .any(|prefix| name.starts_with(prefix)))
+            })
+            .collect(),
+    };
+    let crate_name = args
+        .iter()
+        .skip_while(|arg| *arg != "--crate-name")
+        .nth(1)
+        .map_or("rustc", String::as_str);
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+    let path = dir.join(format!(
+        "{crate_name}-{}-{}.json",
+        invocation.recorded_at.format("%Y%m%dT%H%M%S%.6f"),
+        std::process::id()
+    ));
+    let json = serde_json::to_vec_pretty(&invocation).context("Failed to serialize invocation")?;
+    std::fs::write(&path, json).with_context(|| format!("Failed to write {path:?}"))
+}
+
+/// Run a recorded invocation again, exiting however it does.
+pub fn run(path: &Path) -> anyhow::Result<()> {
+    let json = std::fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let invocation: Invocation =
+        serde_json::from_slice(&json).with_context(|| format!("Invalid recording {path:?}"))?;
+    let our_exe = std::env::current_exe().context("Failed to find our own executable")?;
+    let mut command = Command::new(our_exe);
+    command
+        .args(&invocation.args)
+        .current_dir(&invocation.cwd)
+        .env_remove(RECORD_VAR);
+    for (name, value) in &invocation.env {
+        if name.starts_with("HOPE_") && std::env::var_os(name).is_some() {
+            continue;
+        }
+        command.env(name, value);
+    }
+    let (status, _) =
+        signals::status(&mut command).with_context(|| format!("Failed to replay {path:?}"))?;
+    signals::check()?;
+    if !status.success() {
+        std::process::exit(
+            status
+                .code()
+                .context("Replayed invocation was terminated by a signal")?,
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! Measuring how much memory and CPU time child processes use.
+//!
+//! Compile times alone don't tell you which crates need a big machine
+//! to build; peak memory use does. `wait4` gives us both for the whole
+//! process tree under a child (`rustc` runs its own linker, build scripts
+//! sometimes run compilers), as long as each process waits for its children.
+
+use std::{
+    io,
+    os::unix::process::ExitStatusExt as _,
+    process::{Child, ExitStatus},
+};
+
+use hope_cache_log::ResourceUsage;
+
+/// Like `Child::wait`, but also report what the child used.
+///
+/// This reaps the child behind `Child`'s back, so don't call any of its
+/// other waiting methods afterwards.
+pub fn wait(child: &mut Child) -> io::Result<(ExitStatus, ResourceUsage)> {
+    // Same as `Child::wait`: make sure the child isn't waiting for more input.
+    drop(child.stdin.take());
+    let pid = child.id() as libc::pid_t;
+    let mut status = 0;
+    // SAFETY: `rusage` is plain old data, so all zeroes is a valid value.
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: Both pointers are to live locals of the right types.
+        let result = unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) };
+        if result != -1 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    Ok((ExitStatus::from_raw(status), usage_from(&rusage)))
+}
+
+fn usage_from(rusage: &libc::rusage) -> ResourceUsage {
+    let secs = |time: libc::timeval| time.tv_sec as f64 + time.tv_usec as f64 / 1_000_000.0;
+    // Linux reports kilobytes, but macOS reports bytes.
+    let max_rss_bytes = if cfg!(target_os = "macos") {
+        rusage.ru_maxrss as u64
+    } else {
+        rusage.ru_maxrss as u64 * 1024
+    };
+    ResourceUsage {
+        max_rss_bytes,
+        cpu_secs: secs(rusage.ru_utime) + secs(rusage.ru_stime),
+    }
+}
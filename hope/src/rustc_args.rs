@@ -0,0 +1,174 @@
+//! Making sense of the arguments that Cargo passes to `rustc`.
+
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use clap::Parser;
+use serde::{Serialize, Serializer};
+
+// TODO: I don't like this. I'd instead like to be able to collect
+// the flags and kv-pairs into a custom collection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlagOrKvPair {
+    Flag(String),
+    KvPair(KeyValuePair),
+}
+
+impl FromStr for FlagOrKvPair {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((key, value)) = s.split_once('=') {
+            Ok(Self::KvPair(KeyValuePair {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            }))
+        } else {
+            Ok(Self::Flag(s.to_owned()))
+        }
+    }
+}
+
+impl fmt::Display for FlagOrKvPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Flag(flag) => write!(f, "{flag}"),
+            Self::KvPair(kv_pair) => write!(f, "{}={}", kv_pair.key, kv_pair.value),
+        }
+    }
+}
+
+/// As it was on the command line.
+impl Serialize for FlagOrKvPair {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyValuePair {
+    pub key: String,
+    pub value: String,
+}
+
+// Arguments here mirror the real `rustc` arguments.
+// I'm just using Clap to make it easier to inspect/modify the ones I care about.
+//
+// It's serialized for `hope key --json`, so that the integration tests can
+// check it against real command lines (see "tests/rustc-args"); there's also
+// a fuzz target for it in "fuzz".
+//
+// Only split values on commas (with `value_delimiter`) where `rustc` itself
+// does. Everything else is taken whole, because paths (in `-L`, `--extern`,
+// `--remap-path-prefix`), linker arguments (`-C link-arg=-Wl,--as-needed`),
+// link modifiers (`-l static:+whole-archive,-bundle=foo`), and quoted cfg
+// values can all have commas in them, and splitting them would change what
+// gets hashed into the key. (Pass-through never uses these anyway; it's
+// always the original arguments that go to `rustc`.)
+#[derive(Parser, Debug, Serialize)]
+#[command(disable_version_flag = true, disable_help_flag = true)]
+pub struct Args {
+    // Not required if, e.g., passing `--version`.
+    pub input: Option<String>,
+    #[arg(long)]
+    pub cfg: Vec<String>,
+    #[arg(short = 'L')]
+    pub lib_search_paths: Vec<String>,
+    #[arg(short = 'l')]
+    pub link_to_native_libs: Vec<String>,
+    #[arg(long = "crate-type")]
+    pub crate_types: Vec<String>,
+    #[arg(long)]
+    pub crate_name: Option<String>,
+    #[arg(long)]
+    pub edition: Option<String>,
+    #[arg(long, value_delimiter = ',')]
+    pub emit: Vec<String>,
+    #[arg(long, value_delimiter = ',')]
+    pub print: Vec<String>,
+    #[arg(short = 'g')]
+    pub include_debug_info: bool,
+    #[arg(short = 'O')]
+    pub optimize: bool,
+    #[arg(short = 'o')]
+    pub out: Option<String>,
+    #[arg(long)]
+    pub out_dir: Option<String>,
+    #[arg(long)]
+    pub explain: bool,
+    #[arg(long)]
+    pub test: bool,
+    #[arg(long = "warn", short = 'W', value_delimiter = ',')]
+    pub warn_for_lints: Vec<String>,
+    #[arg(long = "force-warn", value_delimiter = ',')]
+    pub force_warn_for_lints: Vec<String>,
+    #[arg(long = "allow", short = 'A', value_delimiter = ',')]
+    pub allow_lints: Vec<String>,
+    #[arg(long = "deny", short = 'D', value_delimiter = ',')]
+    pub deny_lints: Vec<String>,
+    #[arg(long = "forbid", short = 'F', value_delimiter = ',')]
+    pub forbid_lints: Vec<String>,
+    #[arg(short = 'Z')]
+    pub unstable_options: Vec<String>,
+    #[arg(long)]
+    pub cap_lints: Option<String>,
+    #[arg(short = 'C', long = "codegen")]
+    pub codegen_options: Vec<FlagOrKvPair>,
+    #[arg(short = 'V', long)]
+    pub version: bool,
+    #[arg(short, long)]
+    pub verbose: bool,
+    #[arg(long = "extern")]
+    #[serde(rename = "extern")]
+    pub extern_: Vec<String>,
+    #[arg(long)]
+    pub sysroot: Option<String>,
+    #[arg(long)]
+    pub error_format: Option<String>,
+    #[arg(long)]
+    pub color: Option<String>,
+    #[arg(long)]
+    pub diagnostic_width: Option<u32>,
+    #[arg(long = "remap-path-prefix")]
+    pub remap_path_prefixes: Vec<String>,
+    #[arg(long, value_delimiter = ',')]
+    pub json: Vec<String>,
+    #[arg(long)]
+    pub check_cfg: Vec<String>,
+    #[arg(long)]
+    pub target: Option<String>,
+}
+
+impl Args {
+    /// The `-C extra-filename` suffix (including its leading '-'),
+    /// which Cargo uses to tell apart output files for different builds of a crate.
+    pub fn extra_filename(&self) -> Option<&str> {
+        self.codegen_option("extra-filename")
+    }
+
+    /// The `-C metadata` hash, which feeds into symbol names.
+    ///
+    /// In older versions of Cargo this was always the same as the
+    /// extra filename, but these days it can differ.
+    pub fn metadata_hash(&self) -> Option<&str> {
+        self.codegen_option("metadata")
+    }
+
+    /// Features that Cargo enabled for the crate, from its
+    /// `--cfg feature="..."` arguments.
+    pub fn features(&self) -> BTreeSet<String> {
+        self.cfg
+            .iter()
+            .filter_map(|cfg| cfg.strip_prefix("feature=\"")?.strip_suffix('"'))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    pub fn codegen_option(&self, key: &str) -> Option<&str> {
+        self.codegen_options
+            .iter()
+            .find_map(|codegen_option| match codegen_option {
+                FlagOrKvPair::KvPair(kv_pair) if kv_pair.key == key => Some(kv_pair.value.as_str()),
+                _ => None,
+            })
+    }
+}
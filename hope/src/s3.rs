@@ -0,0 +1,620 @@
+//! Talking to an S3 bucket, for caches shared between machines.
+//!
+//! We only need a handful of object operations, so rather than pull in the
+//! whole AWS SDK, we sign requests ourselves (with Signature Version 4).
+//! Credentials are Hope's own for the bucket's URL, if it has any (see the
+//! `credentials` module), or else from the same places as the AWS CLI (or
+//! at least the common ones), in order:
+//!
+//! - `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, and `AWS_SESSION_TOKEN`
+//! - the shared credentials file (`~/.aws/credentials`, or wherever
+//!   `AWS_SHARED_CREDENTIALS_FILE` says), for the profile named by
+//!   `AWS_PROFILE` (or "default")
+//! - the ECS container credentials endpoint, if we're in a container
+//!   that has one
+//! - the EC2 instance metadata service, unless `AWS_EC2_METADATA_DISABLED`
+//!
+//! The region comes from `AWS_REGION` or `AWS_DEFAULT_REGION`, or else the
+//! profile in the shared config file (`~/.aws/config`, or `AWS_CONFIG_FILE`),
+//! and defaults to "us-east-1". `HOPE_S3_REGION` overrides all of those.
+//!
+//! Self-hosted S3-compatible stores (MinIO, Ceph, and so on) work too: point
+//! `HOPE_S3_ENDPOINT` at one, and maybe set `HOPE_S3_ADDRESSING_STYLE`.
+//! See `config::s3_endpoint` and `config::s3_path_style`.
+//!
+//! TODO: Web identity tokens (e.g. on EKS, or OIDC from CI),
+//! which need a round trip to STS.
+
+use std::{collections::BTreeMap, fmt::Write as _, io::Read as _, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+
+use crate::{
+    cache::RemoteBlobStore,
+    chunks::{self, BlobStore},
+    config,
+};
+
+/// How long to wait for the container and instance metadata services.
+/// They're only there on AWS, so looking for them anywhere else
+/// mustn't hold up every unit of the build.
+const METADATA_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// For everything else; an endpoint that stalls should fail the request
+/// rather than hang the build.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct S3BlobStore {
+    bucket: String,
+    /// Prepended to every key; either empty or ending in a slash.
+    prefix: String,
+    region: String,
+    /// "https", unless a custom endpoint says otherwise.
+    scheme: String,
+    /// The endpoint's host (and port, if it has one), without the bucket,
+    /// e.g. "s3.us-east-1.amazonaws.com".
+    host: String,
+    /// Whether the bucket goes in the path rather than the host name.
+    path_style: bool,
+    credentials: Credentials,
+    agent: ureq::Agent,
+}
+
+impl S3BlobStore {
+    /// Store blobs under the bucket and (optional) prefix in an
+    /// "s3://bucket/prefix" URL, using `credential` (as described in the
+    /// `credentials` module), or else finding AWS credentials as described
+    /// in the module docs.
+    pub fn from_url(url: &str, credential: Option<String>) -> anyhow::Result<Self> {
+        let location = url
+            .strip_prefix("s3://")
+            .with_context(|| format!("{url:?} isn't an S3 URL"))?;
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        anyhow::ensure!(!bucket.is_empty(), "S3 URL {url:?} has no bucket name");
+        let prefix = prefix.trim_matches('/');
+        let region = config::s3_region().unwrap_or_else(region);
+        let (scheme, host) = match config::s3_endpoint() {
+            Some(endpoint) => {
+                let (scheme, host) = endpoint
+                    .split_once("://")
+                    .filter(|(scheme, _)| matches!(*scheme, "http" | "https"))
+                    .with_context(|| {
+                        format!("S3 endpoint {endpoint:?} should start with \"http(s)://\"")
+                    })?;
+                let host = host.trim_end_matches('/');
+                anyhow::ensure!(
+                    !host.is_empty() && !host.contains('/'),
+                    "S3 endpoint {endpoint:?} should be just a scheme and host (and port)"
+                );
+                (scheme.to_owned(), host.to_owned())
+            }
+            None => ("https".to_owned(), format!("s3.{region}.amazonaws.com")),
+        };
+        Ok(Self {
+            bucket: bucket.to_owned(),
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{prefix}/")
+            },
+            region,
+            scheme,
+            host,
+            path_style: config::s3_path_style()?,
+            credentials: match credential {
+                Some(credential) => Credentials::parse(&credential)
+                    .with_context(|| format!("Invalid credential for {url:?}"))?,
+                None => Credentials::discover()?,
+            },
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout_read(IO_TIMEOUT)
+                .timeout_write(IO_TIMEOUT)
+                .build(),
+        })
+    }
+
+    fn read_body(&self, key: &str, response: ureq::Response) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut bytes)
+            .with_context(|| format!("Failed to read {key:?} from {}", self.url()))?;
+        Ok(bytes)
+    }
+
+    /// Send a signed request about the object for `key`.
+    ///
+    /// Gives `None` if there's no such object, for the caller to decide
+    /// whether that's a problem.
+    fn send(
+        &self,
+        method: &str,
+        key: &str,
+        extra_headers: &[(&str, &str)],
+        payload: &[u8],
+    ) -> anyhow::Result<Option<ureq::Response>> {
+        let object_path = uri_encode(&format!("{}{key}", self.prefix));
+        self.send_to(method, &object_path, &[], extra_headers, payload, key)
+    }
+
+    /// Send a signed request to an (already encoded) path in the bucket,
+    /// which is empty for the bucket itself.
+    ///
+    /// `what` is what the request is about, for error messages.
+    fn send_to(
+        &self,
+        method: &str,
+        object_path: &str,
+        query: &[(&str, &str)],
+        extra_headers: &[(&str, &str)],
+        payload: &[u8],
+        what: &str,
+    ) -> anyhow::Result<Option<ureq::Response>> {
+        let (host, path) = if self.path_style {
+            let path = if object_path.is_empty() {
+                format!("/{}", self.bucket)
+            } else {
+                format!("/{}/{object_path}", self.bucket)
+            };
+            (self.host.clone(), path)
+        } else {
+            (
+                format!("{}.{}", self.bucket, self.host),
+                format!("/{object_path}"),
+            )
+        };
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (query_encode(name), query_encode(value)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("&");
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = chunks::hash_bytes(payload);
+
+        // Everything but the authorization itself is signed.
+        let mut headers = BTreeMap::new();
+        headers.insert("host".to_owned(), host.clone());
+        headers.insert("x-amz-content-sha256".to_owned(), payload_hash.clone());
+        headers.insert("x-amz-date".to_owned(), amz_date);
+        if let Some(session_token) = &self.credentials.session_token {
+            headers.insert("x-amz-security-token".to_owned(), session_token.clone());
+        }
+        for (name, value) in extra_headers {
+            headers.insert(name.to_ascii_lowercase(), (*value).to_owned());
+        }
+        let authorization = self.credentials.authorization(
+            &self.region,
+            method,
+            &path,
+            &query,
+            &headers,
+            &payload_hash,
+        );
+
+        let url = if query.is_empty() {
+            format!("{}://{host}{path}", self.scheme)
+        } else {
+            format!("{}://{host}{path}?{query}", self.scheme)
+        };
+        let mut request = self
+            .agent
+            .request(method, &url)
+            .set("authorization", &authorization);
+        for (name, value) in &headers {
+            // `ureq` fills this in from the URL.
+            if name != "host" {
+                request = request.set(name, value);
+            }
+        }
+        // S3 insists on a content length for PUTs, even of empty blobs.
+        let result = if method == "PUT" {
+            request.send_bytes(payload)
+        } else {
+            request.call()
+        };
+        match result {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                Err(anyhow::anyhow!(
+                    "S3 responded to {method} of {what:?} in {} with {status} ({})",
+                    self.url(),
+                    error_code(&body).unwrap_or("no error code")
+                ))
+            }
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to {method} {what:?} in {}", self.url()))
+            }
+        }
+    }
+}
+
+impl RemoteBlobStore for S3BlobStore {
+    /// e.g. "s3://bucket/prefix/".
+    fn url(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.prefix)
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        let Some(response) = self.send("HEAD", key, &[], &[])? else {
+            return Ok(None);
+        };
+        let size = response
+            .header("content-length")
+            .and_then(|size| size.parse().ok())
+            .with_context(|| format!("No size for {key:?} in {}", self.url()))?;
+        Ok(Some(size))
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        let range = format!("bytes=-{len}");
+        let response = self
+            .send("GET", key, &[("range", &range)], &[])?
+            .with_context(|| format!("{key:?} isn't in {}", self.url()))?;
+        self.read_body(key, response)
+    }
+
+    fn list_blobs(&self) -> anyhow::Result<Vec<String>> {
+        // Listings come a page at a time (of up to 1,000 keys).
+        let mut keys = Vec::new();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("list-type", "2"),
+                ("prefix", self.prefix.as_str()),
+                ("delimiter", "/"),
+            ];
+            if let Some(continuation_token) = &continuation_token {
+                query.push(("continuation-token", continuation_token.as_str()));
+            }
+            let response = self
+                .send_to("GET", "", &query, &[], &[], "the list of blobs")?
+                .with_context(|| format!("Bucket for {} doesn't exist", self.url()))?;
+            let body = String::from_utf8(self.read_body("the list of blobs", response)?)
+                .context("List of blobs contained invalid UTF-8")?;
+            keys.extend(
+                xml_values(&body, "Key")
+                    .into_iter()
+                    .filter_map(|key| key.strip_prefix(self.prefix.as_str()).map(str::to_owned)),
+            );
+            if xml_values(&body, "IsTruncated") != ["true"] {
+                return Ok(keys);
+            }
+            continuation_token = Some(
+                xml_values(&body, "NextContinuationToken")
+                    .pop()
+                    .context("Truncated list of blobs with no continuation token")?,
+            );
+        }
+    }
+}
+
+impl BlobStore for S3BlobStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let response = self
+            .send("GET", key, &[], &[])?
+            .with_context(|| format!("{key:?} isn't in {}", self.url()))?;
+        self.read_body(key, response)
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        // Objects spring into existence, so the only thing that can be
+        // missing here is the bucket.
+        self.send("PUT", key, &[], bytes)?
+            .with_context(|| format!("Bucket for {} doesn't exist", self.url()))?;
+        Ok(())
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.send("HEAD", key, &[], &[])?.is_some())
+    }
+}
+
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+/// What the container and instance metadata services give us.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct MetadataCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+}
+
+impl From<MetadataCredentials> for Credentials {
+    fn from(credentials: MetadataCredentials) -> Self {
+        Self {
+            access_key_id: credentials.access_key_id,
+            secret_access_key: credentials.secret_access_key,
+            session_token: credentials.token,
+        }
+    }
+}
+
+impl Credentials {
+    /// "ACCESS_KEY_ID:SECRET_ACCESS_KEY[:SESSION_TOKEN]"
+    fn parse(credential: &str) -> anyhow::Result<Self> {
+        let mut parts = credential.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(access_key_id), Some(secret_access_key), session_token)
+                if !access_key_id.is_empty() && !secret_access_key.is_empty() =>
+            {
+                Ok(Self {
+                    access_key_id: access_key_id.to_owned(),
+                    secret_access_key: secret_access_key.to_owned(),
+                    session_token: session_token.map(str::to_owned),
+                })
+            }
+            _ => anyhow::bail!(
+                "Expected \"ACCESS_KEY_ID:SECRET_ACCESS_KEY\" (and maybe \":SESSION_TOKEN\")"
+            ),
+        }
+    }
+
+    /// Look for credentials in each of the places listed in the module docs.
+    fn discover() -> anyhow::Result<Self> {
+        if let (Ok(access_key_id), Ok(secret_access_key)) = (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            return Ok(Self {
+                access_key_id,
+                secret_access_key,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            });
+        }
+        if let Some(mut section) =
+            shared_file_section("AWS_SHARED_CREDENTIALS_FILE", "credentials", &profile())
+        {
+            if let (Some(access_key_id), Some(secret_access_key)) = (
+                section.remove("aws_access_key_id"),
+                section.remove("aws_secret_access_key"),
+            ) {
+                return Ok(Self {
+                    access_key_id,
+                    secret_access_key,
+                    session_token: section.remove("aws_session_token"),
+                });
+            }
+        }
+        if let Some(credentials) = Self::from_container()? {
+            return Ok(credentials);
+        }
+        if let Some(credentials) = Self::from_instance_metadata() {
+            return Ok(credentials);
+        }
+        anyhow::bail!(
+            "Couldn't find any AWS credentials (in environment variables, the shared \
+             credentials file, or from container or instance metadata)"
+        )
+    }
+
+    /// Credentials for the task's role, if we're running in ECS (or anything
+    /// else that sets up the same endpoint, like CodeBuild).
+    fn from_container() -> anyhow::Result<Option<Self>> {
+        let url = if let Ok(relative_uri) = std::env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI")
+        {
+            format!("http://169.254.170.2{relative_uri}")
+        } else if let Ok(full_uri) = std::env::var("AWS_CONTAINER_CREDENTIALS_FULL_URI") {
+            full_uri
+        } else {
+            return Ok(None);
+        };
+        let mut request = ureq::get(&url).timeout(METADATA_TIMEOUT);
+        if let Ok(token) = std::env::var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+            request = request.set("authorization", &token);
+        }
+        // We were told it's there, so it had better be.
+        let response = request
+            .call()
+            .context("Failed to get credentials from container credentials endpoint")?;
+        let credentials: MetadataCredentials = serde_json::from_reader(response.into_reader())
+            .context("Invalid credentials from container credentials endpoint")?;
+        Ok(Some(credentials.into()))
+    }
+
+    /// Credentials for the instance's role, if we're running on EC2.
+    fn from_instance_metadata() -> Option<Self> {
+        if std::env::var("AWS_EC2_METADATA_DISABLED").is_ok_and(|disabled| disabled == "true") {
+            return None;
+        }
+        let base_url = "http://169.254.169.254/latest";
+        let token = ureq::put(&format!("{base_url}/api/token"))
+            .timeout(METADATA_TIMEOUT)
+            .set("x-aws-ec2-metadata-token-ttl-seconds", "60")
+            .call()
+            .ok()?
+            .into_string()
+            .ok()?;
+        let get = |path: &str| {
+            ureq::get(&format!(
+                "{base_url}/meta-data/iam/security-credentials/{path}"
+            ))
+            .timeout(METADATA_TIMEOUT)
+            .set("x-aws-ec2-metadata-token", &token)
+            .call()
+            .ok()
+        };
+        let role = get("")?.into_string().ok()?;
+        let credentials: MetadataCredentials =
+            serde_json::from_reader(get(role.lines().next()?)?.into_reader()).ok()?;
+        Some(credentials.into())
+    }
+
+    /// Value for the authorization header of a request; see
+    /// <https://docs.aws.amazon.com/IAM/latest/UserGuide/reference_sigv.html>.
+    ///
+    /// The query string must already be in canonical form
+    /// (encoded, and sorted by name), and the headers must include "x-amz-date".
+    fn authorization(
+        &self,
+        region: &str,
+        method: &str,
+        path: &str,
+        query: &str,
+        headers: &BTreeMap<String, String>,
+        payload_hash: &str,
+    ) -> String {
+        let amz_date = &headers["x-amz-date"];
+        let date = &amz_date[..8];
+        let scope = format!("{date}/{region}/s3/aws4_request");
+        let signed_headers = headers
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\n{canonical_headers}\n{signed_headers}\n{payload_hash}"
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            chunks::hash_bytes(canonical_request.as_bytes())
+        );
+        let mut signing_key = hmac(
+            format!("AWS4{}", self.secret_access_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [region, "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part.as_bytes());
+        }
+        let mut signature = String::new();
+        for byte in hmac(&signing_key, string_to_sign.as_bytes()) {
+            let _ = write!(signature, "{byte:02x}");
+        }
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, \
+             Signature={signature}",
+            self.access_key_id
+        )
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode everything but unreserved characters and slashes,
+/// as S3 expects for object paths.
+fn uri_encode(path: &str) -> String {
+    let mut encoded = String::new();
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~/".contains(&byte) {
+            encoded.push(byte.into());
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+/// Percent-encode everything but unreserved characters,
+/// as S3 expects for query strings.
+fn query_encode(value: &str) -> String {
+    uri_encode(value).replace('/', "%2F")
+}
+
+/// Every value of an element in an S3 response, unescaped.
+///
+/// S3's responses are simple enough that this doesn't need a real XML parser.
+fn xml_values(body: &str, element: &str) -> Vec<String> {
+    let open = format!("<{element}>");
+    let close = format!("</{element}>");
+    body.split(open.as_str())
+        .skip(1)
+        .filter_map(|rest| {
+            let (value, _) = rest.split_once(close.as_str())?;
+            Some(
+                value
+                    .replace("&lt;", "<")
+                    .replace("&gt;", ">")
+                    .replace("&quot;", "\"")
+                    .replace("&apos;", "'")
+                    .replace("&amp;", "&"),
+            )
+        })
+        .collect()
+}
+
+/// Pick the error code out of an S3 error response, e.g. "AccessDenied".
+fn error_code(body: &str) -> Option<&str> {
+    let (_, rest) = body.split_once("<Code>")?;
+    let (code, _) = rest.split_once("</Code>")?;
+    Some(code)
+}
+
+fn profile() -> String {
+    std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_owned())
+}
+
+fn region() -> String {
+    if let Ok(region) = std::env::var("AWS_REGION").or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+    {
+        return region;
+    }
+    // Profiles other than the default are prefixed in the config file,
+    // unlike the credentials file.
+    let profile = profile();
+    let section = if profile == "default" {
+        profile
+    } else {
+        format!("profile {profile}")
+    };
+    shared_file_section("AWS_CONFIG_FILE", "config", &section)
+        .and_then(|mut section| section.remove("region"))
+        .unwrap_or_else(|| "us-east-1".to_owned())
+}
+
+/// Settings from a section of one of the AWS CLI's INI files, which live
+/// in "~/.aws" unless the given environment variable says otherwise.
+fn shared_file_section(
+    env_var: &str,
+    default_file_name: &str,
+    section: &str,
+) -> Option<BTreeMap<String, String>> {
+    let path = match std::env::var_os(env_var) {
+        Some(path) => PathBuf::from(path),
+        None => directories::BaseDirs::new()?
+            .home_dir()
+            .join(".aws")
+            .join(default_file_name),
+    };
+    let content = std::fs::read_to_string(path).ok()?;
+    let mut settings = None;
+    for line in content.lines().map(str::trim) {
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            if settings.is_some() {
+                break;
+            }
+            if name.trim() == section {
+                settings = Some(BTreeMap::new());
+            }
+        } else if let (Some(settings), Some((key, value))) = (&mut settings, line.split_once('=')) {
+            settings.insert(key.trim().to_owned(), value.trim().to_owned());
+        }
+    }
+    settings
+}
@@ -0,0 +1,172 @@
+//! Sharing the local cache with other machines over HTTP.
+//!
+//! This lets one developer machine (or a spare box in the office) act as
+//! the remote cache for everyone else on the LAN, with zero cloud setup.
+//!
+//! The protocol is deliberately dumb: every blob in the cache (artifacts,
+//! chunks, manifests, build script output) is addressed by its key under
+//! [`BLOB_PATH_PREFIX`], and supports `GET`, `HEAD`, and `PUT`.
+//! Missing blobs are a 404. Clients can find out what the server supports
+//! from `GET` [`CAPABILITIES_PATH`] (see the `capabilities` module).
+//!
+//! Every `PUT` is refused unless `--allow-push` is given, so that by
+//! default colleagues can pull what you've built without being able to
+//! push anything into your cache. (Their builds stop pushing here after the
+//! first refusal, and carry on regardless.)
+//!
+//! With `--upstream`, the server is a pull-through proxy for another cache
+//! (given as a URL, just like `HOPE_CACHE_URL`): blobs it doesn't have are
+//! fetched from there, kept, and served, so that (e.g.) an office server
+//! fills up from a cloud bucket as the machines behind it build, and only
+//! fetches each blob once. Pushes never go upstream.
+//!
+//! There is no authentication, so only listen on networks you trust.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use tiny_http::{Method, Request, Response, Server};
+
+use crate::{
+    cache::{self, is_valid_key, LocalCache, RemoteBlobStore},
+    capabilities::{Capabilities, CAPABILITIES_PATH},
+    chunks::BlobStore,
+};
+
+pub const BLOB_PATH_PREFIX: &str = "/blobs/";
+
+/// Serve the local cache on `listen` (e.g. "0.0.0.0:7777") until killed.
+pub fn run(listen: &str, allow_push: bool, upstream: Option<&str>) -> anyhow::Result<()> {
+    let cache = Arc::new(PullThrough {
+        local: LocalCache::from_env()?,
+        upstream: upstream
+            .map(cache::store_from_url)
+            .transpose()
+            .context("Invalid '--upstream' cache URL")?,
+    });
+    let server = Server::http(listen)
+        .map_err(|err| anyhow::anyhow!(err))
+        .with_context(|| format!("Failed to listen on {listen:?}"))?;
+    let local_addr = server
+        .server_addr()
+        .to_ip()
+        .context("Server isn't listening on an IP address")?;
+    // Print this on stdout so that scripts (and tests) can find
+    // out which port we got if they asked for port 0.
+    println!("Listening on {local_addr}");
+
+    for request in server.incoming_requests() {
+        let cache = Arc::clone(&cache);
+        std::thread::spawn(move || {
+            if let Err(err) = respond(&cache, request, allow_push) {
+                eprintln!("Failed to respond to request: {err:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn respond(cache: &PullThrough, request: Request, allow_push: bool) -> anyhow::Result<()> {
+    if request.url() == CAPABILITIES_PATH && *request.method() == Method::Get {
+        let body = serde_json::to_vec(&Capabilities::current())?;
+        return Ok(request.respond(Response::from_data(body))?);
+    }
+    if !allow_push && *request.method() == Method::Put {
+        return Ok(request.respond(Response::empty(403))?);
+    }
+    respond_with_blob(cache, request)
+}
+
+/// Answer a `GET`, `HEAD`, or `PUT` of a blob under [`BLOB_PATH_PREFIX`]
+/// from `blobs`. Anything else is a 404.
+pub fn respond_with_blob(blobs: &dyn BlobStore, mut request: Request) -> anyhow::Result<()> {
+    let Some(key) = request
+        .url()
+        .strip_prefix(BLOB_PATH_PREFIX)
+        .map(str::to_owned)
+    else {
+        return Ok(request.respond(Response::empty(404))?);
+    };
+    if !is_valid_key(&key) {
+        return Ok(request.respond(Response::empty(400))?);
+    }
+
+    match request.method() {
+        Method::Get | Method::Head => {
+            if !blobs.has_blob(&key)? {
+                return Ok(request.respond(Response::empty(404))?);
+            }
+            // `tiny_http` leaves out the body for `HEAD` requests.
+            let bytes = blobs.get_blob(&key)?;
+            request.respond(Response::from_data(bytes))?;
+        }
+        Method::Put => {
+            if is_reserved_key(&key) {
+                return Ok(request.respond(Response::empty(403))?);
+            }
+            let mut bytes = Vec::new();
+            request
+                .as_reader()
+                .read_to_end(&mut bytes)
+                .context("Failed to read request body")?;
+            blobs.put_blob(&key, &bytes)?;
+            request.respond(Response::empty(204))?;
+        }
+        _ => request.respond(Response::empty(405))?,
+    }
+    Ok(())
+}
+
+/// Our own bookkeeping files (like the log) live alongside blobs,
+/// but other machines have no business overwriting them.
+fn is_reserved_key(key: &str) -> bool {
+    key.starts_with("hope-")
+}
+
+/// The local cache, plus whatever it can fetch from upstream.
+struct PullThrough {
+    local: LocalCache,
+    upstream: Option<Box<dyn RemoteBlobStore>>,
+}
+
+impl PullThrough {
+    /// Make sure we have the blob, if upstream does.
+    ///
+    /// Not being able to reach upstream just means we don't have it.
+    fn fetch(&self, key: &str) -> anyhow::Result<bool> {
+        if self.local.has_blob(key)? {
+            return Ok(true);
+        }
+        let Some(upstream) = &self.upstream else {
+            return Ok(false);
+        };
+        if is_reserved_key(key) {
+            return Ok(false);
+        }
+        let fetched = upstream.has_blob(key).and_then(|has_blob| {
+            if has_blob {
+                self.local.put_blob(key, &upstream.get_blob(key)?)?;
+            }
+            Ok(has_blob)
+        });
+        Ok(fetched.unwrap_or_else(|err| {
+            eprintln!("Failed to fetch {key:?} from {}: {err:#}", upstream.url());
+            false
+        }))
+    }
+}
+
+impl BlobStore for PullThrough {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.fetch(key)?;
+        self.local.get_blob(key)
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.local.put_blob(key, bytes)
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        self.fetch(key)
+    }
+}
@@ -0,0 +1,224 @@
+//! Noting the start of each Cargo build in the log.
+//!
+//! Cargo runs us once per unit, so there's no one place where a build
+//! starts. Instead, the first unit we see from a given Cargo process claims
+//! the build, by writing that process's ID to a file in the profile dir
+//! (under a lock, as units are built in parallel), and logs a
+//! [`SessionStartedEvent`] describing it. That way the log still makes
+//! sense when it's read weeks later, or gathered up from lots of machines.
+
+use std::{
+    io::{Read as _, Seek as _, Write as _},
+    path::Path,
+    process::Command,
+    sync::OnceLock,
+};
+
+use anyhow::Context;
+use chrono::Utc;
+use hope_cache_log::{write_log_line, CacheLogLine, SessionStartedEvent};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::toolchain::RustcInfo;
+
+/// Records which Cargo process the last session we logged belonged to.
+const SESSION_FILE_NAME: &str = ".hope-session";
+
+/// The ID of the Cargo process running this unit.
+///
+/// That's our parent, as far as we know the first time this is called;
+/// pushes in the background (see the `background_push` module) call it
+/// before they fork, so they still know once they've been orphaned.
+pub fn cargo_pid() -> String {
+    static CARGO_PID: OnceLock<String> = OnceLock::new();
+    CARGO_PID
+        .get_or_init(|| std::os::unix::process::parent_id().to_string())
+        .clone()
+}
+
+/// Log the start of the build that this unit is part of,
+/// unless another unit already has. Returns whether this one did.
+pub fn note_start(
+    cache_dir: &Path,
+    profile_dir: &Path,
+    rustc_info: &RustcInfo,
+) -> anyhow::Result<bool> {
+    let cargo_pid = cargo_pid();
+    let session_path = profile_dir.join(SESSION_FILE_NAME);
+    let session_file = std::fs::File::options()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&session_path)
+        .with_context(|| format!("Failed to open {session_path:?}"))?;
+    let mut session_lock = fd_lock::RwLock::new(session_file);
+    let mut session_guard = session_lock
+        .write()
+        .with_context(|| format!("Failed to lock {session_path:?}"))?;
+    let mut last_cargo_pid = String::new();
+    session_guard.read_to_string(&mut last_cargo_pid)?;
+    if last_cargo_pid == cargo_pid {
+        return Ok(false);
+    }
+    session_guard.set_len(0)?;
+    session_guard.rewind()?;
+    session_guard.write_all(cargo_pid.as_bytes())?;
+
+    let cargo_args = cargo_args(&cargo_pid);
+    // Cross-compiled units live in "{target dir}/{target}/{profile}"
+    // rather than "{target dir}/{profile}", and only the target dir
+    // itself gets a "CACHEDIR.TAG".
+    let (target_dir, target) = match profile_dir.parent() {
+        Some(parent) if parent.join("CACHEDIR.TAG").exists() => {
+            (Some(parent), rustc_info.host.clone())
+        }
+        parent => (
+            parent.and_then(Path::parent),
+            parent
+                .and_then(Path::file_name)
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+        ),
+    };
+    write_log_line(
+        cache_dir,
+        CacheLogLine::SessionStarted(SessionStartedEvent {
+            started_at: Utc::now(),
+            cargo_version: cargo_version(),
+            rustc_version: rustc_info.release.clone(),
+            profile: profile_dir
+                .file_name()
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+            target,
+            jobs: cargo_args
+                .as_deref()
+                .and_then(jobs_from_args)
+                .or_else(|| std::env::var("CARGO_BUILD_JOBS").ok()?.parse().ok()),
+            // Assume the target dir is in the usual place, at the workspace root.
+            resolver: target_dir
+                .and_then(Path::parent)
+                .and_then(|workspace_dir| {
+                    std::fs::read_to_string(workspace_dir.join("Cargo.toml")).ok()
+                })
+                .map(|manifest| resolver_version(&manifest)),
+        }),
+    )?;
+    Ok(true)
+}
+
+/// State that's shared by every unit of one build, and no other.
+#[derive(Serialize, Deserialize)]
+struct BuildState<S> {
+    cargo_pid: String,
+    #[serde(flatten)]
+    state: S,
+}
+
+/// Run `f` on this build's `S`, kept in "{file_name}" in the profile dir,
+/// with the file locked (as units run in parallel), and save whatever
+/// it changed.
+///
+/// Anything in there from an earlier build (or unreadable) is
+/// replaced with `S::default()`.
+pub fn update_state<S, T>(
+    profile_dir: &Path,
+    file_name: &str,
+    f: impl FnOnce(&mut S) -> anyhow::Result<T>,
+) -> anyhow::Result<T>
+where
+    S: Default + Serialize + DeserializeOwned,
+{
+    let cargo_pid = cargo_pid();
+    let path = profile_dir.join(file_name);
+    let file = std::fs::File::options()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {path:?}"))?;
+    let mut lock = fd_lock::RwLock::new(file);
+    let mut guard = lock
+        .write()
+        .with_context(|| format!("Failed to lock {path:?}"))?;
+    let mut json = String::new();
+    guard.read_to_string(&mut json)?;
+    let mut build_state = serde_json::from_str::<BuildState<S>>(&json)
+        .ok()
+        .filter(|build_state| build_state.cargo_pid == cargo_pid)
+        .unwrap_or(BuildState {
+            cargo_pid,
+            state: S::default(),
+        });
+    let result = f(&mut build_state.state)?;
+    guard.set_len(0)?;
+    guard.rewind()?;
+    guard.write_all(&serde_json::to_vec(&build_state)?)?;
+    Ok(result)
+}
+
+fn cargo_version() -> Option<String> {
+    // Cargo tells the crates it builds where it is.
+    let cargo = std::env::var_os("CARGO")?;
+    let output = Command::new(cargo).arg("--version").output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Cargo's command line, if we can get at it (only on Linux, for now).
+fn cargo_args(cargo_pid: &str) -> Option<Vec<String>> {
+    let cmdline = std::fs::read(format!("/proc/{cargo_pid}/cmdline")).ok()?;
+    Some(
+        cmdline
+            .split(|byte| *byte == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect(),
+    )
+}
+
+/// The value of `-j` or `--jobs`, if given.
+fn jobs_from_args(args: &[String]) -> Option<i32> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            // The rest are for whatever Cargo runs.
+            "--" => return None,
+            "-j" | "--jobs" => args.next()?.as_str(),
+            _ => match arg
+                .strip_prefix("--jobs=")
+                .or_else(|| arg.strip_prefix("-j"))
+            {
+                Some(value) => value,
+                None => continue,
+            },
+        };
+        return value.parse().ok();
+    }
+    None
+}
+
+/// The workspace's feature resolver version, either as given or as implied
+/// by its edition.
+///
+/// This just looks for the relevant keys rather than parsing the whole manifest,
+/// which is good enough for all the manifests we've ever seen.
+fn resolver_version(manifest: &str) -> String {
+    let value_of = |key: &str| {
+        manifest.lines().find_map(|line| {
+            let (line_key, value) = line.split_once('=')?;
+            (line_key.trim() == key).then(|| value.trim().trim_matches('"').to_owned())
+        })
+    };
+    if let Some(resolver) = value_of("resolver") {
+        return resolver;
+    }
+    match value_of("edition").as_deref() {
+        Some("2024") => "3",
+        Some("2021") => "2",
+        _ => "1",
+    }
+    .to_owned()
+}
@@ -0,0 +1,111 @@
+//! Capping what cache operations can cost a whole Cargo build.
+//!
+//! `HOPE_PULL_BUDGET_MS` stops any one pull from dragging on, but a remote
+//! cache that's merely slow today can still add a little to every unit, and
+//! that adds up. With `HOPE_SESSION_BUDGET_SECS` (and/or
+//! `HOPE_SESSION_PULL_BUDGET`) set, each unit adds what its pulls and pushes
+//! cost to a running total for the build, kept in the profile dir next to
+//! the session file (see the `session` module). Once the total is over
+//! budget, the rest of the build doesn't try to pull (or push to remote
+//! caches) at all, and just compiles.
+//!
+//! The first unit to find the budget used up says so on stderr, and logs
+//! a [`SessionBudgetEvent`], which `hope stats` reports.
+
+use std::{path::Path, time::Duration};
+
+use chrono::Utc;
+use hope_cache_log::{write_log_line, CacheLogLine, SessionBudgetEvent};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, session};
+
+/// Running totals for the build, kept with `session::update_state`.
+const SPENDING_FILE_NAME: &str = ".hope-session-spending";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Spending {
+    secs: f64,
+    pulled_bytes: u64,
+    /// Whether a unit has already said that the budget ran out.
+    reported: bool,
+}
+
+/// Are there any budgets to keep to?
+pub fn is_set() -> anyhow::Result<bool> {
+    Ok(config::session_budget()?.is_some() || config::session_pull_budget()?.is_some())
+}
+
+/// Why this build shouldn't use the cache any more, if it's over budget.
+///
+/// The first unit to find out also reports it.
+pub fn exhausted(
+    cache_dir: &Path,
+    profile_dir: &Path,
+    crate_unit_name: &str,
+) -> anyhow::Result<Option<String>> {
+    if !is_set()? {
+        return Ok(None);
+    }
+    session::update_state(
+        profile_dir,
+        SPENDING_FILE_NAME,
+        |spending: &mut Spending| {
+            let Some(reason) = over_budget(spending)? else {
+                return Ok(None);
+            };
+            if !spending.reported {
+                spending.reported = true;
+                eprintln!("Hope: {reason}; compiling the rest of this build without the cache");
+                write_log_line(
+                    cache_dir,
+                    CacheLogLine::ExhaustedSessionBudget(SessionBudgetEvent {
+                        crate_unit_name: crate_unit_name.to_owned(),
+                        exhausted_at: Utc::now(),
+                        spent_secs: spending.secs,
+                        pulled_bytes: spending.pulled_bytes,
+                        reason: reason.clone(),
+                    }),
+                )?;
+            }
+            Ok(Some(reason))
+        },
+    )
+}
+
+/// Add what a unit's cache operations cost to the build's totals.
+pub fn spend(profile_dir: &Path, time: Duration, pulled_bytes: u64) -> anyhow::Result<()> {
+    if !is_set()? {
+        return Ok(());
+    }
+    session::update_state(
+        profile_dir,
+        SPENDING_FILE_NAME,
+        |spending: &mut Spending| {
+            spending.secs += time.as_secs_f64();
+            spending.pulled_bytes += pulled_bytes;
+            Ok(())
+        },
+    )
+}
+
+fn over_budget(spending: &Spending) -> anyhow::Result<Option<String>> {
+    if let Some(budget) = config::session_budget()? {
+        if spending.secs > budget.as_secs_f64() {
+            return Ok(Some(format!(
+                "Cache operations have taken {:.1}s, over the budget of {:.1}s",
+                spending.secs,
+                budget.as_secs_f64()
+            )));
+        }
+    }
+    if let Some(budget) = config::session_pull_budget()? {
+        if spending.pulled_bytes > budget {
+            return Ok(Some(format!(
+                "Pulls have fetched {} bytes, over the budget of {budget} bytes",
+                spending.pulled_bytes
+            )));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,82 @@
+//! Spreading one cache's blobs across several stores, for when a single
+//! bucket (or server, or Redis instance) can't keep up.
+//!
+//! Set `HOPE_CACHE_SHARDS` to a comma-separated list of cache URLs instead of
+//! setting `HOPE_CACHE_URL`, e.g. "s3://cache-0/hope,s3://cache-1/hope".
+//! Each blob goes to one shard, picked by rendezvous hashing on its key and
+//! each shard's URL: the order they're listed in doesn't matter, and adding
+//! or removing a shard only moves the blobs that belong on it (about one in
+//! however many shards there are). Those then look like misses until they're
+//! pushed again. Changing a shard's URL counts as removing it and adding
+//! another.
+//!
+//! An entry's blobs are spread out too, so a pull may talk to every shard;
+//! it's all the same to everything else, which just sees one blob store.
+
+use sha2::{Digest, Sha256};
+
+use crate::{cache::RemoteBlobStore, chunks::BlobStore};
+
+pub struct ShardedBlobStore {
+    shards: Vec<Box<dyn RemoteBlobStore>>,
+}
+
+impl ShardedBlobStore {
+    pub fn new(shards: Vec<Box<dyn RemoteBlobStore>>) -> anyhow::Result<Self> {
+        anyhow::ensure!(!shards.is_empty(), "There must be at least one shard");
+        Ok(Self { shards })
+    }
+
+    /// The shard a blob belongs on: whichever scores highest for its key.
+    fn shard(&self, key: &str) -> &dyn RemoteBlobStore {
+        let score = |shard: &dyn RemoteBlobStore| {
+            let hash = Sha256::digest(format!("{}\0{key}", shard.url()));
+            u64::from_be_bytes(hash[..8].try_into().expect("Hash is long enough"))
+        };
+        self.shards
+            .iter()
+            .map(|shard| &**shard)
+            .max_by_key(|shard| score(*shard))
+            .expect("There's always a shard")
+    }
+}
+
+impl BlobStore for ShardedBlobStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.shard(key).get_blob(key)
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.shard(key).put_blob(key, bytes)
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        self.shard(key).has_blob(key)
+    }
+}
+
+impl RemoteBlobStore for ShardedBlobStore {
+    fn url(&self) -> String {
+        self.shards
+            .iter()
+            .map(|shard| shard.url())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        self.shard(key).blob_size(key)
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.shard(key).get_blob_tail(key, len)
+    }
+
+    fn list_blobs(&self) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            keys.extend(shard.list_blobs()?);
+        }
+        Ok(keys)
+    }
+}
@@ -0,0 +1,156 @@
+//! Being interrupted (Ctrl-C, or `kill`) without leaving a mess.
+//!
+//! If we just died when interrupted part way through copying files,
+//! we could leave partial files behind in the cache or target dir.
+//! So while wrapping `rustc` or a build script, we catch SIGINT, SIGTERM,
+//! and SIGHUP, and instead:
+//!
+//! - Pass the signal on to whichever child process (`rustc` or a build
+//!   script) we're waiting for. Ctrl-C in a terminal reaches them anyway,
+//!   but a signal sent to just us (e.g. by a CI runner that's timed out)
+//!   wouldn't.
+//! - Bail out with [`Interrupted`] at the next point that's safe to stop
+//!   at (see [`check`]). That unwinds normally, so temporary files and dirs
+//!   get cleaned up, and files only ever get to their final names whole.
+//!
+//! Then `main` dies of the same signal, so Cargo sees what it expects.
+
+use std::{
+    fmt,
+    io::{self, Read as _},
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use anyhow::Context;
+use hope_cache_log::ResourceUsage;
+use rustix::process::{kill_process, Pid, Signal};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+
+use crate::rusage;
+
+/// The signal we caught, or 0.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+/// Process ID of the child we're waiting for, or 0.
+static CHILD: AtomicI32 = AtomicI32::new(0);
+
+/// Catch signals from now on, rather than dying straight away.
+pub fn install() -> anyhow::Result<()> {
+    for signal in [SIGINT, SIGTERM, SIGHUP] {
+        // SAFETY: The handler only touches atomics and makes
+        // a `kill` system call, which are all async-signal-safe.
+        unsafe { signal_hook::low_level::register(signal, move || on_signal(signal)) }
+            .with_context(|| format!("Failed to install handler for signal {signal}"))?;
+    }
+    Ok(())
+}
+
+fn on_signal(signal: i32) {
+    RECEIVED.store(signal, Ordering::SeqCst);
+    forward(CHILD.load(Ordering::SeqCst), signal);
+}
+
+fn forward(pid: i32, signal: i32) {
+    if let (Some(pid), Some(signal)) = (Pid::from_raw(pid), Signal::from_named_raw(signal)) {
+        // The child may have exited already, and there's nothing useful
+        // to do about any other failure in a signal handler anyway.
+        let _ = kill_process(pid, signal);
+    }
+}
+
+/// The signal we caught, if any.
+pub fn received() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Fail with [`Interrupted`] if we've caught a signal.
+///
+/// Call this before starting anything that shouldn't be started after
+/// an interruption, and before acting on the result of anything that
+/// might have failed _because_ of an interruption.
+pub fn check() -> anyhow::Result<()> {
+    match received() {
+        Some(signal) => Err(Interrupted { signal }.into()),
+        None => Ok(()),
+    }
+}
+
+/// Like `Command::status`, but passes on any signal we catch while waiting,
+/// and also reports what the child used.
+pub fn status(command: &mut Command) -> io::Result<(ExitStatus, ResourceUsage)> {
+    let mut child = spawn(command)?;
+    let result = rusage::wait(&mut child);
+    CHILD.store(0, Ordering::SeqCst);
+    result
+}
+
+/// Like `Command::output`, but passes on any signal we catch while waiting,
+/// and also reports what the child used.
+pub fn output(command: &mut Command) -> io::Result<(Output, ResourceUsage)> {
+    let mut child = spawn(
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+    // Read both at once, so the child can't get stuck writing to either.
+    let mut stderr_pipe = child.stderr.take().expect("Stderr should be piped");
+    let stderr_reader = std::thread::spawn(move || {
+        let mut stderr = Vec::new();
+        stderr_pipe.read_to_end(&mut stderr).map(|_| stderr)
+    });
+    let mut stdout = Vec::new();
+    let read_result = child
+        .stdout
+        .take()
+        .expect("Stdout should be piped")
+        .read_to_end(&mut stdout);
+    let stderr = stderr_reader.join().expect("Stderr reader panicked");
+    let result = rusage::wait(&mut child);
+    CHILD.store(0, Ordering::SeqCst);
+    let (status, usage) = result?;
+    read_result?;
+    let output = Output {
+        status,
+        stdout,
+        stderr: stderr?,
+    };
+    Ok((output, usage))
+}
+
+fn spawn(command: &mut Command) -> io::Result<Child> {
+    let child = command.spawn()?;
+    let pid = child.id() as i32;
+    CHILD.store(pid, Ordering::SeqCst);
+    // If the signal arrived while we were starting it,
+    // the handler didn't know to pass it on.
+    if let Some(signal) = received() {
+        forward(pid, signal);
+    }
+    Ok(child)
+}
+
+/// If we were interrupted, die of the same signal (now that we've cleaned up).
+pub fn reraise() {
+    if let Some(signal) = received() {
+        let _ = signal_hook::low_level::emulate_default_handler(signal);
+        // Just in case the default handler didn't kill us.
+        std::process::exit(128 + signal);
+    }
+}
+
+#[derive(Debug)]
+pub struct Interrupted {
+    signal: i32,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interrupted by signal {}", self.signal)
+    }
+}
+
+impl std::error::Error for Interrupted {}
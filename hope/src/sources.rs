@@ -0,0 +1,183 @@
+//! Verifying that registry sources haven't been tampered with.
+//!
+//! We key everything on Cargo's idea of the crate's identity, which assumes
+//! that crates from the registry are immutable. But nothing stops somebody
+//! (or some tool) from editing files in `~/.cargo/registry/src`, and we really
+//! don't want to push artifacts built from modified sources to a shared cache.
+//!
+//! So rather than trusting that, we can compare the files that `rustc`
+//! actually read against the pristine `.crate` archive that Cargo downloaded.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use flate2::read::GzDecoder;
+
+use crate::{chunks::hash_bytes, entry_manifest::EntryManifest};
+
+/// Find a source file in the package's registry checkout that doesn't match
+/// the package's `.crate` archive, if there is one.
+///
+/// If a dep info file is available then we only check the files listed in it;
+/// otherwise we check every file in the archive.
+pub fn find_modified_source(
+    input_path: &Path,
+    dep_info_path: Option<&Path>,
+) -> anyhow::Result<Option<PathBuf>> {
+    let (package_dir, archive_path) = locate_package(input_path)?;
+    let pristine = read_archive_hashes(&archive_path)?;
+
+    let paths_to_check: Vec<PathBuf> = match dep_info_path {
+        Some(dep_info_path) => {
+            let dep_info_text = std::fs::read_to_string(dep_info_path)
+                .context("Failed to read dep info file for source verification")?;
+            dep_info_paths(&dep_info_text)
+                .into_iter()
+                .filter(|path| path.starts_with(&package_dir))
+                .collect()
+        }
+        None => pristine.keys().map(|path| package_dir.join(path)).collect(),
+    };
+
+    for path in paths_to_check {
+        let relative_path = path
+            .strip_prefix(&package_dir)
+            .expect("We only check paths inside the package dir");
+        let Some(pristine_hash) = pristine.get(relative_path) else {
+            // Not in the archive at all, so somebody must have added it.
+            return Ok(Some(path));
+        };
+        let Ok(content) = std::fs::read(&path) else {
+            return Ok(Some(path));
+        };
+        if hash_bytes(&content) != *pristine_hash {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Hash the package's own source files that a dep info file lists,
+/// by path relative to the package dir, for recording in an entry's manifest.
+pub fn hash_listed_sources(
+    input_path: &Path,
+    dep_info_path: &Path,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let (package_dir, _) = locate_package(input_path)?;
+    let dep_info_text = std::fs::read_to_string(dep_info_path)
+        .context("Failed to read dep info file to hash sources")?;
+    let mut hashes = BTreeMap::new();
+    for path in dep_info_paths(&dep_info_text) {
+        let Ok(relative_path) = path.strip_prefix(&package_dir) else {
+            // Generated by a build script, or not part of this package.
+            continue;
+        };
+        let Some(relative_path) = relative_path.to_str() else {
+            continue;
+        };
+        let content =
+            std::fs::read(&path).with_context(|| format!("Failed to read {path:?} to hash it"))?;
+        hashes.insert(relative_path.to_owned(), hash_bytes(&content));
+    }
+    Ok(hashes)
+}
+
+/// Find a source file that an entry was built from that's missing or
+/// different in the package's checkout here, if there is one.
+///
+/// Cache keys assume that a given version of a package has the same sources
+/// everywhere, but a registry checkout can drift (somebody edits it, or a
+/// vendoring tool only copies part of it), and then a pulled artifact
+/// wouldn't match what building locally would produce. Entries pushed
+/// by older versions of Hope don't record their sources, so always pass.
+pub fn audit(input_path: &Path, manifest: &EntryManifest) -> anyhow::Result<Option<PathBuf>> {
+    if manifest.sources.is_empty() {
+        return Ok(None);
+    }
+    let (package_dir, _) = locate_package(input_path)?;
+    for (relative_path, hash) in &manifest.sources {
+        let path = package_dir.join(relative_path);
+        let matches = std::fs::read(&path).is_ok_and(|content| hash_bytes(&content) == *hash);
+        if !matches {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Work out the package's checkout dir and where Cargo keeps its `.crate` archive.
+///
+/// Cargo lays these out as:
+///
+/// - `{registry}/src/{index}/{package}-{version}/...`
+/// - `{registry}/cache/{index}/{package}-{version}.crate`
+fn locate_package(input_path: &Path) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let mut package_dir = input_path;
+    loop {
+        let parent = package_dir
+            .parent()
+            .context("Input path doesn't look like it's in a registry checkout")?;
+        let parent_is_index = parent
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("index.crates.io-"));
+        if parent_is_index {
+            break;
+        }
+        package_dir = parent;
+    }
+    let index_dir = package_dir.parent().expect("Checked above");
+    let registry_dir = index_dir
+        .parent()
+        .and_then(Path::parent)
+        .context("Registry index dir is missing its parent dirs")?;
+    let mut archive_name = package_dir
+        .file_name()
+        .context("Package dir has no name")?
+        .to_owned();
+    archive_name.push(".crate");
+    let archive_path = registry_dir
+        .join("cache")
+        .join(index_dir.file_name().expect("Checked above"))
+        .join(archive_name);
+    Ok((package_dir.to_owned(), archive_path))
+}
+
+/// Hash every file in a `.crate` archive, keyed by path relative to the package dir.
+fn read_archive_hashes(archive_path: &Path) -> anyhow::Result<HashMap<PathBuf, String>> {
+    let file = File::open(archive_path)
+        .with_context(|| format!("Failed to open crate archive {archive_path:?}"))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+    let mut hashes = HashMap::new();
+    for entry in archive.entries().context("Failed to read crate archive")? {
+        let mut entry = entry.context("Bad entry in crate archive")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        // Entries are all inside a "{package}-{version}" dir; strip that off.
+        let path: PathBuf = entry.path()?.components().skip(1).collect();
+        let mut content = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut content)
+            .with_context(|| format!("Failed to read {path:?} from crate archive"))?;
+        hashes.insert(path, hash_bytes(&content));
+    }
+    Ok(hashes)
+}
+
+/// Every file that a dep info file says the target depends on.
+///
+/// TODO: Handle escaped spaces etc. in file names!
+pub fn dep_info_paths(dep_info_text: &str) -> Vec<PathBuf> {
+    dep_info_text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .flat_map(|(_, deps)| deps.split(' ').filter(|dep| !dep.is_empty()))
+        .map(PathBuf::from)
+        .collect()
+}
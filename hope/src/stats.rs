@@ -0,0 +1,534 @@
+//! Summarising the cache log per crate, and what's taking up space in the cache.
+//!
+//! Besides being handy locally, the aggregate export is meant to be safe
+//! to share publicly (e.g. with registry maintainers deciding which crates
+//! most deserve upstream compile-time work). So it only includes crate names
+//! and numbers: no paths, no timestamps, and no unit hashes.
+//!
+//! `--merge` reports on logs gathered from other cache dirs instead, e.g.
+//! from every CI runner in a fleet, as if they were all one big log.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use hope_cache_log::{read_log, CacheLogLine};
+use serde::Serialize;
+
+use crate::{
+    cache::LocalCache,
+    chunks::ChunkManifest,
+    compression::{self, GZIP_SUFFIX},
+    config, disk_space,
+};
+
+/// Upper bounds of the size histogram buckets, in bytes.
+/// Anything bigger goes in a final open-ended bucket.
+const SIZE_BUCKETS: &[u64] = &[
+    1 << 10,
+    1 << 12,
+    1 << 14,
+    1 << 16,
+    1 << 18,
+    1 << 20,
+    1 << 22,
+    1 << 24,
+    1 << 26,
+    1 << 28,
+    1 << 30,
+];
+
+/// Bumped whenever the aggregate export changes incompatibly.
+const AGGREGATE_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Default, Serialize)]
+struct CrateStats {
+    crate_name: String,
+    /// Times we got it from cache instead of building it.
+    hits: u64,
+    /// Times we had to build it.
+    compiles: u64,
+    total_compile_secs: f64,
+    /// Peak memory use of the hungriest compile, if we know it.
+    max_rss_bytes: u64,
+}
+
+impl CrateStats {
+    fn mean_compile_secs(&self) -> f64 {
+        if self.compiles == 0 {
+            0.0
+        } else {
+            self.total_compile_secs / self.compiles as f64
+        }
+    }
+
+    /// How long the hits would have taken to compile instead,
+    /// going by the compiles we know about.
+    fn estimated_saved_secs(&self) -> f64 {
+        self.hits as f64 * self.mean_compile_secs()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Aggregate<'a> {
+    format_version: u32,
+    crates: &'a [CrateStats],
+}
+
+pub fn run(export_aggregate: bool, sccache_compat: bool, merge: &[PathBuf]) -> anyhow::Result<()> {
+    if !merge.is_empty() {
+        return run_merged(merge, export_aggregate);
+    }
+    let cache_dir =
+        LocalCache::dir_from_env().context("Failed to get local cache dir from environment")?;
+    let log = read_log(&cache_dir).context("Failed to read cache log")?;
+    if sccache_compat {
+        return print_sccache_compat(&log, &cache_dir);
+    }
+    let crates = crate_stats(&log);
+
+    if export_aggregate {
+        return print_aggregate(&crates);
+    }
+
+    if let Some(reason) = disk_space::degraded_reason(&cache_dir) {
+        println!("{reason}");
+        println!();
+    }
+    print_exhausted_budgets(&log);
+    print_fallbacks(&log);
+    print_crate_table(&crates);
+
+    let artifacts = artifact_sizes(&cache_dir)?;
+    println!();
+    println!("{:<30} {:>8} {:>14}", "kind", "count", "total size");
+    let mut by_kind: BTreeMap<ArtifactKind, (u64, u64)> = BTreeMap::new();
+    for (kind, size) in &artifacts {
+        let (count, total) = by_kind.entry(*kind).or_default();
+        *count += 1;
+        *total += size;
+    }
+    for (kind, (count, total)) in by_kind {
+        println!(
+            "{:<30} {count:>8} {:>14}",
+            kind.to_string(),
+            format_size(total)
+        );
+    }
+
+    println!();
+    println!("{:<30} {:>8}", "size", "count");
+    let mut bucket_counts = vec![0u64; SIZE_BUCKETS.len() + 1];
+    for (_, size) in &artifacts {
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|upper_bound| size < upper_bound)
+            .unwrap_or(SIZE_BUCKETS.len());
+        bucket_counts[bucket] += 1;
+    }
+    for (bucket, count) in bucket_counts.into_iter().enumerate() {
+        let label = match SIZE_BUCKETS.get(bucket) {
+            Some(upper_bound) => format!("< {}", format_size(*upper_bound)),
+            None => format!(">= {}", format_size(SIZE_BUCKETS[SIZE_BUCKETS.len() - 1])),
+        };
+        println!("{label:<30} {count:>8}");
+    }
+    Ok(())
+}
+
+/// Report on the logs from several cache dirs at once.
+fn run_merged(cache_dirs: &[PathBuf], export_aggregate: bool) -> anyhow::Result<()> {
+    // There could be a lot of them, and they could be big.
+    let logs = std::thread::scope(|scope| {
+        let readers: Vec<_> = cache_dirs
+            .iter()
+            .map(|cache_dir| {
+                scope.spawn(move || {
+                    read_log(cache_dir)
+                        .with_context(|| format!("Failed to read cache log in {cache_dir:?}"))
+                })
+            })
+            .collect();
+        readers
+            .into_iter()
+            .map(|reader| reader.join().expect("Log reader thread panicked"))
+            .collect::<anyhow::Result<Vec<_>>>()
+    })?;
+    let log: Vec<CacheLogLine> = logs.into_iter().flatten().collect();
+    let crates = crate_stats(&log);
+
+    if export_aggregate {
+        return print_aggregate(&crates);
+    }
+
+    let hits: u64 = crates.iter().map(|stats| stats.hits).sum();
+    let compiles: u64 = crates.iter().map(|stats| stats.compiles).sum();
+    let saved_secs: f64 = crates.iter().map(CrateStats::estimated_saved_secs).sum();
+    println!(
+        "Merged logs from {} cache dirs: {hits} hits and {compiles} compiles, \
+         saving an estimated {saved_secs:.2}s of compiling.",
+        cache_dirs.len()
+    );
+    println!();
+    print_exhausted_budgets(&log);
+    print_fallbacks(&log);
+    print_crate_table(&crates);
+    Ok(())
+}
+
+fn print_aggregate(crates: &[CrateStats]) -> anyhow::Result<()> {
+    let aggregate = Aggregate {
+        format_version: AGGREGATE_FORMAT_VERSION,
+        crates,
+    };
+    println!("{}", serde_json::to_string_pretty(&aggregate)?);
+    Ok(())
+}
+
+fn print_exhausted_budgets(log: &[CacheLogLine]) {
+    let exhausted_budgets: Vec<_> = log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::ExhaustedSessionBudget(event) => Some(event),
+            _ => None,
+        })
+        .collect();
+    if let Some(last) = exhausted_budgets
+        .iter()
+        .max_by_key(|event| event.exhausted_at)
+    {
+        println!(
+            "{} builds ran out of cache budget, most recently at {}: {}",
+            exhausted_budgets.len(),
+            last.exhausted_at.to_rfc3339(),
+            last.reason
+        );
+        println!();
+    }
+}
+
+fn print_fallbacks(log: &[CacheLogLine]) {
+    let fallbacks: Vec<_> = log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::FellBackToLocal(event) => Some(event),
+            _ => None,
+        })
+        .collect();
+    if let Some(last) = fallbacks.iter().max_by_key(|event| event.fell_back_at) {
+        println!(
+            "{} builds gave up on the remote cache, most recently at {} ({}): {}",
+            fallbacks.len(),
+            last.fell_back_at.to_rfc3339(),
+            last.location,
+            last.last_error
+        );
+        println!();
+    }
+}
+
+fn print_crate_table(crates: &[CrateStats]) {
+    println!(
+        "{:<30} {:>8} {:>8} {:>14} {:>14} {:>10}",
+        "crate", "hits", "compiles", "total (s)", "mean (s)", "peak RSS"
+    );
+    for stats in crates {
+        println!(
+            "{:<30} {:>8} {:>8} {:>14.2} {:>14.2} {:>10}",
+            stats.crate_name,
+            stats.hits,
+            stats.compiles,
+            stats.total_compile_secs,
+            stats.mean_compile_secs(),
+            match stats.max_rss_bytes {
+                0 => "-".to_owned(),
+                bytes => format_size(bytes),
+            }
+        );
+    }
+}
+
+/// Print stats laid out like `sccache --show-stats`, for dashboards and
+/// scripts that scrape it, so they keep working when switching over.
+///
+/// Every line sccache prints is here, in the same order and alignment,
+/// even where Hope has nothing to count. Hits are pulls (of any unit,
+/// including build scripts), misses are compiles, and timeouts are pulls
+/// abandoned for going over `HOPE_PULL_BUDGET_MS`.
+fn print_sccache_compat(log: &[CacheLogLine], cache_dir: &Path) -> anyhow::Result<()> {
+    #[derive(Default)]
+    struct Totals {
+        count: u64,
+        secs: f64,
+    }
+    impl Totals {
+        fn add(&mut self, secs: f64) {
+            self.count += 1;
+            self.secs += secs;
+        }
+        fn mean(&self) -> String {
+            let mean = if self.count == 0 {
+                0.0
+            } else {
+                self.secs / self.count as f64
+            };
+            format!("{mean:.3} s")
+        }
+    }
+
+    let mut hits = Totals::default();
+    let mut compiles = Totals::default();
+    let mut pushes = Totals::default();
+    let mut timeouts = 0;
+    let mut unrecognised_layouts = 0;
+    for line in log {
+        match line {
+            CacheLogLine::PulledCrateOutputs(event) => hits.add(event.duration_secs),
+            CacheLogLine::CompiledCrate(event) => compiles.add(event.duration_secs),
+            CacheLogLine::PushedCrateOutputs(event) => pushes.add(event.duration_secs),
+            CacheLogLine::AbandonedPull(_) => timeouts += 1,
+            CacheLogLine::UnrecognisedLayout(_) => unrecognised_layouts += 1,
+            _ => {}
+        }
+    }
+    let requests = hits.count + compiles.count + unrecognised_layouts;
+
+    let stats = [
+        ("Compile requests", requests.to_string()),
+        (
+            "Compile requests executed",
+            (hits.count + compiles.count).to_string(),
+        ),
+        ("Cache hits", hits.count.to_string()),
+        ("Cache hits (Rust)", hits.count.to_string()),
+        ("Cache misses", compiles.count.to_string()),
+        ("Cache misses (Rust)", compiles.count.to_string()),
+        ("Cache timeouts", timeouts.to_string()),
+        ("Cache read errors", "0".to_owned()),
+        ("Forced recaches", "0".to_owned()),
+        ("Cache write errors", "0".to_owned()),
+        ("Compilation failures", "0".to_owned()),
+        ("Cache errors", "0".to_owned()),
+        ("Non-cacheable compilations", "0".to_owned()),
+        ("Non-cacheable calls", unrecognised_layouts.to_string()),
+        ("Non-compilation calls", "0".to_owned()),
+        ("Unsupported compiler calls", "0".to_owned()),
+        ("Average cache write", pushes.mean()),
+        ("Average compiler", compiles.mean()),
+        ("Average cache read hit", hits.mean()),
+        ("Failed distributed compilations", "0".to_owned()),
+    ];
+    let name_width = stats.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    // Like sccache, line up counts with the numbers in durations,
+    // and let the " s" hang off the end.
+    let stat_width = stats
+        .iter()
+        .map(|(_, stat)| stat.trim_end_matches(" s").len())
+        .max()
+        .unwrap_or(0);
+    for (name, stat) in &stats {
+        let width = stat_width + if stat.ends_with(" s") { 2 } else { 0 };
+        println!("{name:<name_width$} {stat:>width$}");
+    }
+    if unrecognised_layouts > 0 {
+        println!();
+        println!("Non-cacheable reasons:");
+        println!(
+            "{:<name_width$} {unrecognised_layouts:>stat_width$}",
+            "unrecognised layout"
+        );
+    }
+    println!();
+
+    let shards = config::cache_shards();
+    let location = match config::cache_url() {
+        Some(url) => url,
+        None if !shards.is_empty() => shards.join(","),
+        None => format!("Local disk: {cache_dir:?}"),
+    };
+    println!("{:<name_width$} {location}", "Cache location");
+    println!(
+        "{:<name_width$} {}",
+        "Version (client)",
+        env!("CARGO_PKG_VERSION")
+    );
+    let cache_size: u64 = artifact_sizes(cache_dir)?
+        .iter()
+        .map(|(_, size)| size)
+        .sum();
+    let (size, unit) = binary_prefixed(cache_size);
+    println!("{:<name_width$} {size:>stat_width$} {unit}", "Cache size");
+    Ok(())
+}
+
+/// e.g. ("123", "MiB"), as sccache shows sizes.
+fn binary_prefixed(bytes: u64) -> (String, &'static str) {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return (bytes.to_string(), "bytes");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    (format!("{size:.0}"), UNITS[unit])
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ArtifactKind {
+    Rlib,
+    Rmeta,
+    /// Proc macros, mostly.
+    Dylib,
+    Staticlib,
+    /// Executables (build scripts, test harnesses).
+    Binary,
+    DepInfo,
+    BuildScriptOutput,
+    EntryManifest,
+    EntryAlias,
+    Attestation,
+    Other,
+}
+
+impl ArtifactKind {
+    fn for_file_name(file_name: &str) -> Self {
+        if file_name.starts_with("build-script-") && file_name.ends_with("-stdout.txt") {
+            return Self::BuildScriptOutput;
+        }
+        if file_name.ends_with(".manifest.json") {
+            return Self::EntryManifest;
+        }
+        if file_name.ends_with(".alias.json") {
+            return Self::EntryAlias;
+        }
+        if file_name.ends_with(".intoto.json") {
+            return Self::Attestation;
+        }
+        match Path::new(file_name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+        {
+            Some("rlib") => Self::Rlib,
+            Some("rmeta") => Self::Rmeta,
+            Some("so" | "dylib" | "dll") => Self::Dylib,
+            Some("a" | "lib") => Self::Staticlib,
+            Some("exe" | "wasm") | None => Self::Binary,
+            Some("d") => Self::DepInfo,
+            Some(_) => Self::Other,
+        }
+    }
+}
+
+impl fmt::Display for ArtifactKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Rlib => "rlib",
+            Self::Rmeta => "rmeta",
+            Self::Dylib => "dylib",
+            Self::Staticlib => "staticlib",
+            Self::Binary => "binary",
+            Self::DepInfo => "dep info",
+            Self::BuildScriptOutput => "build script output",
+            Self::EntryManifest => "entry manifest",
+            Self::EntryAlias => "entry alias",
+            Self::Attestation => "attestation",
+            Self::Other => "other",
+        })
+    }
+}
+
+/// Kind and (logical) size of everything stored in the cache.
+///
+/// Chunked artifacts count at their full reassembled size; the chunks
+/// themselves are shared between artifacts, so aren't counted separately.
+/// Likewise, compressed artifacts count at their uncompressed size.
+fn artifact_sizes(cache_dir: &Path) -> anyhow::Result<Vec<(ArtifactKind, u64)>> {
+    let mut artifacts = Vec::new();
+    for dir_entry in std::fs::read_dir(cache_dir).context("Failed to read cache dir")? {
+        let dir_entry = dir_entry.context("Failed to read cache dir entry")?;
+        let metadata = dir_entry.metadata()?;
+        let Some(file_name) = dir_entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        // Skip our own bookkeeping, half-written blobs, and the chunks dir.
+        if !metadata.is_file() || file_name.starts_with("hope-") || file_name.starts_with('.') {
+            continue;
+        }
+        if let Some(artifact_name) = file_name.strip_suffix(".chunks.json") {
+            let manifest = ChunkManifest::parse(&std::fs::read(dir_entry.path())?)
+                .with_context(|| format!("Invalid chunk manifest {file_name:?}"))?;
+            artifacts.push((
+                ArtifactKind::for_file_name(artifact_name),
+                manifest.total_size,
+            ));
+        } else if let Some(artifact_name) = file_name.strip_suffix(GZIP_SUFFIX) {
+            artifacts.push((
+                ArtifactKind::for_file_name(artifact_name),
+                compression::uncompressed_size(&dir_entry.path())?,
+            ));
+        } else {
+            artifacts.push((ArtifactKind::for_file_name(&file_name), metadata.len()));
+        }
+    }
+    Ok(artifacts)
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes}B"),
+        1024..1_048_576 => format!("{}K", bytes >> 10),
+        1_048_576..1_073_741_824 => format!("{}M", bytes >> 20),
+        _ => format!("{}G", bytes >> 30),
+    }
+}
+
+/// Per-crate stats, most expensive to compile first.
+fn crate_stats(log: &[CacheLogLine]) -> Vec<CrateStats> {
+    let mut by_crate: BTreeMap<&str, CrateStats> = BTreeMap::new();
+    for line in log {
+        let (crate_unit_name, compile_secs, usage) = match line {
+            CacheLogLine::PulledCrateOutputs(event) => (&event.crate_unit_name, None, None),
+            CacheLogLine::CompiledCrate(event) => (
+                &event.crate_unit_name,
+                Some(event.duration_secs),
+                event.usage,
+            ),
+            _ => continue,
+        };
+        let Some(crate_name) = crate_name(crate_unit_name) else {
+            continue;
+        };
+        let stats = by_crate.entry(crate_name).or_insert_with(|| CrateStats {
+            crate_name: crate_name.to_owned(),
+            ..Default::default()
+        });
+        match compile_secs {
+            Some(compile_secs) => {
+                stats.compiles += 1;
+                stats.total_compile_secs += compile_secs;
+                if let Some(usage) = usage {
+                    stats.max_rss_bytes = stats.max_rss_bytes.max(usage.max_rss_bytes);
+                }
+            }
+            None => stats.hits += 1,
+        }
+    }
+    let mut crates: Vec<CrateStats> = by_crate.into_values().collect();
+    crates.sort_by(|a, b| b.total_compile_secs.total_cmp(&a.total_compile_secs));
+    crates
+}
+
+/// Strip the hash off a unit name, leaving just the crate name.
+///
+/// Build scripts all have the same crate name, so lumping them
+/// together wouldn't tell anyone anything useful; skip them.
+fn crate_name(crate_unit_name: &str) -> Option<&str> {
+    let (crate_name, _hash) = crate_unit_name.rsplit_once('-')?;
+    (!crate_name.starts_with("build_script_")).then_some(crate_name)
+}
@@ -0,0 +1,203 @@
+//! Copying entries from one cache to another, e.g. to seed a new region's
+//! bucket, or to move to a different storage provider.
+//!
+//! `hope sync --from <cache> --to <cache>` copies every entry (along with
+//! any aliases pointing at it, its attestation, and the build script output
+//! that goes with it) that the destination doesn't already have. It copies
+//! blob for blob, so chunked and compressed entries stay that way, and
+//! manifests go last, so an interrupted sync never leaves an entry that
+//! looks complete but isn't. Running it again picks up where it left off.
+//!
+//! Only caches that can list what's in them can be synced from: the local
+//! cache, "file://", and "s3://". Any cache can be synced to.
+
+use std::collections::BTreeSet;
+
+use anyhow::Context;
+
+use crate::{
+    attestation::Statement,
+    build_script_inputs::BuildScriptInputs,
+    cache::{self, build_script_execution_key_for_file_name, RemoteBlobStore},
+    chunks::{self, BlobStore, ChunkManifest},
+    compression,
+    entry_manifest::{EntryAlias, EntryManifest},
+};
+
+/// Copy everything for the given crates (or all of them, if none are given)
+/// from one cache to another. Caches are given as URLs, or "local".
+pub fn run(from: &str, to: &str, crate_names: &[String]) -> anyhow::Result<()> {
+    let source = cache::blob_store_from_target(from).context("Invalid '--from' cache")?;
+    let destination = cache::blob_store_from_target(to).context("Invalid '--to' cache")?;
+    // Package names have hyphens where crate names have underscores.
+    let wanted = |name: &str| {
+        crate_names.is_empty()
+            || crate_names
+                .iter()
+                .any(|crate_name| crate_name.replace('-', "_") == name.replace('-', "_"))
+    };
+    let copied = copy(&source, &destination, wanted)?;
+
+    println!(
+        "Copied {} blobs for {} entries and {} build script runs from {} to {}.",
+        copied.blobs,
+        copied.entries,
+        copied.build_script_runs,
+        source.url(),
+        destination.url()
+    );
+    if !copied.missing.is_empty() {
+        println!(
+            "{} blobs were missing from {}, so some entries weren't copied:",
+            copied.missing.len(),
+            source.url()
+        );
+        for key in &copied.missing {
+            println!("    {key}");
+        }
+    }
+    Ok(())
+}
+
+/// What [`copy`] got through.
+pub struct Copied {
+    pub blobs: usize,
+    pub entries: usize,
+    pub build_script_runs: usize,
+    /// Blobs that should have been there to copy, but weren't.
+    pub missing: BTreeSet<String>,
+}
+
+/// Copy entries and build script runs whose crate or package name is
+/// `wanted` from `source` to `destination`, if it doesn't already have them.
+pub fn copy(
+    source: &dyn RemoteBlobStore,
+    destination: &dyn BlobStore,
+    wanted: impl Fn(&str) -> bool,
+) -> anyhow::Result<Copied> {
+    let keys = source.list_blobs()?;
+    let mut copier = Copier::new(source, destination);
+
+    let mut entries = BTreeSet::new();
+    for key in &keys {
+        let Some(storage_name) = EntryManifest::storage_name_for_blob_key(key) else {
+            continue;
+        };
+        if !wanted(name_before_hashes(storage_name)) {
+            continue;
+        }
+        copier.entry(storage_name)?;
+        copier.optional_blob(&Statement::blob_key(storage_name))?;
+        entries.insert(storage_name);
+    }
+    // Aliases are only any use if what they point at came too.
+    for key in &keys {
+        let Some(alias_name) = EntryAlias::alias_name_for_blob_key(key) else {
+            continue;
+        };
+        if EntryAlias::load(source, alias_name)?
+            .is_some_and(|alias| entries.contains(alias.target.as_str()))
+        {
+            copier.blob(key)?;
+        }
+    }
+    let mut build_script_runs = 0;
+    for key in &keys {
+        let Some(execution_key) = build_script_execution_key_for_file_name(key) else {
+            continue;
+        };
+        if !wanted(name_before_hashes(execution_key)) {
+            continue;
+        }
+        // The stdout is what says the run is cached, so it goes last.
+        copier.optional_blob(&BuildScriptInputs::blob_key(execution_key))?;
+        copier.blob(key)?;
+        build_script_runs += 1;
+    }
+
+    Ok(Copied {
+        blobs: copier.copied,
+        entries: entries.len(),
+        build_script_runs,
+        missing: copier.missing,
+    })
+}
+
+/// "{name}-{hash}-{hash}" -> "{name}", for storage names (where the name is
+/// the crate's) and build script execution keys (where it's the package's).
+fn name_before_hashes(key: &str) -> &str {
+    key.rsplitn(3, '-').nth(2).unwrap_or(key)
+}
+
+/// Copies blobs that one store has and another doesn't.
+pub struct Copier<'a> {
+    from: &'a dyn BlobStore,
+    to: &'a dyn BlobStore,
+    /// How many blobs have been copied so far.
+    pub copied: usize,
+    /// Blobs that should have been there to copy, but weren't.
+    pub missing: BTreeSet<String>,
+}
+
+impl<'a> Copier<'a> {
+    pub fn new(from: &'a dyn BlobStore, to: &'a dyn BlobStore) -> Self {
+        Self {
+            from,
+            to,
+            copied: 0,
+            missing: BTreeSet::new(),
+        }
+    }
+
+    /// Copy an entry's files, and then its manifest.
+    pub fn entry(&mut self, storage_name: &str) -> anyhow::Result<()> {
+        let Some(manifest) = EntryManifest::load(self.from, storage_name)? else {
+            self.missing.insert(EntryManifest::blob_key(storage_name));
+            return Ok(());
+        };
+        // Entries pushed by older versions of Hope don't list their files,
+        // so we can't tell what to fetch for them.
+        for file_name in manifest.files.keys() {
+            let chunk_manifest_key = chunks::manifest_key(file_name);
+            if self.from.has_blob(&chunk_manifest_key)? {
+                let chunk_manifest = ChunkManifest::parse(
+                    &self.from.get_blob(&chunk_manifest_key)?,
+                )
+                .with_context(|| format!("Invalid chunk manifest {chunk_manifest_key:?}"))?;
+                for chunk in &chunk_manifest.chunks {
+                    self.blob(&chunks::chunk_key(&chunk.hash))?;
+                }
+                self.blob(&chunk_manifest_key)?;
+            } else if self
+                .from
+                .has_blob(&compression::compressed_key(file_name))?
+            {
+                self.blob(&compression::compressed_key(file_name))?;
+            } else {
+                self.blob(file_name)?;
+            }
+        }
+        // The manifest goes last, so that it's only there if everything else is.
+        self.blob(&EntryManifest::blob_key(storage_name))
+    }
+
+    pub fn blob(&mut self, key: &str) -> anyhow::Result<()> {
+        if !self.optional_blob(key)? {
+            self.missing.insert(key.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Returns whether the blob is now in the destination.
+    pub fn optional_blob(&mut self, key: &str) -> anyhow::Result<bool> {
+        if self.to.has_blob(key)? {
+            return Ok(true);
+        }
+        if !self.from.has_blob(key)? {
+            return Ok(false);
+        }
+        self.to.put_blob(key, &self.from.get_blob(key)?)?;
+        self.copied += 1;
+        Ok(true)
+    }
+}
@@ -0,0 +1,182 @@
+//! What `rustc` calls its outputs on different targets.
+//!
+//! File name prefixes and extensions depend on the target, and some targets
+//! write extra files next to the main output (import libraries and PDBs on
+//! Windows). We need to know exactly which files to expect, or else we'd push
+//! incomplete entries or pull files that Cargo can't find.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::CrateType;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    triple: String,
+}
+
+/// How to turn a unit name into a file name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileNaming {
+    prefix: &'static str,
+    suffix: &'static str,
+}
+
+impl FileNaming {
+    const fn new(prefix: &'static str, suffix: &'static str) -> Self {
+        Self { prefix, suffix }
+    }
+
+    pub fn file_name(self, crate_unit_name: &str) -> String {
+        format!("{}{crate_unit_name}{}", self.prefix, self.suffix)
+    }
+}
+
+impl Target {
+    /// The target `rustc` will build for, given its `--target` argument (if any).
+    ///
+    /// Without `--target`, that's `rustc`'s own host, which isn't necessarily
+    /// what Hope was built for: a statically linked (musl) Hope can wrap
+    /// a glibc `rustc` and vice versa.
+    pub fn from_arg(target: Option<&str>, rustc_host: &str) -> Self {
+        let triple = match target {
+            // Custom targets can be given as a path to a JSON target spec;
+            // `rustc` goes by the file stem for those.
+            Some(target) if target.ends_with(".json") => Path::new(target)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or(target)
+                .to_owned(),
+            Some(target) => target.to_owned(),
+            None => rustc_host.to_owned(),
+        };
+        Self { triple }
+    }
+
+    pub fn triple(&self) -> &str {
+        &self.triple
+    }
+
+    fn is_windows(&self) -> bool {
+        self.triple.contains("-windows")
+    }
+
+    fn is_msvc(&self) -> bool {
+        self.triple.ends_with("-msvc")
+    }
+
+    pub fn is_apple(&self) -> bool {
+        self.triple.contains("-apple-")
+    }
+
+    /// Environment variable that sets the minimum OS version for Apple targets.
+    ///
+    /// Both `rustc` and the `cc` crate read this.
+    pub fn apple_deployment_target_var(&self) -> Option<&'static str> {
+        if !self.is_apple() {
+            return None;
+        }
+        let os = self.triple.split('-').nth(2).unwrap_or_default();
+        Some(match os {
+            "ios" => "IPHONEOS_DEPLOYMENT_TARGET",
+            "tvos" => "TVOS_DEPLOYMENT_TARGET",
+            "watchos" => "WATCHOS_DEPLOYMENT_TARGET",
+            "visionos" => "XROS_DEPLOYMENT_TARGET",
+            _ => "MACOSX_DEPLOYMENT_TARGET",
+        })
+    }
+
+    /// Name of the SDK to build against for Apple targets, as understood by `xcrun`.
+    pub fn apple_sdk_name(&self) -> Option<&'static str> {
+        if !self.is_apple() {
+            return None;
+        }
+        // Mac Catalyst apps are iOS apps built against the macOS SDK.
+        if self.triple.ends_with("-macabi") {
+            return Some("macosx");
+        }
+        let os = self.triple.split('-').nth(2).unwrap_or_default();
+        // Intel builds for mobile platforms only run in the simulator.
+        let simulator = self.triple.ends_with("-sim") || self.triple.starts_with("x86_64-");
+        Some(match (os, simulator) {
+            ("ios", false) => "iphoneos",
+            ("ios", true) => "iphonesimulator",
+            ("tvos", false) => "appletvos",
+            ("tvos", true) => "appletvsimulator",
+            ("watchos", false) => "watchos",
+            ("watchos", true) => "watchsimulator",
+            ("visionos", false) => "xros",
+            ("visionos", true) => "xrsimulator",
+            _ => "macosx",
+        })
+    }
+
+    pub fn is_android(&self) -> bool {
+        self.triple.contains("-android")
+    }
+
+    fn is_wasm(&self) -> bool {
+        self.triple.starts_with("wasm")
+    }
+
+    /// Can `rustc` link crates of the given type for this target at all?
+    ///
+    /// It drops any that it can't (with just a warning), so we mustn't go
+    /// looking for their outputs. Wasm has no dynamic linking beyond cdylibs,
+    /// so there are no dylibs, nor proc macros (which Cargo only ever builds
+    /// for the host anyway).
+    pub fn supports_crate_type(&self, crate_type: CrateType) -> bool {
+        !(self.is_wasm() && matches!(crate_type, CrateType::Dylib | CrateType::ProcMacro))
+    }
+
+    /// Naming for the main output of linking a crate of the given type.
+    ///
+    /// TODO: Emscripten writes a ".js" loader next to the ".wasm" for binaries.
+    pub fn link_naming(&self, crate_type: CrateType) -> FileNaming {
+        match crate_type {
+            // Assume lib is rlib for now, but that is not necessarily going
+            // to be true forever.
+            CrateType::Lib | CrateType::Rlib => FileNaming::new("lib", ".rlib"),
+            CrateType::Staticlib if self.is_msvc() => FileNaming::new("", ".lib"),
+            CrateType::Staticlib => FileNaming::new("lib", ".a"),
+            CrateType::Dylib | CrateType::Cdylib | CrateType::ProcMacro => self.dylib_naming(),
+            CrateType::Bin if self.is_windows() => FileNaming::new("", ".exe"),
+            CrateType::Bin if self.is_wasm() => FileNaming::new("", ".wasm"),
+            CrateType::Bin => FileNaming::new("", ""),
+        }
+    }
+
+    fn dylib_naming(&self) -> FileNaming {
+        if self.is_windows() {
+            FileNaming::new("", ".dll")
+        } else if self.is_wasm() {
+            FileNaming::new("", ".wasm")
+        } else if self.is_apple() {
+            FileNaming::new("lib", ".dylib")
+        } else {
+            FileNaming::new("lib", ".so")
+        }
+    }
+
+    /// Naming for the import library that Windows needs to link against a DLL, if any.
+    pub fn import_lib_naming(&self, crate_type: CrateType) -> Option<FileNaming> {
+        if !self.is_windows() || !matches!(crate_type, CrateType::Dylib | CrateType::Cdylib) {
+            return None;
+        }
+        Some(if self.is_msvc() {
+            FileNaming::new("", ".dll.lib")
+        } else {
+            FileNaming::new("lib", ".dll.a")
+        })
+    }
+
+    /// Does linking a crate of the given type also write a PDB with its debug info?
+    pub fn has_pdb(&self, crate_type: CrateType) -> bool {
+        self.is_msvc()
+            && matches!(
+                crate_type,
+                CrateType::Bin | CrateType::Dylib | CrateType::Cdylib | CrateType::ProcMacro
+            )
+    }
+}
@@ -0,0 +1,238 @@
+//! Pulling from whichever of several caches has an entry first.
+//!
+//! With `HOPE_ALSO_PULL_FROM` set, pulls try the main cache first and then
+//! each of those in turn, so that (e.g.) a project's own cache can sit in
+//! front of a team's, with a public one behind both. Put them in order of
+//! how much you trust them, and how quick they are to get to.
+//!
+//! Pushes only ever go to the main cache (see `HOPE_ALSO_PUSH_TO` for
+//! more than that). Each cache logs its own pulls, so the log says which
+//! one every pull came from.
+//!
+//! `HOPE_PUBLIC_CACHE_URL` adds one more to try last: a public cache that
+//! anyone can pull from, which never gets credentials (even
+//! `HOPE_CACHE_TOKEN`), and is never pushed to. That lets an open-source
+//! project publish a warm cache, e.g. with its CI pushing to a bucket
+//! (`HOPE_CACHE_URL=s3://...`, with credentials) that everyone else pulls
+//! from through a CDN (`HOPE_PUBLIC_CACHE_URL=https://...`). Pulls that
+//! miss everywhere still build and push as usual, to the local cache if
+//! there's no `HOPE_CACHE_URL`.
+//!
+//! With `HOPE_BACKFILL` set too, whatever's pulled from further back is
+//! pushed to the caches in front that missed, so that (e.g.) a region's
+//! mirror fills up from a global bucket as it's used. That's done on the
+//! way through, so it's only ever as good as what was pulled; if it fails,
+//! the pull still counts.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+
+use crate::{
+    build_script_inputs::BuildScriptInputs, cache::Cache, chunks::BlobStore,
+    entry_manifest::EntryManifest, key::CacheKey, over_remote_push_limit, OutputDefn,
+};
+
+pub struct TieredCache {
+    /// The main cache first, then the rest in the order to try them.
+    tiers: Vec<Arc<dyn Cache>>,
+    /// Push what's pulled from one cache to those in front of it that missed.
+    backfill: bool,
+}
+
+impl TieredCache {
+    pub fn new(
+        main: Arc<dyn Cache>,
+        rest: impl IntoIterator<Item = Arc<dyn Cache>>,
+        backfill: bool,
+    ) -> Self {
+        Self {
+            tiers: std::iter::once(main).chain(rest).collect(),
+            backfill,
+        }
+    }
+
+    fn main(&self) -> &dyn Cache {
+        &*self.tiers[0]
+    }
+
+    /// The first thing any cache gives back, or else the last error.
+    fn first_ok<T>(
+        &self,
+        mut attempt: impl FnMut(&dyn Cache) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut last_err = None;
+        for tier in &self.tiers {
+            match attempt(&**tier) {
+                Ok(value) => return Ok(value),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.expect("There's always a main cache"))
+    }
+
+    fn location_list(&self) -> String {
+        self.tiers
+            .iter()
+            .map(|tier| tier.location())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Cache for TieredCache {
+    fn pull_crate(
+        &self,
+        key: &CacheKey,
+        output_defns: &[OutputDefn],
+        arrival_dir: &Path,
+    ) -> anyhow::Result<()> {
+        // Only try the caches that have an entry, but if pulling from one
+        // fails (e.g. its files are corrupt), then the next one might do.
+        let mut last_err = None;
+        for (index, tier) in self.tiers.iter().enumerate() {
+            if !matches!(tier.pull_size(key, output_defns), Ok(Some(_))) {
+                continue;
+            }
+            match tier.pull_crate(key, output_defns, arrival_dir) {
+                Ok(()) => {
+                    if self.backfill {
+                        // The arrival dir is laid out just like a departure dir.
+                        let copy = |missed: &dyn Cache| {
+                            // It was only cached locally for being too big to push.
+                            if missed.is_remote()
+                                && over_remote_push_limit(
+                                    output_defns,
+                                    &key.unit_name,
+                                    arrival_dir,
+                                )?
+                                .is_some()
+                            {
+                                return Ok(());
+                            }
+                            let storage_name = tier.resolve_storage_name(key)?;
+                            let manifest = EntryManifest::load(&**tier, &storage_name)?
+                                .context("Entry has no manifest")?;
+                            missed.push_crate(key, &manifest, output_defns, arrival_dir)
+                        };
+                        backfill(&self.tiers[..index], &key.unit_name, copy);
+                    }
+                    return Ok(());
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            anyhow::anyhow!(
+                "No cache entry for {:?} in any of: {}",
+                key.unit_name,
+                self.location_list()
+            )
+        }))
+    }
+
+    fn push_crate(
+        &self,
+        key: &CacheKey,
+        manifest: &EntryManifest,
+        output_defns: &[OutputDefn],
+        departure_dir: &Path,
+    ) -> anyhow::Result<()> {
+        self.main()
+            .push_crate(key, manifest, output_defns, departure_dir)
+    }
+
+    fn get_build_script_stdout(&self, build_script_execution_key: &str) -> anyhow::Result<Vec<u8>> {
+        let mut last_err = None;
+        for (index, tier) in self.tiers.iter().enumerate() {
+            match tier.get_build_script_stdout(build_script_execution_key) {
+                Ok(stdout) => {
+                    if self.backfill {
+                        let copy = |missed: &dyn Cache| {
+                            if let Some(inputs) =
+                                BuildScriptInputs::load(&**tier, build_script_execution_key)?
+                            {
+                                inputs.store(missed, build_script_execution_key)?;
+                            }
+                            missed.put_build_script_stdout(build_script_execution_key, &stdout)
+                        };
+                        backfill(&self.tiers[..index], build_script_execution_key, copy);
+                    }
+                    return Ok(stdout);
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.expect("There's always a main cache"))
+    }
+
+    fn put_build_script_stdout(
+        &self,
+        build_script_execution_key: &str,
+        stdout: &[u8],
+    ) -> anyhow::Result<()> {
+        self.main()
+            .put_build_script_stdout(build_script_execution_key, stdout)
+    }
+
+    fn pull_size(
+        &self,
+        key: &CacheKey,
+        output_defns: &[OutputDefn],
+    ) -> anyhow::Result<Option<u64>> {
+        for tier in &self.tiers {
+            if let Ok(Some(size)) = tier.pull_size(key, output_defns) {
+                return Ok(Some(size));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Where pushes go; pulls log the location of whichever cache they came from.
+    fn location(&self) -> String {
+        self.main().location()
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        self.main().local_dir()
+    }
+
+    fn is_remote(&self) -> bool {
+        self.main().is_remote()
+    }
+}
+
+impl BlobStore for TieredCache {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.first_ok(|tier| tier.get_blob(key))
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.main().put_blob(key, bytes)
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        // One cache being unreachable shouldn't stop us using the others.
+        Ok(self
+            .tiers
+            .iter()
+            .any(|tier| matches!(tier.has_blob(key), Ok(true))))
+    }
+}
+
+/// Copy something into each of the caches that missed,
+/// carrying on past any that fail.
+fn backfill(
+    missed: &[Arc<dyn Cache>],
+    what: &str,
+    mut copy: impl FnMut(&dyn Cache) -> anyhow::Result<()>,
+) {
+    for tier in missed {
+        if let Err(err) = copy(&**tier) {
+            eprintln!(
+                "Hope failed to backfill {what} into {}: {err:#}",
+                tier.location()
+            );
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! Identifying the exact `rustc` we're wrapping.
+//!
+//! Version strings alone aren't enough to tell toolchains apart: two
+//! nightlies from the same day, or a beta and a locally built compiler,
+//! can look almost identical. The commit hash is what actually identifies
+//! a compiler build, so that's what goes into keys.
+//!
+//! Crates that build native code (usually `-sys` crates, via the `cc` crate
+//! in their build scripts) also depend on the C toolchain, so for those
+//! we identify the C compiler (and archiver, Apple SDK, or Android NDK) too.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{rustc_args::Args, session, target::Target};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Channel {
+    Stable,
+    Beta,
+    Nightly,
+    /// Built from source, e.g. a local compiler checkout.
+    Dev,
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stable => f.write_str("stable"),
+            Self::Beta => f.write_str("beta"),
+            Self::Nightly => f.write_str("nightly"),
+            Self::Dev => f.write_str("dev"),
+        }
+    }
+}
+
+/// Where each build keeps what `rustc -vV` said, in the profile dir.
+const RUSTC_INFO_FILE_NAME: &str = ".hope-session-rustc";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RustcInfo {
+    /// e.g. "1.80.0-nightly"
+    pub release: String,
+    /// Missing for some locally built compilers.
+    pub commit_hash: Option<String>,
+    pub channel: Channel,
+    /// Target triple that `rustc` builds for by default.
+    pub host: String,
+}
+
+impl RustcInfo {
+    /// Ask `rustc` about itself, once per build.
+    ///
+    /// It's a whole extra process per unit otherwise, so the first unit
+    /// of each build saves the answer in the profile dir (see
+    /// `session::update_state`) for the rest. The toolchain can't change
+    /// part way through a build, even behind a rustup proxy, but it can
+    /// between builds, so it's never reused beyond that.
+    pub fn query_for_build(rustc_path: &Path, profile_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(profile_dir) = profile_dir else {
+            return Self::query(rustc_path);
+        };
+        session::update_state(
+            profile_dir,
+            RUSTC_INFO_FILE_NAME,
+            |infos: &mut BTreeMap<PathBuf, Self>| {
+                if let Some(info) = infos.get(rustc_path) {
+                    return Ok(info.clone());
+                }
+                let info = Self::query(rustc_path)?;
+                infos.insert(rustc_path.to_owned(), info.clone());
+                Ok(info)
+            },
+        )
+    }
+
+    /// Ask `rustc` about itself.
+    pub fn query(rustc_path: &Path) -> anyhow::Result<Self> {
+        let output = Command::new(rustc_path)
+            .arg("-vV")
+            .output()
+            .context("Failed to start real `rustc` to get its version")?;
+        anyhow::ensure!(output.status.success(), "'rustc -vV' failed");
+        let text = String::from_utf8(output.stdout).context("'rustc -vV' output wasn't UTF-8")?;
+        Self::parse(&text)
+    }
+
+    fn parse(verbose_version: &str) -> anyhow::Result<Self> {
+        let field = |name: &str| {
+            verbose_version.lines().find_map(|line| {
+                line.strip_prefix(name)
+                    .and_then(|rest| rest.strip_prefix(": "))
+                    .map(str::trim)
+            })
+        };
+        let release = field("release")
+            .context("Missing release in 'rustc -vV' output")?
+            .to_owned();
+        let host = field("host")
+            .context("Missing host in 'rustc -vV' output")?
+            .to_owned();
+        let commit_hash = field("commit-hash")
+            .filter(|hash| *hash != "unknown")
+            .map(str::to_owned);
+        let channel = if release.contains("-nightly") {
+            Channel::Nightly
+        } else if release.contains("-beta") {
+            Channel::Beta
+        } else if release.contains("-dev") {
+            Channel::Dev
+        } else {
+            Channel::Stable
+        };
+        Ok(Self {
+            release,
+            commit_hash,
+            channel,
+            host,
+        })
+    }
+
+    /// Ask whichever `rustc` Cargo would use, when we're not running
+    /// as a wrapper and so haven't been told.
+    pub fn query_default() -> anyhow::Result<Self> {
+        let rustc_path = std::env::var_os("RUSTC")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("rustc"));
+        Self::query(&rustc_path)
+    }
+}
+
+/// Does this unit (probably) contain native code built by its build script?
+///
+/// By convention such packages are named "*-sys", and any build script that
+/// builds a native library has to tell Cargo to link it, which shows up
+/// as `-l` arguments.
+pub fn links_native_code(cargo_package_name: &str, args: &Args) -> bool {
+    cargo_package_name.ends_with("-sys") || !args.link_to_native_libs.is_empty()
+}
+
+/// What we know about the native toolchain a unit was built with.
+///
+/// Anything that's `None` either wasn't relevant or couldn't be detected.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NativeToolchain {
+    /// First line of `$CC --version`; only for crates that link native code.
+    #[serde(default)]
+    pub cc_version: Option<String>,
+    /// First line of `$AR --version`, if an archiver was chosen explicitly
+    /// (e.g. with `AR_aarch64_linux_android`); only for crates that link
+    /// native code.
+    #[serde(default)]
+    pub ar_version: Option<String>,
+    /// Minimum OS version for Apple targets, e.g. from `MACOSX_DEPLOYMENT_TARGET`.
+    ///
+    /// `rustc` bakes this into every object file, so it matters
+    /// even for crates with no native code of their own.
+    #[serde(default)]
+    pub deployment_target: Option<String>,
+    /// Apple SDK version; only for crates that link native code.
+    ///
+    /// Artifacts built against different SDKs will usually link together
+    /// just fine, but can then misbehave at runtime.
+    #[serde(default)]
+    pub sdk_version: Option<String>,
+    /// Android NDK release, e.g. "26.1.10909125"; only for crates that link
+    /// native code for Android.
+    ///
+    /// Each NDK has its own sysroot and C++ standard library as well as
+    /// its own clang, so the compiler's version doesn't tell the whole story.
+    #[serde(default)]
+    pub ndk_version: Option<String>,
+}
+
+impl NativeToolchain {
+    /// Work out what's relevant for a unit built for `target`.
+    ///
+    /// This runs external programs for units that link native code,
+    /// so only pass `links_native_code` when it's (probably) true.
+    pub fn detect(target: &Target, links_native_code: bool) -> Self {
+        let deployment_target = target
+            .apple_deployment_target_var()
+            .and_then(|var| std::env::var(var).ok());
+        if !links_native_code {
+            return Self {
+                deployment_target,
+                ..Default::default()
+            };
+        }
+        let cc = tool_from_env("CC", target).unwrap_or_else(|| default_c_compiler(target));
+        Self {
+            cc_version: first_line_of_output(Command::new(&cc).arg("--version")),
+            ar_version: tool_from_env("AR", target)
+                .and_then(|ar| first_line_of_output(Command::new(ar).arg("--version"))),
+            deployment_target,
+            sdk_version: apple_sdk_version(target),
+            ndk_version: target.is_android().then(|| ndk_version(&cc)).flatten(),
+        }
+    }
+
+    /// Named parts of the cache key.
+    pub fn key_components(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("cc-version", &self.cc_version),
+            ("ar-version", &self.ar_version),
+            ("deployment-target", &self.deployment_target),
+            ("sdk-version", &self.sdk_version),
+            ("ndk-version", &self.ndk_version),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+    }
+}
+
+/// The tool (e.g. "CC" or "AR") that the `cc` crate would use for `target`,
+/// if it's been set explicitly.
+///
+/// This mirrors the environment variables that the `cc` crate looks at,
+/// most specific first.
+fn tool_from_env(tool: &str, target: &Target) -> Option<String> {
+    let triple = target.triple();
+    [
+        format!("{tool}_{triple}"),
+        format!("{tool}_{}", triple.replace('-', "_")),
+        format!("TARGET_{tool}"),
+        tool.to_string(),
+    ]
+    .iter()
+    .find_map(|var| std::env::var(var).ok())
+}
+
+/// The C compiler that the `cc` crate uses for `target` if nothing says
+/// otherwise. That's just "cc", except for:
+///
+/// - bare metal ARM targets (e.g. "thumbv7em-none-eabihf"), which need the
+///   GNU Arm Embedded toolchain; the host's `cc` couldn't build for them at all.
+/// - Android, where it's the NDK's clang wrapper for the target (roughly;
+///   the `cc` crate also tries a few older naming schemes).
+fn default_c_compiler(target: &Target) -> String {
+    let triple = target.triple();
+    if (triple.starts_with("thumb") || triple.starts_with("arm")) && triple.contains("-none-eabi") {
+        "arm-none-eabi-gcc".to_string()
+    } else if target.is_android() {
+        format!("{}-clang", triple.replace("armv7-", "armv7a-"))
+    } else {
+        "cc".to_string()
+    }
+}
+
+/// Release of the Android NDK that the C compiler `cc` belongs to,
+/// from the NDK's "source.properties".
+///
+/// The compiler usually lives deep inside the NDK, so look above it first,
+/// and then in `ANDROID_NDK_HOME` (or one of the other names it goes by).
+fn ndk_version(cc: &str) -> Option<String> {
+    let cc_path = if cc.contains('/') {
+        Some(PathBuf::from(cc))
+    } else {
+        std::env::split_paths(&std::env::var_os("PATH")?)
+            .map(|dir| dir.join(cc))
+            .find(|path| path.exists())
+    };
+    let ndk_dir = cc_path
+        .as_deref()
+        .and_then(|cc_path| {
+            cc_path
+                .ancestors()
+                .find(|dir| dir.join("source.properties").exists())
+                .map(Path::to_owned)
+        })
+        .or_else(|| {
+            [
+                "ANDROID_NDK_HOME",
+                "ANDROID_NDK_ROOT",
+                "ANDROID_NDK",
+                "NDK_HOME",
+            ]
+            .iter()
+            .find_map(std::env::var_os)
+            .map(PathBuf::from)
+        })?;
+    let properties = std::fs::read_to_string(ndk_dir.join("source.properties")).ok()?;
+    properties.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "Pkg.Revision").then(|| value.trim().to_owned())
+    })
+}
+
+/// Version of the Apple SDK that the `cc` crate would build against.
+///
+/// An explicit `SDKROOT` wins, like it does for `xcrun` and the `cc` crate.
+fn apple_sdk_version(target: &Target) -> Option<String> {
+    let sdk = std::env::var("SDKROOT")
+        .ok()
+        .or_else(|| target.apple_sdk_name().map(str::to_owned))?;
+    first_line_of_output(Command::new("xcrun").args(["--sdk", &sdk, "--show-sdk-version"]))
+}
+
+fn first_line_of_output(command: &mut Command) -> Option<String> {
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    stdout.lines().next().map(|line| line.trim().to_owned())
+}
@@ -0,0 +1,202 @@
+//! Keeping remote transfers from swamping the network.
+//!
+//! Cargo runs as many wrappers at once as there are CPUs, and on a cold
+//! cache every one of them might be uploading its outputs at the same time,
+//! which can saturate an office uplink. So remote blob stores can be limited:
+//!
+//! - `HOPE_MAX_UPLOADS` and `HOPE_MAX_DOWNLOADS` cap how many blobs can be
+//!   on their way to or from remote caches at once, across every wrapper
+//!   using the same cache dir. Each transfer takes one of that many slots,
+//!   which are lock files in "transfer-slots" in the local cache dir, and
+//!   waits for one to come free if they're all taken.
+//! - `HOPE_UPLOAD_LIMIT` and `HOPE_DOWNLOAD_LIMIT` cap bytes per second,
+//!   shared out evenly between the slots (4 of them if the count isn't set
+//!   too). A transfer that finishes faster than its share allows keeps its
+//!   slot until it's caught up, so the limit is kept on average over each
+//!   blob rather than second by second; big blobs go over it in bursts.
+//!
+//! Checking whether a blob is there, or how big it is, isn't limited;
+//! only fetching and storing blobs is. The local cache never is.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::{
+    cache::{LocalCache, RemoteBlobStore},
+    chunks::BlobStore,
+    config,
+};
+
+/// Slots to share a bandwidth limit between when there's no count.
+const DEFAULT_SLOTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::Upload => "upload",
+            Direction::Download => "download",
+        }
+    }
+}
+
+/// How transfers in one direction are limited.
+#[derive(Debug)]
+struct Limit {
+    slots: u32,
+    /// Bytes per second for each slot.
+    slot_rate: Option<u64>,
+}
+
+impl Limit {
+    fn from_env(direction: Direction) -> anyhow::Result<Option<Self>> {
+        let (count, rate) = match direction {
+            Direction::Upload => (config::max_uploads()?, config::upload_limit()?),
+            Direction::Download => (config::max_downloads()?, config::download_limit()?),
+        };
+        if count.is_none() && rate.is_none() {
+            return Ok(None);
+        }
+        let slots = count.unwrap_or(DEFAULT_SLOTS);
+        Ok(Some(Self {
+            slots,
+            slot_rate: rate.map(|rate| (rate / u64::from(slots)).max(1)),
+        }))
+    }
+}
+
+/// Limit transfers to and from `store` as the environment says to,
+/// or leave it be if it doesn't.
+pub fn limit_from_env(store: Box<dyn RemoteBlobStore>) -> anyhow::Result<Box<dyn RemoteBlobStore>> {
+    let uploads = Limit::from_env(Direction::Upload)?;
+    let downloads = Limit::from_env(Direction::Download)?;
+    if uploads.is_none() && downloads.is_none() {
+        return Ok(store);
+    }
+    Ok(Box::new(LimitedStore {
+        inner: store,
+        slots_dir: LocalCache::dir_from_env()?.join("transfer-slots"),
+        uploads,
+        downloads,
+    }))
+}
+
+struct LimitedStore<S> {
+    inner: S,
+    slots_dir: PathBuf,
+    uploads: Option<Limit>,
+    downloads: Option<Limit>,
+}
+
+impl<S> LimitedStore<S> {
+    /// Run `transfer` in a slot, and keep the slot until the
+    /// bytes it moved are within the bandwidth limit.
+    fn transfer<T>(
+        &self,
+        direction: Direction,
+        transfer: impl FnOnce() -> anyhow::Result<(T, usize)>,
+    ) -> anyhow::Result<T> {
+        let limit = match direction {
+            Direction::Upload => &self.uploads,
+            Direction::Download => &self.downloads,
+        };
+        let Some(limit) = limit else {
+            return transfer().map(|(value, _)| value);
+        };
+        let _slot = take_slot(&self.slots_dir, direction, limit.slots)?;
+        let started = Instant::now();
+        let (value, len) = transfer()?;
+        if let Some(slot_rate) = limit.slot_rate {
+            let allowed = Duration::from_secs_f64(len as f64 / slot_rate as f64);
+            if let Some(early) = allowed.checked_sub(started.elapsed()) {
+                std::thread::sleep(early);
+            }
+        }
+        Ok(value)
+    }
+}
+
+/// Wait for one of the slots for `direction` to come free, and take it.
+/// It's given back when the returned file is closed.
+fn take_slot(slots_dir: &Path, direction: Direction, slots: u32) -> anyhow::Result<File> {
+    std::fs::create_dir_all(slots_dir)
+        .with_context(|| format!("Failed to create {slots_dir:?}"))?;
+    let mut waited = Duration::ZERO;
+    loop {
+        // Start somewhere different each time, so that wrappers
+        // don't all pile onto the first slot.
+        let first = fastrand::u32(..slots);
+        for i in (first..slots).chain(0..first) {
+            let path = slots_dir.join(format!("{}-{i}.lock", direction.name()));
+            let file = File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open {path:?}"))?;
+            match rustix::fs::flock(&file, rustix::fs::FlockOperation::NonBlockingLockExclusive) {
+                Ok(()) => return Ok(file),
+                Err(rustix::io::Errno::WOULDBLOCK) => {}
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Failed to lock {path:?}"));
+                }
+            }
+        }
+        // Back off a bit more each time, up to a point,
+        // and jitter it so waiting wrappers don't move in step.
+        waited = (waited * 2).clamp(Duration::from_millis(10), Duration::from_millis(200));
+        std::thread::sleep(waited.mul_f64(0.5 + fastrand::f64()));
+    }
+}
+
+impl<S: RemoteBlobStore> BlobStore for LimitedStore<S> {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.transfer(Direction::Download, || {
+            let bytes = self.inner.get_blob(key)?;
+            let len = bytes.len();
+            Ok((bytes, len))
+        })
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.transfer(Direction::Upload, || {
+            Ok((self.inner.put_blob(key, bytes)?, bytes.len()))
+        })
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        self.inner.has_blob(key)
+    }
+}
+
+impl<S: RemoteBlobStore> RemoteBlobStore for LimitedStore<S> {
+    fn url(&self) -> String {
+        self.inner.url()
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        self.inner.blob_size(key)
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.transfer(Direction::Download, || {
+            let bytes = self.inner.get_blob_tail(key, len)?;
+            let len = bytes.len();
+            Ok((bytes, len))
+        })
+    }
+
+    fn list_blobs(&self) -> anyhow::Result<Vec<String>> {
+        self.inner.list_blobs()
+    }
+}
@@ -0,0 +1,193 @@
+//! Pushing to remote caches later, instead of in the middle of the build.
+//!
+//! Uploading a freshly built unit can take longer than building it, and
+//! every rustc invocation that waits for one holds up whatever depends on
+//! it. With `HOPE_ASYNC_PUSH=1`, pushes to remote caches go to a queue on
+//! disk instead, and `hope flush` uploads everything in it, e.g. as the last
+//! step of a CI job:
+//!
+//! ```text
+//! HOPE_ASYNC_PUSH=1 cargo build
+//! hope flush
+//! ```
+//!
+//! Each remote cache gets its own queue, at "upload-queue/{hash}" in the
+//! local cache dir, which says which cache it's for ("main" for the one in
+//! `HOPE_CACHE_URL` and friends, or a URL from `HOPE_ALSO_PUSH_TO`) and
+//! holds queued entries blob for blob as that cache would store them. So
+//! `hope flush` needs the same cache settings as the build had; it copies
+//! entries much like `hope sync` does (see the `sync` module), manifests
+//! last, so an interrupted flush never leaves a half-pushed entry behind.
+//!
+//! Queued entries aren't in the remote cache until flushed, so other
+//! machines won't see them (and this one won't pull them) until then.
+//! Flush after the build is done: anything pushed while a flush is running
+//! might not make it.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::{self, Cache, LocalCache, RemoteBlobStore, RemoteCache},
+    chunks::BlobStore,
+    config,
+    daemon_socket::DaemonBlobStore,
+    sync,
+};
+
+/// Where pushes should go: `cache` and `also_push_to` themselves, or
+/// with `HOPE_ASYNC_PUSH` set, queues in front of the remote ones.
+pub fn push_targets(
+    cache: &Arc<dyn Cache>,
+    also_push_to: &[Arc<dyn Cache>],
+) -> anyhow::Result<Vec<Arc<dyn Cache>>> {
+    let targets = std::iter::once(cache).chain(also_push_to);
+    if !config::async_push() {
+        return Ok(targets.cloned().collect());
+    }
+    targets
+        .zip(cache::push_target_names())
+        .map(|(target, name)| {
+            if !target.is_remote() {
+                return Ok(Arc::clone(target));
+            }
+            let queue: Arc<dyn Cache> = Arc::new(RemoteCache::new(QueuedStore::open(&name)?)?);
+            Ok(queue)
+        })
+        .collect()
+}
+
+/// A queue's blobs, which live in the local cache dir until they're flushed.
+struct QueuedStore {
+    spool: LocalCache,
+    name: String,
+}
+
+impl QueuedStore {
+    fn open(name: &str) -> anyhow::Result<Self> {
+        let dir = queue_dir(name)?;
+        let spool_dir = dir.join("spool");
+        std::fs::create_dir_all(&spool_dir)
+            .with_context(|| format!("Failed to create upload queue at {spool_dir:?}"))?;
+        std::fs::write(dir.join("target"), name)
+            .with_context(|| format!("Failed to write upload queue's target in {dir:?}"))?;
+        Ok(Self {
+            spool: LocalCache::new(spool_dir),
+            name: name.to_owned(),
+        })
+    }
+}
+
+impl BlobStore for QueuedStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.spool.get_blob(key)
+    }
+
+    fn put_blob(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.spool.put_blob(key, bytes)
+    }
+
+    fn has_blob(&self, key: &str) -> anyhow::Result<bool> {
+        self.spool.has_blob(key)
+    }
+}
+
+impl RemoteBlobStore for QueuedStore {
+    /// So that the log says where queued entries are headed.
+    fn url(&self) -> String {
+        format!("upload queue for {}", self.name)
+    }
+
+    fn blob_size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        self.spool.blob_size(key)
+    }
+
+    fn get_blob_tail(&self, key: &str, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.spool.get_blob_tail(key, len)
+    }
+}
+
+fn queues_dir() -> anyhow::Result<PathBuf> {
+    Ok(LocalCache::dir_from_env()?.join("upload-queue"))
+}
+
+/// Queues are named for a hash of their target, which might be
+/// a URL with all sorts of things in it.
+fn queue_dir(name: &str) -> anyhow::Result<PathBuf> {
+    let hash = format!("{:x}", Sha256::digest(name));
+    Ok(queues_dir()?.join(&hash[..16]))
+}
+
+/// Where a queue's entries should end up.
+fn destination(name: &str) -> anyhow::Result<Box<dyn RemoteBlobStore>> {
+    if name != cache::MAIN_CACHE {
+        return cache::blob_store_from_target(name);
+    }
+    if let Some(socket) = config::daemon_socket() {
+        return Ok(Box::new(DaemonBlobStore::new(&socket)));
+    }
+    cache::remote_store_from_env()?
+        .context("Queued pushes are for a remote cache, but none is set in the environment")
+}
+
+/// Upload everything in every queue.
+pub fn flush() -> anyhow::Result<()> {
+    let queues_dir = queues_dir()?;
+    if !queues_dir.exists() {
+        println!("Nothing to upload.");
+        return Ok(());
+    }
+    let mut failed = false;
+    for dir_entry in std::fs::read_dir(&queues_dir).context("Failed to read upload queues")? {
+        let dir = dir_entry.context("Failed to read upload queue")?.path();
+        if let Err(err) = flush_queue(&dir) {
+            eprintln!("Failed to flush upload queue in {dir:?}: {err:#}");
+            failed = true;
+        }
+    }
+    anyhow::ensure!(!failed, "Some queued pushes weren't uploaded");
+    Ok(())
+}
+
+fn flush_queue(dir: &Path) -> anyhow::Result<()> {
+    let name = std::fs::read_to_string(dir.join("target"))
+        .context("Failed to read which cache the queue is for")?;
+    let destination = destination(&name)?;
+
+    // Move what's queued so far out of the way, so that anything queued
+    // from now on waits for the next flush rather than getting lost.
+    let spool_dir = dir.join("spool");
+    if spool_dir.exists() {
+        let flushing_dir = dir.join(format!("flushing-{}", std::process::id()));
+        std::fs::rename(&spool_dir, &flushing_dir)
+            .with_context(|| format!("Failed to move {spool_dir:?} out of the way"))?;
+    }
+    // Including any left behind by flushes that didn't finish.
+    for dir_entry in std::fs::read_dir(dir).context("Failed to read upload queue")? {
+        let flushing_dir = dir_entry.context("Failed to read upload queue")?.path();
+        let is_flushing = flushing_dir
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .is_some_and(|file_name| file_name.starts_with("flushing-"));
+        if !is_flushing {
+            continue;
+        }
+        let copied = sync::copy(&LocalCache::new(&flushing_dir), &destination, |_| true)?;
+        println!(
+            "Uploaded {} blobs for {} entries to {}.",
+            copied.blobs,
+            copied.entries,
+            destination.url()
+        );
+        // Whatever's missing was only partly queued (e.g. by a build that
+        // was killed), and isn't worth keeping.
+        std::fs::remove_dir_all(&flushing_dir)
+            .with_context(|| format!("Failed to remove {flushing_dir:?}"))?;
+    }
+    Ok(())
+}
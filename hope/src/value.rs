@@ -0,0 +1,135 @@
+//! Working out whether a unit is worth caching at all, on this machine and
+//! with this cache.
+//!
+//! Every pull takes some time however small the unit is, and plenty of
+//! crates build quicker than that. With `HOPE_SKIP_CHEAP_UNITS` set, we
+//! compare how long a crate has taken to build here before with how long
+//! pulls from the cache in use have been taking, and leave alone any crate
+//! that's quicker to build. Both come from the log, so this tunes itself to
+//! whatever machine and cache it's used with, and it does nothing until
+//! there's some history to go on.
+//!
+//! The log only ever grows, so rather than read it for every unit, the first
+//! unit of each build boils it down to the median times per crate and per
+//! cache, which the rest of the build's units share (see
+//! `session::update_state`).
+
+use std::{collections::BTreeMap, path::Path};
+
+use hope_cache_log::{read_log_if_any, CacheLogLine};
+use serde::{Deserialize, Serialize};
+
+use crate::session;
+
+/// Timings for the build, kept with `session::update_state`.
+const TIMINGS_FILE_NAME: &str = ".hope-session-timings";
+
+/// Don't trust the pull time until there have been at least this many pulls.
+const MIN_PULLS: usize = 3;
+
+/// Only go by this many of the latest pulls, so that a cache that's
+/// got quicker (or slower) is noticed.
+const RECENT_PULLS: usize = 20;
+
+/// What the log says about how long things take, as of the start of a build.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Timings {
+    /// Whether a unit has read the log for this build yet.
+    read: bool,
+    /// Median time to build each crate here, by crate name.
+    compile_secs: BTreeMap<String, f64>,
+    /// Median time of the latest pulls from each cache, by `Cache::location`,
+    /// for caches that have been pulled from enough to go by.
+    pull_secs: BTreeMap<String, f64>,
+}
+
+impl Timings {
+    fn from_log(log: &[CacheLogLine]) -> Self {
+        // Versions and features of a crate get different unit names,
+        // but they tend to take about as long as each other to build.
+        let mut compile_secs: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+        let mut pull_secs: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+        for line in log {
+            match line {
+                CacheLogLine::CompiledCrate(event) if !event.remote => compile_secs
+                    .entry(crate_name_of(&event.crate_unit_name).to_owned())
+                    .or_default()
+                    .push(event.duration_secs),
+                CacheLogLine::PulledCrateOutputs(event) => pull_secs
+                    .entry(event.copied_from.clone())
+                    .or_default()
+                    .push(event.duration_secs),
+                _ => {}
+            }
+        }
+        Self {
+            read: true,
+            compile_secs: compile_secs
+                .into_iter()
+                .filter_map(|(crate_name, secs)| Some((crate_name, median(secs)?)))
+                .collect(),
+            pull_secs: pull_secs
+                .into_iter()
+                .filter(|(_, secs)| secs.len() >= MIN_PULLS)
+                .filter_map(|(cache_location, mut secs)| {
+                    let recent = secs.split_off(secs.len().saturating_sub(RECENT_PULLS));
+                    Some((cache_location, median(recent)?))
+                })
+                .collect(),
+        }
+    }
+
+    fn skip_reason(&self, cache_location: &str, crate_unit_name: &str) -> Option<String> {
+        let compile_secs = *self.compile_secs.get(crate_name_of(crate_unit_name))?;
+        let pull_secs = *self.pull_secs.get(cache_location)?;
+        (compile_secs < pull_secs).then(|| {
+            format!(
+                "it builds in {compile_secs:.3}s here, which is quicker than \
+                 a typical pull from {cache_location} ({pull_secs:.3}s)"
+            )
+        })
+    }
+}
+
+/// Why a unit isn't worth pulling or pushing, if it isn't.
+///
+/// `cache_location` is what the log says pulls from the cache in use
+/// were copied from; see `Cache::location`. Without a profile dir to keep
+/// this build's timings in, this reads the log itself.
+pub fn skip_reason(
+    cache_dir: &Path,
+    profile_dir: Option<&Path>,
+    cache_location: &str,
+    crate_unit_name: &str,
+) -> anyhow::Result<Option<String>> {
+    // There's no log at all until something has been cached.
+    let read_timings = || -> anyhow::Result<Timings> {
+        Ok(Timings::from_log(
+            &read_log_if_any(cache_dir)?.unwrap_or_default(),
+        ))
+    };
+    let Some(profile_dir) = profile_dir else {
+        return Ok(read_timings()?.skip_reason(cache_location, crate_unit_name));
+    };
+    session::update_state(profile_dir, TIMINGS_FILE_NAME, |timings: &mut Timings| {
+        if !timings.read {
+            *timings = read_timings()?;
+        }
+        Ok(timings.skip_reason(cache_location, crate_unit_name))
+    })
+}
+
+/// "{crate name}-{hash}" -> "{crate name}"
+fn crate_name_of(crate_unit_name: &str) -> &str {
+    crate_unit_name
+        .rsplit_once('-')
+        .map_or(crate_unit_name, |(crate_name, _)| crate_name)
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    Some(values[values.len() / 2])
+}
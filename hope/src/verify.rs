@@ -0,0 +1,110 @@
+//! Checking cache entries for corruption, and repairing what we can.
+//!
+//! Each entry's manifest records a hash of every output file, so we can
+//! tell when one has been damaged (by a failing disk, a half-finished copy
+//! into the cache by some other tool, etc.). Lots of entries share
+//! identical files (e.g. builds that differ only in something that doesn't
+//! affect a particular output), so with `--repair` we first look for
+//! an intact copy elsewhere in the cache, and only throw the entry away
+//! if there isn't one.
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+
+use crate::{
+    cache::LocalCache,
+    chunks::{self, BlobStore},
+    compression,
+};
+
+pub fn run(repair: bool) -> anyhow::Result<()> {
+    let cache = LocalCache::from_env()?;
+    let entries = cache.entries()?;
+
+    // Where to find each file by hash, for repairs.
+    let mut files_by_hash: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (_, manifest) in &entries {
+        for (file_name, hash) in &manifest.files {
+            files_by_hash.entry(hash).or_default().push(file_name);
+        }
+    }
+
+    let mut checked = 0;
+    let mut unverifiable = 0;
+    let mut problems = 0;
+    for (storage_name, manifest) in &entries {
+        if manifest.files.is_empty() {
+            // Pushed by an older version of Hope.
+            unverifiable += 1;
+            continue;
+        }
+        checked += 1;
+        let mut entry_ok = true;
+        for (file_name, hash) in &manifest.files {
+            if read_artifact(&cache, file_name)
+                .is_ok_and(|content| chunks::hash_bytes(&content) == *hash)
+            {
+                continue;
+            }
+            problems += 1;
+            println!("{file_name}: missing or corrupt");
+            if !repair {
+                continue;
+            }
+            let sibling = files_by_hash[hash.as_str()]
+                .iter()
+                .filter(|sibling| **sibling != file_name)
+                .find_map(|sibling| {
+                    let content = read_artifact(&cache, sibling).ok()?;
+                    (chunks::hash_bytes(&content) == *hash).then_some((sibling, content))
+                });
+            match sibling {
+                Some((sibling, content)) => {
+                    store_whole(&cache, file_name, &content)?;
+                    println!("    repaired from {sibling}");
+                }
+                None => entry_ok = false,
+            }
+        }
+        if !entry_ok {
+            cache.remove_entry(storage_name)?;
+            println!("    no intact copy found; removed entry {storage_name}");
+        }
+    }
+
+    println!("Checked {checked} entries; {problems} problems found.");
+    if unverifiable > 0 {
+        println!("{unverifiable} entries have no file hashes recorded, so couldn't be checked.");
+    }
+    anyhow::ensure!(
+        problems == 0 || repair,
+        "Cache has problems; run `hope verify --repair` to fix them"
+    );
+    Ok(())
+}
+
+/// Read a stored output file, whether it was stored whole, compressed, or in chunks.
+pub fn read_artifact(cache: &LocalCache, file_name: &str) -> anyhow::Result<Vec<u8>> {
+    if cache.has_blob(&compression::compressed_key(file_name))? {
+        return compression::get_compressed(cache, file_name);
+    }
+    if !cache.has_blob(&chunks::manifest_key(file_name))? {
+        return cache.get_blob(file_name);
+    }
+    let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+    let temp_path = temp_dir.path().join(file_name);
+    chunks::get_chunked(cache, file_name, &temp_path)?;
+    std::fs::read(&temp_path).with_context(|| format!("Failed to read reassembled {file_name:?}"))
+}
+
+/// Replace a stored output file with a whole copy.
+///
+/// Pulls prefer chunks when there's a chunk manifest, or a compressed copy
+/// if there is one, so get rid of those; the chunks themselves are shared,
+/// so we leave them alone.
+fn store_whole(cache: &LocalCache, file_name: &str, content: &[u8]) -> anyhow::Result<()> {
+    cache.remove_blob(&chunks::manifest_key(file_name))?;
+    cache.remove_blob(&compression::compressed_key(file_name))?;
+    cache.put_blob(file_name, content)
+}
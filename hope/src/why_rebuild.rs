@@ -0,0 +1,632 @@
+//! Explaining why Cargo rebuilt a crate (or would), to tell rebuilds that
+//! Hope caused apart from ones that Cargo would have done anyway.
+//!
+//! For each unit it's built for a package, Cargo keeps a fingerprint in
+//! "{profile dir}/.fingerprint/{package}-{hash}": a JSON file of what went
+//! into it (the compiler, features, profile, flags, and the fingerprints of
+//! its dependencies), the hash of that, and a dep info file whose mtime is
+//! when the unit was last built. Cargo rebuilds a unit when:
+//!
+//! - any of that changes, which mostly also changes the hash, so the new
+//!   build goes in a dir of its own beside the old one, and we can diff them;
+//! - one of its source files is newer than its dep info file;
+//! - one of its dependencies' outputs is newer than its own outputs;
+//! - or one of its outputs is missing.
+//!
+//! We check each of those for the most recent build of each of the
+//! package's units, and say what Hope's log has to say about the unit too.
+//! Pulls that leave outputs looking older or newer than they should are
+//! Hope's fault (see the `mtime` module); the rest is Cargo being Cargo.
+//!
+//! Cargo's fingerprint files aren't a stable format, so this only uses the
+//! parts of them that have been around for a long time, and says so when
+//! it comes across something it doesn't understand.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use hope_cache_log::{read_log, CacheLogLine};
+use serde_json::Value;
+
+use crate::{cache::LocalCache, clean_project, sources};
+
+/// One build of a unit, as Cargo remembers it.
+struct Unit {
+    /// e.g. "target/debug/.fingerprint/cfg-if-d995ec1fb643b77d"
+    dir: PathBuf,
+    /// The hash that Cargo also passes as `-C extra-filename`.
+    hash: String,
+    /// What kind of unit, and its target's name, e.g. "lib-cfg_if"
+    /// or "run-build-script-build-script-build".
+    kind: String,
+    fingerprint: Value,
+    /// When Cargo last built it, going by the mtime of its dep info file.
+    built_at: Option<SystemTime>,
+}
+
+impl Unit {
+    /// What the unit's outputs (and Hope's log) call it, e.g. "cfg_if-d995ec1fb643b77d".
+    fn crate_unit_name(&self) -> String {
+        // Kinds are "{flavor}{target kind}-{target name}", where the flavor
+        // is "test-", "doc-", "run-", or nothing.
+        let kind = ["test-", "doc-", "run-"]
+            .iter()
+            .find_map(|flavor| self.kind.strip_prefix(flavor))
+            .unwrap_or(&self.kind);
+        let target_name = [
+            "lib-",
+            "bin-",
+            "integration-test-",
+            "example-",
+            "bench-",
+            "build-script-",
+        ]
+        .iter()
+        .find_map(|target_kind| kind.strip_prefix(target_kind))
+        .unwrap_or(kind);
+        format!("{}-{}", target_name.replace('-', "_"), self.hash)
+    }
+
+    fn dir_name(&self) -> &str {
+        self.dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+    }
+}
+
+/// Where things are that fingerprints have paths relative to.
+struct Dirs {
+    target_dir: PathBuf,
+    profile_dir: PathBuf,
+    /// Sources of the workspace's own packages are relative to this.
+    workspace_dir: PathBuf,
+    /// Paths that build scripts watch are relative to their package's
+    /// dir, which we only know for the workspace's own packages.
+    package_dir: Option<PathBuf>,
+}
+
+pub fn run(package_name: &str, target: Option<&str>, profile: &str) -> anyhow::Result<()> {
+    let metadata = clean_project::cargo_metadata()?;
+    let target_dir = metadata["target_directory"]
+        .as_str()
+        .map(PathBuf::from)
+        .context("'cargo metadata' didn't say where the target dir is")?;
+    let workspace_dir = metadata["workspace_root"]
+        .as_str()
+        .map(PathBuf::from)
+        .context("'cargo metadata' didn't say where the workspace is")?;
+    let package_dir = metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|package| package["name"].as_str() == Some(package_name))
+        .and_then(|package| package["manifest_path"].as_str())
+        .and_then(|manifest_path| Path::new(manifest_path).parent())
+        .map(Path::to_owned);
+    let profile_dir = match target {
+        Some(target) => target_dir.join(target).join(profile),
+        None => target_dir.join(profile),
+    };
+    let fingerprint_dir = profile_dir.join(".fingerprint");
+    anyhow::ensure!(
+        fingerprint_dir.exists(),
+        "Nothing has been built in {profile_dir:?} yet"
+    );
+    let units = find_units(&fingerprint_dir, package_name)?;
+    if units.is_empty() {
+        println!("Cargo hasn't built {package_name} in {profile_dir:?}.");
+        return Ok(());
+    }
+    let log = read_log(&LocalCache::dir_from_env()?).unwrap_or_default();
+    let fingerprint_hashes = fingerprint_hashes(&fingerprint_dir)?;
+    let dirs = Dirs {
+        target_dir,
+        profile_dir,
+        workspace_dir,
+        package_dir,
+    };
+
+    let mut by_kind: BTreeMap<&str, Vec<&Unit>> = BTreeMap::new();
+    for unit in &units {
+        by_kind.entry(&unit.kind).or_default().push(unit);
+    }
+    for builds in by_kind.values_mut() {
+        builds.sort_by_key(|unit| std::cmp::Reverse(unit.built_at));
+        let latest = builds[0];
+        println!("{} in {}", latest.kind, latest.dir_name());
+        println!("    last built {}", describe_time(latest.built_at));
+        for line in hope_history(&log, &latest.crate_unit_name()) {
+            println!("    {line}");
+        }
+
+        let reasons = stale_reasons(&dirs, latest, &fingerprint_hashes, &log);
+        if reasons.is_empty() {
+            println!("    nothing here would make Cargo rebuild it");
+        } else {
+            println!("    Cargo would rebuild it because:");
+            for reason in reasons {
+                println!("        {reason}");
+            }
+        }
+
+        if let Some(previous) = builds.get(1) {
+            println!(
+                "    compared with the build in {} ({}):",
+                previous.dir_name(),
+                describe_time(previous.built_at)
+            );
+            for difference in differences(&previous.fingerprint, &latest.fingerprint) {
+                println!("        {difference}");
+            }
+        }
+    }
+    println!();
+    println!(
+        "For Cargo's own account of a rebuild, build with \
+         `CARGO_LOG=cargo::core::compiler::fingerprint=info`."
+    );
+    Ok(())
+}
+
+/// Every build of every unit of a package that Cargo has fingerprints for.
+fn find_units(fingerprint_dir: &Path, package_name: &str) -> anyhow::Result<Vec<Unit>> {
+    let mut units = Vec::new();
+    let dir_entries = std::fs::read_dir(fingerprint_dir)
+        .with_context(|| format!("Failed to read {fingerprint_dir:?}"))?;
+    for dir_entry in dir_entries {
+        let dir = dir_entry?.path();
+        let Some((package, hash)) = dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.rsplit_once('-'))
+        else {
+            continue;
+        };
+        // Package names use hyphens or underscores, and people mix them up.
+        if package.replace('_', "-") != package_name.replace('_', "-") {
+            continue;
+        }
+        let hash = hash.to_owned();
+        for file_entry in
+            std::fs::read_dir(&dir).with_context(|| format!("Failed to read {dir:?}"))?
+        {
+            let path = file_entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(kind) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let json = std::fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+            let fingerprint = serde_json::from_slice(&json)
+                .with_context(|| format!("Failed to parse {path:?}"))?;
+            units.push(Unit {
+                // Build script runs don't have dep info files,
+                // so Cargo goes by their output instead.
+                built_at: mtime(&dir.join(format!("dep-{kind}"))).or_else(|| {
+                    let build_dir = fingerprint_dir.parent()?.join("build");
+                    mtime(&build_dir.join(dir.file_name()?).join("output"))
+                }),
+                dir: dir.clone(),
+                hash: hash.clone(),
+                kind: kind.to_owned(),
+                fingerprint,
+            });
+        }
+    }
+    Ok(units)
+}
+
+/// Every unit's fingerprint hash (as Cargo writes it, in hex), and which
+/// build of which unit it's for, so we can find the builds of dependencies.
+fn fingerprint_hashes(
+    fingerprint_dir: &Path,
+) -> anyhow::Result<BTreeMap<String, (PathBuf, String)>> {
+    let mut hashes = BTreeMap::new();
+    for dir_entry in std::fs::read_dir(fingerprint_dir)? {
+        let dir = dir_entry?.path();
+        let Ok(file_entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for file_entry in file_entries {
+            let path = file_entry?.path();
+            let Some(kind) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if !dir.join(format!("{kind}.json")).exists() {
+                continue;
+            }
+            if let Ok(hash) = std::fs::read_to_string(&path) {
+                hashes.insert(hash.trim().to_owned(), (dir.clone(), kind.to_owned()));
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// What Hope's log says about a unit.
+fn hope_history(log: &[CacheLogLine], crate_unit_name: &str) -> Vec<String> {
+    let mut last_pull = None;
+    let mut last_compile = None;
+    let mut compiled_after_pull = None;
+    for line in log {
+        match line {
+            CacheLogLine::PulledCrateOutputs(event) if event.crate_unit_name == crate_unit_name => {
+                last_pull = Some(event);
+            }
+            CacheLogLine::CompiledCrate(event) if event.crate_unit_name == crate_unit_name => {
+                if let Some(pull) = last_pull {
+                    compiled_after_pull = Some((pull.copied_at, event.compiled_at));
+                }
+                last_compile = Some(event);
+            }
+            _ => {}
+        }
+    }
+    let mut history = Vec::new();
+    if let Some(pull) = last_pull {
+        history.push(format!(
+            "Hope last pulled it from {} at {}",
+            pull.copied_from,
+            pull.copied_at.to_rfc3339()
+        ));
+    }
+    if let Some(compile) = last_compile {
+        history.push(format!(
+            "Hope last compiled it at {}",
+            compile.compiled_at.to_rfc3339()
+        ));
+    }
+    if let Some((pulled_at, compiled_at)) = compiled_after_pull {
+        history.push(format!(
+            "Cargo had it compiled again at {} after Hope pulled it at {}; \
+             unless something it depends on changed in between, that's \
+             likely Hope's doing, and worth reporting",
+            compiled_at.to_rfc3339(),
+            pulled_at.to_rfc3339()
+        ));
+    }
+    if history.is_empty() {
+        history.push("Hope's log has nothing about it".to_owned());
+    }
+    history
+}
+
+/// Why Cargo would rebuild `unit` if it were asked to now.
+fn stale_reasons(
+    dirs: &Dirs,
+    unit: &Unit,
+    fingerprint_hashes: &BTreeMap<String, (PathBuf, String)>,
+    log: &[CacheLogLine],
+) -> Vec<String> {
+    let mut reasons = Vec::new();
+    let Some(built_at) = unit.built_at else {
+        reasons.push("it has no dep info, as if its last build never finished".to_owned());
+        return reasons;
+    };
+    let own_outputs = outputs(&dirs.profile_dir, unit);
+    if own_outputs.is_empty() {
+        reasons.push("its outputs are missing".to_owned());
+    }
+    let outputs_mtime = own_outputs.iter().filter_map(|path| mtime(path)).max();
+
+    for local in unit.fingerprint["local"].as_array().into_iter().flatten() {
+        if local.get("CheckDepInfo").is_some() {
+            reasons.extend(changed_sources(dirs, unit, built_at));
+        } else if let Some(rerun) = local.get("RerunIfChanged") {
+            // Only the workspace's own packages have a dir we know, and
+            // nobody edits the rest.
+            let Some(package_dir) = &dirs.package_dir else {
+                continue;
+            };
+            let output_mtime = rerun["output"]
+                .as_str()
+                .and_then(|output| mtime(&dirs.target_dir.join(output)));
+            for path in rerun["paths"].as_array().into_iter().flatten() {
+                let Some(path) = path.as_str() else {
+                    continue;
+                };
+                let changed_at = newest_mtime(&package_dir.join(path));
+                if changed_at.is_none() || changed_at > output_mtime {
+                    reasons.push(format!(
+                        "{path:?}, which its build script watches, changed at {}",
+                        describe_time(changed_at)
+                    ));
+                }
+            }
+        } else if local.get("Precalculated").is_some() {
+            // A build script that doesn't say what to watch gets rerun
+            // when anything in its package changes.
+            let Some(package_dir) = &dirs.package_dir else {
+                continue;
+            };
+            let changed_at = newest_package_mtime(package_dir);
+            if changed_at > Some(built_at) {
+                reasons.push(format!(
+                    "something in the package changed at {}, and its build script \
+                     doesn't say what it watches, so Cargo reruns it for anything",
+                    describe_time(changed_at)
+                ));
+            }
+        } else if let Some(rerun) = local.get("RerunIfEnvChanged") {
+            let var = rerun["var"].as_str().unwrap_or_default();
+            let was = rerun["val"].as_str();
+            let now = std::env::var(var).ok();
+            if was != now.as_deref() {
+                reasons.push(format!(
+                    "its build script watches ${var}, which was {} and is {} in this shell",
+                    describe_value(was),
+                    describe_value(now.as_deref())
+                ));
+            }
+        } else {
+            reasons.push(format!(
+                "Cargo checks something we don't understand: {local}"
+            ));
+        }
+    }
+
+    for dep in unit.fingerprint["deps"].as_array().into_iter().flatten() {
+        let (Some(name), Some(hash)) = (dep[1].as_str(), dep[3].as_u64()) else {
+            reasons.push(format!("it has a dependency we don't understand: {dep}"));
+            continue;
+        };
+        let hex: String = hash
+            .to_le_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let Some((dep_dir, dep_kind)) = fingerprint_hashes.get(&hex) else {
+            reasons.push(format!(
+                "dependency {name} has changed since (none of its builds match \
+                 the one it was built against)"
+            ));
+            continue;
+        };
+        let dep_unit = Unit {
+            dir: dep_dir.clone(),
+            hash: dep_dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.rsplit_once('-'))
+                .map(|(_, hash)| hash.to_owned())
+                .unwrap_or_default(),
+            kind: dep_kind.clone(),
+            fingerprint: Value::Null,
+            built_at: None,
+        };
+        let dep_mtime = outputs(&dirs.profile_dir, &dep_unit)
+            .iter()
+            .filter_map(|path| mtime(path))
+            .max();
+        if dep_mtime > outputs_mtime && outputs_mtime.is_some() {
+            let mut reason = format!(
+                "dependency {name} was built at {}, after it was (at {})",
+                describe_time(dep_mtime),
+                describe_time(outputs_mtime)
+            );
+            let dep_unit_name = dep_unit.crate_unit_name();
+            let pulled = log.iter().any(|line| {
+                matches!(line, CacheLogLine::PulledCrateOutputs(event) if event.crate_unit_name == dep_unit_name)
+            });
+            if pulled {
+                reason.push_str(
+                    "; Hope pulled that, so if it hadn't changed, \
+                     Hope left it looking newer than it should",
+                );
+            }
+            reasons.push(reason);
+        }
+    }
+    reasons
+}
+
+/// Source files listed in the unit's dep info file that have changed since
+/// it was built (or gone).
+fn changed_sources(dirs: &Dirs, unit: &Unit, built_at: SystemTime) -> Vec<String> {
+    let dep_info_name = format!("{}.d", unit.crate_unit_name());
+    let Some(dep_info) = [
+        dirs.profile_dir.join("deps").join(&dep_info_name),
+        dirs.profile_dir
+            .join("build")
+            .join(unit.dir_name())
+            .join(&dep_info_name),
+    ]
+    .into_iter()
+    .find_map(|path| std::fs::read_to_string(path).ok()) else {
+        return vec![format!("its dep info file ({dep_info_name}) is missing")];
+    };
+    let paths: BTreeSet<PathBuf> = sources::dep_info_paths(&dep_info).into_iter().collect();
+    let mut changed = Vec::new();
+    for path in paths {
+        match mtime(&dirs.workspace_dir.join(&path)) {
+            None => changed.push(format!("{} is gone", path.display())),
+            Some(changed_at) if changed_at > built_at => changed.push(format!(
+                "{} changed at {}, after it was built (at {})",
+                path.display(),
+                describe_time(Some(changed_at)),
+                describe_time(Some(built_at))
+            )),
+            Some(_) => {}
+        }
+    }
+    changed
+}
+
+/// The files a unit's build left in the profile dir.
+fn outputs(profile_dir: &Path, unit: &Unit) -> Vec<PathBuf> {
+    let build_dir = profile_dir.join("build").join(unit.dir_name());
+    if unit.kind.starts_with("run-") {
+        return [build_dir.join("output")]
+            .into_iter()
+            .filter(|path| path.exists())
+            .collect();
+    }
+    let crate_unit_name = unit.crate_unit_name();
+    let mut outputs = Vec::new();
+    for dir in [profile_dir.join("deps"), build_dir] {
+        let Ok(dir_entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for dir_entry in dir_entries.flatten() {
+            let file_name = dir_entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            let stem = file_name.split('.').next().unwrap_or_default();
+            if (stem == crate_unit_name || stem.strip_prefix("lib") == Some(&crate_unit_name))
+                && !file_name.ends_with(".d")
+            {
+                outputs.push(dir_entry.path());
+            }
+        }
+    }
+    outputs
+}
+
+/// How two builds of the same unit differ in what went into them.
+fn differences(old: &Value, new: &Value) -> Vec<String> {
+    let (Some(old), Some(new)) = (old.as_object(), new.as_object()) else {
+        return vec!["(couldn't compare them)".to_owned()];
+    };
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let mut differences = Vec::new();
+    for key in keys {
+        let (old_value, new_value) = (&old[key.as_str()], &new[key.as_str()]);
+        // Each build's files are in its own dirs, which isn't news.
+        if key == "local" && without_own_paths(old_value) == without_own_paths(new_value) {
+            continue;
+        }
+        if old_value == new_value {
+            continue;
+        }
+        differences.push(match key.as_str() {
+            "rustc" => "the compiler is different (e.g. after a toolchain update)".to_owned(),
+            "profile" => "profile settings are different (opt-level, debug info, etc.)".to_owned(),
+            "target" => "the target is different (crate types, edition, etc.)".to_owned(),
+            "path" => "its source is somewhere else".to_owned(),
+            "config" => "Cargo config that goes into it is different".to_owned(),
+            "compile_kind" => "it's compiled for a different target".to_owned(),
+            "local" => format!(
+                "what Cargo checks for changes is different: {} -> {}",
+                without_own_paths(old_value),
+                without_own_paths(new_value)
+            ),
+            "deps" => format!("dependencies: {}", dep_differences(old_value, new_value)),
+            "features" | "declared_features" | "rustflags" => {
+                format!(
+                    "{key}: {} -> {}",
+                    describe_json(old_value),
+                    describe_json(new_value)
+                )
+            }
+            _ => format!("{key}: {old_value} -> {new_value}"),
+        });
+    }
+    if differences.is_empty() {
+        differences.push(
+            "nothing in their fingerprints, so it's in what goes into the hash \
+             (e.g. where the package came from, or the lockfile)"
+                .to_owned(),
+        );
+    }
+    differences
+}
+
+fn dep_differences(old: &Value, new: &Value) -> String {
+    let deps = |deps: &Value| -> BTreeMap<String, Value> {
+        deps.as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|dep| Some((dep[1].as_str()?.to_owned(), dep[3].clone())))
+            .collect()
+    };
+    let (old, new) = (deps(old), deps(new));
+    let mut changes = Vec::new();
+    for (name, hash) in &new {
+        match old.get(name) {
+            None => changes.push(format!("+{name}")),
+            Some(old_hash) if old_hash != hash => {
+                changes.push(format!("{name} (built differently)"))
+            }
+            Some(_) => {}
+        }
+    }
+    changes.extend(
+        old.keys()
+            .filter(|name| !new.contains_key(*name))
+            .map(|name| format!("-{name}")),
+    );
+    changes.join(", ")
+}
+
+fn without_own_paths(local: &Value) -> Value {
+    let mut local = local.clone();
+    for check in local.as_array_mut().into_iter().flatten() {
+        if let Some(Value::Object(check_dep_info)) = check.get_mut("CheckDepInfo") {
+            check_dep_info.remove("dep_info");
+        }
+        if let Some(Value::Object(rerun_if_changed)) = check.get_mut("RerunIfChanged") {
+            rerun_if_changed.remove("output");
+        }
+    }
+    local
+}
+
+/// Features and the like are JSON lists, sometimes inside strings.
+fn describe_json(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn describe_value(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("{value:?}"),
+        None => "unset".to_owned(),
+    }
+}
+
+fn describe_time(time: Option<SystemTime>) -> String {
+    match time {
+        Some(time) => DateTime::<Utc>::from(time).to_rfc3339(),
+        None => "never (or it's gone)".to_owned(),
+    }
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).ok()?.modified().ok()
+}
+
+/// The newest mtime of anything at `path`, which Cargo
+/// looks all the way through if it's a dir.
+fn newest_mtime(path: &Path) -> Option<SystemTime> {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok()?.modified().ok())
+        .max()
+}
+
+/// The newest mtime of the package's files, roughly as Cargo sees them:
+/// leaving out the target dir and anything hidden.
+fn newest_package_mtime(package_dir: &Path) -> Option<SystemTime> {
+    walkdir::WalkDir::new(package_dir)
+        .into_iter()
+        .filter_entry(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            entry.depth() == 0 || !(name.starts_with('.') || name == "target")
+        })
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok()?.modified().ok())
+        .max()
+}
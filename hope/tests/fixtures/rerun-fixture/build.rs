@@ -0,0 +1,37 @@
+//! A build script whose output depends on an environment variable and a file,
+//! and which passes metadata on to dependents through `links`.
+//!
+//! With `RERUN_FIXTURE_LINK_KIND` set (to "static" or "dylib"), it also
+//! links an empty native library of that kind.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=input.txt");
+    println!("cargo:rerun-if-env-changed=RERUN_FIXTURE_INPUT");
+
+    let input_file = std::fs::read_to_string("input.txt").unwrap();
+    let input_env = std::env::var("RERUN_FIXTURE_INPUT").unwrap_or_else(|_| "unset".to_owned());
+    println!("cargo:rustc-env=RERUN_FIXTURE_FILE={}", input_file.trim());
+    println!("cargo:rustc-env=RERUN_FIXTURE_ENV={input_env}");
+    // Becomes `DEP_RERUN_FIXTURE_ENV` for dependents' build scripts.
+    println!("cargo:env={input_env}");
+
+    println!("cargo:rerun-if-env-changed=RERUN_FIXTURE_LINK_KIND");
+    if let Ok(link_kind) = std::env::var("RERUN_FIXTURE_LINK_KIND") {
+        let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+        // An archive with nothing in it.
+        std::fs::write(out_dir.join("librerunfixture.a"), "!<arch>\n").unwrap();
+        let empty_source = out_dir.join("empty.c");
+        std::fs::write(&empty_source, "").unwrap();
+        let status = std::process::Command::new("cc")
+            .arg("-shared")
+            .arg("-o")
+            .arg(out_dir.join("librerunfixture.so"))
+            .arg(&empty_source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        println!("cargo:rustc-link-search=native={}", out_dir.display());
+        println!("cargo:rustc-link-lib={link_kind}=rerunfixture");
+    }
+}
@@ -0,0 +1,2 @@
+pub const FILE: &str = env!("RERUN_FIXTURE_FILE");
+pub const ENV: &str = env!("RERUN_FIXTURE_ENV");
@@ -1,17 +1,23 @@
 use std::{
+    collections::{BTreeSet, HashMap},
     env,
-    path::PathBuf,
-    process::{Command, Stdio},
-    sync::LazyLock,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{Arc, LazyLock, Mutex},
 };
 
 use hope_cache_log::{
-    BuildScriptRunEvent, BuildScriptWrapperRunEvent, CacheLogLine, PullCrateOutputsEvent,
-    PushCrateOutputsEvent,
+    BuildScriptRunEvent, BuildScriptWrapperRunEvent, CacheLogLine, CompileCrateEvent,
+    PortabilityCheckEvent, PullCrateOutputsEvent, PushCrateOutputsEvent, SessionStartedEvent,
+    SkipPushEvent,
 };
+use sha2::{Digest, Sha256};
 use tempfile::{tempdir, TempDir};
 
 const WRAPPER_PATH: &str = env!("CARGO_BIN_EXE_hope");
+const SERVER_PATH: &str = env!("CARGO_BIN_EXE_hope-server");
 
 struct DepSpec {
     name: String,
@@ -33,7 +39,8 @@ static TEST_DEPS: LazyLock<Vec<DepSpec>> = LazyLock::new(|| {
     vec![
         DepSpec::new("anyhow", "1.0.0", true),
         DepSpec::new("serde_derive", "1.0.0", false),
-        DepSpec::new("typenum", "1.17.0", true),
+        // Later versions of typenum don't have a build script.
+        DepSpec::new("typenum", "=1.17.0", true),
         DepSpec::new("ring", "0.17.8", true),
     ]
 });
@@ -118,6 +125,4661 @@ fn build_lots_of_deps() {
     }
 }
 
+#[test]
+fn binary_log_exports_to_jsonl() {
+    let cache_dir = CacheDir::new();
+
+    let package = Package::with_env(&cache_dir, &[("HOPE_LOG_FORMAT", "cbor")]);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    // Everything should have gone to the binary log.
+    assert!(!cache_dir.dir.path().join("hope-log.jsonl").exists());
+    assert!(cache_dir.dir.path().join("hope-log.cbor").exists());
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 1);
+
+    // Exporting should give us one JSON object per log line.
+    let output = cache_dir
+        .hope()
+        .args(["log", "--export", "jsonl"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let exported: Vec<CacheLogLine> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(exported.len(), log.len());
+}
+
+#[test]
+fn switching_log_format_keeps_the_log_in_order() {
+    let cache_dir = CacheDir::new();
+    for format in ["cbor", "jsonl", "cbor"] {
+        let package = Package::with_env(&cache_dir, &[("HOPE_LOG_FORMAT", format)]);
+        package.add("cfg-if@1.0.0");
+        package.build();
+    }
+    assert!(cache_dir.dir.path().join("hope-log.jsonl").exists());
+    assert!(cache_dir.dir.path().join("hope-log.cbor").exists());
+
+    let log = cache_dir.read_log().unwrap();
+    let cfg_if_events: Vec<&str> = log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::PushedCrateOutputs(event)
+                if event.crate_unit_name.starts_with("cfg_if") =>
+            {
+                Some("push")
+            }
+            CacheLogLine::PulledCrateOutputs(event)
+                if event.crate_unit_name.starts_with("cfg_if") =>
+            {
+                Some("pull")
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(cfg_if_events, ["push", "pull", "pull"]);
+    let session_starts: Vec<_> = log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::SessionStarted(event) => Some(event.started_at),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(session_starts.len(), 3);
+    assert!(session_starts.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+#[test]
+fn each_build_logs_one_session_started_event() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.add("anyhow@1.0.0");
+    package.build();
+
+    let sessions = |log: &[CacheLogLine]| -> Vec<SessionStartedEvent> {
+        log.iter()
+            .filter_map(|line| match line {
+                CacheLogLine::SessionStarted(event) => Some(event.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+    let log = cache_dir.read_log().unwrap();
+    let sessions_so_far = sessions(&log);
+    assert_eq!(sessions_so_far.len(), 1);
+    let session = &sessions_so_far[0];
+    assert!(session
+        .cargo_version
+        .as_ref()
+        .unwrap()
+        .starts_with("cargo "));
+    assert!(!session.rustc_version.is_empty());
+    assert_eq!(session.profile, "debug");
+    // Depending on the edition `cargo init` picked.
+    assert!(matches!(session.resolver.as_deref(), Some("2" | "3")));
+
+    assert!(package
+        .cargo()
+        .args(["build", "--release", "-j", "3"])
+        .current_dir(package.dir.path())
+        .status()
+        .unwrap()
+        .success());
+    let log = cache_dir.read_log().unwrap();
+    let sessions_so_far = sessions(&log);
+    assert_eq!(sessions_so_far.len(), 2);
+    let session = &sessions_so_far[1];
+    assert_eq!(session.profile, "release");
+    if cfg!(target_os = "linux") {
+        assert_eq!(session.jobs, Some(3));
+    }
+}
+
+#[test]
+fn rustc_is_only_asked_its_version_once_per_build() {
+    let cache_dir = CacheDir::new();
+    let bin_dir = tempdir().unwrap();
+    let log_path = bin_dir.path().join("version-queries");
+    let rustc = version_counting_rustc(bin_dir.path(), &log_path);
+    let env = [("RUSTC", rustc.as_str())];
+
+    let package = Package::with_env(&cache_dir, &env);
+    package.add("cfg-if@1.0.0");
+    package.add("itoa@1.0.16");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if").len(), 1);
+    assert_eq!(filter_compile_crate_events(&log, "itoa").len(), 1);
+
+    let queries = std::fs::read_to_string(&log_path).unwrap().lines().count();
+    assert_eq!(queries, 1);
+}
+
+#[test]
+fn determinism_report_compares_cached_crates() {
+    let cache_dir = CacheDir::new();
+
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    let output = package
+        .hope()
+        .args(["determinism-report", "--json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["compared_units"], 1);
+    assert_eq!(report["non_reproducible"].as_array().unwrap().len(), 0);
+}
+
+#[test]
+fn determinism_report_rebuilds_a_sample_with_the_given_profile() {
+    let cache_dir = CacheDir::new();
+
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.add("itoa@1.0.16");
+    assert!(package
+        .cargo()
+        .args(["build", "--release"])
+        .current_dir(package.dir.path())
+        .status()
+        .unwrap()
+        .success());
+
+    let output = package
+        .hope()
+        .args([
+            "determinism-report",
+            "--json",
+            "--sample",
+            "1",
+            "--profile",
+            "release",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["compared_units"], 1);
+    assert_eq!(report["non_reproducible"].as_array().unwrap().len(), 0);
+}
+
+#[test]
+fn chunked_artifacts_round_trip() {
+    let cache_dir = CacheDir::new();
+    let env = [("HOPE_CHUNK_SIZE", "4K")];
+
+    let package_a = Package::with_env(&cache_dir, &env);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+    assert!(cache_dir.dir.path().join("chunks").is_dir());
+
+    // A second package should be able to reassemble everything from the chunks.
+    let package_b = Package::with_env(&cache_dir, &env);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+}
+
+#[test]
+fn implausible_chunk_manifests_fall_back_to_building() {
+    let cache_dir = CacheDir::new();
+    let env = [("HOPE_CHUNK_SIZE", "4K")];
+
+    let package_a = Package::with_env(&cache_dir, &env);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+    for dir_entry in std::fs::read_dir(cache_dir.dir.path()).unwrap() {
+        let path = dir_entry.unwrap().path();
+        if !path.to_str().unwrap().ends_with(".chunks.json") {
+            continue;
+        }
+        let mut manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        manifest["total_size"] = u64::MAX.into();
+        std::fs::write(&path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+    }
+
+    // Rather than trying to allocate all the memory in the world,
+    // it should give up on the cache entry and build instead.
+    let package_b = Package::with_env(&cache_dir, &env);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if").len(), 2);
+}
+
+#[test]
+fn compression_rules_choose_codecs_per_crate() {
+    let cache_dir = CacheDir::new();
+    let env = [
+        ("HOPE_COMPRESSION", "gzip:9"),
+        ("HOPE_COMPRESSION_RULES", "anyhow=none"),
+    ];
+
+    let package_a = Package::with_env(&cache_dir, &env);
+    package_a.add("cfg-if@1.0.0");
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+    let stored: Vec<String> = std::fs::read_dir(cache_dir.dir.path())
+        .unwrap()
+        .map(|dir_entry| dir_entry.unwrap().file_name().into_string().unwrap())
+        .filter(|file_name| file_name.ends_with(".rlib") || file_name.ends_with(".rlib.gz"))
+        .collect();
+    let stored_as = |crate_name: &str| -> Vec<&String> {
+        stored
+            .iter()
+            .filter(|file_name| file_name.starts_with(&format!("lib{crate_name}-")))
+            .collect()
+    };
+    assert!(!stored_as("cfg_if").is_empty());
+    assert!(stored_as("cfg_if")
+        .iter()
+        .all(|file_name| file_name.ends_with(".gz")));
+    assert!(!stored_as("anyhow").is_empty());
+    assert!(stored_as("anyhow")
+        .iter()
+        .all(|file_name| !file_name.ends_with(".gz")));
+
+    // Pulls and checks shouldn't care how things were stored,
+    // even with different settings.
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow").len(), 1);
+    assert!(cache_dir.hope().arg("verify").status().unwrap().success());
+}
+
+#[test]
+fn checksum_freshness_does_not_rebuild_pulled_crates() {
+    let cache_dir = CacheDir::new();
+    // `-Z checksum-freshness` is nightly-only, so pretend to be nightly.
+    let env = [
+        ("RUSTC_BOOTSTRAP", "1"),
+        ("CARGO_UNSTABLE_CHECKSUM_FRESHNESS", "true"),
+    ];
+
+    let package_a = Package::with_env(&cache_dir, &env);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    let package_b = Package::with_env(&cache_dir, &env);
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow").len(), 1);
+
+    // The checksums Cargo goes by come with the pulled dep info file.
+    let deps_dir = package_b.dir.path().join("target/debug/deps");
+    let dep_info = std::fs::read_dir(&deps_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            file_name.starts_with("anyhow-") && file_name.ends_with(".d")
+        })
+        .unwrap();
+    let dep_info = std::fs::read_to_string(dep_info).unwrap();
+    assert!(
+        dep_info
+            .lines()
+            .any(|line| line.starts_with("# checksum:") && line.contains("lib.rs")),
+        "{dep_info}"
+    );
+
+    // Cargo should consider everything we pulled to be fresh.
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow").len(), 1);
+    assert_eq!(filter_push_crate_outputs_events(&log, "anyhow").len(), 1);
+}
+
+#[test]
+fn verify_sources_refuses_to_push_modified_checkouts() {
+    let cache_dir = CacheDir::new();
+    let env = [("HOPE_VERIFY_SOURCES", "1")];
+
+    // Use a private Cargo home so we can scribble on the registry checkout
+    // without upsetting anyone else.
+    let cargo_home = PrivateCargoHome::new();
+    let env = [env[0], ("CARGO_HOME", cargo_home.path())];
+
+    let package = Package::with_env(&cache_dir, &env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 1);
+
+    // Now tamper with the checkout and build again from scratch.
+    let lib_rs = cargo_home
+        .registry_checkout("cfg-if-1.0.")
+        .join("src/lib.rs");
+    let mut source = std::fs::read_to_string(&lib_rs).unwrap();
+    source.push_str("\n// Local modification!\n");
+    std::fs::write(&lib_rs, source).unwrap();
+
+    let fresh_cache_dir = CacheDir::new();
+    let package = Package::with_env(&fresh_cache_dir, &env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = fresh_cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_skipped_push_events(&log, "cfg_if").len(), 1);
+}
+
+#[test]
+fn source_audit_refuses_to_pull_over_drifted_checkouts() {
+    let cache_dir = CacheDir::new();
+    // Use a private Cargo home so we can scribble on the registry checkout
+    // without upsetting anyone else.
+    let cargo_home = PrivateCargoHome::new();
+    let env = [("CARGO_HOME", cargo_home.path())];
+    let audit_env = [env[0], ("HOPE_AUDIT_SOURCES", "1")];
+
+    let package = Package::with_env(&cache_dir, &audit_env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let manifests = cache_dir.entry_manifests("cfg_if");
+    assert_eq!(manifests.len(), 1);
+    assert!(manifests[0]["sources"]["src/lib.rs"].is_string());
+
+    // Same sources here, so the audit passes.
+    let package = Package::with_env(&cache_dir, &audit_env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+
+    // Now the checkout drifts from what the entry was built from.
+    let lib_rs = cargo_home
+        .registry_checkout("cfg-if-1.0.")
+        .join("src/lib.rs");
+    let mut source = std::fs::read_to_string(&lib_rs).unwrap();
+    source.push_str("\n// Local modification!\n");
+    std::fs::write(&lib_rs, source).unwrap();
+
+    let package = Package::with_env(&cache_dir, &audit_env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if").len(), 2);
+
+    // The audit is opt-in.
+    let package = Package::with_env(&cache_dir, &env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 2);
+}
+
+#[test]
+fn unit_name_collisions_between_packages_are_cache_misses() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let manifest_paths = cache_dir.entry_manifest_paths("cfg_if");
+    assert_eq!(manifest_paths.len(), 1);
+    let mut manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest_paths[0]).unwrap()).unwrap();
+    let package_id = manifest["package_id"].as_str().unwrap();
+    assert!(
+        package_id.starts_with("registry+https://github.com/rust-lang/crates.io-index#cfg-if@1.0.")
+    );
+
+    // Pretend some other package got there first with the same unit name.
+    manifest["package_id"] = "registry+https://example.com/index#cfg-if@1.0.0".into();
+    std::fs::write(&manifest_paths[0], manifest.to_string()).unwrap();
+
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if").len(), 2);
+}
+
+#[test]
+fn hooks_see_cache_events_and_can_veto_pulls() {
+    let cache_dir = CacheDir::new();
+    let hook_dir = tempdir().unwrap();
+    let events_path = hook_dir.path().join("events.jsonl");
+    let hook = format!("cat >> {:?}", events_path);
+    let hook_env = [("HOPE_HOOK", hook.as_str())];
+    let events = || -> Vec<serde_json::Value> {
+        std::fs::read_to_string(&events_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    };
+    let event_names = |events: &[serde_json::Value]| -> Vec<String> {
+        events
+            .iter()
+            .map(|event| event["event"].as_str().unwrap().to_owned())
+            .collect()
+    };
+
+    let package = Package::with_env(&cache_dir, &hook_env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let pushed = events();
+    assert_eq!(
+        event_names(&pushed),
+        ["before-pull", "missed", "before-push", "pushed"]
+    );
+    assert!(pushed[0]["crate_unit_name"]
+        .as_str()
+        .unwrap()
+        .starts_with("cfg_if-"));
+    std::fs::remove_file(&events_path).unwrap();
+
+    let package = Package::with_env(&cache_dir, &hook_env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    assert_eq!(event_names(&events()), ["before-pull", "pulled"]);
+
+    // A failing "before" hook means no pull.
+    let veto = r#"test "$HOPE_HOOK_EVENT" != before-pull"#;
+    let package = Package::with_env(&cache_dir, &[("HOPE_HOOK", veto)]);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if").len(), 2);
+}
+
+#[cfg(feature = "wasm-plugins")]
+#[test]
+fn policy_plugins_decide_what_to_cache_and_pull() {
+    let cache_dir = CacheDir::new();
+    let plugin_dir = tempdir().unwrap();
+    let write_plugin = |name: &str, functions: &str| -> String {
+        let path = plugin_dir.path().join(name);
+        std::fs::write(
+            &path,
+            format!(
+                "(module\n\
+                   (memory (export \"memory\") 1)\n\
+                   (func (export \"hope_alloc\") (param i32) (result i32) i32.const 1024)\n\
+                   {functions})"
+            ),
+        )
+        .unwrap();
+        path.to_str().unwrap().to_owned()
+    };
+    // Input starts with `{"crate_name":"`, so look for "cf" straight after.
+    let picky = write_plugin(
+        "picky.wat",
+        "(func (export \"hope_cacheable\") (param $ptr i32) (param i32) (result i32)\n\
+           (i32.eqz (i32.and\n\
+             (i32.eq (i32.load8_u offset=15 (local.get $ptr)) (i32.const 99))\n\
+             (i32.eq (i32.load8_u offset=16 (local.get $ptr)) (i32.const 102)))))\n\
+         (func (export \"hope_key_includes\") (param i32 i32) (result i32) i32.const 1)",
+    );
+    let stubborn = write_plugin(
+        "stubborn.wat",
+        "(func (export \"hope_accept_pull\") (param i32 i32) (result i32) i32.const 0)",
+    );
+
+    for _ in 0..2 {
+        let package = Package::with_env(&cache_dir, &[("HOPE_POLICY_PLUGIN", &picky)]);
+        package.add("cfg-if@1.0.0");
+        package.add("anyhow@1.0.0");
+        package.build();
+    }
+    let log = cache_dir.read_log().unwrap();
+    // It's as if Hope weren't there for whatever isn't cacheable...
+    assert!(filter_compile_crate_events(&log, "cfg_if-").is_empty());
+    assert!(cache_dir.entry_manifests("cfg_if").is_empty());
+    // ...and everything else is keyed with the plugin's say.
+    assert_eq!(filter_compile_crate_events(&log, "anyhow-").len(), 1);
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow-").len(), 1);
+    let manifests = cache_dir.entry_manifests("anyhow");
+    assert_eq!(manifests.len(), 1);
+    assert!(manifests[0]["key_policy"]
+        .as_str()
+        .unwrap()
+        .starts_with("strict+plugin("));
+
+    // A rejected pull is just a miss.
+    let package = Package::with_env(&cache_dir, &[("HOPE_POLICY_PLUGIN", &stubborn)]);
+    package.add("anyhow@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_compile_crate_events(&log, "anyhow-").len(), 2);
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow-").len(), 1);
+}
+
+#[test]
+fn interrupted_pulls_and_pushes_leave_no_partial_files() {
+    let cache_dir = CacheDir::new();
+    // Have the hook stand in for somebody hitting Ctrl-C (or a CI runner
+    // timing out) just as Hope is about to push or pull.
+    let interrupt_at = |event: &str| {
+        format!(
+            r#"cat > /dev/null; if [ "$HOPE_HOOK_EVENT" = {event} ]; then kill -TERM $PPID; fi"#
+        )
+    };
+    let leftovers = |dir: &Path| -> Vec<PathBuf> {
+        walkdir::WalkDir::new(dir)
+            .min_depth(1)
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| {
+                let file_name = path.file_name().unwrap().to_str().unwrap();
+                file_name.starts_with(".tmp") || file_name.contains("cfg_if")
+            })
+            .collect()
+    };
+
+    let hook = interrupt_at("before-push");
+    let package = Package::with_env(&cache_dir, &[("HOPE_HOOK", &hook)]);
+    package.add("cfg-if@1.0.0");
+    assert!(!package
+        .cargo()
+        .arg("build")
+        .current_dir(package.dir.path())
+        .status()
+        .unwrap()
+        .success());
+    assert!(cache_dir.entry_manifest_paths("cfg_if").is_empty());
+    let cache_leftovers: Vec<PathBuf> = leftovers(cache_dir.dir.path())
+        .into_iter()
+        .filter(|path| !path.starts_with(cache_dir.dir.path().join("locks")))
+        .collect();
+    assert!(cache_leftovers.is_empty(), "{cache_leftovers:?}");
+
+    // Now fill the cache for real, and interrupt a pull.
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    assert_eq!(cache_dir.entry_manifest_paths("cfg_if").len(), 1);
+    let hook = interrupt_at("before-pull");
+    let package = Package::with_env(&cache_dir, &[("HOPE_HOOK", &hook)]);
+    package.add("cfg-if@1.0.0");
+    assert!(!package
+        .cargo()
+        .arg("build")
+        .current_dir(package.dir.path())
+        .status()
+        .unwrap()
+        .success());
+    let deps_dir = package.dir.path().join("target/debug/deps");
+    let target_leftovers = leftovers(&deps_dir);
+    assert!(target_leftovers.is_empty(), "{target_leftovers:?}");
+    let log = cache_dir.read_log().unwrap();
+    assert!(filter_pull_crate_outputs_events(&log, "cfg_if").is_empty());
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if").len(), 2);
+}
+
+#[test]
+fn compiles_and_build_script_runs_record_resource_usage() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("anyhow@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+
+    let compiles = filter_compile_crate_events(&log, "anyhow");
+    assert_eq!(compiles.len(), 1);
+    let usage = compiles[0].usage.unwrap();
+    // Even the smallest `rustc` run needs a few megabytes.
+    assert!(usage.max_rss_bytes > 1 << 20, "{usage:?}");
+    assert!(usage.cpu_secs > 0.0, "{usage:?}");
+
+    let build_script_runs = filter_ran_build_script_events(&log, "anyhow");
+    assert_eq!(build_script_runs.len(), 1);
+    assert!(build_script_runs[0].usage.unwrap().max_rss_bytes > 0);
+
+    let output = cache_dir.hope().arg("stats").output().unwrap();
+    assert!(output.status.success());
+    let stats = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stats.lines().next().unwrap().contains("peak RSS"),
+        "{stats}"
+    );
+    let anyhow_line = stats
+        .lines()
+        .find(|line| line.starts_with("anyhow "))
+        .unwrap();
+    assert!(anyhow_line.ends_with('M'), "{anyhow_line}");
+}
+
+#[test]
+fn portability_check_notices_remapped_paths() {
+    let home = env::var("HOME").unwrap();
+
+    // Without remapping, registry source paths end up in the artifacts.
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    let checks = filter_portability_check_events(&log, "cfg_if");
+    assert_eq!(checks.len(), 1);
+    assert!(checks[0].score < 100);
+    assert!(checks[0].leaked_prefixes.contains(&home));
+
+    // With remapping, they shouldn't.
+    let cache_dir = CacheDir::new();
+    let rustflags = format!("--remap-path-prefix={home}=/home");
+    let package = Package::with_env(&cache_dir, &[("RUSTFLAGS", &rustflags)]);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    let checks = filter_portability_check_events(&log, "cfg_if");
+    assert_eq!(checks.len(), 1);
+    assert_eq!(checks[0].score, 100);
+}
+
+#[test]
+fn package_profile_overrides_get_separate_cache_entries() {
+    let cache_dir = CacheDir::new();
+
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    // Bumping the opt level for dependencies only changes codegen flags,
+    // so this must not be served the unoptimized build.
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.append_to_manifest("\n[profile.dev.package.\"*\"]\nopt-level = 3\n");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 2);
+
+    // But a package with the same override should get the optimized build.
+    let package_c = Package::new(&cache_dir);
+    package_c.add("cfg-if@1.0.0");
+    package_c.append_to_manifest("\n[profile.dev.package.\"*\"]\nopt-level = 3\n");
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+}
+
+#[test]
+fn metadata_hashes_get_separate_cache_entries() {
+    let cache_dir = CacheDir::new();
+    let wrapper_dir = tempdir().unwrap();
+    let wrapper = metadata_changing_wrapper(wrapper_dir.path());
+
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    // Same unit names, but different symbol names,
+    // so these can't stand in for each other.
+    let package_b = Package::with_env(&cache_dir, &[("RUSTC_WRAPPER", &wrapper)]);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    let pushes = filter_push_crate_outputs_events(&log, "cfg_if");
+    assert_eq!(pushes.len(), 2);
+    assert_eq!(pushes[0].crate_unit_name, pushes[1].crate_unit_name);
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 0);
+
+    let package_c = Package::with_env(&cache_dir, &[("RUSTC_WRAPPER", &wrapper)]);
+    package_c.add("cfg-if@1.0.0");
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+}
+
+#[test]
+fn native_lib_link_kinds_get_separate_cache_entries() {
+    let cache_dir = CacheDir::new();
+    let registry = FixtureRegistry::new();
+    let build = |link_kind: &str| {
+        let package = Package::new(&cache_dir);
+        package.use_fixture_registry(&registry);
+        let output = package
+            .cargo()
+            .arg("build")
+            .env("RERUN_FIXTURE_LINK_KIND", link_kind)
+            .current_dir(package.dir.path())
+            .stderr(Stdio::piped())
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        package
+    };
+
+    // Same unit names, but one has the library bundled into it,
+    // so these can't stand in for each other.
+    let _package_a = build("static");
+    let _package_b = build("dylib");
+    let log = cache_dir.read_log().unwrap();
+    let pushes = filter_push_crate_outputs_events(&log, "rerun_fixture");
+    assert_eq!(pushes.len(), 2);
+    assert_eq!(pushes[0].crate_unit_name, pushes[1].crate_unit_name);
+    assert_eq!(
+        filter_pull_crate_outputs_events(&log, "rerun_fixture").len(),
+        0
+    );
+
+    let _package_c = build("dylib");
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(
+        filter_pull_crate_outputs_events(&log, "rerun_fixture").len(),
+        1
+    );
+}
+
+#[test]
+fn panic_strategies_get_separate_cache_entries() {
+    let cache_dir = CacheDir::new();
+    let abort = "\n[profile.dev]\npanic = \"abort\"\n";
+
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    // Crates built to abort can't be linked into ones that unwind, or vice versa.
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.append_to_manifest(abort);
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 2);
+
+    let package_c = Package::new(&cache_dir);
+    package_c.add("cfg-if@1.0.0");
+    package_c.append_to_manifest(abort);
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+
+    // No key policy gets to ignore the difference,
+    // nor a difference in `-C metadata`.
+    for ignored in ["debug-info,codegen:panic", "debug-info,metadata"] {
+        let package_d = Package::with_env(
+            &cache_dir,
+            &[("HOPE_KEY_POLICY", "custom"), ("HOPE_KEY_IGNORE", ignored)],
+        );
+        package_d.add("cfg-if@1.0.0");
+        let output = package_d
+            .cargo()
+            .arg("build")
+            .current_dir(package_d.dir.path())
+            .stderr(Stdio::piped())
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{ignored}");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("HOPE_KEY_IGNORE"));
+    }
+}
+
+#[test]
+fn build_override_profiles_invalidate_build_scripts() {
+    let cache_dir = CacheDir::new();
+    let build_override = "\n[profile.dev.build-override]\nopt-level = 3\n";
+
+    let package_a = Package::new(&cache_dir);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_ran_build_script_events(&log, "anyhow").len(), 1);
+
+    // Optimizing build scripts means a different build script executable,
+    // so we shouldn't trust the unoptimized one's output.
+    let package_b = Package::new(&cache_dir);
+    package_b.add("anyhow@1.0.0");
+    package_b.append_to_manifest(build_override);
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_ran_build_script_events(&log, "anyhow").len(), 2);
+    assert_eq!(
+        filter_push_crate_outputs_events(&log, "build_script_build").len(),
+        2
+    );
+
+    // But the same override again should reuse everything.
+    let package_c = Package::new(&cache_dir);
+    package_c.add("anyhow@1.0.0");
+    package_c.append_to_manifest(build_override);
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_ran_build_script_events(&log, "anyhow").len(), 2);
+    assert_eq!(
+        filter_push_crate_outputs_events(&log, "build_script_build").len(),
+        2
+    );
+}
+
+#[test]
+fn entries_for_other_targets_are_not_pulled() {
+    let cache_dir = CacheDir::new();
+
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    // Pretend the entry was built for some other target.
+    let manifest_paths = cache_dir.entry_manifest_paths("cfg_if");
+    assert_eq!(manifest_paths.len(), 1);
+    let mut manifest = cache_dir.entry_manifests("cfg_if").remove(0);
+    manifest["target"] = "wasm32-unknown-unknown".into();
+    std::fs::write(&manifest_paths[0], manifest.to_string()).unwrap();
+
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 2);
+}
+
+#[test]
+fn crates_of_every_edition_are_cached() {
+    let cache_dir = CacheDir::new();
+    let deps = [
+        ("scopeguard@1.2.0", "scopeguard", "2015"),
+        ("cfg-if@1.0.0", "cfg_if", "2018"),
+        ("itoa@1.0.16", "itoa", "2021"),
+        ("hashbrown@0.17.0", "hashbrown", "2024"),
+    ];
+
+    let package_a = Package::new(&cache_dir);
+    for (dep, _, _) in deps {
+        package_a.add(dep);
+    }
+    package_a.build();
+    let log = cache_dir.read_log().unwrap();
+    for (_, crate_name, edition) in deps {
+        assert_eq!(filter_push_crate_outputs_events(&log, crate_name).len(), 1);
+        let manifests = cache_dir.entry_manifests(crate_name);
+        assert_eq!(manifests.len(), 1);
+        assert_eq!(manifests[0]["edition"], edition);
+    }
+
+    let package_b = Package::new(&cache_dir);
+    for (dep, _, _) in deps {
+        package_b.add(dep);
+    }
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    for (_, crate_name, _) in deps {
+        assert_eq!(filter_pull_crate_outputs_events(&log, crate_name).len(), 1);
+    }
+}
+
+#[test]
+fn serve_exposes_local_cache_over_http() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    let server = CacheServer::spawn(
+        &cache_dir,
+        &["serve", "--listen", "127.0.0.1:0", "--allow-push"],
+        "Listening on ",
+    );
+
+    // Everything we pushed should be available.
+    let manifest_path = &cache_dir.entry_manifest_paths("cfg_if")[0];
+    let manifest_key = manifest_path.file_name().unwrap().to_str().unwrap();
+    let mut body = String::new();
+    ureq::get(&server.blob_url(manifest_key))
+        .call()
+        .unwrap()
+        .into_reader()
+        .read_to_string(&mut body)
+        .unwrap();
+    assert_eq!(body, std::fs::read_to_string(manifest_path).unwrap());
+
+    // With '--allow-push', other machines should be able to push to it, too.
+    let response = ureq::put(&server.blob_url("chunks/abc")).send_bytes(b"hello");
+    assert_eq!(response.unwrap().status(), 204);
+    assert_eq!(
+        std::fs::read(cache_dir.dir.path().join("chunks/abc")).unwrap(),
+        b"hello"
+    );
+
+    let status_of = |result: Result<ureq::Response, ureq::Error>| match result {
+        Ok(response) => response.status(),
+        Err(ureq::Error::Status(status, _)) => status,
+        Err(err) => panic!("Request failed: {err}"),
+    };
+    assert_eq!(
+        status_of(ureq::get(&server.blob_url("no-such-blob")).call()),
+        404
+    );
+    assert_eq!(
+        status_of(ureq::put(&server.blob_url("hope-log.jsonl")).send_bytes(b"")),
+        403
+    );
+    assert_eq!(
+        status_of(ureq::get(&server.blob_url("chunks//abc")).call()),
+        400
+    );
+
+    // Otherwise it shares what's there, but won't take anything new.
+    let read_only_server = CacheServer::start(&cache_dir);
+    assert_eq!(
+        status_of(ureq::put(&read_only_server.blob_url("chunks/def")).send_bytes(b"hello")),
+        403
+    );
+    assert!(!cache_dir.dir.path().join("chunks/def").exists());
+
+    // The server has "aho_corasick", but not "memchr", which it depends on,
+    // so a colleague compiles that first and has the push refused. That's
+    // not the server failing (even at the strictest error limit), so they
+    // still pull "aho_corasick" after.
+    package.add("aho-corasick@1.1.3");
+    package.build();
+    for manifest_path in cache_dir.entry_manifest_paths("memchr") {
+        std::fs::remove_file(manifest_path).unwrap();
+    }
+    let cache_url = format!("http://{}/blobs", read_only_server.addr);
+    let colleague_cache_dir = CacheDir::new();
+    let colleague = Package::with_env(
+        &colleague_cache_dir,
+        &[
+            ("HOPE_CACHE_URL", cache_url.as_str()),
+            ("HOPE_REMOTE_ERROR_LIMIT", "1"),
+        ],
+    );
+    colleague.add("aho-corasick@1.1.3");
+    colleague.add("cfg-if@1.0.0");
+    colleague.add("itoa@1.0.16");
+    colleague.add("scopeguard@1.2.0");
+    assert!(colleague
+        .cargo()
+        .args(["build", "-j", "4"])
+        .current_dir(colleague.dir.path())
+        .status()
+        .unwrap()
+        .success());
+    let log = colleague_cache_dir.read_log().unwrap();
+    for dep in ["memchr-", "itoa-", "scopeguard-"] {
+        assert_eq!(filter_compile_crate_events(&log, dep).len(), 1, "{dep}");
+    }
+    for dep in ["aho_corasick-", "cfg_if-"] {
+        assert_eq!(
+            filter_pull_crate_outputs_events(&log, dep).len(),
+            1,
+            "{dep}"
+        );
+    }
+    assert!(!log
+        .iter()
+        .any(|line| matches!(line, CacheLogLine::FellBackToLocal(_))));
+    assert!(cache_dir.entry_manifests("itoa").is_empty());
+}
+
+#[test]
+fn serve_pulls_through_from_upstream() {
+    let upstream_dir = CacheDir::new();
+    let upstream_url = format!("file://{}", upstream_dir.dir.path().display());
+    let package_a = Package::new(&upstream_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    let server_cache_dir = CacheDir::new();
+    let server = CacheServer::spawn(
+        &server_cache_dir,
+        &[
+            "serve",
+            "--listen",
+            "127.0.0.1:0",
+            "--upstream",
+            &upstream_url,
+        ],
+        "Listening on ",
+    );
+    let cache_url = format!("http://{}/blobs", server.addr);
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &[("HOPE_CACHE_URL", cache_url.as_str())]);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+
+    let log = cache_dir_b.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "cfg_if-").is_empty());
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+    // The server kept what it fetched, so it doesn't need upstream for it again.
+    assert_eq!(server_cache_dir.entry_manifests("cfg_if").len(), 1);
+    std::fs::remove_dir_all(upstream_dir.dir.path()).unwrap();
+    std::fs::create_dir(upstream_dir.dir.path()).unwrap();
+    let cache_dir_c = CacheDir::new();
+    let package_c = Package::with_env(&cache_dir_c, &[("HOPE_CACHE_URL", cache_url.as_str())]);
+    package_c.add("cfg-if@1.0.0");
+    package_c.build();
+    let log = cache_dir_c.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+}
+
+#[test]
+fn capabilities_are_reported_as_json() {
+    let cache_dir = CacheDir::new();
+    let output = cache_dir
+        .hope()
+        .args(["capabilities", "--json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let capabilities: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(capabilities["version"], env!("CARGO_PKG_VERSION"));
+    assert!(capabilities["schema_version"].as_u64().unwrap() >= 1);
+    let backends = capabilities["backends"].as_array().unwrap();
+    assert!(backends.contains(&"local".into()) && backends.contains(&"http".into()));
+    assert!(capabilities["compression_codecs"]
+        .as_array()
+        .unwrap()
+        .contains(&"none".into()));
+    assert_eq!(capabilities["platform"]["os"], env::consts::OS);
+
+    // `hope serve` answers with the same report.
+    let server = CacheServer::start(&cache_dir);
+    let served = ureq::get(&format!("http://{}/capabilities", server.addr))
+        .call()
+        .unwrap()
+        .into_string()
+        .unwrap();
+    let served: serde_json::Value = serde_json::from_str(&served).unwrap();
+    assert_eq!(served, capabilities);
+}
+
+// Hope's handling of mtimes (see its `mtime` module) depends on how
+// the platform behaves, so check what it relies on everywhere CI runs.
+fn mtime_probe(dir: &Path) -> serde_json::Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_hope"))
+        .args(["mtime-probe", "--json"])
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn mtime_findings_hold_on_macos() {
+    let dir = tempdir().unwrap();
+    let report = mtime_probe(dir.path());
+    assert_eq!(report["found"]["copy_preserves_mtime"], true);
+    assert_eq!(report["strategy"], "invoked-timestamp");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn mtime_findings_hold_on_linux() {
+    let dir = tempdir().unwrap();
+    let report = mtime_probe(dir.path());
+    assert_eq!(report["found"]["copy_preserves_mtime"], false);
+    assert_eq!(report["found"]["fs_clock_lags_system_time"], true);
+    assert_eq!(report["strategy"], "as-written");
+}
+
+#[test]
+fn mtime_strategy_keeps_pulled_units_fresh() {
+    let cache_dir = CacheDir::new();
+
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+    // Build again after touching the package's own source, which should
+    // only rebuild the package itself.
+    let main_path = package_b.dir.path().join("src/main.rs");
+    std::fs::write(&main_path, std::fs::read(&main_path).unwrap()).unwrap();
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    for crate_name in ["cfg_if", "anyhow"] {
+        assert_eq!(filter_compile_crate_events(&log, crate_name).len(), 1);
+        assert_eq!(filter_pull_crate_outputs_events(&log, crate_name).len(), 1);
+    }
+}
+
+#[test]
+fn daemon_serves_status_and_health() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    let daemon = CacheServer::spawn(
+        &cache_dir,
+        &["daemon", "--status-port", "0"],
+        "Status endpoint listening on ",
+    );
+    let get_json = |path: &str| -> (u16, serde_json::Value) {
+        let response = match ureq::get(&format!("http://{}{path}", daemon.addr)).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(_, response)) => response,
+            Err(err) => panic!("Request failed: {err}"),
+        };
+        let status = response.status();
+        (
+            status,
+            serde_json::from_str(&response.into_string().unwrap()).unwrap(),
+        )
+    };
+
+    let (status_code, status) = get_json("/status");
+    assert_eq!(status_code, 200);
+    assert_eq!(status["entries"], 1);
+    assert_eq!(status["pulls"], 1);
+    assert_eq!(status["compiles"], 1);
+    assert_eq!(status["pushes"], 1);
+    assert_eq!(status["hit_rate"], 0.5);
+    assert!(status["size_bytes"].as_u64().unwrap() > 0);
+
+    let (status_code, health) = get_json("/health");
+    assert_eq!(status_code, 200);
+    assert_eq!(health["healthy"], true);
+}
+
+#[test]
+fn daemon_serves_repeated_pulls_from_memory() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    let daemon = CacheServer::spawn(
+        &cache_dir,
+        &["daemon", "--status-port", "0", "--memory-cache-size", "64M"],
+        "Status endpoint listening on ",
+    );
+    let cache_url = format!("http://{}/blobs", daemon.addr);
+    let env = [("HOPE_CACHE_URL", cache_url.as_str())];
+    for _ in 0..2 {
+        let local_cache_dir = CacheDir::new();
+        let package = Package::with_env(&local_cache_dir, &env);
+        package.add("cfg-if@1.0.0");
+        package.build();
+        let log = local_cache_dir.read_log().unwrap();
+        assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+    }
+
+    // The second build's pull came from memory.
+    let status: serde_json::Value = serde_json::from_str(
+        &ureq::get(&format!("http://{}/status", daemon.addr))
+            .call()
+            .unwrap()
+            .into_string()
+            .unwrap(),
+    )
+    .unwrap();
+    let memory_cache = &status["memory_cache"];
+    assert!(memory_cache["entries"].as_u64().unwrap() > 0);
+    assert!(memory_cache["misses"].as_u64().unwrap() > 0);
+    assert!(memory_cache["hits"].as_u64().unwrap() >= memory_cache["misses"].as_u64().unwrap());
+    assert!(memory_cache["size_bytes"].as_u64().unwrap() <= 64 << 20);
+}
+
+#[test]
+fn daemon_fronts_the_remote_cache_on_a_socket() {
+    let remote_cache_dir = CacheDir::new();
+    let remote_cache_url = format!("file://{}", remote_cache_dir.dir.path().display());
+    let daemon_cache_dir = CacheDir::new();
+    let socket_dir = tempfile::tempdir().unwrap();
+    let socket = socket_dir.path().join("hope.sock");
+    let daemon = CacheServer::spawn_command(
+        daemon_cache_dir
+            .hope()
+            .args(["daemon", "--status-port", "0", "--socket"])
+            .arg(&socket)
+            .env("HOPE_CACHE_URL", &remote_cache_url),
+        "Status endpoint listening on ",
+    );
+
+    // Builds only know about the socket, not where the cache really is.
+    let env = [("HOPE_DAEMON_SOCKET", socket.to_str().unwrap())];
+    let first_cache_dir = CacheDir::new();
+    let package = Package::with_env(&first_cache_dir, &env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = first_cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if-").len(), 1);
+    assert!(
+        std::fs::read_dir(remote_cache_dir.dir.path())
+            .unwrap()
+            .count()
+            > 0
+    );
+
+    let second_cache_dir = CacheDir::new();
+    let package = Package::with_env(&second_cache_dir, &env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = second_cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+
+    // The daemon remembers what it fetched, for the next build.
+    let status: serde_json::Value = serde_json::from_str(
+        &ureq::get(&format!("http://{}/status", daemon.addr))
+            .call()
+            .unwrap()
+            .into_string()
+            .unwrap(),
+    )
+    .unwrap();
+    assert!(status["remote_memory_cache"]["entries"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn lockfile_index_warms_up_an_empty_cache() {
+    let shared_cache_dir = CacheDir::new();
+    let package_a = Package::new(&shared_cache_dir);
+    package_a.add("anyhow@1.0.0");
+    let lockfile = package_a.dir.path().join("Cargo.lock");
+    assert!(package_a
+        .cargo()
+        .args(["build", "--locked"])
+        .env("HOPE_LOCKFILE_INDEX", &lockfile)
+        .current_dir(package_a.dir.path())
+        .status()
+        .unwrap()
+        .success());
+    let server = CacheServer::start(&shared_cache_dir);
+
+    // A CI machine starting from nothing fetches everything up front...
+    let ci_cache_dir = CacheDir::new();
+    let output = ci_cache_dir
+        .hope()
+        .args(["warm-up", "--from", &format!("http://{}", server.addr)])
+        .arg("--lockfile")
+        .arg(&lockfile)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(!String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("missing"));
+
+    // ...and then doesn't need to build anything.
+    let package_b = Package::new(&ci_cache_dir);
+    package_b.add("anyhow@1.0.0");
+    std::fs::copy(&lockfile, package_b.dir.path().join("Cargo.lock")).unwrap();
+    package_b.build();
+    let log = ci_cache_dir.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "anyhow").is_empty());
+    assert!(filter_ran_build_script_events(&log, "anyhow").is_empty());
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow").len(), 1);
+}
+
+#[test]
+fn warm_up_refuses_keys_from_a_poisoned_index() {
+    // A server that answers every request with the same index, whose
+    // record would have us write outside the cache dir.
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let _ = request.respond(tiny_http::Response::from_string("alias ../escaped\n"));
+        }
+    });
+
+    let dir = tempdir().unwrap();
+    let cache_dir = dir.path().join("cache");
+    let lockfile = dir.path().join("Cargo.lock");
+    std::fs::write(&lockfile, "").unwrap();
+    let status = Command::new(WRAPPER_PATH)
+        .env("HOPE_CACHE_DIR", &cache_dir)
+        .args(["warm-up", "--from", &url])
+        .arg("--lockfile")
+        .arg(&lockfile)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    assert!(!dir.path().join("escaped.alias.json").exists());
+}
+
+#[test]
+fn plain_http_server_works_as_remote_cache() {
+    // `hope serve` will do as a plain HTTP server; we only use its blobs path,
+    // just like we would an nginx location that takes `PUT`s.
+    let server_cache_dir = CacheDir::new();
+    let server = CacheServer::spawn(
+        &server_cache_dir,
+        &["serve", "--listen", "127.0.0.1:0", "--allow-push"],
+        "Listening on ",
+    );
+    let cache_url = format!("http://{}/blobs", server.addr);
+    let env = [("HOPE_CACHE_URL", cache_url.as_str())];
+
+    let cache_dir_a = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir_a, &env);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &env);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+
+    let log = cache_dir_b.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "cfg_if-").is_empty());
+    let pulls = filter_pull_crate_outputs_events(&log, "cfg_if-");
+    assert_eq!(pulls.len(), 1);
+    assert_eq!(pulls[0].copied_from, format!("{cache_url}/"));
+    assert_eq!(server_cache_dir.entry_manifests("cfg_if").len(), 1);
+}
+
+#[test]
+fn clean_prestages_what_comes_back_from_the_cache() {
+    let shared_dir = CacheDir::new();
+    let cache_url = format!("file://{}", shared_dir.dir.path().display());
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(&cache_dir, &[("HOPE_CACHE_URL", cache_url.as_str())]);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    let output = package
+        .hope()
+        .args(["clean", "--prestage"])
+        .env("HOPE_CACHE_URL", &cache_url)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("will come back from the cache"), "{stdout}");
+    assert!(stdout.contains("Staged"), "{stdout}");
+    assert!(!package.dir.path().join("target/debug").exists());
+
+    // Even with the remote cache gone, the next build gets it from what was staged.
+    std::fs::remove_dir_all(shared_dir.dir.path()).unwrap();
+    std::fs::create_dir(shared_dir.dir.path()).unwrap();
+    let log_len = cache_dir.read_log().unwrap().len();
+    package.build();
+    let log = cache_dir.read_log().unwrap().split_off(log_len);
+    assert!(filter_compile_crate_events(&log, "cfg_if-").is_empty());
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+}
+
+#[test]
+fn shared_dir_works_as_remote_cache() {
+    let shared_dir = CacheDir::new();
+    let cache_url = format!("file://{}", shared_dir.dir.path().display());
+    let env = [("HOPE_CACHE_URL", cache_url.as_str())];
+
+    let cache_dir_a = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir_a, &env);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &env);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+
+    let log = cache_dir_b.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "cfg_if-").is_empty());
+    let pulls = filter_pull_crate_outputs_events(&log, "cfg_if-");
+    assert_eq!(pulls.len(), 1);
+    assert_eq!(pulls[0].copied_from, format!("{cache_url}/"));
+    // It's laid out like any other cache dir, so it can be served,
+    // garbage collected, and so on, just the same.
+    assert_eq!(shared_dir.entry_manifests("cfg_if").len(), 1);
+    assert!(cache_dir_a.entry_manifests("cfg_if").is_empty());
+
+    // Anything else is rejected up front, rather than silently using the local cache.
+    let package_c = Package::with_env(&CacheDir::new(), &[("HOPE_CACHE_URL", "ftp://nope")]);
+    package_c.add("cfg-if@1.0.0");
+    let output = package_c
+        .cargo()
+        .arg("build")
+        .current_dir(package_c.dir.path())
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("HOPE_CACHE_URL"));
+    assert!(stderr.contains("Unsupported cache URL"));
+}
+
+#[test]
+fn oversized_artifacts_are_not_pushed_to_remote_caches() {
+    let shared_dir = CacheDir::new();
+    let cache_url = format!("file://{}", shared_dir.dir.path().display());
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(
+        &cache_dir,
+        &[
+            ("HOPE_CACHE_URL", cache_url.as_str()),
+            ("HOPE_MAX_REMOTE_PUSH_SIZE", "1K"),
+        ],
+    );
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    assert!(shared_dir.entry_manifests("cfg_if").is_empty());
+    let skips = filter_skipped_push_events(&cache_dir.read_log().unwrap(), "cfg_if-");
+    assert_eq!(skips.len(), 1);
+    assert!(
+        skips[0]
+            .reason
+            .contains("over the remote push limit of 1024 bytes"),
+        "{}",
+        skips[0].reason
+    );
+
+    // It's still cached locally, so the next build needn't compile it again.
+    assert_eq!(cache_dir.entry_manifests("cfg_if").len(), 1);
+    let package_b = Package::with_env(
+        &cache_dir,
+        &[
+            ("HOPE_CACHE_URL", cache_url.as_str()),
+            ("HOPE_MAX_REMOTE_PUSH_SIZE", "1K"),
+        ],
+    );
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if-").len(), 1);
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+}
+
+#[test]
+fn background_pushes_finish_after_the_build_does() {
+    let cache_dir = CacheDir::new();
+    let env = [
+        ("HOPE_BACKGROUND_PUSH", "1"),
+        ("HOPE_FAIL_POINT", "push_crate:delay_ms=2000"),
+    ];
+    let package_a = Package::with_env(&cache_dir, &env);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    // Cargo didn't wait for the push...
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if-").len(), 1);
+    assert!(filter_push_crate_outputs_events(&log, "cfg_if-").is_empty());
+
+    // ...which still happens.
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    while filter_push_crate_outputs_events(&cache_dir.read_log().unwrap(), "cfg_if-").is_empty() {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "Background push never finished"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+}
+
+#[test]
+fn background_pushes_push_what_was_built() {
+    let cache_dir = CacheDir::new();
+    let env = [
+        ("HOPE_BACKGROUND_PUSH", "1"),
+        ("HOPE_FAIL_POINT", "background_push:delay_ms=2000"),
+    ];
+    let package = Package::with_env(&cache_dir, &env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    // Change the outputs before the push gets going.
+    let rlib_in = |dir: &Path| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                let file_name = path.file_name().unwrap().to_str().unwrap();
+                file_name.starts_with("libcfg_if-") && file_name.ends_with(".rlib")
+            })
+    };
+    let built_path = rlib_in(&package.dir.path().join("target/debug/deps")).unwrap();
+    let built = std::fs::read(&built_path).unwrap();
+    std::fs::write(&built_path, b"not what was built").unwrap();
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    while filter_push_crate_outputs_events(&cache_dir.read_log().unwrap(), "cfg_if-").is_empty() {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "Background push never finished"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let pushed = std::fs::read(rlib_in(cache_dir.dir.path()).unwrap()).unwrap();
+    assert!(pushed == built, "Pushed rlib isn't what was built");
+}
+
+#[test]
+fn async_pushes_wait_in_a_queue_until_flushed() {
+    let shared_dir = CacheDir::new();
+    let cache_url = format!("file://{}", shared_dir.dir.path().display());
+    let env = [
+        ("HOPE_CACHE_URL", cache_url.as_str()),
+        ("HOPE_ASYNC_PUSH", "1"),
+    ];
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(&cache_dir, &env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    let log = cache_dir.read_log().unwrap();
+    let pushes = filter_push_crate_outputs_events(&log, "cfg_if-");
+    assert_eq!(pushes.len(), 1);
+    assert_eq!(pushes[0].copied_from, "upload queue for main");
+    assert!(shared_dir.entry_manifests("cfg_if").is_empty());
+
+    let flush = || {
+        let output = cache_dir
+            .hope()
+            .arg("flush")
+            .env("HOPE_CACHE_URL", &cache_url)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert!(flush().contains("for 1 entries"));
+    assert_eq!(shared_dir.entry_manifests("cfg_if").len(), 1);
+    // There's nothing left to upload the second time.
+    assert!(!flush().contains("for 1 entries"));
+
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &[("HOPE_CACHE_URL", &cache_url)]);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir_b.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+}
+
+#[test]
+fn read_only_builds_leave_pushes_in_a_backlog() {
+    let shared_dir = CacheDir::new();
+    let cache_url = format!("file://{}", shared_dir.dir.path().display());
+    let cache_dir = CacheDir::new();
+    let read_only_env = [
+        ("HOPE_CACHE_URL", cache_url.as_str()),
+        ("HOPE_READ_ONLY", "1"),
+    ];
+    let package_a = Package::with_env(&cache_dir, &read_only_env);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    let log = cache_dir.read_log().unwrap();
+    assert!(filter_push_crate_outputs_events(&log, "cfg_if-").is_empty());
+    assert!(log.iter().any(|line| matches!(
+        line,
+        CacheLogLine::SkippedPush(event)
+            if event.crate_unit_name.starts_with("cfg_if-")
+                && event.reason == "read-only mode; saved for `hope push-backlog`"
+    )));
+    assert!(shared_dir.entry_manifests("cfg_if").is_empty());
+
+    let push_backlog = || {
+        let output = cache_dir
+            .hope()
+            .arg("push-backlog")
+            .env("HOPE_CACHE_URL", &cache_url)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert!(push_backlog().starts_with("Pushed 1 entries"));
+    assert_eq!(shared_dir.entry_manifests("cfg_if").len(), 1);
+    assert!(push_backlog().starts_with("Pushed 0 entries"));
+
+    // Outputs that have gone from the target dir since can't be pushed.
+    let package_b = Package::with_env(&cache_dir, &read_only_env);
+    package_b.add("itoa@1.0.16");
+    package_b.build();
+    std::fs::remove_dir_all(package_b.dir.path().join("target")).unwrap();
+    assert!(push_backlog().contains("1 had changed or were gone"));
+    assert!(shared_dir.entry_manifests("itoa").is_empty());
+}
+
+#[test]
+fn pushes_fan_out_to_every_cache_that_will_take_them() {
+    let shared_dir = CacheDir::new();
+    let also_push_to = format!(
+        "http://127.0.0.1:1/nobody-home, file://{}",
+        shared_dir.dir.path().display()
+    );
+    let cache_dir = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir, &[("HOPE_ALSO_PUSH_TO", &also_push_to)]);
+    // One with a build script, so that its output gets pushed everywhere too.
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    // The server that isn't there doesn't stop the rest getting the entry.
+    let log = cache_dir.read_log().unwrap();
+    let pushes = filter_push_crate_outputs_events(&log, "anyhow-");
+    assert_eq!(pushes.len(), 2);
+    assert_eq!(pushes[0].copied_from, "local cache");
+    assert_eq!(
+        pushes[1].copied_from,
+        format!("file://{}/", shared_dir.dir.path().display())
+    );
+    assert_eq!(cache_dir.entry_manifests("anyhow").len(), 1);
+    assert_eq!(shared_dir.entry_manifests("anyhow").len(), 1);
+
+    // Either one is enough for another build to pull everything from.
+    let cache_url = format!("file://{}", shared_dir.dir.path().display());
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &[("HOPE_CACHE_URL", &cache_url)]);
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+    let log = cache_dir_b.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "anyhow-").is_empty());
+    assert!(filter_ran_build_script_events(&log, "anyhow").is_empty());
+}
+
+#[test]
+fn pulls_fall_back_through_caches_in_order() {
+    let empty_dir = CacheDir::new();
+    let team_dir = CacheDir::new();
+    let empty_url = format!("file://{}", empty_dir.dir.path().display());
+    let team_url = format!("file://{}", team_dir.dir.path().display());
+    let package_a = Package::with_env(&CacheDir::new(), &[("HOPE_CACHE_URL", &team_url)]);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    // Neither the local cache nor the first fallback has it, but the second does.
+    let also_pull_from = format!("{empty_url},{team_url}");
+    let cache_dir = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir, &[("HOPE_ALSO_PULL_FROM", &also_pull_from)]);
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "anyhow-").is_empty());
+    assert!(filter_ran_build_script_events(&log, "anyhow").is_empty());
+    let pulls = filter_pull_crate_outputs_events(&log, "anyhow-");
+    assert!(!pulls.is_empty());
+    for pull in pulls {
+        assert_eq!(pull.copied_from, format!("{team_url}/"));
+    }
+    // Pushes still only go to the main cache.
+    assert!(empty_dir.entry_manifests("anyhow").is_empty());
+}
+
+#[test]
+fn pulls_from_further_back_can_backfill_nearer_caches() {
+    let mirror_dir = CacheDir::new();
+    let global_dir = CacheDir::new();
+    let mirror_url = format!("file://{}", mirror_dir.dir.path().display());
+    let global_url = format!("file://{}", global_dir.dir.path().display());
+    let package_a = Package::with_env(&CacheDir::new(), &[("HOPE_CACHE_URL", &global_url)]);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    let package_b = Package::with_env(
+        &CacheDir::new(),
+        &[
+            ("HOPE_CACHE_URL", &mirror_url),
+            ("HOPE_ALSO_PULL_FROM", &global_url),
+            ("HOPE_BACKFILL", "1"),
+        ],
+    );
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+    assert_eq!(mirror_dir.entry_manifests("anyhow").len(), 1);
+
+    // Now the mirror has everything (build script output included) on its own.
+    let cache_dir = CacheDir::new();
+    let package_c = Package::with_env(&cache_dir, &[("HOPE_CACHE_URL", &mirror_url)]);
+    package_c.add("anyhow@1.0.0");
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "anyhow-").is_empty());
+    assert!(filter_ran_build_script_events(&log, "anyhow").is_empty());
+    for pull in filter_pull_crate_outputs_events(&log, "anyhow-") {
+        assert_eq!(pull.copied_from, format!("{mirror_url}/"));
+    }
+}
+
+#[test]
+fn rustc_args_parse_the_way_rustc_would() {
+    // Fields of Hope's parsed `Args`, and the options they come from.
+    const FIELDS: &[(&str, &str)] = &[
+        ("crate_name", "crate-name"),
+        ("edition", "edition"),
+        ("crate_types", "crate-type"),
+        ("emit", "emit"),
+        ("cfg", "cfg"),
+        ("check_cfg", "check-cfg"),
+        ("codegen_options", "C"),
+        ("lib_search_paths", "L"),
+        ("link_to_native_libs", "l"),
+        ("extern", "extern"),
+        ("unstable_options", "Z"),
+        ("remap_path_prefixes", "remap-path-prefix"),
+        ("target", "target"),
+        ("out_dir", "out-dir"),
+        ("cap_lints", "cap-lints"),
+        ("json", "json"),
+        ("test", "test"),
+    ];
+
+    let cache_dir = CacheDir::new();
+    let corpus_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/rustc-args");
+    let mut corpus: Vec<PathBuf> = std::fs::read_dir(&corpus_dir)
+        .unwrap()
+        .map(|dir_entry| dir_entry.unwrap().path())
+        .collect();
+    corpus.sort();
+    assert!(!corpus.is_empty());
+    for path in corpus {
+        let rustc_args: Vec<String> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        let output = cache_dir
+            .hope()
+            .args(["key", "--json", "--"])
+            .args(&rustc_args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{path:?}");
+        let key: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let parsed = &key["args"];
+
+        let (input, expected) = rustc_arg_values(&rustc_args);
+        assert_eq!(parsed["input"].as_str(), input.as_deref(), "{path:?}");
+        for (field, option) in FIELDS {
+            let expected = expected.get(*option).cloned().unwrap_or_default();
+            let actual: Vec<String> = match &parsed[*field] {
+                serde_json::Value::Null | serde_json::Value::Bool(false) => Vec::new(),
+                serde_json::Value::Bool(true) => vec![String::new()],
+                serde_json::Value::String(value) => vec![value.clone()],
+                serde_json::Value::Array(values) => values
+                    .iter()
+                    .map(|value| value.as_str().unwrap().to_owned())
+                    .collect(),
+                other => panic!("Unexpected {field} in {path:?}: {other}"),
+            };
+            assert_eq!(actual, expected, "{field} in {path:?}");
+        }
+    }
+}
+
+#[test]
+fn key_command_matches_key_used_by_build() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    let output = package
+        .cargo()
+        .args(["build", "-v"])
+        .current_dir(package.dir.path())
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // e.g. "Running `/path/to/hope /path/to/rustc --crate-name cfg_if ...`"
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let invocation = stderr
+        .lines()
+        .find(|line| line.contains("--crate-name cfg_if "))
+        .unwrap();
+    let mut rustc_args = shell_words(
+        invocation
+            .trim()
+            .trim_start_matches("Running `")
+            .trim_end_matches('`'),
+    );
+    // Skip past hope itself.
+    rustc_args.remove(0);
+
+    let output = cache_dir
+        .hope()
+        .args(["key", "--json", "--"])
+        .args(&rustc_args)
+        .env("CARGO_PKG_NAME", "cfg-if")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let key: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let storage_name = key["storage_name"].as_str().unwrap();
+    assert!(cache_dir.entry_manifest_paths("cfg_if")[0]
+        .to_str()
+        .unwrap()
+        .ends_with(&format!("/{storage_name}.manifest.json")));
+    assert!(key["alias_name"].is_null());
+
+    // Other policies show what they leave out.
+    let output = cache_dir
+        .hope()
+        .args(["key", "--json", "--"])
+        .args(&rustc_args)
+        .env("HOPE_KEY_POLICY", "relaxed")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let key: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(key["storage_name"], storage_name);
+    assert!(key["alias_name"].is_string());
+    let debuginfo = key["components"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|component| {
+            component["name"] == "codegen"
+                && component["value"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("debuginfo=")
+        })
+        .unwrap();
+    assert_eq!(debuginfo["included"], false);
+}
+
+#[test]
+fn shards_split_a_cache_between_stores() {
+    let shard_dirs = [CacheDir::new(), CacheDir::new()];
+    let shard_urls: Vec<String> = shard_dirs
+        .iter()
+        .map(|shard_dir| format!("file://{}", shard_dir.dir.path().display()))
+        .collect();
+    let shards = shard_urls.join(",");
+
+    let cache_dir_a = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir_a, &[("HOPE_CACHE_SHARDS", &shards)]);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    // Which shard a blob is on doesn't depend on the order they're listed in.
+    let reversed_shards = format!("{},{}", shard_urls[1], shard_urls[0]);
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &[("HOPE_CACHE_SHARDS", &reversed_shards)]);
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+
+    let log = cache_dir_b.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "anyhow-").is_empty());
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow-").len(), 1);
+
+    // Every blob is on exactly one shard.
+    let blobs_in = |shard_dir: &CacheDir| -> Vec<PathBuf> {
+        walkdir::WalkDir::new(shard_dir.dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| {
+                entry
+                    .path()
+                    .strip_prefix(shard_dir.dir.path())
+                    .unwrap()
+                    .to_owned()
+            })
+            .filter(|path| !path.to_string_lossy().starts_with('.'))
+            .collect()
+    };
+    let blobs_0 = blobs_in(&shard_dirs[0]);
+    let blobs_1 = blobs_in(&shard_dirs[1]);
+    assert!(!blobs_0.is_empty() || !blobs_1.is_empty());
+    for blob in &blobs_0 {
+        assert!(!blobs_1.contains(blob), "{blob:?}");
+    }
+    let manifests = shard_dirs[0].entry_manifests("anyhow").len()
+        + shard_dirs[1].entry_manifests("anyhow").len();
+    assert_eq!(manifests, 1);
+}
+
+#[test]
+fn recorded_invocations_can_be_replayed() {
+    let cache_dir = CacheDir::new();
+    let recordings_dir = tempdir().unwrap();
+    let recordings = recordings_dir.path().to_str().unwrap();
+    let package = Package::with_env(&cache_dir, &[("HOPE_RECORD_INVOCATIONS", recordings)]);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    let recording = std::fs::read_dir(recordings_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .starts_with("cfg_if-")
+        })
+        .expect("cfg-if's invocation should have been recorded");
+    let invocation: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&recording).unwrap()).unwrap();
+    assert!(invocation["args"]
+        .as_array()
+        .unwrap()
+        .contains(&"--crate-name".into()));
+    assert_eq!(invocation["env"]["CARGO_PKG_NAME"], "cfg-if");
+    assert!(invocation["env"]["HOPE_RECORD_INVOCATIONS"].is_null());
+
+    // Replaying it goes through Hope again, and finds it in the cache this time.
+    let recordings_before = std::fs::read_dir(recordings_dir.path()).unwrap().count();
+    let output = cache_dir
+        .hope()
+        .arg("replay")
+        .arg(&recording)
+        .env("HOPE_RECORD_INVOCATIONS", recordings)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if-").len(), 1);
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+    assert_eq!(
+        std::fs::read_dir(recordings_dir.path()).unwrap().count(),
+        recordings_before
+    );
+}
+
+#[test]
+fn hope_server_keeps_namespaces_apart_and_counts_usage() {
+    let server_dir = tempdir().unwrap();
+    let new_token = |name: &str, namespaces: serde_json::Value| {
+        let output = Command::new(SERVER_PATH)
+            .args(["new-token", "--name", name])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let token = String::from_utf8(output.stdout).unwrap().trim().to_owned();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let mut entry: serde_json::Value =
+            serde_json::from_str(stderr.split_once('\n').unwrap().1).unwrap();
+        entry["namespaces"] = namespaces;
+        (token, entry)
+    };
+    let (ci_token, ci_entry) = new_token("ci", serde_json::json!({ "my-project": "write" }));
+    let (dev_token, dev_entry) = new_token("dev", serde_json::json!({ "*": "read" }));
+    let tokens_path = server_dir.path().join("tokens.json");
+    std::fs::write(
+        &tokens_path,
+        serde_json::json!({ "tokens": [ci_entry, dev_entry] }).to_string(),
+    )
+    .unwrap();
+    let root = server_dir.path().join("root");
+    let server = CacheServer::spawn_command(
+        Command::new(SERVER_PATH)
+            .args(["serve", "--listen", "127.0.0.1:0", "--tokens"])
+            .arg(&tokens_path)
+            .arg("--root")
+            .arg(&root),
+        "Listening on ",
+    );
+    let cache_url = format!("http://{}/ns/my-project/blobs", server.addr);
+    let build = |token: Option<&str>| -> CacheDir {
+        let cache_dir = CacheDir::new();
+        let mut env = vec![("HOPE_CACHE_URL", cache_url.as_str())];
+        env.extend(token.map(|token| ("HOPE_CACHE_TOKEN", token)));
+        let package = Package::with_env(&cache_dir, &env);
+        package.add("cfg-if@1.0.0");
+        package.build();
+        cache_dir
+    };
+
+    // Without a token, there's no cache at all, but the build still works.
+    let log = build(None).read_log().unwrap();
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if-").len(), 1);
+    assert!(!root.join("my-project").exists());
+
+    // CI can push, and developers can pull but not push.
+    build(Some(&ci_token));
+    let log = build(Some(&dev_token)).read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "cfg_if-").is_empty());
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+    let status_of = |result: Result<ureq::Response, ureq::Error>| match result {
+        Ok(response) => response.status(),
+        Err(ureq::Error::Status(status, _)) => status,
+        Err(err) => panic!("Request failed: {err}"),
+    };
+    let put = |namespace: &str, token: &str| {
+        status_of(
+            ureq::put(&format!(
+                "http://{}/ns/{namespace}/blobs/chunks/abc",
+                server.addr
+            ))
+            .set("authorization", &format!("Bearer {token}"))
+            .send_bytes(b"hello"),
+        )
+    };
+    assert_eq!(put("my-project", &dev_token), 403);
+    assert_eq!(put("other-project", &ci_token), 403);
+    assert_eq!(put("my-project", "hope_not-a-real-token"), 401);
+    assert_eq!(put(".usage.json", &ci_token), 400);
+
+    let usage = ureq::get(&format!("http://{}/ns/my-project/usage", server.addr))
+        .set("authorization", &format!("Bearer {ci_token}"))
+        .call()
+        .unwrap()
+        .into_string()
+        .unwrap();
+    let usage: serde_json::Value = serde_json::from_str(&usage).unwrap();
+    assert!(usage["stored_blobs"].as_u64().unwrap() > 0);
+    assert!(usage["by_token"]["ci"]["writes"].as_u64().unwrap() > 0);
+    assert!(usage["by_token"]["dev"]["reads"].as_u64().unwrap() > 0);
+    assert_eq!(usage["by_token"]["dev"]["writes"], 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn credentials_come_from_the_keyring_or_the_credentials_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server_dir = tempdir().unwrap();
+    let new_token = |name: &str, namespaces: serde_json::Value| {
+        let output = Command::new(SERVER_PATH)
+            .args(["new-token", "--name", name])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let token = String::from_utf8(output.stdout).unwrap().trim().to_owned();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let mut entry: serde_json::Value =
+            serde_json::from_str(stderr.split_once('\n').unwrap().1).unwrap();
+        entry["namespaces"] = namespaces;
+        (token, entry)
+    };
+    let (ci_token, ci_entry) = new_token("ci", serde_json::json!({ "my-project": "write" }));
+    let (dev_token, dev_entry) = new_token("dev", serde_json::json!({ "*": "read" }));
+    let tokens_path = server_dir.path().join("tokens.json");
+    std::fs::write(
+        &tokens_path,
+        serde_json::json!({ "tokens": [ci_entry, dev_entry] }).to_string(),
+    )
+    .unwrap();
+    let root = server_dir.path().join("root");
+    let server = CacheServer::spawn_command(
+        Command::new(SERVER_PATH)
+            .args(["serve", "--listen", "127.0.0.1:0", "--tokens"])
+            .arg(&tokens_path)
+            .arg("--root")
+            .arg(&root),
+        "Listening on ",
+    );
+    let cache_url = format!("http://{}/ns/my-project/blobs", server.addr);
+
+    // A stand-in for the Secret Service's `secret-tool`,
+    // keeping secrets in files named for their URLs.
+    let keyring_dir = tempdir().unwrap();
+    let bin_dir = tempdir().unwrap();
+    let secret_tool = bin_dir.path().join("secret-tool");
+    std::fs::write(
+        &secret_tool,
+        format!(
+            "#!/bin/sh\n\
+             file=\"{}/$(echo \"$5\" | tr -c 'a-z0-9\\n' _)\"\n\
+             case \"$1\" in\n\
+               store) file=\"{}/$(echo \"$7\" | tr -c 'a-z0-9\\n' _)\"; cat > \"$file\" ;;\n\
+               lookup) cat \"$file\" ;;\n\
+               clear) rm \"$file\" ;;\n\
+             esac\n",
+            keyring_dir.path().display(),
+            keyring_dir.path().display(),
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&secret_tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", bin_dir.path().display(), env::var("PATH").unwrap());
+    let credentials_file = server_dir.path().join("credentials");
+    let credentials_file = credentials_file.to_str().unwrap();
+    let env = [
+        ("HOPE_CACHE_URL", cache_url.as_str()),
+        ("HOPE_CREDENTIALS_FILE", credentials_file),
+        ("PATH", path.as_str()),
+    ];
+
+    // `hope login` saves the CI token in the keyring, and builds use it
+    // if they're allowed to look there.
+    let mut login = Command::new(WRAPPER_PATH)
+        .args(["login", &cache_url])
+        .envs(env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    writeln!(login.stdin.take().unwrap(), "{ci_token}").unwrap();
+    assert!(login.wait().unwrap().success());
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(&cache_dir, &env);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    assert!(!root.join("my-project").exists());
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(
+        &cache_dir,
+        &[env[0], env[1], env[2], ("HOPE_KEYRING_IN_BUILDS", "1")],
+    );
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if-").len(), 1);
+    assert!(root.join("my-project").exists());
+
+    // The credentials file comes first, for the longest prefix of the URL,
+    // but only if nobody else can read it.
+    std::fs::write(
+        credentials_file,
+        format!(
+            "[http://{addr}]\ntoken = not-this-one\n\n\
+             [http://{addr}/ns/my-project]\ntoken = {dev_token}\n\n\
+             [http://{addr}/ns/my-project/blobs-elsewhere]\ntoken = nor-this-one\n",
+            addr = server.addr
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(credentials_file, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(&cache_dir, &env);
+    package.add("cfg-if@1.0.0");
+    let output = package
+        .cargo()
+        .arg("build")
+        .current_dir(package.dir.path())
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("other users can read it"));
+
+    std::fs::set_permissions(credentials_file, std::fs::Permissions::from_mode(0o600)).unwrap();
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "cfg_if-").is_empty());
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 1);
+    let usage = ureq::get(&format!("http://{}/ns/my-project/usage", server.addr))
+        .set("authorization", &format!("Bearer {ci_token}"))
+        .call()
+        .unwrap()
+        .into_string()
+        .unwrap();
+    let usage: serde_json::Value = serde_json::from_str(&usage).unwrap();
+    assert!(usage["by_token"]["dev"]["reads"].as_u64().unwrap() > 0);
+
+    // `hope logout` forgets the keyring's.
+    let status = Command::new(WRAPPER_PATH)
+        .args(["logout", &cache_url])
+        .envs(env)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(std::fs::read_dir(keyring_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn redis_works_as_remote_cache_with_ttl() {
+    let redis = FakeRedis::start();
+    let cache_url = format!("redis://{}/2", redis.addr);
+    let env = [
+        ("HOPE_CACHE_URL", cache_url.as_str()),
+        ("HOPE_REDIS_TTL_SECS", "3600"),
+    ];
+
+    let cache_dir_a = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir_a, &env);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &env);
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+
+    let log = cache_dir_b.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "anyhow-").is_empty());
+    let pulls = filter_pull_crate_outputs_events(&log, "anyhow-");
+    assert_eq!(pulls.len(), 1);
+    assert_eq!(pulls[0].copied_from, cache_url);
+
+    // Artifacts and build script output alike expire if they stop being used.
+    let ttls = redis.ttls();
+    assert!(ttls.keys().any(|key| key.starts_with("hope:build-script-")));
+    for (key, ttl) in &ttls {
+        assert!(key.starts_with("hope:"));
+        assert_eq!(*ttl, Some(3600), "{key}");
+    }
+}
+
+#[test]
+fn github_actions_cache_works_as_remote_cache() {
+    let gha_cache = FakeGhaCache::start();
+    let env = [
+        ("HOPE_CACHE_URL", "gha://test-scope"),
+        ("ACTIONS_CACHE_URL", gha_cache.url.as_str()),
+        ("ACTIONS_RUNTIME_TOKEN", FakeGhaCache::TOKEN),
+    ];
+
+    let cache_dir_a = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir_a, &env);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &env);
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+
+    let log = cache_dir_b.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "anyhow-").is_empty());
+    let pulls = filter_pull_crate_outputs_events(&log, "anyhow-");
+    assert_eq!(pulls.len(), 1);
+    assert_eq!(pulls[0].copied_from, "gha://test-scope/");
+
+    let keys = gha_cache.committed_keys();
+    assert!(keys
+        .iter()
+        .any(|key| key.starts_with("hope/test-scope/anyhow-") && key.ends_with(".manifest.json")));
+    assert!(keys.iter().all(|key| key.starts_with("hope/test-scope/")));
+}
+
+#[test]
+fn bazel_remote_cache_works_as_remote_cache() {
+    let bazel = FakeBazelCache::start();
+    let cache_url = format!("bazel+{}/some/prefix", bazel.url);
+    let env = [("HOPE_CACHE_URL", cache_url.as_str())];
+
+    let cache_dir_a = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir_a, &env);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &env);
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+
+    let log = cache_dir_b.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "anyhow-").is_empty());
+    let pulls = filter_pull_crate_outputs_events(&log, "anyhow-");
+    assert_eq!(pulls.len(), 1);
+    assert_eq!(pulls[0].copied_from, format!("{cache_url}/"));
+
+    // Everything is either content or an action result pointing at it.
+    let paths = bazel.paths();
+    assert!(paths
+        .iter()
+        .any(|path| path.starts_with("/some/prefix/cas/")));
+    assert!(paths
+        .iter()
+        .any(|path| path.starts_with("/some/prefix/ac/")));
+    for path in &paths {
+        assert!(
+            path.starts_with("/some/prefix/cas/") || path.starts_with("/some/prefix/ac/"),
+            "{path}"
+        );
+    }
+}
+
+#[test]
+fn public_cache_is_pulled_from_without_credentials() {
+    // CI pushes to the bucket, with credentials.
+    let s3 = FakeS3::start();
+    let publisher_cache_dir = CacheDir::new();
+    let publisher = Package::with_env(
+        &publisher_cache_dir,
+        &[
+            ("HOPE_CACHE_URL", "s3://test-bucket/some/prefix"),
+            ("HOPE_S3_ENDPOINT", s3.url.as_str()),
+            ("AWS_ACCESS_KEY_ID", "minioadmin"),
+            ("AWS_SECRET_ACCESS_KEY", "minioadmin"),
+        ],
+    );
+    publisher.add("cfg-if@1.0.0");
+    publisher.build();
+
+    // Anyone else pulls from it over plain HTTP(S), and pushes locally.
+    let requests_before = s3.requests.lock().unwrap().len();
+    let public_url = format!("{}/test-bucket/some/prefix", s3.url);
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(
+        &cache_dir,
+        &[
+            ("HOPE_PUBLIC_CACHE_URL", public_url.as_str()),
+            ("HOPE_CACHE_TOKEN", "not-for-the-public"),
+        ],
+    );
+    package.add("cfg-if@1.0.0");
+    package.add("itoa@1.0.16");
+    package.build();
+
+    let log = cache_dir.read_log().unwrap();
+    let pulls = filter_pull_crate_outputs_events(&log, "cfg_if-");
+    assert_eq!(pulls.len(), 1);
+    assert_eq!(pulls[0].copied_from, format!("{public_url}/"));
+    assert_eq!(filter_compile_crate_events(&log, "itoa-").len(), 1);
+    assert_eq!(cache_dir.entry_manifests("itoa").len(), 1);
+    let requests = s3.requests.lock().unwrap();
+    assert!(requests.len() > requests_before);
+    for (path, authorization) in &requests[requests_before..] {
+        assert!(authorization.is_empty(), "{path} had credentials");
+    }
+}
+
+#[test]
+fn s3_compatible_store_works_as_remote_cache() {
+    let s3 = FakeS3::start();
+    let env = [
+        ("HOPE_CACHE_URL", "s3://test-bucket/some/prefix"),
+        ("HOPE_S3_ENDPOINT", s3.url.as_str()),
+        ("HOPE_S3_REGION", "home-lab-1"),
+        ("AWS_ACCESS_KEY_ID", "minioadmin"),
+        ("AWS_SECRET_ACCESS_KEY", "minioadmin"),
+    ];
+
+    let cache_dir_a = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir_a, &env);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &env);
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+
+    let log = cache_dir_b.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "anyhow-").is_empty());
+    let pulls = filter_pull_crate_outputs_events(&log, "anyhow-");
+    assert_eq!(pulls.len(), 1);
+    assert_eq!(pulls[0].copied_from, "s3://test-bucket/some/prefix/");
+
+    // The bucket goes in the path, since there's no DNS for it,
+    // and requests are signed for the region we asked for.
+    let requests = s3.requests.lock().unwrap();
+    assert!(!requests.is_empty());
+    for (path, authorization) in requests.iter() {
+        assert!(path.starts_with("/test-bucket/some/prefix/"), "{path}");
+        assert!(
+            authorization.contains("/home-lab-1/s3/aws4_request"),
+            "{authorization}"
+        );
+    }
+}
+
+#[test]
+fn sync_copies_entries_between_caches() {
+    let s3 = FakeS3::start();
+    let s3_env = [
+        ("HOPE_S3_ENDPOINT", s3.url.as_str()),
+        ("AWS_ACCESS_KEY_ID", "minioadmin"),
+        ("AWS_SECRET_ACCESS_KEY", "minioadmin"),
+    ];
+    let bucket_url = "s3://test-bucket/some/prefix";
+
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("anyhow@1.0.0");
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    // Local to S3, for just one crate.
+    let output = cache_dir
+        .hope()
+        .envs(s3_env)
+        .args(["sync", "--from", "local", "--to", bucket_url])
+        .args(["--filter", "anyhow"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("for 1 entries and 1 build script runs"),
+        "{stdout}"
+    );
+
+    // S3 to a shared dir; everything in the bucket comes along.
+    let shared_dir = CacheDir::new();
+    let shared_url = format!("file://{}", shared_dir.dir.path().display());
+    let output = cache_dir
+        .hope()
+        .envs(s3_env)
+        .args(["sync", "--from", bucket_url, "--to", &shared_url])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(shared_dir.entry_manifests("anyhow").len(), 1);
+    assert!(shared_dir.entry_manifests("cfg_if").is_empty());
+
+    // Which is then as good as if it had been built with that cache.
+    let cache_dir_b = CacheDir::new();
+    let package_b = Package::with_env(&cache_dir_b, &[("HOPE_CACHE_URL", &shared_url)]);
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+    let log = cache_dir_b.read_log().unwrap();
+    assert!(filter_compile_crate_events(&log, "anyhow-").is_empty());
+    assert!(filter_ran_build_script_events(&log, "anyhow").is_empty());
+
+    // Syncing again has nothing left to do.
+    let output = cache_dir
+        .hope()
+        .envs(s3_env)
+        .args(["sync", "--from", bucket_url, "--to", &shared_url])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("Copied 0 blobs"));
+}
+
+#[test]
+fn stats_export_aggregate_counts_hits_and_compiles() {
+    let cache_dir = CacheDir::new();
+    for _ in 0..2 {
+        let package = Package::new(&cache_dir);
+        package.add("cfg-if@1.0.0");
+        package.build();
+    }
+
+    let output = cache_dir
+        .hope()
+        .args(["stats", "--export-aggregate"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let aggregate: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let crates = aggregate["crates"].as_array().unwrap();
+    let cfg_if = crates
+        .iter()
+        .find(|stats| stats["crate_name"] == "cfg_if")
+        .unwrap();
+    assert_eq!(cfg_if["hits"], 1);
+    assert_eq!(cfg_if["compiles"], 1);
+
+    // Nothing machine-specific should leak into it.
+    let text = String::from_utf8(output.stdout).unwrap();
+    assert!(!text.contains(cache_dir.dir.path().to_str().unwrap()));
+    assert!(!text.contains("cfg_if-"));
+}
+
+#[test]
+fn stats_merge_adds_up_logs_from_several_cache_dirs() {
+    // One machine compiles cfg-if and then gets a hit; another just compiles it.
+    let machine_a = CacheDir::new();
+    for _ in 0..2 {
+        let package = Package::new(&machine_a);
+        package.add("cfg-if@1.0.0");
+        package.build();
+    }
+    let machine_b = CacheDir::new();
+    let package = Package::new(&machine_b);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    // From somewhere with no cache of its own.
+    let elsewhere = CacheDir::new();
+    let output = elsewhere
+        .hope()
+        .args(["stats", "--merge"])
+        .args([machine_a.dir.path(), machine_b.dir.path()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Merged logs from 2 cache dirs: 1 hits and 2 compiles"));
+    let cfg_if_row: Vec<&str> = stdout
+        .lines()
+        .find(|line| line.starts_with("cfg_if "))
+        .unwrap()
+        .split_whitespace()
+        .collect();
+    assert_eq!(cfg_if_row[1..3], ["1", "2"]);
+
+    // A dir with no log in it is a mistake worth hearing about.
+    let output = elsewhere
+        .hope()
+        .args(["stats", "--merge"])
+        .args([machine_a.dir.path(), elsewhere.dir.path()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn stats_sccache_compat_looks_like_sccache() {
+    let cache_dir = CacheDir::new();
+    for _ in 0..2 {
+        let package = Package::new(&cache_dir);
+        package.add("cfg-if@1.0.0");
+        package.build();
+    }
+
+    let output = cache_dir
+        .hope()
+        .args(["stats", "--sccache-compat"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let text = String::from_utf8(output.stdout).unwrap();
+    // What scrapers usually look for: a name, then a number, then maybe a unit.
+    let stat = |name: &str| {
+        text.lines()
+            .find_map(|line| {
+                let value = line.strip_prefix(name)?;
+                value.starts_with("  ").then(|| value.trim().to_owned())
+            })
+            .unwrap()
+    };
+    assert_eq!(stat("Compile requests"), "2");
+    assert_eq!(stat("Cache hits"), "1");
+    assert_eq!(stat("Cache hits (Rust)"), "1");
+    assert_eq!(stat("Cache misses"), "1");
+    assert!(stat("Average compiler").ends_with(" s"));
+    assert!(stat("Cache location").starts_with("Local disk: "));
+    assert_eq!(stat("Version (client)"), env!("CARGO_PKG_VERSION"));
+
+    // Stats line up in a column, as in sccache.
+    let ends: Vec<usize> = text
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter(|line| !line.ends_with(" s"))
+        .map(str::len)
+        .collect();
+    assert!(ends.windows(2).all(|pair| pair[0] == pair[1]));
+}
+
+#[test]
+fn purge_removes_entries_for_channel() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+    let manifests = cache_dir.entry_manifests("cfg_if");
+    assert_eq!(manifests.len(), 1);
+    let channel = manifests[0]["channel"].as_str().unwrap().to_owned();
+
+    // Purging some other channel shouldn't touch it.
+    let other_channel = if channel == "nightly" {
+        "stable"
+    } else {
+        "nightly"
+    };
+    assert!(cache_dir
+        .hope()
+        .args(["purge", "--channel", other_channel])
+        .stdout(Stdio::null())
+        .status()
+        .unwrap()
+        .success());
+    assert_eq!(cache_dir.entry_manifests("cfg_if").len(), 1);
+
+    assert!(cache_dir
+        .hope()
+        .args(["purge", "--channel", &channel])
+        .stdout(Stdio::null())
+        .status()
+        .unwrap()
+        .success());
+    assert_eq!(cache_dir.entry_manifests("cfg_if").len(), 0);
+
+    // So the next package has to build it again.
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 2);
+}
+
+#[test]
+#[cfg_attr(
+    not(debug_assertions),
+    ignore = "fail points are compiled out of release builds"
+)]
+fn failed_pulls_fall_back_to_rustc() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    let package_b = Package::with_env(&cache_dir, &[("HOPE_FAIL_POINT", "pull_crate:io_error")]);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if").len(), 2);
+}
+
+#[test]
+#[cfg_attr(
+    not(debug_assertions),
+    ignore = "fail points are compiled out of release builds"
+)]
+fn failed_pushes_do_not_fail_the_build() {
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(&cache_dir, &[("HOPE_FAIL_POINT", "push_crate:io_error")]);
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if").len(), 1);
+}
+
+#[test]
+#[cfg_attr(
+    not(debug_assertions),
+    ignore = "fail points are compiled out of release builds"
+)]
+fn slow_pulls_are_abandoned() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    let package_b = Package::with_env(
+        &cache_dir,
+        &[
+            ("HOPE_FAIL_POINT", "pull_crate:delay_ms=5000"),
+            ("HOPE_PULL_BUDGET_MS", "100"),
+        ],
+    );
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    let abandoned = log
+        .iter()
+        .filter(|line| matches!(line, CacheLogLine::AbandonedPull(event) if event.crate_unit_name.starts_with("cfg_if")))
+        .count();
+    assert_eq!(abandoned, 1);
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if").len(), 2);
+    // It should still have pushed after building.
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 2);
+}
+
+#[test]
+fn builds_fall_back_to_the_local_cache_when_the_remote_one_is_down() {
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(
+        &cache_dir,
+        &[
+            ("HOPE_CACHE_URL", "http://127.0.0.1:1/nobody-home"),
+            ("HOPE_REMOTE_ERROR_LIMIT", "1"),
+        ],
+    );
+    package.add("cfg-if@1.0.0");
+    package.add("itoa@1.0.16");
+    // One unit at a time, so that the second one knows the first gave up.
+    assert!(package
+        .cargo()
+        .args(["build", "-j1"])
+        .current_dir(package.dir.path())
+        .status()
+        .unwrap()
+        .success());
+
+    let log = cache_dir.read_log().unwrap();
+    let fallbacks: Vec<_> = log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::FellBackToLocal(event) => Some(event),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(fallbacks.len(), 1);
+    assert!(fallbacks[0].location.contains("127.0.0.1:1"));
+    // The first unit's push waits for the remote cache to come back...
+    assert!(log.iter().any(|line| matches!(
+        line,
+        CacheLogLine::SkippedPush(event)
+            if event.reason.starts_with("Gave up on the remote cache")
+    )));
+    // ...and the second one went to the local cache instead.
+    assert_eq!(
+        cache_dir.entry_manifests("cfg_if").len() + cache_dir.entry_manifests("itoa").len(),
+        1
+    );
+
+    let stats = cache_dir.hope().arg("stats").output().unwrap();
+    assert!(String::from_utf8(stats.stdout)
+        .unwrap()
+        .contains("1 builds gave up on the remote cache"));
+}
+
+#[test]
+fn remote_caches_that_time_out_trip_the_circuit_breaker() {
+    let shared_dir = CacheDir::new();
+    let cache_url = format!("file://{}", shared_dir.dir.path().display());
+    let cache_dir = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir, &[("HOPE_CACHE_URL", cache_url.as_str())]);
+    package_a.add("cfg-if@1.0.0");
+    package_a.add("itoa@1.0.16");
+    package_a.build();
+
+    let package_b = Package::with_env(
+        &cache_dir,
+        &[
+            ("HOPE_CACHE_URL", cache_url.as_str()),
+            ("HOPE_FAIL_POINT", "pull_crate:delay_ms=3000"),
+            ("HOPE_PULL_BUDGET_MS", "100"),
+            ("HOPE_REMOTE_ERROR_LIMIT", "1"),
+        ],
+    );
+    package_b.add("cfg-if@1.0.0");
+    package_b.add("itoa@1.0.16");
+    assert!(package_b
+        .cargo()
+        .args(["build", "-j1"])
+        .current_dir(package_b.dir.path())
+        .status()
+        .unwrap()
+        .success());
+
+    let log = cache_dir.read_log().unwrap();
+    let fallbacks: Vec<_> = log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::FellBackToLocal(event) => Some(event),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(fallbacks.len(), 1);
+    assert!(fallbacks[0].last_error.contains("didn't finish within"));
+    let compiles = filter_compile_crate_events(&log, "cfg_if-").len()
+        + filter_compile_crate_events(&log, "itoa-").len();
+    assert_eq!(compiles, 4);
+    // The second unit used the local cache instead.
+    assert_eq!(
+        cache_dir.entry_manifests("cfg_if").len() + cache_dir.entry_manifests("itoa").len(),
+        1
+    );
+}
+
+#[test]
+fn uploads_keep_to_the_bandwidth_limit() {
+    let shared_dir = CacheDir::new();
+    let cache_url = format!("file://{}", shared_dir.dir.path().display());
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(
+        &cache_dir,
+        &[
+            ("HOPE_CACHE_URL", cache_url.as_str()),
+            ("HOPE_MAX_UPLOADS", "1"),
+            ("HOPE_UPLOAD_LIMIT", "64K"),
+        ],
+    );
+    package.add("cfg-if@1.0.0");
+    package.add("itoa@1.0.16");
+    let started = std::time::Instant::now();
+    package.build();
+    let elapsed = started.elapsed();
+
+    let uploaded: u64 = walkdir::WalkDir::new(shared_dir.dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert!(uploaded > 0);
+    let allowed = std::time::Duration::from_secs_f64(uploaded as f64 / (64 << 10) as f64);
+    assert!(
+        elapsed >= allowed,
+        "Uploaded {uploaded} bytes in {elapsed:?}, but should have taken at least {allowed:?}"
+    );
+
+    // There was only ever one slot to upload in.
+    let slots: Vec<_> = std::fs::read_dir(cache_dir.dir.path().join("transfer-slots"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(slots, ["upload-0.lock"]);
+}
+
+#[test]
+fn session_budget_stops_pulls_for_the_rest_of_the_build() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.add("itoa@1.0.16");
+    package_a.build();
+
+    // One slow pull is enough to use up the whole budget.
+    let package_b = Package::with_env(
+        &cache_dir,
+        &[
+            ("HOPE_FAIL_POINT", "pull_crate:delay_ms=500"),
+            ("HOPE_SESSION_BUDGET_SECS", "0.2"),
+        ],
+    );
+    package_b.add("cfg-if@1.0.0");
+    package_b.add("itoa@1.0.16");
+    // One unit at a time, so that the second one sees what the first spent.
+    assert!(package_b
+        .cargo()
+        .args(["build", "-j1"])
+        .current_dir(package_b.dir.path())
+        .status()
+        .unwrap()
+        .success());
+
+    let log = cache_dir.read_log().unwrap();
+    let pulls = filter_pull_crate_outputs_events(&log, "cfg_if-").len()
+        + filter_pull_crate_outputs_events(&log, "itoa-").len();
+    let compiles = filter_compile_crate_events(&log, "cfg_if-").len()
+        + filter_compile_crate_events(&log, "itoa-").len();
+    assert_eq!(pulls, 1);
+    assert_eq!(compiles, 3);
+    let exhausted = log
+        .iter()
+        .filter(|line| matches!(line, CacheLogLine::ExhaustedSessionBudget(_)))
+        .count();
+    assert_eq!(exhausted, 1);
+
+    let stats = cache_dir.hope().arg("stats").output().unwrap();
+    assert!(String::from_utf8(stats.stdout)
+        .unwrap()
+        .contains("1 builds ran out of cache budget"));
+}
+
+#[test]
+fn reading_the_log_does_not_wait_for_other_readers() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    // Hold a shared lock on the log, as another reader would.
+    let log_file = std::fs::File::open(cache_dir.dir.path().join("hope-log.jsonl")).unwrap();
+    let log_lock = fd_lock::RwLock::new(log_file);
+    let _read_guard = log_lock.read().unwrap();
+
+    let mut stats = cache_dir
+        .hope()
+        .arg("stats")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    let status = loop {
+        if let Some(status) = stats.try_wait().unwrap() {
+            break status;
+        }
+        if std::time::Instant::now() > deadline {
+            stats.kill().unwrap();
+            panic!("'hope stats' is waiting for another reader of the log");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    };
+    assert!(status.success());
+}
+
+#[test]
+fn stats_breaks_down_artifacts_by_kind() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    let output = cache_dir.hope().arg("stats").output().unwrap();
+    assert!(output.status.success());
+    let text = String::from_utf8(output.stdout).unwrap();
+    let count_for = |kind: &str| -> u64 {
+        let line = text
+            .lines()
+            .find(|line| line.starts_with(&format!("{kind} ")))
+            .unwrap();
+        line[kind.len()..]
+            .split_whitespace()
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    assert_eq!(count_for("rlib"), 1);
+    assert_eq!(count_for("rmeta"), 1);
+    assert_eq!(count_for("entry manifest"), 1);
+}
+
+#[test]
+fn completions_and_manpage_cover_subcommands() {
+    for shell in ["bash", "zsh", "fish"] {
+        let output = Command::new(WRAPPER_PATH)
+            .args(["completions", shell])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let script = String::from_utf8(output.stdout).unwrap();
+        assert!(script.contains("determinism-report"), "{shell}");
+    }
+
+    let output = Command::new(WRAPPER_PATH).arg("manpage").output().unwrap();
+    assert!(output.status.success());
+    let manpage = String::from_utf8(output.stdout).unwrap();
+    assert!(manpage.contains(".TH hope"));
+    assert!(manpage.contains("serve"));
+}
+
+#[test]
+fn install_wrapper_sets_up_cargo_from_just_the_binary() {
+    let cargo_home = tempfile::tempdir().unwrap();
+    let config_path = cargo_home.path().join("config.toml");
+    std::fs::write(&config_path, "[net]\ngit-fetch-with-cli = true\n").unwrap();
+    let install = || {
+        let output = Command::new(WRAPPER_PATH)
+            .arg("install-wrapper")
+            .env("CARGO_HOME", cargo_home.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    };
+
+    install();
+    let installed = cargo_home.path().join("bin/hope");
+    assert_eq!(
+        std::fs::read(&installed).unwrap(),
+        std::fs::read(WRAPPER_PATH).unwrap()
+    );
+    assert!(Command::new(&installed)
+        .arg("capabilities")
+        .output()
+        .unwrap()
+        .status
+        .success());
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    assert!(config.contains("git-fetch-with-cli = true"));
+    assert!(config.contains(&format!(
+        "[build]\nrustc-wrapper = {:?}",
+        installed.to_str().unwrap()
+    )));
+
+    // Doing it again (e.g. to upgrade) doesn't pile up settings.
+    install();
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    assert_eq!(config.matches("rustc-wrapper").count(), 1);
+}
+
+#[test]
+fn clock_skew_is_reported_on_pull() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    // Pretend it was pushed by a machine whose clock is a day ahead.
+    let manifest_path = &cache_dir.entry_manifest_paths("cfg_if")[0];
+    let mut manifest = cache_dir.entry_manifests("cfg_if").remove(0);
+    let pushed_at = chrono::Utc::now() + chrono::Duration::days(1);
+    manifest["pushed_at"] = pushed_at.to_rfc3339().into();
+    std::fs::write(manifest_path, manifest.to_string()).unwrap();
+
+    // It should still pull, but complain about it.
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+    let skew_events: Vec<_> = log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::DetectedClockSkew(event)
+                if event.crate_unit_name.starts_with("cfg_if") =>
+            {
+                Some(event)
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(skew_events.len(), 1);
+    assert!(skew_events[0].skew_secs > 23.0 * 60.0 * 60.0);
+}
+
+#[test]
+fn ls_verbose_shows_build_environment() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    let output = cache_dir.hope().arg("ls").output().unwrap();
+    assert!(output.status.success());
+    let listing = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        listing
+            .lines()
+            .filter(|line| line.starts_with("cfg_if-"))
+            .count(),
+        1
+    );
+    assert!(!listing.contains("rustc:"));
+
+    let output = cache_dir.hope().args(["ls", "--verbose"]).output().unwrap();
+    assert!(output.status.success());
+    let listing = String::from_utf8(output.stdout).unwrap();
+    assert!(listing.contains("    rustc: "));
+    assert!(listing.contains(&format!("    os: {}", env::consts::OS)));
+}
+
+#[test]
+fn c_compiler_is_part_of_the_key_for_sys_crates() {
+    // Stand-ins for two different C compilers, which are really both
+    // the system one.
+    let compilers_dir = tempdir().unwrap();
+    let cc_1 = fake_c_compiler(compilers_dir.path(), "cc-1", "fakecc version 1.0");
+    let cc_2 = fake_c_compiler(compilers_dir.path(), "cc-2", "fakecc version 2.0");
+
+    let cache_dir = CacheDir::new();
+    // `windows-sys` is pure Rust on other platforms, but it's a "-sys" crate
+    // so we can't know that.
+    let package_a = Package::with_env(&cache_dir, &[("CC", &cc_1)]);
+    package_a.add("windows-sys@0.61.2");
+    package_a.build();
+
+    let package_b = Package::with_env(&cache_dir, &[("CC", &cc_2)]);
+    package_b.add("windows-sys@0.61.2");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(
+        filter_pull_crate_outputs_events(&log, "windows_sys").len(),
+        0
+    );
+    assert_eq!(
+        filter_push_crate_outputs_events(&log, "windows_sys").len(),
+        2
+    );
+    // Crates that don't build native code don't care.
+    assert_eq!(
+        filter_pull_crate_outputs_events(&log, "windows_link").len(),
+        1
+    );
+
+    let package_c = Package::with_env(&cache_dir, &[("CC", &cc_1)]);
+    package_c.add("windows-sys@0.61.2");
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(
+        filter_pull_crate_outputs_events(&log, "windows_sys").len(),
+        1
+    );
+
+    let cc_versions: Vec<_> = cache_dir
+        .entry_manifests("windows_sys")
+        .into_iter()
+        .map(|manifest| manifest["environment"]["cc_version"].clone())
+        .collect();
+    assert_eq!(cc_versions.len(), 2);
+    assert!(cc_versions.contains(&"fakecc version 1.0".into()));
+    assert!(cc_versions.contains(&"fakecc version 2.0".into()));
+}
+
+#[test]
+fn explain_points_out_c_compiler_mismatches() {
+    let compilers_dir = tempdir().unwrap();
+    let cc_1 = fake_c_compiler(compilers_dir.path(), "cc-1", "fakecc version 1.0");
+    let cc_2 = fake_c_compiler(compilers_dir.path(), "cc-2", "fakecc version 2.0");
+
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(&cache_dir, &[("CC", &cc_1)]);
+    package.add("windows-sys@0.61.2");
+    package.build();
+
+    let explain = |cc: &str| {
+        let output = package
+            .hope()
+            .args(["explain", "windows-sys"])
+            .env("CC", cc)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let explanation = explain(&cc_1);
+    assert!(explanation.contains("no differences"));
+    let explanation = explain(&cc_2);
+    assert!(explanation.contains("cc: fakecc version 1.0 (here: fakecc version 2.0)"));
+}
+
+#[test]
+fn explain_points_out_feature_differences_and_what_caused_them() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("windows-sys@0.61.2");
+    package_a.build();
+
+    // Something else turns on another feature.
+    let package_b = Package::new(&cache_dir);
+    assert!(package_b
+        .cargo()
+        .args([
+            "add",
+            "windows-sys@0.61.2",
+            "--features",
+            "Win32_Foundation"
+        ])
+        .current_dir(package_b.dir.path())
+        .status()
+        .unwrap()
+        .success());
+    assert!(package_b
+        .cargo()
+        .arg("generate-lockfile")
+        .current_dir(package_b.dir.path())
+        .status()
+        .unwrap()
+        .success());
+    let output = package_b
+        .hope()
+        .args(["explain", "windows-sys"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let explanation = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        explanation.contains("feature \"Win32_Foundation\": off (here: on, enabled by foo v0.1.0"),
+        "{explanation}"
+    );
+    assert!(
+        !explanation.contains("feature \"default\""),
+        "{explanation}"
+    );
+}
+
+#[test]
+fn why_rebuild_explains_from_cargo_fingerprints() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+
+    let why_rebuild = |crate_name: &str| {
+        let output = package_b
+            .hope()
+            .args(["why-rebuild", crate_name])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let explanation = why_rebuild("cfg-if");
+    assert!(
+        explanation.contains("Hope last pulled it from"),
+        "{explanation}"
+    );
+    assert!(
+        explanation.contains("nothing here would make Cargo rebuild it"),
+        "{explanation}"
+    );
+
+    // A different profile makes a new build beside the old one.
+    assert!(package_b
+        .cargo()
+        .arg("build")
+        .env("CARGO_PROFILE_DEV_OPT_LEVEL", "1")
+        .current_dir(package_b.dir.path())
+        .status()
+        .unwrap()
+        .success());
+    let explanation = why_rebuild("cfg-if");
+    assert!(
+        explanation.contains("profile settings are different"),
+        "{explanation}"
+    );
+
+    let main_rs = std::fs::File::options()
+        .write(true)
+        .open(package_b.dir.path().join("src/main.rs"))
+        .unwrap();
+    main_rs
+        .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(3600))
+        .unwrap();
+    let explanation = why_rebuild("foo");
+    assert!(
+        explanation.contains("Cargo would rebuild it because:")
+            && explanation.contains("src/main.rs changed at"),
+        "{explanation}"
+    );
+}
+
+#[test]
+fn glibc_and_musl_entries_are_kept_apart() {
+    let gnu_target = format!("{}-unknown-linux-gnu", env::consts::ARCH);
+    let musl_target = format!("{}-unknown-linux-musl", env::consts::ARCH);
+    if !target_is_installed(&gnu_target) || !target_is_installed(&musl_target) {
+        eprintln!("{gnu_target} and {musl_target} aren't both installed; skipping test");
+        return;
+    }
+
+    let cache_dir = CacheDir::new();
+    // A "-sys" crate, so that it's treated as containing native code.
+    let package_a = Package::new(&cache_dir);
+    package_a.add("windows-sys@0.61.2");
+    package_a.build_for_target(&gnu_target);
+
+    let package_b = Package::new(&cache_dir);
+    package_b.add("windows-sys@0.61.2");
+    package_b.build_for_target(&musl_target);
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(
+        filter_pull_crate_outputs_events(&log, "windows_sys").len(),
+        0
+    );
+    assert_eq!(
+        filter_push_crate_outputs_events(&log, "windows_sys").len(),
+        2
+    );
+    let mut targets: Vec<String> = cache_dir
+        .entry_manifests("windows_sys")
+        .into_iter()
+        .map(|manifest| manifest["target"].as_str().unwrap().to_owned())
+        .collect();
+    targets.sort();
+    assert_eq!(targets, [gnu_target.clone(), musl_target.clone()]);
+
+    // Each should then be pulled for its own target.
+    let package_c = Package::new(&cache_dir);
+    package_c.add("windows-sys@0.61.2");
+    package_c.build_for_target(&musl_target);
+    package_c.build_for_target(&gnu_target);
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(
+        filter_pull_crate_outputs_events(&log, "windows_sys").len(),
+        2
+    );
+    assert_eq!(
+        filter_push_crate_outputs_events(&log, "windows_sys").len(),
+        2
+    );
+}
+
+#[test]
+fn embedded_targets_are_cached() {
+    let target = "thumbv7em-none-eabihf";
+    if !target_is_installed(target) {
+        eprintln!("{target} isn't installed; skipping test");
+        return;
+    }
+
+    let cache_dir = CacheDir::new();
+    let new_package = || {
+        let package = Package::new(&cache_dir);
+        // There's no `std` for this target, so make it a `no_std` library.
+        std::fs::remove_file(package.dir.path().join("src/main.rs")).unwrap();
+        std::fs::write(package.dir.path().join("src/lib.rs"), "#![no_std]\n").unwrap();
+        for dep in [
+            "cortex-m@0.7.7",
+            "cortex-m-rt@0.7.5",
+            "embedded-hal@1.0.0",
+            "heapless@0.8.0",
+        ] {
+            package.add(dep);
+        }
+        package
+    };
+
+    let package_a = new_package();
+    package_a.build_for_target(target);
+    let package_b = new_package();
+    package_b.build_for_target(target);
+    let log = cache_dir.read_log().unwrap();
+    for crate_name in ["cortex_m", "cortex_m_rt", "embedded_hal", "heapless"] {
+        assert_eq!(
+            filter_pull_crate_outputs_events(&log, &format!("{crate_name}-")).len(),
+            1,
+            "{crate_name} should have been pulled"
+        );
+        for manifest in cache_dir.entry_manifests(crate_name) {
+            assert_eq!(manifest["target"], target);
+        }
+    }
+    // Its build script generates the linker script, so it had better
+    // have been replayed rather than skipped.
+    assert_eq!(filter_ran_build_script_events(&log, "cortex-m-rt").len(), 1);
+
+    // And Cargo should consider everything we pulled to be fresh.
+    package_b.build_for_target(target);
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_compile_crate_events(&log, "cortex_m-").len(), 1);
+}
+
+#[test]
+fn wasm_projects_share_dependencies() {
+    let target = "wasm32-unknown-unknown";
+    if !target_is_installed(target) {
+        eprintln!("{target} isn't installed; skipping test");
+        return;
+    }
+
+    let cache_dir = CacheDir::new();
+    // Like a wasm-pack project: a library built as both a cdylib (for the
+    // ".wasm") and an rlib (for tests).
+    let library = Package::new(&cache_dir);
+    std::fs::rename(
+        library.dir.path().join("src/main.rs"),
+        library.dir.path().join("src/lib.rs"),
+    )
+    .unwrap();
+    library.append_to_manifest("\n[lib]\ncrate-type = [\"cdylib\", \"rlib\"]\n");
+    library.add("wasm-bindgen@0.2");
+    library.build_for_target(target);
+
+    // And like a Trunk project: a binary.
+    let app = Package::new(&cache_dir);
+    app.add("wasm-bindgen@0.2");
+    app.build_for_target(target);
+    assert!(app
+        .dir
+        .path()
+        .join(format!("target/{target}/debug/foo.wasm"))
+        .exists());
+
+    let log = cache_dir.read_log().unwrap();
+    // The proc macros are built for the host, but still shared.
+    for crate_name in ["wasm_bindgen", "wasm_bindgen_macro", "wasm_bindgen_shared"] {
+        assert_eq!(
+            filter_pull_crate_outputs_events(&log, &format!("{crate_name}-")).len(),
+            1,
+            "{crate_name} should have been pulled"
+        );
+    }
+    assert!(cache_dir
+        .entry_manifests("wasm_bindgen")
+        .iter()
+        .all(|manifest| manifest["target"] == target));
+}
+
+#[test]
+fn android_entries_are_keyed_by_ndk() {
+    let target = "aarch64-linux-android";
+    let Some(ndk_dir) = env::var_os("ANDROID_NDK_HOME").map(PathBuf::from) else {
+        eprintln!("ANDROID_NDK_HOME isn't set; skipping test");
+        return;
+    };
+    if !target_is_installed(target) {
+        eprintln!("{target} isn't installed; skipping test");
+        return;
+    }
+
+    // Set up the NDK the way the `cc` crate and Cargo expect to be told about it.
+    let host_tag = if cfg!(target_os = "macos") {
+        "darwin-x86_64"
+    } else {
+        "linux-x86_64"
+    };
+    let bin_dir = ndk_dir
+        .join("toolchains/llvm/prebuilt")
+        .join(host_tag)
+        .join("bin");
+    let clang = bin_dir.join("aarch64-linux-android21-clang");
+    let clang = clang.to_str().unwrap();
+    let llvm_ar = bin_dir.join("llvm-ar");
+    let env = [
+        ("CC_aarch64_linux_android", clang),
+        ("AR_aarch64_linux_android", llvm_ar.to_str().unwrap()),
+        ("CARGO_TARGET_AARCH64_LINUX_ANDROID_LINKER", clang),
+    ];
+
+    let cache_dir = CacheDir::new();
+    for _ in 0..2 {
+        let package = Package::with_env(&cache_dir, &env);
+        package.add("zstd-sys@2");
+        package.build_for_target(target);
+    }
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(
+        filter_pull_crate_outputs_events(&log, "zstd_sys-").len(),
+        1,
+        "zstd_sys should have been pulled"
+    );
+
+    let properties = std::fs::read_to_string(ndk_dir.join("source.properties")).unwrap();
+    let ndk_version = properties
+        .lines()
+        .find_map(|line| line.strip_prefix("Pkg.Revision = "))
+        .unwrap();
+    let manifests = cache_dir.entry_manifests("zstd_sys");
+    assert!(!manifests.is_empty());
+    for manifest in manifests {
+        let native_toolchain = &manifest["environment"]["native_toolchain"];
+        assert_eq!(native_toolchain["ndk_version"], ndk_version);
+        assert!(native_toolchain["ar_version"].is_string());
+    }
+}
+
+#[test]
+fn misses_can_be_handed_off_to_a_remote_builder() {
+    // Stand-ins for a build farm: one that "builds remotely" by just
+    // running the command it's given, after keeping a copy of the request,
+    // and one that's always down.
+    let builders_dir = tempdir().unwrap();
+    let requests_dir = builders_dir.path().join("requests");
+    std::fs::create_dir(&requests_dir).unwrap();
+    let working_builder = builders_dir.path().join("working-builder");
+    std::fs::write(
+        &working_builder,
+        format!(
+            "#!/bin/sh\ncp \"$1\" \"{}/$$.json\"\nshift\nexec \"$@\"\n",
+            requests_dir.display()
+        ),
+    )
+    .unwrap();
+    let broken_builder = builders_dir.path().join("broken-builder");
+    std::fs::write(&broken_builder, "#!/bin/sh\nexit 1\n").unwrap();
+    for builder in [&working_builder, &broken_builder] {
+        std::fs::set_permissions(builder, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+    }
+
+    let cache_dir = CacheDir::new();
+    let package_a = Package::with_env(
+        &cache_dir,
+        &[("HOPE_REMOTE_BUILDER", working_builder.to_str().unwrap())],
+    );
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+    let log = cache_dir.read_log().unwrap();
+    let compile_events = filter_compile_crate_events(&log, "cfg_if");
+    assert_eq!(compile_events.len(), 1);
+    assert!(compile_events[0].remote);
+    // Remote builds get cached like any other.
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 1);
+    let requests: Vec<serde_json::Value> = std::fs::read_dir(&requests_dir)
+        .unwrap()
+        .map(|entry| {
+            serde_json::from_slice(&std::fs::read(entry.unwrap().path()).unwrap()).unwrap()
+        })
+        .collect();
+    let cfg_if_request = requests
+        .iter()
+        .find(|request| {
+            request["args"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|arg| arg == "cfg_if")
+        })
+        .unwrap();
+    assert!(cfg_if_request["inputs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|input| input.as_str().unwrap().ends_with("src/lib.rs")));
+
+    // If the farm is down, we should just build locally.
+    let package_b = Package::with_env(
+        &cache_dir,
+        &[("HOPE_REMOTE_BUILDER", broken_builder.to_str().unwrap())],
+    );
+    package_b.add("itoa@1.0.16");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    let compile_events = filter_compile_crate_events(&log, "itoa");
+    assert_eq!(compile_events.len(), 1);
+    assert!(!compile_events[0].remote);
+}
+
+#[test]
+fn relaxed_policy_aliases_equivalent_entries() {
+    let no_debug_info = "\n[profile.dev.package.\"*\"]\ndebug = 0\n";
+    let cache_dir = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir, &[("HOPE_KEY_POLICY", "relaxed")]);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+    assert_eq!(cache_dir.entry_alias_paths("cfg_if").len(), 1);
+
+    // The relaxed entry doesn't match exactly, so a strict build mustn't use it.
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.append_to_manifest(no_debug_info);
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 2);
+    let mut policies: Vec<String> = cache_dir
+        .entry_manifests("cfg_if")
+        .into_iter()
+        .map(|manifest| manifest["key_policy"].as_str().unwrap().to_owned())
+        .collect();
+    policies.sort();
+    assert_eq!(policies, ["relaxed", "strict"]);
+
+    // But a relaxed build without debug info is happy with it.
+    let package_c = Package::with_env(&cache_dir, &[("HOPE_KEY_POLICY", "relaxed")]);
+    package_c.add("cfg-if@1.0.0");
+    package_c.append_to_manifest(no_debug_info);
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 2);
+    assert_eq!(cache_dir.entry_manifest_paths("cfg_if").len(), 2);
+}
+
+#[test]
+fn verify_repairs_corrupt_files_from_identical_copies() {
+    // Two entries for `windows-sys` with identical outputs: it's pure Rust
+    // here, but as a "-sys" crate, it gets a different key per C compiler.
+    let compilers_dir = tempdir().unwrap();
+    let cache_dir = CacheDir::new();
+    for (name, version) in [
+        ("cc-1", "fakecc version 1.0"),
+        ("cc-2", "fakecc version 2.0"),
+    ] {
+        let cc = fake_c_compiler(compilers_dir.path(), name, version);
+        let package = Package::with_env(&cache_dir, &[("CC", &cc)]);
+        package.add("windows-sys@0.61.2");
+        package.build();
+    }
+    let verify = |args: &[&str]| {
+        let output = cache_dir.hope().arg("verify").args(args).output().unwrap();
+        (
+            output.status.success(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    };
+    assert!(verify(&[]).0);
+
+    // Damage one of the two `windows-sys` rlibs, and the only `windows-link` one.
+    let cache_files: Vec<PathBuf> = std::fs::read_dir(cache_dir.dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    let rlibs = |prefix: &str| -> Vec<&PathBuf> {
+        cache_files
+            .iter()
+            .filter(|path| {
+                let file_name = path.file_name().unwrap().to_str().unwrap();
+                file_name.starts_with(prefix) && file_name.ends_with(".rlib")
+            })
+            .collect()
+    };
+    let windows_sys_rlibs = rlibs("libwindows_sys-");
+    assert_eq!(windows_sys_rlibs.len(), 2);
+    std::fs::write(windows_sys_rlibs[0], b"garbage").unwrap();
+    let windows_link_rlibs = rlibs("libwindows_link-");
+    assert_eq!(windows_link_rlibs.len(), 1);
+    std::fs::write(windows_link_rlibs[0], b"garbage").unwrap();
+
+    let (success, report) = verify(&[]);
+    assert!(!success);
+    assert!(report.contains("2 problems found"));
+
+    let (success, report) = verify(&["--repair"]);
+    assert!(success);
+    assert!(report.contains("repaired from libwindows_sys-"));
+    assert!(report.contains("removed entry windows_link-"));
+    assert_ne!(std::fs::read(windows_sys_rlibs[0]).unwrap(), b"garbage");
+    assert!(!windows_link_rlibs[0].exists());
+    assert!(cache_dir.entry_manifest_paths("windows_link").is_empty());
+
+    let (success, report) = verify(&[]);
+    assert!(success);
+    assert!(report.contains("0 problems found"));
+}
+
+#[test]
+fn direct_pulls_rename_into_the_target_dir() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    let package_b = Package::with_env(&cache_dir, &[("HOPE_DIRECT_PULL", "1")]);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+
+    let deps_dir = package_b.dir.path().join("target/debug/deps");
+    let file_names: Vec<String> = std::fs::read_dir(&deps_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(file_names
+        .iter()
+        .any(|name| name.starts_with("libcfg_if-") && name.ends_with(".rlib")));
+    // Nothing should be left behind.
+    assert!(!file_names
+        .iter()
+        .any(|name| name.starts_with(".hope-arrival-")));
+}
+
+#[test]
+fn leaf_only_mode_skips_small_transitive_dependencies() {
+    // `windows-link` is a tiny dependency of `windows-sys`.
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("windows-sys@0.61.2");
+    package_a.build();
+
+    let leaf_only = [("HOPE_LEAF_ONLY", "1"), ("HOPE_LEAF_ONLY_MIN_SIZE", "1G")];
+    let package_b = Package::with_env(&cache_dir, &leaf_only);
+    package_b.add("windows-sys@0.61.2");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    // The workspace's own dependency is still cached...
+    assert_eq!(
+        filter_pull_crate_outputs_events(&log, "windows_sys-").len(),
+        1
+    );
+    // ...but the one below it is built, even though it's in the cache...
+    assert_eq!(
+        filter_pull_crate_outputs_events(&log, "windows_link-").len(),
+        0
+    );
+    assert_eq!(filter_compile_crate_events(&log, "windows_link-").len(), 2);
+    // ...and not pushed either.
+    let skipped = filter_skipped_push_events(&log, "windows_link-");
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0]
+        .reason
+        .contains("not a direct dependency of the workspace"));
+}
+
+#[test]
+fn crates_quicker_to_build_than_pull_are_skipped() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+
+    // Pretend that pulls from the local cache have been very slow lately.
+    for _ in 0..3 {
+        hope_cache_log::write_log_line(
+            cache_dir.dir.path(),
+            CacheLogLine::PulledCrateOutputs(PullCrateOutputsEvent {
+                crate_unit_name: "something_big-0123456789abcdef".to_owned(),
+                copied_at: chrono::Utc::now(),
+                copied_from: "local cache".to_owned(),
+                duration_secs: 1000.0,
+            }),
+        )
+        .unwrap();
+    }
+
+    let package_b = Package::with_env(&cache_dir, &[("HOPE_SKIP_CHEAP_UNITS", "1")]);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    // It's in the cache, but built again anyway...
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if-").len(), 0);
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if-").len(), 2);
+    // ...and not pushed either.
+    let skipped = filter_skipped_push_events(&log, "cfg_if-");
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0].reason.contains("quicker than a typical pull"));
+
+    // Without the history to go on, it's cached as usual.
+    let cache_dir = CacheDir::new();
+    let package_c = Package::with_env(&cache_dir, &[("HOPE_SKIP_CHEAP_UNITS", "1")]);
+    package_c.add("cfg-if@1.0.0");
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if-").len(), 1);
+
+    // But a log that can't be read isn't taken for no history.
+    std::fs::write(
+        cache_dir.dir.path().join("hope-log.jsonl"),
+        "not a log line\n",
+    )
+    .unwrap();
+    let package_d = Package::with_env(&cache_dir, &[("HOPE_SKIP_CHEAP_UNITS", "1")]);
+    package_d.add("cfg-if@1.0.0");
+    assert!(!package_d
+        .cargo()
+        .arg("build")
+        .current_dir(package_d.dir.path())
+        .status()
+        .unwrap()
+        .success());
+}
+
+#[test]
+fn caches_owned_by_other_users_are_left_alone() {
+    use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+
+    // Shared caches can be made group-writable.
+    let cache_dir = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir, &[("HOPE_GROUP_WRITABLE", "1")]);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+    let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode();
+    assert_ne!(
+        mode(&cache_dir.dir.path().join("hope-log.jsonl")) & 0o020,
+        0
+    );
+    assert_ne!(mode(&cache_dir.dir.path().join("locks")) & 0o020, 0);
+
+    // Only root can give away a cache dir, so the rest needs root.
+    if std::fs::metadata(cache_dir.dir.path()).unwrap().uid() != 0 {
+        return;
+    }
+    let nobody = 65534;
+    let cache_dir = CacheDir::new();
+    std::os::unix::fs::chown(cache_dir.dir.path(), Some(nobody), Some(nobody)).unwrap();
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    assert!(cache_dir.entry_manifest_paths("cfg_if").is_empty());
+
+    // Unless we're told to use it anyway, and give back what we create.
+    let package_c = Package::with_env(&cache_dir, &[("HOPE_ADOPT_CACHE_OWNER", "1")]);
+    package_c.add("cfg-if@1.0.0");
+    package_c.build();
+    assert_eq!(cache_dir.entry_manifest_paths("cfg_if").len(), 1);
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if-").len(), 1);
+    let mut dirs = vec![cache_dir.dir.path().to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            let metadata = entry.metadata().unwrap();
+            assert_eq!(metadata.uid(), nobody, "{:?} is not theirs", entry.path());
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+    }
+}
+
+#[test]
+fn cache_files_and_dirs_get_configured_modes() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let cache_dir = CacheDir::new();
+    let package = Package::with_env(
+        &cache_dir,
+        &[("HOPE_FILE_MODE", "640"), ("HOPE_DIR_MODE", "2750")],
+    );
+    package.add("cfg-if@1.0.0");
+    package.build();
+    let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+    let manifest_paths = cache_dir.entry_manifest_paths("cfg_if");
+    assert_eq!(manifest_paths.len(), 1);
+    assert_eq!(mode(&manifest_paths[0]), 0o640);
+    assert_eq!(mode(&cache_dir.dir.path().join("hope-log.jsonl")), 0o640);
+    assert_eq!(mode(&cache_dir.dir.path().join("locks")), 0o2750);
+
+    // Modes have to make sense.
+    let package = Package::with_env(&CacheDir::new(), &[("HOPE_FILE_MODE", "rw-rw-r--")]);
+    package.add("cfg-if@1.0.0");
+    let output = package
+        .cargo()
+        .arg("build")
+        .current_dir(package.dir.path())
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("HOPE_FILE_MODE"));
+}
+
+#[test]
+fn pulls_and_pushes_are_skipped_when_disk_space_is_low() {
+    // Pretend we're low on space by asking for more to be kept free
+    // than any disk has.
+    let low_space = [("HOPE_MIN_FREE_SPACE", "1000000000G")];
+    let cache_dir = CacheDir::new();
+    let package_a = Package::with_env(&cache_dir, &low_space);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "cfg_if").len(), 0);
+    let skipped = filter_skipped_push_events(&log, "cfg_if");
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0].reason.contains("not enough free space"));
+
+    // That should be called out, along with what to do about it.
+    let marker_path = cache_dir.dir.path().join("hope-degraded");
+    assert!(marker_path.exists());
+    let output = cache_dir.hope().arg("stats").output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("hope gc --max-size"));
+
+    // With room again, it's back to normal.
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.build();
+    assert!(!marker_path.exists());
+
+    let package_c = Package::with_env(&cache_dir, &low_space);
+    package_c.add("cfg-if@1.0.0");
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 0);
+    assert_eq!(filter_compile_crate_events(&log, "cfg_if").len(), 3);
+
+    // Every build that's stuck in degraded mode should say so, once.
+    for _ in 0..2 {
+        let package = Package::with_env(&cache_dir, &low_space);
+        package.add("cfg-if@1.0.0");
+        let output = package
+            .cargo()
+            .arg("build")
+            .current_dir(package.dir.path())
+            .stderr(Stdio::piped())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr.matches("hope gc --max-size").count(), 1, "{stderr}");
+    }
+}
+
+#[test]
+fn builds_warn_up_front_when_projected_pulls_wont_fit() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    let lockfile = package_a.dir.path().join("Cargo.lock");
+    assert!(package_a
+        .cargo()
+        .args(["build", "--locked"])
+        .env("HOPE_LOCKFILE_INDEX", &lockfile)
+        .current_dir(package_a.dir.path())
+        .status()
+        .unwrap()
+        .success());
+
+    // Pretend we're low on space, as in the test above.
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    std::fs::copy(&lockfile, package_b.dir.path().join("Cargo.lock")).unwrap();
+    let output = package_b
+        .cargo()
+        .args(["build", "--locked"])
+        .env(
+            "HOPE_LOCKFILE_INDEX",
+            package_b.dir.path().join("Cargo.lock"),
+        )
+        .env("HOPE_MIN_FREE_SPACE", "1000000000G")
+        .current_dir(package_b.dir.path())
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.matches("lockfile index projects").count(),
+        1,
+        "{stderr}"
+    );
+}
+
+#[test]
+fn gc_removes_oldest_entries_first() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+    let package_b = Package::new(&cache_dir);
+    package_b.add("itoa@1.0.16");
+    package_b.build();
+
+    // Make room for about one of the two.
+    let itoa_size: u64 = std::fs::read_dir(cache_dir.dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_name().to_str().unwrap().contains("itoa-"))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    let output = cache_dir
+        .hope()
+        .args(["gc", "--max-size", &itoa_size.to_string()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(cache_dir.entry_manifest_paths("cfg_if").is_empty());
+    assert_eq!(cache_dir.entry_manifest_paths("itoa").len(), 1);
+}
+
+#[test]
+fn gc_removes_chunks_of_evicted_entries() {
+    let cache_dir = CacheDir::new();
+    let env = [("HOPE_CHUNK_SIZE", "4K")];
+    let package_a = Package::with_env(&cache_dir, &env);
+    package_a.add("cfg-if@1.0.0");
+    package_a.build();
+    let package_b = Package::with_env(&cache_dir, &env);
+    package_b.add("itoa@1.0.16");
+    package_b.build();
+
+    // Make room for just itoa, chunks and all.
+    let chunks_dir = cache_dir.dir.path().join("chunks");
+    let mut itoa_size = 0;
+    let mut itoa_chunks = BTreeSet::new();
+    for entry in std::fs::read_dir(cache_dir.dir.path()).unwrap() {
+        let entry = entry.unwrap();
+        let file_name = entry.file_name().into_string().unwrap();
+        if !file_name.contains("itoa-") {
+            continue;
+        }
+        itoa_size += entry.metadata().unwrap().len();
+        if file_name.ends_with(".chunks.json") {
+            let manifest: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(entry.path()).unwrap()).unwrap();
+            for chunk in manifest["chunks"].as_array().unwrap() {
+                itoa_chunks.insert(chunk["hash"].as_str().unwrap().to_owned());
+            }
+        }
+    }
+    assert!(!itoa_chunks.is_empty());
+    itoa_size += itoa_chunks
+        .iter()
+        .map(|hash| std::fs::metadata(chunks_dir.join(hash)).unwrap().len())
+        .sum::<u64>();
+    let output = cache_dir
+        .hope()
+        .args(["gc", "--max-size", &itoa_size.to_string()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(cache_dir.entry_manifest_paths("cfg_if").is_empty());
+    assert_eq!(cache_dir.entry_manifest_paths("itoa").len(), 1);
+    let chunks_left: BTreeSet<String> = std::fs::read_dir(&chunks_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(chunks_left, itoa_chunks);
+
+    // What's left should still be pullable.
+    let package_c = Package::with_env(&cache_dir, &env);
+    package_c.add("itoa@1.0.16");
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "itoa").len(), 1);
+}
+
+#[test]
+fn diff_build_script_shows_changed_output() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+    let package_b = Package::new(&cache_dir);
+    package_b.add("anyhow@=1.0.40");
+    package_b.build();
+
+    // "build-script-anyhow-{run hash}-{key}-stdout.txt"
+    let mut run_hashes: Vec<String> = std::fs::read_dir(cache_dir.dir.path())
+        .unwrap()
+        .filter_map(|entry| {
+            let file_name = entry.unwrap().file_name().into_string().unwrap();
+            let rest = file_name
+                .strip_prefix("build-script-anyhow-")?
+                .strip_suffix("-stdout.txt")?;
+            Some(rest.split('-').next().unwrap().to_owned())
+        })
+        .collect();
+    run_hashes.sort();
+    assert_eq!(run_hashes.len(), 2);
+
+    let output = cache_dir
+        .hope()
+        .args([
+            "diff-build-script",
+            "anyhow",
+            &run_hashes[0],
+            &run_hashes[1],
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout
+        .lines()
+        .any(|line| line.starts_with("+cargo:") || line.starts_with("-cargo:")));
+
+    // Unknown runs should list what's there.
+    let output = cache_dir
+        .hope()
+        .args(["diff-build-script", "anyhow", "nope", &run_hashes[1]])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&run_hashes[0]));
+}
+
+// Each mutation that might (or might not) make Cargo rerun the fixture's build script.
+struct RerunMutation {
+    name: &'static str,
+    env: &'static [(&'static str, &'static str)],
+    new_input_file: Option<&'static str>,
+}
+
+const RERUN_MUTATIONS: &[RerunMutation] = &[
+    RerunMutation {
+        name: "nothing",
+        env: &[],
+        new_input_file: None,
+    },
+    RerunMutation {
+        name: "watched env var",
+        env: &[("RERUN_FIXTURE_INPUT", "changed")],
+        new_input_file: None,
+    },
+    RerunMutation {
+        name: "unwatched env var",
+        env: &[("RERUN_FIXTURE_UNWATCHED", "changed")],
+        new_input_file: None,
+    },
+    RerunMutation {
+        name: "watched file",
+        env: &[],
+        new_input_file: Some("changed"),
+    },
+];
+
+#[test]
+fn build_script_replay_matches_cargo_rerun_decisions() {
+    for mutation in RERUN_MUTATIONS {
+        let name = mutation.name;
+        let registry = FixtureRegistry::new();
+        let cache_dir = CacheDir::new();
+
+        // Warm the cache from elsewhere first, so that the projects
+        // under test replay the build script's cached output.
+        let warm_up = Package::new(&cache_dir);
+        warm_up.use_fixture_registry(&registry);
+        warm_up.build_fixture_user(&[]);
+
+        let vanilla = Package::with_env(&cache_dir, &[("RUSTC_WRAPPER", "")]);
+        vanilla.use_fixture_registry(&registry);
+        let with_hope = Package::new(&cache_dir);
+        with_hope.use_fixture_registry(&registry);
+        assert_eq!(
+            with_hope.build_fixture_user(&[]),
+            vanilla.build_fixture_user(&[]),
+            "{name}: first build"
+        );
+
+        if let Some(new_input_file) = mutation.new_input_file {
+            registry.write_input_file(new_input_file);
+        }
+        let expected = vanilla.build_fixture_user(mutation.env);
+        assert_eq!(
+            with_hope.build_fixture_user(mutation.env),
+            expected,
+            "{name}: rebuild in same target dir"
+        );
+
+        // And in a new project, which will find the original output in the cache.
+        let fresh = Package::new(&cache_dir);
+        fresh.use_fixture_registry(&registry);
+        assert_eq!(
+            fresh.build_fixture_user(mutation.env).1,
+            expected.1,
+            "{name}: build in fresh target dir"
+        );
+    }
+}
+
+#[test]
+fn whole_package_pulls_build_crates_whose_build_scripts_ran_locally() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("anyhow@1.0.0");
+    package_a.build();
+
+    let forget_build_script_output = || {
+        for dir_entry in std::fs::read_dir(cache_dir.dir.path()).unwrap() {
+            let path = dir_entry.unwrap().path();
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            if file_name.starts_with("build-script-anyhow-") {
+                std::fs::remove_file(&path).unwrap();
+            }
+        }
+    };
+
+    // The build script has to run for real, so the crate gets built too.
+    forget_build_script_output();
+    let package_b = Package::with_env(&cache_dir, &[("HOPE_WHOLE_PACKAGE_PULLS", "1")]);
+    package_b.add("anyhow@1.0.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_ran_build_script_events(&log, "anyhow").len(), 2);
+    assert_eq!(filter_compile_crate_events(&log, "anyhow").len(), 2);
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow").len(), 0);
+
+    // Whereas by default, the crate gets pulled regardless.
+    forget_build_script_output();
+    let package_c = Package::new(&cache_dir);
+    package_c.add("anyhow@1.0.0");
+    package_c.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_ran_build_script_events(&log, "anyhow").len(), 3);
+    assert_eq!(filter_compile_crate_events(&log, "anyhow").len(), 2);
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow").len(), 1);
+}
+
+#[test]
+fn deferred_build_scripts_run_and_stale_state_is_cleaned_up() {
+    let cache_dir = CacheDir::new();
+    let package_a = Package::new(&cache_dir);
+    package_a.add("typenum@=1.17.0");
+    package_a.build();
+
+    // Keep typenum's build script output, but lose the crate itself, so that
+    // the next build replays the build script, and then has to run it for
+    // real when it compiles the crate (which includes generated code).
+    for dir_entry in std::fs::read_dir(cache_dir.dir.path()).unwrap() {
+        let path = dir_entry.unwrap().path();
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        if file_name.starts_with("typenum-") || file_name.starts_with("libtypenum-") {
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+    let package_b = Package::new(&cache_dir);
+    package_b.add("typenum@=1.17.0");
+    package_b.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_ran_build_script_events(&log, "typenum").len(), 1);
+    assert_eq!(filter_compile_crate_events(&log, "typenum").len(), 2);
+
+    // The deferred run used up its invocation info.
+    let build_dirs: Vec<PathBuf> =
+        std::fs::read_dir(package_b.dir.path().join("target/debug/build"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .starts_with("typenum-")
+            })
+            .collect();
+    let run_dir = build_dirs
+        .iter()
+        .find(|dir| dir.join("out").is_dir())
+        .unwrap();
+    let info_path = run_dir.join("out/build-script-invocation-info.json");
+    assert!(!info_path.exists());
+    let compile_dir = build_dirs
+        .iter()
+        .find(|dir| dir.join("real-build-script").exists())
+        .unwrap();
+    let symlink_path = compile_dir.join("real-build-script");
+
+    // Now leave behind the sort of mess an interrupted build might.
+    let stale_info = serde_json::json!({
+        "real_build_script_path": "/nonexistent/build-script-build",
+        "env_vars": { "OUT_DIR": run_dir.join("out") },
+        "work_dir": "/",
+    });
+    std::fs::write(&info_path, stale_info.to_string()).unwrap();
+    std::fs::remove_file(&symlink_path).unwrap();
+    std::os::unix::fs::symlink("/nonexistent/build-script-build", &symlink_path).unwrap();
+
+    let clean_project = |args: &[&str]| {
+        let output = package_b
+            .hope()
+            .arg("clean-project")
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let report = clean_project(&["--dry-run"]);
+    assert!(
+        report.contains("Checked 2 deferred build script files"),
+        "{report}"
+    );
+    assert!(report.contains("2 stale"), "{report}");
+    assert!(info_path.exists());
+    assert!(symlink_path.symlink_metadata().is_ok());
+
+    let report = clean_project(&[]);
+    assert!(report.contains("2 stale"), "{report}");
+    assert!(!info_path.exists());
+    assert!(symlink_path.symlink_metadata().is_err());
+    let report = clean_project(&[]);
+    assert!(
+        report.contains("Checked 0 deferred build script files"),
+        "{report}"
+    );
+}
+
+#[test]
+fn unsupported_target_dir_layouts_fall_back_to_rustc() {
+    let cache_dir = CacheDir::new();
+    // Cargo's per-package build dir layout is nightly-only, so pretend to be nightly.
+    let env = [
+        ("RUSTC_BOOTSTRAP", "1"),
+        ("CARGO_UNSTABLE_BUILD_DIR_NEW_LAYOUT", "true"),
+    ];
+    // Older Cargos don't know the setting at all, and refuse to do anything.
+    if !Command::new("cargo")
+        .arg("version")
+        .envs(env)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .success()
+    {
+        eprintln!("This Cargo doesn't have the per-package build dir layout; skipping test");
+        return;
+    }
+    let package = Package::with_env(&cache_dir, &env);
+    package.add("typenum@=1.17.0");
+    package.build();
+    if !package
+        .dir
+        .path()
+        .join("target/debug/build/typenum")
+        .is_dir()
+    {
+        eprintln!("This Cargo doesn't have the per-package build dir layout; skipping test");
+        return;
+    }
+    let log = cache_dir.read_log().unwrap();
+    assert!(filter_push_crate_outputs_events(&log, "typenum").is_empty());
+    assert!(filter_compile_crate_events(&log, "typenum").is_empty());
+    let layout_problems: Vec<_> = log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::UnrecognisedLayout(event) => Some(event),
+            _ => None,
+        })
+        .collect();
+    assert!(layout_problems
+        .iter()
+        .any(|event| event.path.contains("/build/typenum/")));
+
+    // The usual layout is still fine.
+    let package = Package::new(&cache_dir);
+    package.add("typenum@=1.17.0");
+    package.build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "typenum").len(), 1);
+}
+
+#[test]
+fn target_dir_on_the_command_line_is_respected() {
+    let cache_dir = CacheDir::new();
+    // Put the target dirs somewhere with a "build" dir in the path
+    // (like a lot of CI systems do) to make sure we don't confuse that
+    // with Cargo's build script dirs.
+    let target_dirs = tempdir().unwrap();
+    let target_dir_a = target_dirs.path().join("build/target-a");
+    let target_dir_b = target_dirs.path().join("build/target-b");
+
+    let package_a = Package::new(&cache_dir);
+    package_a.add("anyhow@1.0.0");
+    package_a.add("typenum@=1.17.0");
+    package_a.build_with_target_dir(&target_dir_a);
+    assert!(target_dir_a.join("debug").is_dir());
+    assert!(!package_a.dir.path().join("target").exists());
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_push_crate_outputs_events(&log, "anyhow").len(), 1);
+    assert_eq!(filter_push_crate_outputs_events(&log, "typenum").len(), 1);
+    assert_eq!(filter_ran_build_script_events(&log, "anyhow").len(), 1);
+
+    // Pull everything into a different target dir.
+    let package_b = Package::new(&cache_dir);
+    package_b.add("anyhow@1.0.0");
+    package_b.add("typenum@=1.17.0");
+    package_b.build_with_target_dir(&target_dir_b);
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow").len(), 1);
+    assert_eq!(filter_pull_crate_outputs_events(&log, "typenum").len(), 1);
+    assert_eq!(filter_ran_build_script_events(&log, "anyhow").len(), 1);
+
+    // Cargo should consider everything we pulled to be fresh.
+    package_b.build_with_target_dir(&target_dir_b);
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "anyhow").len(), 1);
+    assert_eq!(filter_compile_crate_events(&log, "anyhow").len(), 1);
+
+    // Lose typenum itself but keep its build script output,
+    // so that its build script has to run deferred in a third target dir.
+    for dir_entry in std::fs::read_dir(cache_dir.dir.path()).unwrap() {
+        let path = dir_entry.unwrap().path();
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        if file_name.starts_with("typenum-") || file_name.starts_with("libtypenum-") {
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+    let target_dir_c = target_dirs.path().join("build/target-c");
+    let package_c = Package::new(&cache_dir);
+    package_c.add("typenum@=1.17.0");
+    package_c.build_with_target_dir(&target_dir_c);
+    let log = cache_dir.read_log().unwrap();
+    // The deferred run isn't logged, but the crate wouldn't have compiled
+    // without the code it generates.
+    assert_eq!(filter_ran_build_script_events(&log, "typenum").len(), 1);
+    assert_eq!(filter_compile_crate_events(&log, "typenum").len(), 2);
+}
+
+#[test]
+fn rollout_percent_caches_a_stable_subset_of_packages() {
+    let pushed_crates = |percent: &str| {
+        let cache_dir = CacheDir::new();
+        let package = Package::with_env(&cache_dir, &[("HOPE_ROLLOUT_PERCENT", percent)]);
+        package.add("anyhow@1.0.0");
+        package.add("serde_derive@1.0.0");
+        package.build();
+        // There's no log at all if Hope never touched the cache.
+        let log = cache_dir.read_log().unwrap_or_default();
+        let mut crates: Vec<String> = filter_push_crate_outputs_events(&log, "")
+            .into_iter()
+            .map(|event| event.crate_unit_name)
+            .collect();
+        crates.sort();
+        crates
+    };
+
+    assert!(pushed_crates("0").is_empty());
+    let everything = pushed_crates("100");
+    let half = pushed_crates("50");
+    assert!(!half.is_empty() && half.len() < everything.len());
+    // Same packages every time.
+    assert_eq!(pushed_crates("50"), half);
+    assert!(half.iter().all(|crate_| everything.contains(crate_)));
+}
+
+#[test]
+fn pushed_entries_are_attested() {
+    let cache_dir = CacheDir::new();
+    let builder_id = "https://ci.example.com/jobs/1";
+    let package = Package::with_env(&cache_dir, &[("HOPE_BUILDER_ID", builder_id)]);
+    package.add("cfg-if@1.0.0");
+    package.build();
+
+    let output = cache_dir
+        .hope()
+        .args(["attest", "export", "cfg-if"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let statement: serde_json::Value =
+        serde_json::from_str(stdout.lines().next().unwrap()).unwrap();
+    assert_eq!(statement["_type"], "https://in-toto.io/Statement/v1");
+    assert_eq!(statement["predicateType"], "https://slsa.dev/provenance/v1");
+    assert_eq!(
+        statement["predicate"]["runDetails"]["builder"]["id"],
+        builder_id
+    );
+    assert!(!statement["subject"].as_array().unwrap().is_empty());
+
+    let attest_verify = |args: &[&str]| {
+        cache_dir
+            .hope()
+            .args(["attest", "verify"])
+            .args(args)
+            .output()
+            .unwrap()
+            .status
+            .success()
+    };
+    assert!(attest_verify(&[]));
+
+    // Tampering with an attested file should be caught.
+    let rlib_path = std::fs::read_dir(cache_dir.dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            file_name.starts_with("libcfg_if-") && file_name.ends_with(".rlib")
+        })
+        .unwrap();
+    std::fs::write(&rlib_path, b"not what was built").unwrap();
+    assert!(!attest_verify(&["cfg-if"]));
+}
+
+#[test]
+fn parallel_builds_share_a_cache_dir_consistently() {
+    // Several projects with the same deps, all built at once with lots of
+    // jobs, so that the same units get pushed and pulled concurrently.
+    // Repeat it a few times, because races don't always show up first go.
+    const PROJECTS: usize = 4;
+    const ROUNDS: usize = 3;
+    const CRATES: &[&str] = &["anyhow", "serde_derive", "typenum", "proc_macro2"];
+    let cache_dir = CacheDir::new();
+    let mut build_script_runs_after_first_round = None;
+    let mut pulls_before_round = 0;
+    for round in 0..ROUNDS {
+        let packages: Vec<Package> = (0..PROJECTS)
+            .map(|_| {
+                let package = Package::with_env(&cache_dir, &[("CARGO_BUILD_JOBS", "16")]);
+                package.append_to_manifest(
+                    "anyhow = \"1.0.0\"\n\
+                     serde_derive = \"1.0.0\"\n\
+                     typenum = \"=1.17.0\"\n",
+                );
+                package
+            })
+            .collect();
+        std::thread::scope(|scope| {
+            for package in &packages {
+                scope.spawn(|| package.build());
+            }
+        });
+
+        // Every log line should have been written whole.
+        let log = cache_dir.read_log().unwrap();
+
+        // Every file an entry lists should be intact, and there should be
+        // exactly one entry per unit, no matter how many builds pushed it.
+        let output = cache_dir.hope().arg("verify").output().unwrap();
+        let report = String::from_utf8(output.stdout).unwrap();
+        assert!(output.status.success(), "round {round}: {report}");
+        assert!(
+            report.contains("0 problems found"),
+            "round {round}: {report}"
+        );
+        for crate_name in CRATES {
+            assert_eq!(
+                cache_dir.entry_manifest_paths(crate_name).len(),
+                1,
+                "round {round}: {crate_name}"
+            );
+        }
+
+        // Nothing should be left half-written.
+        for dir_entry in std::fs::read_dir(cache_dir.dir.path()).unwrap() {
+            let file_name = dir_entry.unwrap().file_name();
+            assert!(
+                !file_name.to_str().unwrap().starts_with(".tmp"),
+                "round {round}: {file_name:?} left behind"
+            );
+        }
+
+        // After the first round, everything should come from the cache.
+        let build_script_runs: Vec<usize> = CRATES
+            .iter()
+            .map(|crate_name| filter_ran_build_script_events(&log, crate_name).len())
+            .collect();
+        let pulls = filter_pull_crate_outputs_events(&log, "anyhow").len();
+        match &build_script_runs_after_first_round {
+            None => build_script_runs_after_first_round = Some(build_script_runs),
+            Some(expected) => {
+                assert_eq!(&build_script_runs, expected, "round {round}");
+                assert_eq!(pulls - pulls_before_round, PROJECTS, "round {round}");
+            }
+        }
+        pulls_before_round = pulls;
+    }
+}
+
+#[test]
+fn observe_mode_projects_savings_without_pulling() {
+    let cache_dir = CacheDir::new();
+    let observe = |package: &Package| {
+        let output = package.hope().arg("observe").output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let new_package = || {
+        let package = Package::new(&cache_dir);
+        package.add("anyhow@1.0.0");
+        package.add("cfg-if@1.0.0");
+        package
+    };
+
+    // `anyhow`, its build script, and `cfg-if`. Nothing is cached yet,
+    // but all of them would be pulled next time.
+    let report = observe(&new_package());
+    let lines: Vec<&str> = report.lines().collect();
+    assert!(lines[0].starts_with("Compiled 3 units"), "{report}");
+    assert!(lines[1].trim().starts_with("0 of them"), "{report}");
+    assert!(lines[2].trim().starts_with("3 of them"), "{report}");
+    assert_eq!(
+        filter_push_crate_outputs_events(&cache_dir.read_log().unwrap(), "cfg_if").len(),
+        1
+    );
+
+    // Now everything could be pulled, but still isn't.
+    let report = observe(&new_package());
+    let lines: Vec<&str> = report.lines().collect();
+    assert!(lines[0].starts_with("Compiled 3 units"), "{report}");
+    assert!(lines[1].trim().starts_with("3 of them"), "{report}");
+    assert!(lines[2].trim().starts_with("3 of them"), "{report}");
+    let log = cache_dir.read_log().unwrap();
+    assert!(filter_pull_crate_outputs_events(&log, "cfg_if").is_empty());
+    let compile_events = filter_compile_crate_events(&log, "cfg_if");
+    assert_eq!(compile_events.len(), 2);
+    assert!(!compile_events[0].observed_hit);
+    assert!(compile_events[1].observed_hit);
+
+    // And outside observe mode, it is.
+    new_package().build();
+    let log = cache_dir.read_log().unwrap();
+    assert_eq!(filter_pull_crate_outputs_events(&log, "cfg_if").len(), 1);
+}
+
+#[test]
+fn bench_compare_prints_a_table_using_only_fresh_caches() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+
+    // A remote cache from the environment would make the cold cache warm.
+    let remote_dir = tempdir().unwrap();
+    let remote_url = format!("file://{}", remote_dir.path().to_str().unwrap());
+    let output = package
+        .hope()
+        .args(["bench-compare", "--runs", "1"])
+        .env("HOPE_CACHE_URL", &remote_url)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let table = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 4, "{table}");
+    assert!(lines[0].starts_with("scenario"), "{table}");
+    assert!(lines[1].starts_with("without hope"), "{table}");
+    assert!(lines[2].starts_with("hope, cold cache"), "{table}");
+    assert!(lines[3].starts_with("hope, warm cache"), "{table}");
+    assert_eq!(std::fs::read_dir(remote_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn bench_compare_ignores_hope_settings_from_the_environment() {
+    let cache_dir = CacheDir::new();
+    let package = Package::new(&cache_dir);
+    package.add("cfg-if@1.0.0");
+
+    // Observe mode would have the warm run compile everything again.
+    let output = package
+        .hope()
+        .args(["bench-compare", "--runs", "1"])
+        .env("HOPE_OBSERVE", "1")
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success(), "{stderr}");
+    assert!(
+        stderr.contains("hope, warm cache pulled 1 units"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_harness_binaries_are_cached() {
+    let cache_dir = CacheDir::new();
+
+    let package_a = Package::new(&cache_dir);
+    package_a.add("cfg-if@1.0.0");
+    package_a.cargo_test(&["-p", "cfg-if"]);
+    let pushed: BTreeSet<String> =
+        filter_push_crate_outputs_events(&cache_dir.read_log().unwrap(), "")
+            .into_iter()
+            .map(|event| event.crate_unit_name)
+            .collect();
+    // The library, its unit test harness, and the harness for its
+    // "tests/xcrate.rs" integration test.
+    assert_eq!(
+        pushed
+            .iter()
+            .filter(|name| name.starts_with("cfg_if-"))
+            .count(),
+        2,
+        "{pushed:?}"
+    );
+    assert!(
+        pushed.iter().any(|name| name.starts_with("xcrate-")),
+        "{pushed:?}"
+    );
+
+    // The second package should pull the test harness binaries and still be able to run them.
+    let pulled_since = |log_len: usize| {
+        let log = cache_dir.read_log().unwrap();
+        filter_pull_crate_outputs_events(&log[log_len..], "")
+            .into_iter()
+            .map(|event| event.crate_unit_name)
+            .collect::<BTreeSet<String>>()
+    };
+    let log_len = cache_dir.read_log().unwrap().len();
+    let package_b = Package::new(&cache_dir);
+    package_b.add("cfg-if@1.0.0");
+    package_b.cargo_test(&["-p", "cfg-if"]);
+    assert_eq!(pulled_since(log_len), pushed);
+
+    // Same again under nextest, if it's installed. (CI installs it.)
+    let has_nextest = Command::new("cargo")
+        .args(["nextest", "--version"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .success();
+    if has_nextest {
+        let log_len = cache_dir.read_log().unwrap().len();
+        let package_c = Package::new(&cache_dir);
+        package_c.add("cfg-if@1.0.0");
+        package_c.cargo_nextest_run(&["-p", "cfg-if"]);
+        assert_eq!(pulled_since(log_len), pushed);
+    } else {
+        eprintln!("cargo-nextest isn't installed; skipping nextest part of test");
+    }
+}
+
 // TODO:
 // - Multiple versions of the same dependency
 // - Deps where the source mtimes are newer.
@@ -144,18 +4806,509 @@ impl CacheDir {
     pub fn read_log(&self) -> anyhow::Result<Vec<CacheLogLine>> {
         hope_cache_log::read_log(self.dir.path())
     }
+
+    fn entry_manifest_paths(&self, crate_name: &str) -> Vec<PathBuf> {
+        std::fs::read_dir(self.dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let file_name = path.file_name().unwrap().to_str().unwrap();
+                file_name.starts_with(&format!("{crate_name}-"))
+                    && file_name.ends_with(".manifest.json")
+            })
+            .collect()
+    }
+
+    fn entry_alias_paths(&self, crate_name: &str) -> Vec<PathBuf> {
+        std::fs::read_dir(self.dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let file_name = path.file_name().unwrap().to_str().unwrap();
+                file_name.starts_with(&format!("{crate_name}-"))
+                    && file_name.ends_with(".alias.json")
+            })
+            .collect()
+    }
+
+    fn entry_manifests(&self, crate_name: &str) -> Vec<serde_json::Value> {
+        self.entry_manifest_paths(crate_name)
+            .iter()
+            .map(|path| serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap())
+            .collect()
+    }
+
+    // Run one of hope's own subcommands against this cache dir.
+    fn hope(&self) -> Command {
+        let mut command = Command::new(WRAPPER_PATH);
+        command.env("HOPE_CACHE_DIR", self.dir.path().to_str().unwrap());
+        command
+    }
+}
+
+// A long-running `hope` process (usually `hope serve`) for a cache dir,
+// killed when dropped.
+struct CacheServer {
+    child: Child,
+    addr: String,
+}
+
+impl CacheServer {
+    fn start(cache_dir: &CacheDir) -> Self {
+        Self::spawn(
+            cache_dir,
+            &["serve", "--listen", "127.0.0.1:0"],
+            "Listening on ",
+        )
+    }
+
+    // Run some other long-running subcommand that announces its address
+    // on the first line of stdout, after `banner`.
+    fn spawn(cache_dir: &CacheDir, args: &[&str], banner: &str) -> Self {
+        Self::spawn_command(cache_dir.hope().args(args), banner)
+    }
+
+    fn spawn_command(command: &mut Command, banner: &str) -> Self {
+        let mut child = command.stdout(Stdio::piped()).spawn().unwrap();
+        let mut first_line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut first_line)
+            .unwrap();
+        let addr = first_line.trim().strip_prefix(banner).unwrap().to_owned();
+        Self { child, addr }
+    }
+
+    fn blob_url(&self, key: &str) -> String {
+        format!("http://{}/blobs/{key}", self.addr)
+    }
+}
+
+impl Drop for CacheServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Just enough of Redis for Hope's Redis backend, in a background thread,
+// remembering what TTL (in seconds) each key was last given.
+struct FakeRedis {
+    addr: String,
+    entries: Arc<Mutex<FakeRedisEntries>>,
+}
+
+type FakeRedisEntries = HashMap<Vec<u8>, (Vec<u8>, Option<u64>)>;
+
+impl FakeRedis {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let redis = Self {
+            addr: listener.local_addr().unwrap().to_string(),
+            entries: Arc::default(),
+        };
+        let entries = redis.entries.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let entries = entries.clone();
+                std::thread::spawn(move || serve_fake_redis(stream.unwrap(), &entries));
+            }
+        });
+        redis
+    }
+
+    fn ttls(&self) -> HashMap<String, Option<u64>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, (_, ttl))| (String::from_utf8(key.clone()).unwrap(), *ttl))
+            .collect()
+    }
+}
+
+fn serve_fake_redis(stream: TcpStream, entries: &Mutex<FakeRedisEntries>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let read_line = |reader: &mut BufReader<TcpStream>| {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_owned()
+    };
+    let number = |arg: &[u8]| -> i64 { std::str::from_utf8(arg).unwrap().parse().unwrap() };
+    let bulk = |value: Option<&[u8]>| match value {
+        Some(value) => [format!("${}\r\n", value.len()).as_bytes(), value, b"\r\n"].concat(),
+        None => b"$-1\r\n".to_vec(),
+    };
+    loop {
+        // e.g. "*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n"
+        let header = read_line(&mut reader);
+        let Some(count) = header.strip_prefix('*') else {
+            return;
+        };
+        let args: Vec<Vec<u8>> = (0..count.parse().unwrap())
+            .map(|_| {
+                let len: usize = read_line(&mut reader)[1..].parse().unwrap();
+                let mut arg = vec![0; len + 2];
+                reader.read_exact(&mut arg).unwrap();
+                arg.truncate(len);
+                arg
+            })
+            .collect();
+        let mut entries = entries.lock().unwrap();
+        let reply = match args[0].to_ascii_uppercase().as_slice() {
+            b"AUTH" | b"SELECT" => b"+OK\r\n".to_vec(),
+            b"SET" => {
+                let ttl = args.get(4).map(|ttl| number(ttl) as u64);
+                entries.insert(args[1].clone(), (args[2].clone(), ttl));
+                b"+OK\r\n".to_vec()
+            }
+            b"GET" | b"GETEX" => match entries.get_mut(&args[1]) {
+                Some((value, ttl)) => {
+                    if let Some(new_ttl) = args.get(3) {
+                        *ttl = Some(number(new_ttl) as u64);
+                    }
+                    bulk(Some(value))
+                }
+                None => bulk(None),
+            },
+            b"EXISTS" => format!(":{}\r\n", u8::from(entries.contains_key(&args[1]))).into_bytes(),
+            b"STRLEN" => format!(
+                ":{}\r\n",
+                entries.get(&args[1]).map_or(0, |(value, _)| value.len())
+            )
+            .into_bytes(),
+            b"GETRANGE" => {
+                let value = entries.get(&args[1]).map_or(&[][..], |(value, _)| value);
+                let len = value.len() as i64;
+                let index = |arg: &[u8]| {
+                    let index = number(arg);
+                    (if index < 0 { len + index } else { index }).clamp(0, len) as usize
+                };
+                let (start, end) = (index(&args[2]), index(&args[3]));
+                bulk(Some(&value[start..(end + 1).clamp(start, value.len())]))
+            }
+            _ => b"-ERR unknown command\r\n".to_vec(),
+        };
+        writer.write_all(&reply).unwrap();
+    }
+}
+
+// Just enough of an S3-compatible store (e.g. MinIO) to cache things in,
+// in a background thread. It doesn't check signatures.
+// Just enough of a Bazel remote cache's HTTP protocol, in a background thread.
+// Like `bazel-remote`, it won't take anything into the CAS under the wrong hash.
+struct FakeBazelCache {
+    url: String,
+    entries: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl FakeBazelCache {
+    fn start() -> Self {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let bazel = Self {
+            url: format!("http://{}", server.server_addr().to_ip().unwrap()),
+            entries: Arc::default(),
+        };
+        let entries = bazel.entries.clone();
+        std::thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = Vec::new();
+                request.as_reader().read_to_end(&mut body).unwrap();
+                let path = request.url().to_owned();
+                let mut entries = entries.lock().unwrap();
+                let response = match request.method().as_str() {
+                    "PUT" => {
+                        let hash = path.rsplit('/').next().unwrap();
+                        let content_hash = format!("{:x}", Sha256::digest(&body));
+                        if path.contains("/cas/") && hash != content_hash {
+                            tiny_http::Response::empty(400)
+                        } else {
+                            entries.insert(path, body);
+                            tiny_http::Response::empty(200)
+                        }
+                    }
+                    "GET" => match entries.get(&path) {
+                        Some(bytes) => {
+                            request
+                                .respond(tiny_http::Response::from_data(bytes.clone()))
+                                .unwrap();
+                            continue;
+                        }
+                        None => tiny_http::Response::empty(404),
+                    },
+                    _ => tiny_http::Response::empty(405),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        bazel
+    }
+
+    fn paths(&self) -> Vec<String> {
+        self.entries.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+// Anonymous requests can read objects, but not write them, or tell
+// whether missing ones are there.
+struct FakeS3 {
+    url: String,
+    // Path and authorization header of every request.
+    requests: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl FakeS3 {
+    fn start() -> Self {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let s3 = Self {
+            url: format!("http://{}", server.server_addr().to_ip().unwrap()),
+            requests: Arc::default(),
+        };
+        let requests = s3.requests.clone();
+        std::thread::spawn(move || {
+            let mut objects: HashMap<String, Vec<u8>> = HashMap::new();
+            for mut request in server.incoming_requests() {
+                let mut body = Vec::new();
+                request.as_reader().read_to_end(&mut body).unwrap();
+                let header = |name: &'static str| {
+                    request
+                        .headers()
+                        .iter()
+                        .find(|header| header.field.equiv(name))
+                        .map(|header| header.value.to_string())
+                };
+                let path = request.url().to_owned();
+                requests
+                    .lock()
+                    .unwrap()
+                    .push((path.clone(), header("Authorization").unwrap_or_default()));
+                // Send a length even for HEAD, as S3 does.
+                let object = |bytes: &[u8]| {
+                    tiny_http::Response::from_data(bytes.to_vec())
+                        .with_chunked_threshold(usize::MAX)
+                        .boxed()
+                };
+                if let Some((bucket, query)) = path
+                    .split_once('?')
+                    .filter(|(_, query)| query.split('&').any(|param| param == "list-type=2"))
+                {
+                    // Only what `hope sync` asks for: everything under the
+                    // prefix and not in a "directory", in one page.
+                    let prefix = query
+                        .split('&')
+                        .find_map(|param| param.strip_prefix("prefix="))
+                        .unwrap_or_default()
+                        .replace("%2F", "/");
+                    let mut keys = String::new();
+                    for object_path in objects.keys() {
+                        let Some(key) = object_path.strip_prefix(&format!("{bucket}/")) else {
+                            continue;
+                        };
+                        if key
+                            .strip_prefix(&prefix)
+                            .is_some_and(|name| !name.contains('/'))
+                        {
+                            keys.push_str(&format!("<Contents><Key>{key}</Key></Contents>"));
+                        }
+                    }
+                    let body = format!(
+                        "<ListBucketResult><IsTruncated>false</IsTruncated>{keys}</ListBucketResult>"
+                    );
+                    request
+                        .respond(tiny_http::Response::from_string(body))
+                        .unwrap();
+                    continue;
+                }
+                let anonymous = header("Authorization").is_none();
+                let response = match (request.method().as_str(), objects.get(&path)) {
+                    // Like a bucket whose objects anyone can read, but not list.
+                    ("PUT", _) | (_, None) if anonymous => {
+                        tiny_http::Response::from_string("<Error><Code>AccessDenied</Code></Error>")
+                            .with_status_code(403)
+                            .boxed()
+                    }
+                    ("PUT", _) => {
+                        objects.insert(path, body);
+                        tiny_http::Response::empty(200).boxed()
+                    }
+                    ("GET", Some(bytes)) => match header("Range") {
+                        // Only suffix ranges, e.g. "bytes=-100".
+                        Some(range) => {
+                            let len: usize =
+                                range.strip_prefix("bytes=-").unwrap().parse().unwrap();
+                            object(&bytes[bytes.len().saturating_sub(len)..]).with_status_code(206)
+                        }
+                        None => object(bytes),
+                    },
+                    ("HEAD", Some(bytes)) => object(bytes),
+                    _ => tiny_http::Response::from_string("<Error><Code>NoSuchKey</Code></Error>")
+                        .with_status_code(404)
+                        .boxed(),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        s3
+    }
+}
+
+// Just enough of the GitHub Actions cache service, in a background thread.
+struct FakeGhaCache {
+    url: String,
+    // Indexed by cache ID.
+    entries: Arc<Mutex<Vec<FakeGhaEntry>>>,
+}
+
+struct FakeGhaEntry {
+    key: String,
+    content: Vec<u8>,
+    committed: bool,
+}
+
+impl FakeGhaCache {
+    const TOKEN: &'static str = "fake-runtime-token";
+
+    fn start() -> Self {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let cache = Self {
+            url: format!("http://{}/", server.server_addr().to_ip().unwrap()),
+            entries: Arc::default(),
+        };
+        let url = cache.url.clone();
+        let entries = cache.entries.clone();
+        std::thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = Vec::new();
+                request.as_reader().read_to_end(&mut body).unwrap();
+                let header = |name: &'static str| {
+                    request
+                        .headers()
+                        .iter()
+                        .find(|header| header.field.equiv(name))
+                        .map(|header| header.value.to_string())
+                };
+                let authorized = header("Authorization") == Some(format!("Bearer {}", Self::TOKEN));
+                let mut entries = entries.lock().unwrap();
+                let request_url = request.url().to_owned();
+                let (path, query) = request_url.split_once('?').unwrap_or((&request_url, ""));
+                let segments: Vec<&str> = path.split('/').skip(1).collect();
+                let json = |value: serde_json::Value| {
+                    tiny_http::Response::from_data(serde_json::to_vec(&value).unwrap()).boxed()
+                };
+                let status = |code: u16| tiny_http::Response::empty(code).boxed();
+                let method = request.method().as_str().to_owned();
+                let response = match (method.as_str(), segments.as_slice()) {
+                    // Downloads don't need the token; their URLs are secret enough.
+                    ("GET" | "HEAD", ["archive", id]) => {
+                        let entry = &entries[id.parse::<usize>().unwrap()];
+                        tiny_http::Response::from_data(entry.content.clone()).boxed()
+                    }
+                    _ if !authorized => status(401),
+                    ("GET", ["_apis", "artifactcache", "cache"]) => {
+                        let keys = query
+                            .split('&')
+                            .find_map(|param| param.strip_prefix("keys="))
+                            .map(percent_decode)
+                            .unwrap();
+                        // Like the real thing, fall back to a prefix match.
+                        let committed = || {
+                            entries
+                                .iter()
+                                .enumerate()
+                                .filter(|(_, entry)| entry.committed)
+                        };
+                        match committed()
+                            .find(|(_, entry)| entry.key == keys)
+                            .or_else(|| committed().find(|(_, entry)| entry.key.starts_with(&keys)))
+                        {
+                            Some((id, entry)) => json(serde_json::json!({
+                                "cacheKey": entry.key,
+                                "archiveLocation": format!("{url}archive/{id}"),
+                            })),
+                            None => status(204),
+                        }
+                    }
+                    ("POST", ["_apis", "artifactcache", "caches"]) => {
+                        let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                        let key = request["key"].as_str().unwrap();
+                        if entries.iter().any(|entry| entry.key == key) {
+                            status(409)
+                        } else {
+                            entries.push(FakeGhaEntry {
+                                key: key.to_owned(),
+                                content: Vec::new(),
+                                committed: false,
+                            });
+                            json(serde_json::json!({ "cacheId": entries.len() - 1 }))
+                        }
+                    }
+                    ("PATCH", ["_apis", "artifactcache", "caches", id]) => {
+                        // e.g. "bytes 0-99/*"
+                        let range = header("Content-Range").unwrap();
+                        let start: usize = range[6..range.find('-').unwrap()].parse().unwrap();
+                        let content = &mut entries[id.parse::<usize>().unwrap()].content;
+                        content.resize(content.len().max(start + body.len()), 0);
+                        content[start..start + body.len()].copy_from_slice(&body);
+                        status(204)
+                    }
+                    ("POST", ["_apis", "artifactcache", "caches", id]) => {
+                        entries[id.parse::<usize>().unwrap()].committed = true;
+                        status(204)
+                    }
+                    _ => status(404),
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        cache
+    }
+
+    fn committed_keys(&self) -> Vec<String> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.committed)
+            .map(|entry| entry.key.clone())
+            .collect()
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&tail[..2]).unwrap(), 16).unwrap());
+            rest = &tail[2..];
+        } else {
+            bytes.push(if byte == b'+' { b' ' } else { byte });
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).unwrap()
 }
 
 struct Package {
     dir: TempDir,
     cache_dir: PathBuf,
+    // Extra environment variables to set for every Cargo invocation.
+    env: Vec<(String, String)>,
 }
 
 impl Package {
     fn new(cache_dir: &CacheDir) -> Self {
+        Self::with_env(cache_dir, &[])
+    }
+
+    fn with_env(cache_dir: &CacheDir, env: &[(&str, &str)]) -> Self {
         let package = Self {
             dir: tempdir().unwrap(),
             cache_dir: cache_dir.dir.path().to_owned(),
+            env: env
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
         };
         package.init();
         package
@@ -176,6 +5329,8 @@ impl Package {
         // Pass through the cache dir we're using for this test.
         command.env("HOPE_CACHE_DIR", self.cache_dir.to_str().unwrap());
 
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+
         if std::env::var("HOPE_VERBOSE") == Ok("true".to_string()) {
             command.arg("-v");
         } else {
@@ -190,6 +5345,18 @@ impl Package {
         command
     }
 
+    // Run one of hope's own subcommands from within this package.
+    fn hope(&self) -> Command {
+        let mut command = Command::new(WRAPPER_PATH);
+        command.current_dir(self.dir.path());
+        command.env("HOPE_CACHE_DIR", self.cache_dir.to_str().unwrap());
+        if env::var("HOPE_TEST_OFFLINE") == Ok("1".to_string()) {
+            command.env("CARGO_NET_OFFLINE", "true");
+        }
+        command.stderr(Stdio::null());
+        command
+    }
+
     fn init(&self) {
         assert!(self
             .cargo()
@@ -210,6 +5377,57 @@ impl Package {
             .success());
     }
 
+    fn append_to_manifest(&self, text: &str) {
+        let manifest_path = self.dir.path().join("Cargo.toml");
+        let mut manifest = std::fs::read_to_string(&manifest_path).unwrap();
+        manifest.push_str(text);
+        std::fs::write(&manifest_path, manifest).unwrap();
+    }
+
+    fn cargo_test(&self, args: &[&str]) {
+        assert!(self
+            .cargo()
+            .arg("test")
+            .args(args)
+            .current_dir(self.dir.path())
+            .status()
+            .unwrap()
+            .success());
+    }
+
+    fn cargo_nextest_run(&self, args: &[&str]) {
+        assert!(self
+            .cargo()
+            .args(["nextest", "run"])
+            .args(args)
+            .current_dir(self.dir.path())
+            .status()
+            .unwrap()
+            .success());
+    }
+
+    fn build_for_target(&self, target: &str) {
+        assert!(self
+            .cargo()
+            .args(["build", "--target", target])
+            .current_dir(self.dir.path())
+            .status()
+            .unwrap()
+            .success());
+    }
+
+    fn build_with_target_dir(&self, target_dir: &Path) {
+        assert!(self
+            .cargo()
+            .arg("build")
+            .arg("--target-dir")
+            .arg(target_dir)
+            .current_dir(self.dir.path())
+            .status()
+            .unwrap()
+            .success());
+    }
+
     fn build(&self) {
         assert!(self
             .cargo()
@@ -219,6 +5437,226 @@ impl Package {
             .unwrap()
             .success());
     }
+
+    // Depend on "rerun-fixture" (and nothing else from crates.io),
+    // and pass on everything its build script tells us.
+    fn use_fixture_registry(&self, registry: &FixtureRegistry) {
+        let cargo_config_dir = self.dir.path().join(".cargo");
+        std::fs::create_dir_all(&cargo_config_dir).unwrap();
+        std::fs::write(
+            cargo_config_dir.join("config.toml"),
+            format!(
+                "[source.crates-io]\nreplace-with = \"fixtures\"\n\n\
+                 [source.fixtures]\ndirectory = {:?}\n",
+                registry.path()
+            ),
+        )
+        .unwrap();
+        self.append_to_manifest("rerun-fixture = \"0.1.0\"\n");
+        std::fs::write(
+            self.dir.path().join("build.rs"),
+            "fn main() {\n    \
+                 let links_env = std::env::var(\"DEP_RERUN_FIXTURE_ENV\").unwrap();\n    \
+                 println!(\"cargo:rustc-env=LINKS_ENV={links_env}\");\n\
+             }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            self.dir.path().join("src/main.rs"),
+            "fn main() {\n    \
+                 println!(\"{} {} {}\", rerun_fixture::FILE, rerun_fixture::ENV, env!(\"LINKS_ENV\"));\n\
+             }\n",
+        )
+        .unwrap();
+    }
+
+    // Build a package set up by `use_fixture_registry`, and run it.
+    //
+    // Returns whether Cargo considered "rerun-fixture" fresh,
+    // and what the binary printed.
+    fn build_fixture_user(&self, env: &[(&str, &str)]) -> (bool, String) {
+        let output = self
+            .cargo()
+            .args(["build", "--message-format=json"])
+            .envs(env.iter().copied())
+            .current_dir(self.dir.path())
+            .stdout(Stdio::piped())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let fixture_fresh = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|message| {
+                message["reason"] == "compiler-artifact"
+                    && message["target"]["name"] == "rerun_fixture"
+            })
+            .unwrap()["fresh"]
+            .as_bool()
+            .unwrap();
+        let output = Command::new(self.dir.path().join("target/debug/foo"))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        (fixture_fresh, String::from_utf8(output.stdout).unwrap())
+    }
+}
+
+// A Cargo home that shares the real registry index and downloaded crates,
+// but has its own extracted sources, so tests can modify them freely.
+struct PrivateCargoHome {
+    dir: TempDir,
+}
+
+impl PrivateCargoHome {
+    fn new() -> Self {
+        let real_cargo_home = env::var("CARGO_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(env::var("HOME").unwrap()).join(".cargo"));
+        let dir = tempdir().unwrap();
+        let registry_dir = dir.path().join("registry");
+        std::fs::create_dir_all(registry_dir.join("src")).unwrap();
+        for shared in ["index", "cache"] {
+            std::os::unix::fs::symlink(
+                real_cargo_home.join("registry").join(shared),
+                registry_dir.join(shared),
+            )
+            .unwrap();
+        }
+        Self { dir }
+    }
+
+    fn path(&self) -> &str {
+        self.dir.path().to_str().unwrap()
+    }
+
+    // Find the extracted source dir for a package, by name prefix.
+    fn registry_checkout(&self, package_dir_prefix: &str) -> PathBuf {
+        let src_dir = self.dir.path().join("registry").join("src");
+        for index_dir in std::fs::read_dir(src_dir).unwrap() {
+            for package_dir in std::fs::read_dir(index_dir.unwrap().path()).unwrap() {
+                let package_dir = package_dir.unwrap();
+                if package_dir
+                    .file_name()
+                    .to_str()
+                    .unwrap()
+                    .starts_with(package_dir_prefix)
+                {
+                    return package_dir.path();
+                }
+            }
+        }
+        panic!("Couldn't find registry checkout for {package_dir_prefix}");
+    }
+}
+
+// A directory source containing just the "rerun-fixture" crate from
+// "tests/fixtures", for exercising Cargo's `rerun-if` rules.
+//
+// Hope only caches crates from crates.io, which it recognizes by
+// the registry checkout's directory name, so we borrow that name.
+struct FixtureRegistry {
+    dir: TempDir,
+}
+
+impl FixtureRegistry {
+    fn new() -> Self {
+        let registry = Self {
+            dir: tempdir().unwrap(),
+        };
+        copy_dir(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/rerun-fixture"),
+            &registry.package_dir(),
+        );
+        std::fs::write(
+            registry.package_dir().join(".cargo-checksum.json"),
+            r#"{"files":{}}"#,
+        )
+        .unwrap();
+        registry
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.path().join("index.crates.io-fixtures")
+    }
+
+    fn package_dir(&self) -> PathBuf {
+        self.path().join("rerun-fixture-0.1.0")
+    }
+
+    fn write_input_file(&self, content: &str) {
+        std::fs::write(self.package_dir().join("input.txt"), content).unwrap();
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()));
+        } else {
+            std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
+// Is the standard library for this target available to the default `rustc`?
+fn target_is_installed(target: &str) -> bool {
+    let output = Command::new("rustc")
+        .args(["--print", "target-libdir", "--target", target])
+        .output()
+        .unwrap();
+    output.status.success() && Path::new(String::from_utf8(output.stdout).unwrap().trim()).exists()
+}
+
+// Write a shell script that claims to be some other C compiler,
+// but is really the system one; returns its path.
+fn fake_c_compiler(dir: &Path, name: &str, version: &str) -> String {
+    let path = dir.join(name);
+    std::fs::write(
+        &path,
+        format!(
+            "#!/bin/sh\nif [ \"$1\" = --version ]; then echo '{version}'; exit 0; fi\nexec cc \"$@\"\n"
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+// Write a shell script that runs the real `rustc`, but appends a line to
+// `log_path` every time it's asked for its version while building a unit
+// (rather than by Cargo itself); returns its path.
+fn version_counting_rustc(dir: &Path, log_path: &Path) -> String {
+    let path = dir.join("rustc");
+    std::fs::write(
+        &path,
+        format!(
+            "#!/bin/sh\nif [ \"$1\" = -vV ] && [ -n \"$CARGO_CRATE_NAME\" ]; then echo >> '{}'; fi\nexec rustc \"$@\"\n",
+            log_path.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+// Write a shell script that runs Hope as the `rustc` wrapper,
+// but with a different `-C metadata` than Cargo asked for,
+// leaving `-C extra-filename` (and so the unit name) alone; returns its path.
+fn metadata_changing_wrapper(dir: &Path) -> String {
+    let path = dir.join("hope-with-other-metadata");
+    std::fs::write(
+        &path,
+        format!(
+            "#!/bin/sh\nfor arg; do\n    shift\n    case \"$arg\" in\n        metadata=*) arg=\"$arg-other\" ;;\n    esac\n    set -- \"$@\" \"$arg\"\ndone\nexec '{WRAPPER_PATH}' \"$@\"\n"
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+    path.to_str().unwrap().to_owned()
 }
 
 fn filter_push_crate_outputs_events(
@@ -240,6 +5678,72 @@ fn filter_push_crate_outputs_events(
         .collect()
 }
 
+// The values given for each option in a `rustc` command line (and its
+// input), split up the way `rustc` itself would: only a few options take
+// comma-separated lists, and everything else is taken whole.
+fn rustc_arg_values(args: &[String]) -> (Option<String>, HashMap<String, Vec<String>>) {
+    const FLAGS: &[&str] = &["test", "g", "O", "V", "version", "v", "verbose"];
+    const LISTS: &[&str] = &["emit", "json", "print"];
+    let mut input = None;
+    let mut values: HashMap<String, Vec<String>> = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (option, value) = if let Some(long) = arg.strip_prefix("--") {
+            match long.split_once('=') {
+                Some((option, value)) => (option, Some(value)),
+                None => (long, None),
+            }
+        } else if let Some(short) = arg.strip_prefix('-') {
+            let (option, value) = short.split_at(1);
+            (option, Some(value).filter(|value| !value.is_empty()))
+        } else {
+            input = Some(arg.clone());
+            continue;
+        };
+        let option = match option {
+            "codegen" => "C",
+            "warn" => "W",
+            "allow" => "A",
+            "deny" => "D",
+            "forbid" => "F",
+            option => option,
+        };
+        let option_values = values.entry(option.to_owned()).or_default();
+        if FLAGS.contains(&option) {
+            option_values.push(String::new());
+            continue;
+        }
+        let value = value.unwrap_or_else(|| args.next().unwrap());
+        if LISTS.contains(&option) {
+            option_values.extend(value.split(',').map(str::to_owned));
+        } else {
+            option_values.push(value.to_owned());
+        }
+    }
+    (input, values)
+}
+
+// Split a command line as Cargo prints it (quoting arguments
+// the way a POSIX shell would) back into arguments.
+fn shell_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                word.extend(chars.by_ref().take_while(|c| *c != '\''));
+            }
+            '\\' => word.get_or_insert_with(String::new).extend(chars.next()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
 fn filter_pull_crate_outputs_events(
     log: &[CacheLogLine],
     crate_name: &str,
@@ -259,6 +5763,57 @@ fn filter_pull_crate_outputs_events(
         .collect()
 }
 
+fn filter_compile_crate_events(log: &[CacheLogLine], crate_name: &str) -> Vec<CompileCrateEvent> {
+    log.iter()
+        .filter_map(|line| match line {
+            CacheLogLine::CompiledCrate(compile_event) => {
+                if compile_event.crate_unit_name.starts_with(crate_name) {
+                    Some(compile_event)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+        .cloned()
+        .collect()
+}
+
+fn filter_skipped_push_events(log: &[CacheLogLine], crate_name: &str) -> Vec<SkipPushEvent> {
+    log.iter()
+        .filter_map(|line| match line {
+            CacheLogLine::SkippedPush(skip_event) => {
+                if skip_event.crate_unit_name.starts_with(crate_name) {
+                    Some(skip_event)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+        .cloned()
+        .collect()
+}
+
+fn filter_portability_check_events(
+    log: &[CacheLogLine],
+    crate_name: &str,
+) -> Vec<PortabilityCheckEvent> {
+    log.iter()
+        .filter_map(|line| match line {
+            CacheLogLine::CheckedPortability(check_event) => {
+                if check_event.crate_unit_name.starts_with(crate_name) {
+                    Some(check_event)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+        .cloned()
+        .collect()
+}
+
 fn filter_ran_build_script_events(
     log: &[CacheLogLine],
     crate_name: &str,
@@ -0,0 +1,360 @@
+//! A long-running soak test against real-world projects.
+//!
+//! The integration tests build tiny packages against a fresh cache, which
+//! is good for pinning down behaviour but can't notice things that only go
+//! wrong over time, at scale, or with crates that do odd things in their
+//! build scripts. So this builds a few popular projects against a cache that
+//! persists from one run to the next (e.g. nightly in CI, across toolchain
+//! updates), and keeps a history of how it went.
+//!
+//! It only runs with `HOPE_SOAK_DIR` set, to a dir to keep the cache,
+//! checkouts, and "history.jsonl" in:
+//!
+//! ```text
+//! HOPE_SOAK_DIR=~/hope-soak cargo test --release --test soak -- --nocapture
+//! ```
+//!
+//! Each run picks the next few [`PROJECTS`] in rotation (or those named in
+//! `HOPE_SOAK_PROJECTS`, comma-separated), and for each one:
+//!
+//! 1. Builds it in a fresh target dir, pulling whatever the cache already
+//!    has from earlier runs; how much that is gets recorded, so that a hit
+//!    rate that slowly drops over the nights shows up in the history.
+//! 2. Builds it again in another fresh target dir, where every unit that the
+//!    first build pulled or pushed should be pulled, not compiled.
+//! 3. Runs its tests (or the like) in that second target dir, so that
+//!    they use what came out of the cache.
+//!
+//! Projects are pinned to a release, but most of them don't lock their
+//! dependencies for tests and examples, so those drift from night to night
+//! like they would for anyone else building them.
+
+use std::{
+    collections::BTreeSet,
+    env,
+    io::Write as _,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Instant,
+};
+
+use hope_cache_log::{read_log, CacheLogLine};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+
+const WRAPPER_PATH: &str = env!("CARGO_BIN_EXE_hope");
+
+struct Project {
+    name: &'static str,
+    repo: &'static str,
+    /// Tag to check out.
+    rev: &'static str,
+    /// Arguments for Cargo to build it.
+    build: &'static [&'static str],
+    /// Arguments for Cargo to check that what it built works.
+    check: &'static [&'static str],
+}
+
+const PROJECTS: &[Project] = &[
+    Project {
+        name: "ripgrep",
+        repo: "https://github.com/BurntSushi/ripgrep",
+        rev: "14.1.1",
+        build: &["build", "--workspace"],
+        check: &["test", "--workspace"],
+    },
+    Project {
+        name: "tokio-examples",
+        repo: "https://github.com/tokio-rs/tokio",
+        rev: "tokio-1.41.1",
+        build: &["build", "-p", "examples", "--examples"],
+        check: &["test", "-p", "tokio-util"],
+    },
+    Project {
+        name: "fd",
+        repo: "https://github.com/sharkdp/fd",
+        rev: "v10.2.0",
+        build: &["build"],
+        check: &["test"],
+    },
+    Project {
+        name: "hyperfine",
+        repo: "https://github.com/sharkdp/hyperfine",
+        rev: "v1.19.0",
+        build: &["build"],
+        check: &["test"],
+    },
+];
+
+/// How many projects each run builds, unless `HOPE_SOAK_PROJECTS` says.
+const PROJECTS_PER_RUN: usize = 2;
+
+/// How many earlier runs of a project to show alongside this one.
+const HISTORY_SHOWN: usize = 7;
+
+/// How one project went in one run, as kept in "history.jsonl".
+#[derive(Debug, Serialize, Deserialize)]
+struct Run {
+    date: String,
+    project: String,
+    rev: String,
+    rustc: String,
+    first: BuildCounts,
+    second: BuildCounts,
+    /// Units that the second build compiled even though
+    /// the first one pulled or pushed them.
+    rebuilt: Vec<String>,
+    check_passed: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BuildCounts {
+    pulls: usize,
+    compiles: usize,
+    pushes: usize,
+    secs: f64,
+}
+
+impl BuildCounts {
+    fn hit_rate(&self) -> f64 {
+        let units = self.pulls + self.compiles;
+        if units == 0 {
+            return 0.0;
+        }
+        self.pulls as f64 / units as f64
+    }
+}
+
+#[test]
+fn soak() {
+    let Some(soak_dir) = env::var_os("HOPE_SOAK_DIR").map(PathBuf::from) else {
+        eprintln!("Skipping soak test; set HOPE_SOAK_DIR to run it");
+        return;
+    };
+    let cache_dir = soak_dir.join("cache");
+    std::fs::create_dir_all(&cache_dir).unwrap();
+
+    let rustc = rustc_version();
+    let date = chrono::Utc::now().date_naive();
+    let mut failures = Vec::new();
+    for project in projects_for(date) {
+        let run = soak_project(&soak_dir, &cache_dir, project, &rustc, &date.to_string());
+        print_history(&soak_dir, &run);
+        if !run.rebuilt.is_empty() {
+            failures.push(format!(
+                "{}: rebuilt units that should have come from the cache: {}",
+                project.name,
+                run.rebuilt.join(", ")
+            ));
+        }
+        if !run.check_passed {
+            failures.push(format!(
+                "{}: 'cargo {}' failed with what came from the cache",
+                project.name,
+                project.check.join(" ")
+            ));
+        }
+        let mut history = std::fs::File::options()
+            .create(true)
+            .append(true)
+            .open(soak_dir.join("history.jsonl"))
+            .unwrap();
+        writeln!(history, "{}", serde_json::to_string(&run).unwrap()).unwrap();
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// The projects to build today: the next few in rotation, so that
+/// each of them comes around every couple of nights.
+fn projects_for(date: chrono::NaiveDate) -> Vec<&'static Project> {
+    let names = env::var("HOPE_SOAK_PROJECTS").unwrap_or_default();
+    if !names.trim().is_empty() {
+        return names
+            .split(',')
+            .map(|name| {
+                let name = name.trim();
+                PROJECTS
+                    .iter()
+                    .find(|project| project.name == name)
+                    .unwrap_or_else(|| panic!("No soak project called {name:?}"))
+            })
+            .collect();
+    }
+    let day = date
+        .signed_duration_since(chrono::NaiveDate::default())
+        .num_days() as usize;
+    let first = day * PROJECTS_PER_RUN;
+    (first..first + PROJECTS_PER_RUN)
+        .map(|i| &PROJECTS[i % PROJECTS.len()])
+        .collect()
+}
+
+fn soak_project(
+    soak_dir: &Path,
+    cache_dir: &Path,
+    project: &Project,
+    rustc: &str,
+    date: &str,
+) -> Run {
+    eprintln!("Soaking {} {}", project.name, project.rev);
+    let checkout = checkout(soak_dir, project);
+    // Downloading crates shouldn't count towards how long builds take.
+    let status = Command::new("cargo")
+        .arg("fetch")
+        .current_dir(&checkout)
+        .status()
+        .unwrap();
+    assert!(
+        status.success(),
+        "'cargo fetch' failed for {}",
+        project.name
+    );
+
+    let first_target_dir = tempdir().unwrap();
+    let (first, first_log) = build(cache_dir, &checkout, first_target_dir.path(), project.build);
+    let second_target_dir = tempdir().unwrap();
+    let (second, second_log) = build(
+        cache_dir,
+        &checkout,
+        second_target_dir.path(),
+        project.build,
+    );
+
+    let cached: BTreeSet<&str> = first_log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::PulledCrateOutputs(event) => Some(event.crate_unit_name.as_str()),
+            CacheLogLine::PushedCrateOutputs(event) => Some(event.crate_unit_name.as_str()),
+            _ => None,
+        })
+        .collect();
+    let rebuilt = second_log
+        .iter()
+        .filter_map(|line| match line {
+            CacheLogLine::CompiledCrate(event) => Some(event.crate_unit_name.as_str()),
+            _ => None,
+        })
+        .filter(|name| cached.contains(name))
+        .map(str::to_owned)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    let check_passed = hope_cargo(cache_dir, &checkout, second_target_dir.path())
+        .args(project.check)
+        .status()
+        .unwrap()
+        .success();
+
+    Run {
+        date: date.to_owned(),
+        project: project.name.to_owned(),
+        rev: project.rev.to_owned(),
+        rustc: rustc.to_owned(),
+        first,
+        second,
+        rebuilt,
+        check_passed,
+    }
+}
+
+/// Where the project's source is, cloning it first if need be.
+fn checkout(soak_dir: &Path, project: &Project) -> PathBuf {
+    let dir = soak_dir
+        .join("checkouts")
+        .join(format!("{}-{}", project.name, project.rev));
+    if !dir.exists() {
+        let status = Command::new("git")
+            .args(["clone", "--quiet", "--depth", "1", "--branch"])
+            .arg(project.rev)
+            .arg(project.repo)
+            .arg(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "Failed to clone {}", project.repo);
+    }
+    dir
+}
+
+/// Build in `target_dir`, and count what happened in the log meanwhile.
+fn build(
+    cache_dir: &Path,
+    checkout: &Path,
+    target_dir: &Path,
+    args: &[&str],
+) -> (BuildCounts, Vec<CacheLogLine>) {
+    let logged_before = read_log(cache_dir).map_or(0, |log| log.len());
+    let started = Instant::now();
+    let status = hope_cargo(cache_dir, checkout, target_dir)
+        .args(args)
+        .status()
+        .unwrap();
+    let secs = started.elapsed().as_secs_f64();
+    assert!(status.success(), "'cargo {}' failed", args.join(" "));
+
+    let log: Vec<_> = read_log(cache_dir)
+        .unwrap()
+        .into_iter()
+        .skip(logged_before)
+        .collect();
+    let mut counts = BuildCounts {
+        secs,
+        ..BuildCounts::default()
+    };
+    for line in &log {
+        match line {
+            CacheLogLine::PulledCrateOutputs(_) => counts.pulls += 1,
+            CacheLogLine::CompiledCrate(_) => counts.compiles += 1,
+            CacheLogLine::PushedCrateOutputs(_) => counts.pushes += 1,
+            _ => {}
+        }
+    }
+    (counts, log)
+}
+
+fn hope_cargo(cache_dir: &Path, checkout: &Path, target_dir: &Path) -> Command {
+    let mut command = Command::new("cargo");
+    command
+        .current_dir(checkout)
+        .env("RUSTC_WRAPPER", WRAPPER_PATH)
+        .env("HOPE_CACHE_DIR", cache_dir)
+        .env("CARGO_TARGET_DIR", target_dir);
+    command
+}
+
+fn rustc_version() -> String {
+    let output = Command::new("rustc").arg("--version").output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap().trim().to_owned()
+}
+
+/// Show this run of a project alongside the last few,
+/// so that trends are easy to spot in CI logs.
+fn print_history(soak_dir: &Path, run: &Run) {
+    let history = std::fs::read_to_string(soak_dir.join("history.jsonl")).unwrap_or_default();
+    let earlier: Vec<Run> = history
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .filter(|earlier: &Run| earlier.project == run.project)
+        .collect();
+    println!("{} {}:", run.project, run.rev);
+    println!(
+        "  {:<10}  {:>9}  {:>9}  {:>9}  {:>9}  {:<5}  rustc",
+        "date", "hits", "1st secs", "2nd secs", "rebuilt", "check"
+    );
+    for run in earlier[earlier.len().saturating_sub(HISTORY_SHOWN)..]
+        .iter()
+        .chain([run])
+    {
+        println!(
+            "  {:<10}  {:>8.1}%  {:>9.1}  {:>9.1}  {:>9}  {:<5}  {}",
+            run.date,
+            run.first.hit_rate() * 100.0,
+            run.first.secs,
+            run.second.secs,
+            run.rebuilt.len(),
+            if run.check_passed { "ok" } else { "FAIL" },
+            run.rustc
+        );
+    }
+}